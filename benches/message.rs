@@ -0,0 +1,120 @@
+//! Baseline of the hot discovery v4 path: message encoding and decoding, packet signing
+//! and verification, and the distance computation behind every table operation.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use devp2p_rs::disc::v4::{
+    kad::distance,
+    message::*,
+    packet::{decode_packet, encode_packet},
+    NodeId, NodeRecord,
+};
+use ethereum_types::H256;
+use fastrlp::Encodable;
+use secp256k1::SecretKey;
+use std::net::Ipv4Addr;
+
+const EXPIRE: u64 = 2_000_000_000;
+
+fn record(i: u8) -> NodeRecord {
+    NodeRecord {
+        address: Ip(Ipv4Addr::new(10, 0, 0, i).into()),
+        tcp_port: 30303,
+        udp_port: 30303,
+        id: NodeId::random(),
+    }
+}
+
+/// One message of each type, Neighbours with the full [`MAX_NEIGHBOURS`] nodes.
+fn messages(secret_key: &SecretKey) -> Vec<(&'static str, Message)> {
+    let (from, to) = (record(1), record(2));
+    vec![
+        (
+            "ping",
+            Message::Ping(PingMessage {
+                version: PROTOCOL_VERSION,
+                from: from.into(),
+                to: to.into(),
+                expire: EXPIRE,
+                enr_seq: Some(1),
+            }),
+        ),
+        (
+            "pong",
+            Message::Pong(PongMessage::respond_to(H256::random(), to.into(), EXPIRE)),
+        ),
+        (
+            "find_node",
+            Message::FindNode(FindNodeMessage {
+                id: NodeId::random(),
+                expire: EXPIRE,
+            }),
+        ),
+        (
+            "neighbours",
+            Message::Neighbours(NeighboursMessage {
+                nodes: (0..MAX_NEIGHBOURS as u8).map(record).collect(),
+                expire: EXPIRE,
+            }),
+        ),
+        (
+            "enr_request",
+            Message::EnrRequest(EnrRequestMessage { expire: EXPIRE }),
+        ),
+        (
+            "enr_response",
+            Message::EnrResponse(EnrResponseMessage::new(
+                H256::random(),
+                from.to_enr(secret_key).unwrap(),
+            )),
+        ),
+    ]
+}
+
+fn message(c: &mut Criterion) {
+    let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    for (name, message) in messages(&secret_key) {
+        let mut group = c.benchmark_group(name);
+        group.bench_function("encode", |b| {
+            b.iter(|| {
+                let mut out = Vec::with_capacity(message.length());
+                black_box(&message).encode(&mut out);
+                out
+            })
+        });
+        let mut encoded = Vec::new();
+        message.encode(&mut encoded);
+        let packet_type = message.packet_type();
+        group.bench_function("decode", |b| {
+            b.iter(|| Message::decode(packet_type, &mut black_box(&encoded[..])).unwrap())
+        });
+        group.finish();
+    }
+}
+
+fn packet(c: &mut Criterion) {
+    let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    let message = Message::FindNode(FindNodeMessage {
+        id: NodeId::random(),
+        expire: EXPIRE,
+    });
+    let datagram = encode_packet(&message, &secret_key);
+
+    let mut group = c.benchmark_group("packet");
+    group.bench_function("sign", |b| {
+        b.iter(|| encode_packet(black_box(&message), &secret_key))
+    });
+    group.bench_function("verify", |b| {
+        b.iter(|| decode_packet(black_box(&datagram)).unwrap().node_id)
+    });
+    group.finish();
+}
+
+fn kad(c: &mut Criterion) {
+    let (n1, n2) = (NodeId::random(), NodeId::random());
+    c.bench_function("distance", |b| {
+        b.iter(|| distance(black_box(n1), black_box(n2)))
+    });
+}
+
+criterion_group!(benches, message, packet, kad);
+criterion_main!(benches);
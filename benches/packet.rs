@@ -0,0 +1,56 @@
+//! Encoding of outgoing discovery v4 packets, allocating a datagram per packet versus
+//! reusing one buffer.
+
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use devp2p_rs::disc::v4::{
+    message::{FindNodeMessage, Ip, Message, NeighboursMessage, MAX_NEIGHBOURS},
+    packet::{encode_packet, encode_packet_into, MAX_PACKET_SIZE},
+    NodeId, NodeRecord,
+};
+use secp256k1::SecretKey;
+use std::net::Ipv4Addr;
+
+fn messages() -> [(&'static str, Message); 2] {
+    [
+        (
+            "find_node",
+            Message::FindNode(FindNodeMessage {
+                id: NodeId::random(),
+                expire: 2_000_000_000,
+            }),
+        ),
+        (
+            "neighbours",
+            Message::Neighbours(NeighboursMessage {
+                nodes: (0..MAX_NEIGHBOURS)
+                    .map(|_| NodeRecord {
+                        address: Ip(Ipv4Addr::new(10, 0, 0, 1).into()),
+                        tcp_port: 30303,
+                        udp_port: 30303,
+                        id: NodeId::random(),
+                    })
+                    .collect(),
+                expire: 2_000_000_000,
+            }),
+        ),
+    ]
+}
+
+fn encode(c: &mut Criterion) {
+    let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    for (name, message) in messages() {
+        let mut group = c.benchmark_group(name);
+        group.bench_function("encode_packet", |b| {
+            b.iter(|| encode_packet(black_box(&message), &secret_key))
+        });
+        group.bench_function("encode_packet_into", |b| {
+            let mut buf = BytesMut::with_capacity(MAX_PACKET_SIZE);
+            b.iter(|| encode_packet_into(black_box(&message), &secret_key, &mut buf))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
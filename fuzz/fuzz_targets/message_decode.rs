@@ -0,0 +1,16 @@
+//! Decoding arbitrary datagrams never panics.
+
+#![no_main]
+
+use devp2p_rs::disc::{v4, v5};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = v4::packet::decode_packet(data);
+    let _ = v5::session::decode_packet(&v5::NodeId::zero(), data);
+
+    if let Some((&message_type, message_data)) = data.split_first() {
+        let _ = v4::message::Message::decode(message_type, &mut &*message_data);
+        let _ = v5::message::Message::decode(message_type, &mut &*message_data);
+    }
+});
@@ -0,0 +1,29 @@
+//! Every encodable discovery v4 message decodes back into itself.
+
+#![no_main]
+
+use devp2p_rs::disc::v4::message::{Message, MessageError, MAX_NEIGHBOURS};
+use fastrlp::Encodable;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|message: Message| {
+    let mut data = Vec::new();
+    message.encode(&mut data);
+    assert_eq!(data.len(), message.length());
+
+    let buf = &mut &data[..];
+    match Message::decode(message.packet_type(), buf) {
+        Ok(decoded) => {
+            assert!(buf.is_empty());
+
+            // Messages don't implement `PartialEq`, so compare the encodings instead.
+            let mut reencoded = Vec::new();
+            decoded.encode(&mut reencoded);
+            assert_eq!(reencoded, data);
+        }
+        Err(MessageError::TooManyNeighbours) => {
+            assert!(matches!(message, Message::Neighbours(m) if m.nodes.len() > MAX_NEIGHBOURS));
+        }
+        Err(e) => panic!("failed to decode {:?}: {}", message, e),
+    }
+});
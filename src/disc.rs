@@ -1,6 +1,7 @@
 use crate::{NodeRecord, PeerId};
 use async_stream::stream;
-use futures::{stream::BoxStream, StreamExt};
+use futures::{ready, stream::BoxStream, StreamExt};
+use lru::LruCache;
 use std::{collections::HashMap, net::SocketAddr, pin::Pin, task::Poll, time::Duration};
 use tokio::time::sleep;
 use tokio_stream::Stream;
@@ -8,6 +9,8 @@ use tokio_stream::Stream;
 pub mod v4;
 pub use self::v4::{Discv4, Discv4Builder};
 
+pub mod v5;
+
 pub mod dns;
 
 pub use self::dns::DnsDiscovery;
@@ -39,3 +42,103 @@ impl Stream for StaticNodes {
         self.0.poll_next_unpin(cx)
     }
 }
+
+/// Discovered nodes that were not seen recently: only the first sighting of a node is
+/// yielded, and optionally its move to another endpoint.
+///
+/// The last `capacity` nodes seen are remembered, so that memory stays bounded on a crawler
+/// that sees millions of them, and a node that fell out is yielded again. Errors of the
+/// inner stream are passed through.
+pub struct NewNodes<S> {
+    inner: S,
+    seen: LruCache<PeerId, SocketAddr>,
+    endpoint_changes: bool,
+}
+
+impl<S> NewNodes<S> {
+    /// New nodes of `inner`, remembering the last `capacity` ones, at least one.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            seen: LruCache::new(capacity.max(1)),
+            endpoint_changes: false,
+        }
+    }
+
+    /// Yield a node again once it shows up at another address than it was seen at last.
+    pub fn with_endpoint_changes(mut self, endpoint_changes: bool) -> Self {
+        self.endpoint_changes = endpoint_changes;
+        self
+    }
+}
+
+impl<S> Stream for NewNodes<S>
+where
+    S: Stream<Item = anyhow::Result<NodeRecord>> + Unpin,
+{
+    type Item = anyhow::Result<NodeRecord>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            let record = match ready!(self.inner.poll_next_unpin(cx)) {
+                Some(Ok(record)) => record,
+                other => return Poll::Ready(other),
+            };
+            let endpoint_changes = self.endpoint_changes;
+            match self.seen.put(record.id, record.addr) {
+                Some(addr) if !endpoint_changes || addr == record.addr => continue,
+                _ => return Poll::Ready(Some(Ok(record))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    #[tokio::test]
+    async fn new_nodes_only() {
+        let record = |id: u64, port| NodeRecord {
+            id: PeerId::from_low_u64_be(id),
+            addr: SocketAddr::from(([10, 0, 0, 1], port)),
+        };
+        let records = [
+            record(1, 30303),
+            record(2, 30303),
+            record(1, 30303),
+            record(1, 30304),
+            record(3, 30303),
+            // Evicted by now with the capacity of two.
+            record(2, 30303),
+        ];
+        let yielded = |endpoint_changes| {
+            NewNodes::new(stream::iter(records.map(Ok)), 2)
+                .with_endpoint_changes(endpoint_changes)
+                .map(|record| {
+                    let record = record.unwrap();
+                    (record.id.to_low_u64_be(), record.addr.port())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            yielded(false).await,
+            [(1, 30303), (2, 30303), (3, 30303), (2, 30303)]
+        );
+        assert_eq!(
+            yielded(true).await,
+            [(1, 30303), (2, 30303), (1, 30304), (3, 30303), (2, 30303)]
+        );
+
+        let mut errors = NewNodes::new(
+            stream::iter([Err::<NodeRecord, _>(anyhow::anyhow!("failed"))]),
+            1,
+        );
+        assert!(errors.next().await.unwrap().is_err());
+    }
+}
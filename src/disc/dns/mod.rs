@@ -1,4 +1,7 @@
-use crate::{util::pk2id, NodeRecord};
+use crate::{
+    util::{keccak256, pk2id},
+    NodeRecord,
+};
 use anyhow::{anyhow, bail};
 use arrayvec::ArrayString;
 use async_stream::{stream, try_stream};
@@ -8,6 +11,7 @@ use derive_more::{Deref, Display};
 use educe::Educe;
 use enr::{Enr, EnrKeyUnambiguous, EnrPublicKey};
 use maplit::hashset;
+use parking_lot::Mutex;
 use secp256k1::{PublicKey, SecretKey};
 use std::{
     collections::{HashMap, HashSet},
@@ -25,10 +29,17 @@ use tokio_stream::{Stream, StreamExt};
 use tracing::*;
 
 mod backend;
-pub use self::backend::Backend;
+mod tree;
+pub use self::{
+    backend::Backend,
+    tree::{tree_link, Tree, TreeError, MAX_BRANCH_CHILDREN, MAX_RECORD_LEN},
+};
 
 type Base32Hash = ArrayString<BASE32_HASH_LEN>;
 
+/// Names already resolved during a query, so that subtrees linking back to them are skipped.
+type Visited = Arc<Mutex<HashSet<String>>>;
+
 pub type QueryStream<K> = Pin<Box<dyn Stream<Item = anyhow::Result<Enr<K>>> + Send + 'static>>;
 
 pub const BASE32_HASH_LEN: usize = 26;
@@ -122,6 +133,19 @@ impl Stream for DnsDiscovery {
 #[error("Invalid Enr: {0}")]
 pub struct InvalidEnr(String);
 
+#[derive(Debug, Error)]
+#[error("Hash mismatch for {fqdn}: record hashes to {computed}")]
+pub struct HashMismatch {
+    pub fqdn: String,
+    pub computed: Base32Hash,
+}
+
+/// Subdomain of the record in the tree: base32 of the first 16 bytes of its keccak256 hash.
+fn record_hash(record: &str) -> Base32Hash {
+    Base32Hash::from(&BASE32_NOPAD.encode(&keccak256(record.as_bytes())[..16]))
+        .expect("16 bytes are encoded into exactly BASE32_HASH_LEN characters")
+}
+
 fn debug_bytes(b: &Bytes, f: &mut std::fmt::Formatter) -> std::fmt::Result {
     write!(f, "{}", hex::encode(b))
 }
@@ -169,7 +193,7 @@ impl Display for RootRecord {
             f,
             "{} sig={}",
             self.base,
-            BASE64.encode(self.signature.as_ref())
+            BASE64URL_NOPAD.encode(self.signature.as_ref())
         )
     }
 }
@@ -331,10 +355,15 @@ fn resolve_branch<B: Backend, K: EnrKeyUnambiguous>(
     host: String,
     children: HashSet<Base32Hash>,
     kind: BranchKind<K::PublicKey>,
+    visited: Visited,
 ) -> QueryStream<K> {
     let (tx, mut branches_res) = tokio::sync::mpsc::channel(1);
     for subdomain in &children {
         let fqdn = format!("{}.{}", subdomain, host);
+        if !visited.lock().insert(fqdn.clone()) {
+            trace!("Skipping already resolved {}", fqdn);
+            continue;
+        }
         task_group.spawn_with_name(format!("DNS discovery: {}", fqdn), {
             let subdomain = *subdomain;
             let tx = tx.clone();
@@ -343,18 +372,24 @@ fn resolve_branch<B: Backend, K: EnrKeyUnambiguous>(
             let kind = kind.clone();
             let fqdn = fqdn.clone();
             let task_group = task_group.clone();
+            let visited = visited.clone();
             async move {
                 if let Err(e) = {
                     let tx = tx.clone();
                     async move {
-                        let record = backend.get_record(fqdn).await?;
+                        let record = backend.get_record(fqdn.clone()).await?;
                         if let Some(record) = record {
                             trace!("Resolved record {}: {:?}", subdomain, record);
-                            let record = record.parse()?;
-                            match record {
+                            let parsed = record.parse::<DnsRecord<K>>()?;
+                            let computed = record_hash(&record);
+                            if computed != subdomain {
+                                return Err(HashMismatch { fqdn, computed }.into());
+                            }
+                            match parsed {
                                 DnsRecord::Branch { children } => {
-                                    let mut t =
-                                        resolve_branch(task_group, backend, host, children, kind);
+                                    let mut t = resolve_branch(
+                                        task_group, backend, host, children, kind, visited,
+                                    );
                                     while let Some(item) = t.try_next().await? {
                                         let _ = tx.send(Ok(item)).await;
                                     }
@@ -375,6 +410,7 @@ fn resolve_branch<B: Backend, K: EnrKeyUnambiguous>(
                                                 Some(public_key),
                                                 None,
                                                 remote_whitelist.clone(),
+                                                visited,
                                             );
                                             while let Some(item) = t.try_next().await? {
                                                 let _ = tx.send(Ok(item)).await;
@@ -440,8 +476,14 @@ fn resolve_tree<B: Backend, K: EnrKeyUnambiguous>(
     public_key: Option<K::PublicKey>,
     seen_sequence: Option<usize>,
     remote_whitelist: Option<Arc<HashMap<String, K::PublicKey>>>,
+    visited: Visited,
 ) -> QueryStream<K> {
     Box::pin(try_stream! {
+        if !visited.lock().insert(host.clone()) {
+            trace!("Skipping already resolved tree at {}", host);
+            return;
+        }
+
         let task_group = task_group.unwrap_or_default();
         let record = backend.get_record(host.clone()).await?;
         if let Some(record) = &record {
@@ -460,12 +502,12 @@ fn resolve_tree<B: Backend, K: EnrKeyUnambiguous>(
                     }
                 }
 
-                let mut s = resolve_branch(task_group.clone(), backend.clone(), host.clone(), hashset![ *link_root ], BranchKind::Link { remote_whitelist });
+                let mut s = resolve_branch(task_group.clone(), backend.clone(), host.clone(), hashset![ *link_root ], BranchKind::Link { remote_whitelist }, visited.clone());
                 while let Some(record) = s.try_next().await? {
                     yield record;
                 }
 
-                let mut s = resolve_branch(task_group.clone(),backend.clone(), host.clone(), hashset![ *enr_root ], BranchKind::Enr);
+                let mut s = resolve_branch(task_group.clone(),backend.clone(), host.clone(), hashset![ *enr_root ], BranchKind::Enr, visited);
                 while let Some(record) = s.try_next().await? {
                     yield record;
                 }
@@ -522,6 +564,7 @@ impl<B: Backend, K: EnrKeyUnambiguous> Resolver<B, K> {
             public_key,
             self.seen_sequence,
             self.remote_whitelist.clone(),
+            Default::default(),
         )
     }
 
@@ -545,7 +588,7 @@ mod tests {
     use super::*;
     use hex_literal::hex;
     use maplit::hashmap;
-    use secp256k1::{PublicKey, SecretKey};
+    use secp256k1::{PublicKey, SecretKey, SECP256K1};
     use std::collections::{HashMap, HashSet};
 
     fn test_records_to_hashmap(
@@ -620,6 +663,86 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn hash_mismatch() {
+        const TEST_RECORDS: &[(&str, &str)] = &[
+            ("n",                            "enrtree-root:v1 e=INDMVBZEEQ4ESVYAKGIYU74EAA l=C7HRFPF3BLGF3YR4DY5KX3SMBE seq=3 sig=Vl3AmunLur0JZ3sIyJPSH6A3Vvdp4F40jWQeCmkIhmcgwE4VC5U9wpK8C_uL_CMY29fd6FAhspRvq2z_VysTLAA"),
+            ("C7HRFPF3BLGF3YR4DY5KX3SMBE.n", "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@morenodes.example.org"),
+            ("INDMVBZEEQ4ESVYAKGIYU74EAA.n", "enr:-HW4QOFzoVLaFJnNhbgMoDXPnOvcdVuj7pDpqRvh6BRDO68aVi5ZcjB3vzQRZH2IcLBGHzo8uUN3snqmgTiE56CH3AMBgmlkgnY0iXNlY3AyNTZrMaECC2_24YYkYHEgdzxlSNKQEnHhuNAbNlMlWJxrJxbAFvA"),
+        ];
+
+        let data = test_records_to_hashmap_geth(TEST_RECORDS);
+
+        let err = Resolver::<_, SecretKey>::new(Arc::new(data))
+            .query("n", None)
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref::<HashMismatch>(), Some(e) if e.fqdn == "INDMVBZEEQ4ESVYAKGIYU74EAA.n" && e.computed.as_str() == "2XS2367YHAXJFGLZHVAWLQD4ZY"),
+            "{}",
+            err
+        );
+    }
+
+    fn signed_root(secret_key: &SecretKey, enr_root: Base32Hash, link_root: Base32Hash) -> String {
+        let root = UnsignedRoot {
+            enr_root,
+            link_root,
+            sequence: 1,
+        }
+        .to_string();
+        let (rec, sig) = SECP256K1
+            .sign_ecdsa_recoverable(
+                &secp256k1::Message::from_slice(keccak256(root.as_bytes()).as_bytes()).unwrap(),
+                secret_key,
+            )
+            .serialize_compact();
+        let sig = [&sig[..], &[rec.to_i32() as u8]].concat();
+        format!("{} sig={}", root, BASE64URL_NOPAD.encode(&sig))
+    }
+
+    #[tokio::test]
+    async fn link_cycle() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let public_key = PublicKey::from_secret_key(SECP256K1, &secret_key);
+        let link = |domain: &str| {
+            format!(
+                "{}{}@{}",
+                LINK_PREFIX,
+                BASE32_NOPAD.encode(&public_key.serialize()),
+                domain
+            )
+        };
+
+        // Trees of a.org and b.org link to each other.
+        let empty_branch = BRANCH_PREFIX.to_string();
+        let mut data = HashMap::new();
+        for (domain, other) in [("a.org", "b.org"), ("b.org", "a.org")] {
+            let link = link(other);
+            data.insert(
+                domain.to_string(),
+                signed_root(&secret_key, record_hash(&empty_branch), record_hash(&link)),
+            );
+            data.insert(
+                format!("{}.{}", record_hash(&empty_branch), domain),
+                empty_branch.clone(),
+            );
+            data.insert(format!("{}.{}", record_hash(&link), domain), link);
+        }
+
+        let records = Resolver::<_, SecretKey>::new(Arc::new(data))
+            .with_remote_whitelist(Arc::new(hashmap! {
+                "a.org".to_string() => public_key,
+                "b.org".to_string() => public_key,
+            }))
+            .query_tree(link("a.org"))
+            .collect::<Result<Vec<_>, _>>()
+            .await
+            .unwrap();
+        assert!(records.is_empty());
+    }
+
     #[tokio::test]
     async fn bad_node() {
         const TEST_RECORDS: &[(&str, &str)] = &[
@@ -0,0 +1,221 @@
+//! Writer side of EIP-1459: a signed tree of ENRs and links, ready to be published as TXT
+//! records.
+
+use super::{
+    record_hash, Base32Hash, RootRecord, UnsignedRoot, BRANCH_PREFIX, ENR_PREFIX, LINK_PREFIX,
+};
+use crate::util::keccak256;
+use data_encoding::BASE32_NOPAD;
+use enr::{Enr, EnrKeyUnambiguous};
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Longest TXT record value published, a limit most DNS providers are fine with.
+pub const MAX_RECORD_LEN: usize = 2000;
+/// Children of a branch record. Same as go-ethereum, which keeps branches around 370
+/// bytes, so that they fit into a single UDP response along with the rest of the message.
+pub const MAX_BRANCH_CHILDREN: usize = 13;
+
+#[derive(Debug, Error)]
+pub enum TreeError {
+    #[error(
+        "record of {len} bytes is longer than {} bytes: {record}",
+        MAX_RECORD_LEN
+    )]
+    RecordTooLong { record: String, len: usize },
+    #[error("link is not an enrtree:// URL: {0}")]
+    InvalidLink(String),
+}
+
+/// Signed tree of ENRs and links to other trees.
+///
+/// Leaves are sorted, so the same inputs always produce the same records, and only the root
+/// changes with the sequence number.
+#[derive(Clone, Debug)]
+pub struct Tree {
+    root: RootRecord,
+    /// Subdomain of every record but the root, keyed by its hash.
+    records: HashMap<Base32Hash, String>,
+}
+
+impl Tree {
+    /// Tree of `enrs` and `links`, e.g. `enrtree://<key>@nodes.example.org` of other trees,
+    /// signed with `secret_key`. The `sequence` number has to be increased on every update
+    /// for clients to notice it.
+    pub fn new<K: EnrKeyUnambiguous>(
+        enrs: &[Enr<K>],
+        links: &[String],
+        sequence: usize,
+        secret_key: &SecretKey,
+    ) -> Result<Self, TreeError> {
+        for link in links {
+            if !link.starts_with(LINK_PREFIX) || !link.contains('@') {
+                return Err(TreeError::InvalidLink(link.clone()));
+            }
+        }
+
+        let mut records = HashMap::new();
+        let enr_root = build(&mut records, enrs.iter().map(Enr::to_base64).collect())?;
+        let link_root = build(&mut records, links.to_vec())?;
+
+        let base = UnsignedRoot {
+            enr_root,
+            link_root,
+            sequence,
+        };
+        let (recovery_id, signature) = SECP256K1
+            .sign_ecdsa_recoverable(
+                &secp256k1::Message::from_slice(keccak256(base.to_string().as_bytes()).as_bytes())
+                    .expect("hash is 32 bytes"),
+                secret_key,
+            )
+            .serialize_compact();
+        let root = RootRecord {
+            base,
+            signature: [&signature[..], &[recovery_id.to_i32() as u8]]
+                .concat()
+                .into(),
+        };
+
+        Ok(Self { root, records })
+    }
+
+    pub fn root(&self) -> &RootRecord {
+        &self.root
+    }
+
+    /// TXT records to publish the tree under `domain` with: the root at `domain` itself,
+    /// the rest at their hashes below it.
+    pub fn records(&self, domain: &str) -> HashMap<String, String> {
+        self.records
+            .iter()
+            .map(|(hash, record)| (format!("{hash}.{domain}"), record.clone()))
+            .chain(std::iter::once((domain.to_string(), self.root.to_string())))
+            .collect()
+    }
+}
+
+/// `enrtree://` link to the tree published under `domain`, signed by `public_key`.
+pub fn tree_link(public_key: &PublicKey, domain: &str) -> String {
+    format!(
+        "{}{}@{}",
+        LINK_PREFIX,
+        BASE32_NOPAD.encode(&public_key.serialize()),
+        domain
+    )
+}
+
+/// Add the subtree of `leaves` to `records`, returning the hash of its top record.
+///
+/// Leaves are grouped into branches of [`MAX_BRANCH_CHILDREN`], and those into branches
+/// again until a single record is left. No leaves make up an empty branch.
+fn build(
+    records: &mut HashMap<Base32Hash, String>,
+    mut leaves: Vec<String>,
+) -> Result<Base32Hash, TreeError> {
+    leaves.sort_unstable();
+    leaves.dedup();
+
+    let mut level = Vec::with_capacity(leaves.len());
+    for leaf in leaves {
+        debug_assert!(leaf.starts_with(ENR_PREFIX) || leaf.starts_with(LINK_PREFIX));
+        level.push(insert(records, leaf)?);
+    }
+    loop {
+        if level.len() == 1 {
+            return Ok(level[0]);
+        }
+        let branches = level
+            .chunks(MAX_BRANCH_CHILDREN)
+            .map(|children| {
+                let children = children
+                    .iter()
+                    .map(|hash| hash.as_str())
+                    .collect::<Vec<_>>();
+                format!("{}{}", BRANCH_PREFIX, children.join(","))
+            })
+            .collect::<Vec<_>>();
+        // An empty level still yields the single empty branch.
+        let branches = if branches.is_empty() {
+            vec![BRANCH_PREFIX.to_string()]
+        } else {
+            branches
+        };
+        level = branches
+            .into_iter()
+            .map(|branch| insert(records, branch))
+            .collect::<Result<_, _>>()?;
+    }
+}
+
+fn insert(
+    records: &mut HashMap<Base32Hash, String>,
+    record: String,
+) -> Result<Base32Hash, TreeError> {
+    if record.len() > MAX_RECORD_LEN {
+        return Err(TreeError::RecordTooLong {
+            len: record.len(),
+            record,
+        });
+    }
+    let hash = record_hash(&record);
+    records.insert(hash, record);
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::Resolver, *};
+    use enr::EnrBuilder;
+    use std::{collections::HashSet, sync::Arc};
+    use tokio_stream::StreamExt;
+
+    fn random_enr() -> Enr<SecretKey> {
+        EnrBuilder::new("v4")
+            .build(&SecretKey::new(&mut secp256k1::rand::thread_rng()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn published_tree_resolves() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let public_key = PublicKey::from_secret_key(SECP256K1, &secret_key);
+
+        // Enough for two levels of branches.
+        let enrs = (0..MAX_BRANCH_CHILDREN * 2 + 1)
+            .map(|_| random_enr())
+            .collect::<Vec<_>>();
+        let tree = Tree::new(&enrs, &[], 7, &secret_key).unwrap();
+        assert_eq!(tree.root().sequence, 7);
+
+        let records = tree.records("nodes.example.org");
+        // ENRs, three branches below the top one, and the empty link branch.
+        assert_eq!(records.len(), 1 + enrs.len() + 4 + 1);
+        assert!(records
+            .values()
+            .all(|record| record.len() <= MAX_RECORD_LEN));
+        assert_eq!(
+            Tree::new(&enrs, &[], 7, &secret_key)
+                .unwrap()
+                .records("nodes.example.org"),
+            records
+        );
+
+        let resolved = Resolver::<_, SecretKey>::new(Arc::new(records))
+            .query_tree(tree_link(&public_key, "nodes.example.org"))
+            .map(|enr| enr.map(|enr| enr.to_base64()))
+            .collect::<Result<HashSet<_>, _>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            resolved,
+            enrs.iter().map(Enr::to_base64).collect::<HashSet<_>>()
+        );
+
+        assert!(matches!(
+            Tree::new::<SecretKey>(&[], &["nodes.example.org".to_string()], 1, &secret_key),
+            Err(TreeError::InvalidLink(_))
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+//! Time source of the [`Node`](super::Node), for message expiration, endpoint proofs,
+//! timeouts of pending requests and the times nodes were last seen.
+//!
+//! Sleeps between the rounds of background tasks still run on the Tokio timer, which tests
+//! can [pause](tokio::time::pause) on their own.
+
+use super::util::unix_timestamp;
+use parking_lot::Mutex;
+use std::{fmt::Debug, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// Monotonic time, for timeouts and rates.
+    fn now(&self) -> Instant;
+    /// Wall clock time in seconds, which other nodes agree on, for expiration.
+    fn unix_timestamp(&self) -> u64;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_timestamp(&self) -> u64 {
+        unix_timestamp()
+    }
+}
+
+/// Clock that only moves when [advanced](Self::advance). Clones share the time.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    start_timestamp: u64,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    /// Clock standing at the current time.
+    pub fn new() -> Self {
+        Self::starting_at(unix_timestamp())
+    }
+
+    /// Clock standing at `timestamp`, e.g. to match the expiration of recorded packets.
+    pub fn starting_at(timestamp: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            start_timestamp: timestamp,
+            elapsed: Default::default(),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock()
+    }
+
+    fn unix_timestamp(&self) -> u64 {
+        self.start_timestamp + self.elapsed.lock().as_secs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_advances_together() {
+        let clock = MockClock::starting_at(1_000);
+        let now = clock.now();
+        let other = clock.clone();
+
+        other.advance(Duration::from_millis(1_500));
+        assert_eq!(clock.now() - now, Duration::from_millis(1_500));
+        assert_eq!(clock.unix_timestamp(), 1_001);
+    }
+}
@@ -0,0 +1,201 @@
+//! Bookkeeping of the [crawler](super::Node::crawler) mode: every node heard of is emitted
+//! once, and again whenever its endpoint changes, and queued to be queried for more nodes.
+
+use super::{node::NodeRecord, NodeId};
+use lru::LruCache;
+use std::collections::VecDeque;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::trace;
+
+/// Limit on the nodes the crawler keeps track of, the ones heard of least recently are
+/// forgotten first.
+pub const MAX_CRAWLED_NODES: usize = 100_000;
+
+/// Nodes buffered in the [`CrawlStream`] for a consumer that falls behind, newer nodes are
+/// dropped from the stream once it is full.
+pub const CRAWL_STREAM_CAPACITY: usize = 1024;
+
+/// Node observed by the crawler.
+#[derive(Clone, Copy, Debug)]
+pub struct CrawledNode {
+    pub id: NodeId,
+    /// Latest endpoint of the node.
+    pub record: NodeRecord,
+    /// Unix timestamp of when the node was heard of for the first time.
+    pub first_seen: u64,
+}
+
+pub type CrawlStream = ReceiverStream<CrawledNode>;
+
+#[derive(Debug)]
+pub struct Crawler {
+    /// Nodes seen most recently, up to the capacity.
+    seen: LruCache<NodeId, CrawledNode>,
+    /// Nodes to be queried, new ones and the ones that changed endpoint, up to the capacity.
+    queue: VecDeque<NodeRecord>,
+    capacity: usize,
+    tx: Sender<CrawledNode>,
+}
+
+fn same_endpoint(a: &NodeRecord, b: &NodeRecord) -> bool {
+    a.address == b.address && a.udp_port == b.udp_port && a.tcp_port == b.tcp_port
+}
+
+impl Crawler {
+    pub fn new() -> (Self, CrawlStream) {
+        Self::with_capacity(MAX_CRAWLED_NODES)
+    }
+
+    /// Crawler keeping track of up to `capacity` nodes, at least one.
+    pub fn with_capacity(capacity: usize) -> (Self, CrawlStream) {
+        let capacity = capacity.max(1);
+        let (tx, rx) = channel(CRAWL_STREAM_CAPACITY);
+        (
+            Self {
+                seen: LruCache::new(capacity),
+                queue: VecDeque::new(),
+                capacity,
+                tx,
+            },
+            ReceiverStream::new(rx),
+        )
+    }
+
+    /// Emit the record unless it was already seen with the same endpoint.
+    pub fn observe(&mut self, record: NodeRecord, now: u64) {
+        let (node, moved) = match self.seen.get_mut(&record.id) {
+            Some(node) => {
+                if same_endpoint(&node.record, &record) {
+                    return;
+                }
+                node.record = record;
+                (*node, true)
+            }
+            None => {
+                let node = CrawledNode {
+                    id: record.id,
+                    record,
+                    first_seen: now,
+                };
+                self.seen.put(record.id, node);
+                (node, false)
+            }
+        };
+        // A moved node still waiting for its query is queried at the new endpoint instead.
+        let queued = moved
+            .then(|| self.queue.iter_mut().find(|queued| queued.id == record.id))
+            .flatten();
+        if let Some(queued) = queued {
+            *queued = record;
+        } else if self.queue.len() < self.capacity {
+            // Queued once the queue has room, or in the next round otherwise.
+            self.queue.push_back(record);
+        }
+        if self.tx.try_send(node).is_err() {
+            trace!("Crawl stream is full, dropping {}", node.id);
+        }
+    }
+
+    /// Up to `count` next nodes to send FindNode to. Once all of them were queried, returns
+    /// nothing for once and starts the next round over all nodes seen so far.
+    pub fn next_to_query(&mut self, count: usize) -> Vec<NodeRecord> {
+        if self.queue.is_empty() {
+            self.queue
+                .extend(self.seen.iter().map(|(_, node)| node.record));
+            return Vec::new();
+        }
+        self.queue.drain(..count.min(self.queue.len())).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::v4::message::Ip;
+    use std::net::Ipv4Addr;
+    use tokio_stream::StreamExt;
+
+    fn record(id: NodeId, udp_port: u16) -> NodeRecord {
+        NodeRecord {
+            address: Ip(Ipv4Addr::new(10, 0, 0, 1).into()),
+            tcp_port: 0,
+            udp_port,
+            id,
+        }
+    }
+
+    #[tokio::test]
+    async fn emits_new_and_moved_nodes() {
+        let (mut crawler, stream) = Crawler::new();
+        let id = NodeId::random();
+
+        crawler.observe(record(id, 30303), 100);
+        crawler.observe(record(id, 30303), 200);
+        crawler.observe(record(id, 30304), 300);
+        drop(crawler);
+
+        let emitted = stream.collect::<Vec<_>>().await;
+        assert_eq!(emitted.len(), 2);
+        assert_eq!(emitted[0].record.udp_port, 30303);
+        assert_eq!(emitted[1].record.udp_port, 30304);
+        assert!(emitted
+            .iter()
+            .all(|node| node.id == id && node.first_seen == 100));
+    }
+
+    #[test]
+    fn queue_starts_over() {
+        let (mut crawler, _stream) = Crawler::new();
+        assert!(crawler.next_to_query(3).is_empty());
+
+        let ids = [NodeId::random(), NodeId::random()];
+        for id in ids {
+            crawler.observe(record(id, 30303), 0);
+        }
+        assert_eq!(crawler.next_to_query(1)[0].id, ids[0]);
+        assert_eq!(crawler.next_to_query(3)[0].id, ids[1]);
+        // End of the round.
+        assert!(crawler.next_to_query(3).is_empty());
+        assert_eq!(crawler.next_to_query(3).len(), 2);
+        assert_eq!(crawler.len(), 2);
+    }
+
+    #[test]
+    fn capacity_is_bounded() {
+        let (mut crawler, _stream) = Crawler::with_capacity(2);
+        for _ in 0..3 {
+            crawler.observe(record(NodeId::random(), 30303), 0);
+        }
+        assert_eq!(crawler.len(), 2);
+        assert_eq!(crawler.next_to_query(3).len(), 2);
+
+        let (mut crawler, _stream) = Crawler::with_capacity(0);
+        crawler.observe(record(NodeId::random(), 30303), 0);
+        assert_eq!(crawler.len(), 1);
+    }
+
+    #[test]
+    fn moved_node_is_queued_once() {
+        let (mut crawler, _stream) = Crawler::new();
+        let id = NodeId::random();
+
+        crawler.observe(record(id, 30303), 0);
+        crawler.observe(record(id, 30304), 0);
+        let queued = crawler.next_to_query(3);
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].udp_port, 30304);
+
+        // Queued again once it moves after its query.
+        crawler.observe(record(id, 30305), 0);
+        assert_eq!(crawler.next_to_query(3)[0].udp_port, 30305);
+    }
+}
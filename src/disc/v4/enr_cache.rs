@@ -0,0 +1,150 @@
+//! EIP-868 node records of remote nodes.
+//!
+//! Pings and Pongs carry the ENR sequence number of their sender. Once it is higher than
+//! the one of the record we have, the record is requested again with ENRRequest, and the
+//! ENRResponse replaces it if it answers that request and is signed by the same node.
+
+use super::{util::pk2id, NodeId};
+use crate::types::Enr;
+use ethereum_types::H256;
+use lru::LruCache;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// Number of node records remembered, least recently updated ones are evicted first.
+pub const MAX_CACHED_ENRS: usize = 1024;
+/// How long to wait for ENRResponse before the record may be requested again.
+pub const ENR_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct EnrCache {
+    records: LruCache<NodeId, Enr>,
+    /// ENRRequests we sent, by packet hash that the response has to echo.
+    pending: HashMap<H256, (NodeId, Instant)>,
+}
+
+impl Default for EnrCache {
+    fn default() -> Self {
+        Self {
+            records: LruCache::new(MAX_CACHED_ENRS),
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl EnrCache {
+    pub fn get(&self, node_id: &NodeId) -> Option<&Enr> {
+        self.records.peek(node_id)
+    }
+
+    /// Whether the node reported `enr_seq` newer than its cached record, and the record
+    /// is not being requested already.
+    pub fn is_outdated(&mut self, node_id: NodeId, enr_seq: u64, now: Instant) -> bool {
+        self.pending.retain(|_, (_, sent_at)| {
+            now.saturating_duration_since(*sent_at) < ENR_REQUEST_TIMEOUT
+        });
+        if self.pending.values().any(|(id, _)| *id == node_id) {
+            return false;
+        }
+
+        match self.records.peek(&node_id) {
+            Some(enr) => enr.seq() < enr_seq,
+            None => true,
+        }
+    }
+
+    /// Remember that an ENRRequest with `hash` was sent to `node_id` at `now`.
+    pub fn request_sent(&mut self, hash: H256, node_id: NodeId, now: Instant) {
+        self.pending.insert(hash, (node_id, now));
+    }
+
+    /// Store `enr` received from `node_id` in response to the request with `request_hash`.
+    ///
+    /// Returns `false` if the response is unsolicited, or the record is not one of `node_id`
+    /// or older than the cached one.
+    pub fn insert_response(
+        &mut self,
+        node_id: NodeId,
+        request_hash: H256,
+        enr: Enr,
+        now: Instant,
+    ) -> bool {
+        match self.pending.get(&request_hash) {
+            Some((id, sent_at))
+                if *id == node_id
+                    && now.saturating_duration_since(*sent_at) < ENR_REQUEST_TIMEOUT => {}
+            _ => return false,
+        }
+        self.pending.remove(&request_hash);
+
+        if pk2id(&enr.public_key()) != node_id {
+            return false;
+        }
+        if let Some(cached) = self.records.peek(&node_id) {
+            if cached.seq() > enr.seq() {
+                return false;
+            }
+        }
+
+        self.records.put(node_id, enr);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{PublicKey, SecretKey, SECP256K1};
+
+    fn record(secret_key: &SecretKey, seq: u64) -> Enr {
+        let mut enr = enr::EnrBuilder::new("v4").build(secret_key).unwrap();
+        enr.set_seq(seq, secret_key).unwrap();
+        enr
+    }
+
+    #[test]
+    fn newer_seq_is_requested_once() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let node_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key));
+        let now = Instant::now();
+        let mut cache = EnrCache::default();
+
+        assert!(cache.is_outdated(node_id, 1, now));
+        let hash = H256::random();
+        cache.request_sent(hash, node_id, now);
+        assert!(!cache.is_outdated(node_id, 1, now));
+
+        // Unsolicited or from another node.
+        assert!(!cache.insert_response(node_id, H256::random(), record(&secret_key, 2), now));
+        assert!(!cache.insert_response(NodeId::random(), hash, record(&secret_key, 2), now));
+
+        assert!(cache.insert_response(node_id, hash, record(&secret_key, 2), now));
+        assert_eq!(cache.get(&node_id).unwrap().seq(), 2);
+        assert!(!cache.is_outdated(node_id, 2, now));
+        assert!(cache.is_outdated(node_id, 3, now));
+    }
+
+    #[test]
+    fn record_must_be_signed_by_sender() {
+        let node_id = NodeId::random();
+        let now = Instant::now();
+        let mut cache = EnrCache::default();
+
+        let hash = H256::random();
+        cache.request_sent(hash, node_id, now);
+        let other_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        assert!(!cache.insert_response(node_id, hash, record(&other_key, 1), now));
+        assert!(cache.get(&node_id).is_none());
+    }
+
+    #[test]
+    fn request_expires() {
+        let node_id = NodeId::random();
+        let now = Instant::now();
+        let mut cache = EnrCache::default();
+
+        cache.request_sent(H256::random(), node_id, now);
+        assert!(!cache.is_outdated(node_id, 1, now));
+        assert!(cache.is_outdated(node_id, 1, now + ENR_REQUEST_TIMEOUT));
+    }
+}
@@ -0,0 +1,108 @@
+//! Discrete protocol events, for dashboards and debugging tools beyond [metrics](super::metrics).
+
+use super::{message::Endpoint, node::NodeRecord, NodeId};
+use parking_lot::Mutex;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+
+#[derive(Clone, Copy, Debug)]
+pub enum DiscoveryEvent {
+    /// Correctly signed packet received from another node.
+    PacketReceived {
+        node_id: NodeId,
+        addr: SocketAddr,
+        packet_type: u8,
+    },
+    /// Node answered our Ping, proving its endpoint.
+    EndpointProven { node_id: NodeId, addr: SocketAddr },
+    /// Node entered a bucket of the table, rather than its replacements.
+    NodeAdded(NodeRecord),
+    /// Node was evicted from the table.
+    NodeRemoved(NodeId),
+    /// Node in the table was seen at another endpoint, and proved it by answering our Ping
+    /// there.
+    AddressChanged {
+        node_id: NodeId,
+        old: Endpoint,
+        new: Endpoint,
+    },
+    /// Ping came from another IP than the one its sender reported, typically because of
+    /// a NAT. The node is reached at the source address.
+    ReportedAddressMismatch {
+        node_id: NodeId,
+        reported: Endpoint,
+        source: SocketAddr,
+    },
+    /// Table snapshot was restored, with `restored` of its `total` nodes that answered.
+    TableRestored { restored: usize, total: usize },
+    /// Lookup finished with `found` nodes that responded.
+    LookupCompleted { target: NodeId, found: usize },
+}
+
+pub type EventStream = ReceiverStream<DiscoveryEvent>;
+
+/// Listeners of [`DiscoveryEvent`]s. Emitting never blocks: events that do not fit into
+/// the channel of a lagging listener are dropped and counted.
+#[derive(Debug, Default)]
+pub struct EventListeners {
+    listeners: Mutex<Vec<Sender<DiscoveryEvent>>>,
+    dropped: AtomicU64,
+}
+
+impl EventListeners {
+    /// Capacity of zero is rounded up to one.
+    pub fn subscribe(&self, capacity: usize) -> EventStream {
+        let (tx, rx) = channel(capacity.max(1));
+        self.listeners.lock().push(tx);
+        ReceiverStream::new(rx)
+    }
+
+    /// Send the event to every listener, forgetting the ones that went away.
+    pub fn emit(&self, event: DiscoveryEvent) {
+        self.listeners
+            .lock()
+            .retain(|listener| match listener.try_send(event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+    }
+
+    /// Number of events dropped so far because listeners lagged behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn drops_instead_of_blocking() {
+        let listeners = EventListeners::default();
+        let mut lagging = listeners.subscribe(1);
+        let closed = listeners.subscribe(1);
+        drop(closed);
+
+        let id = NodeId::random();
+        for found in 0..3 {
+            listeners.emit(DiscoveryEvent::LookupCompleted { target: id, found });
+        }
+
+        assert_eq!(listeners.dropped(), 2);
+        assert_eq!(listeners.listeners.lock().len(), 1);
+        assert!(matches!(
+            lagging.next().await,
+            Some(DiscoveryEvent::LookupCompleted { target, found: 0 }) if target == id
+        ));
+    }
+}
@@ -0,0 +1,277 @@
+//! Filtering of the nodes discovery interacts with.
+//!
+//! Nodes rejected by the [`NodeFilter`] of a [`Node`](super::Node) are not added to its table,
+//! do not get their Pings answered and are not advertised to other nodes in Neighbours.
+//! Once the ENR of a node is known, it is checked as well.
+
+use super::{NodeId, NodeRecord};
+use crate::{
+    forkid::{EnrForkIdExt, ForkFilter, ForkIdValidation},
+    types::Enr,
+};
+use auto_impl::auto_impl;
+use parking_lot::RwLock;
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+};
+
+/// Policy deciding which nodes discovery is allowed to deal with.
+#[auto_impl(&, Box, Arc)]
+pub trait NodeFilter: Send + Sync + 'static {
+    fn allow(&self, record: &NodeRecord) -> bool;
+
+    /// Whether the node with this ENR is allowed, in addition to [`NodeFilter::allow`].
+    fn allow_enr(&self, _enr: &Enr) -> bool {
+        true
+    }
+}
+
+/// Rejects nodes with unspecified, loopback, private (RFC 1918 and unique local IPv6)
+/// and link-local addresses.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PublicAddressFilter;
+
+fn is_unique_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xfe00 == 0xfc00
+}
+
+fn is_unicast_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Whether the address is not one of those rejected by [`PublicAddressFilter`].
+pub(crate) fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(ip) => {
+            !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local())
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || is_unique_local(&ip)
+                || is_unicast_link_local(&ip))
+        }
+    }
+}
+
+impl NodeFilter for PublicAddressFilter {
+    fn allow(&self, record: &NodeRecord) -> bool {
+        is_public(record.address.0)
+    }
+}
+
+/// Rejects nodes whose ID is not a valid public key, see [`NodeRecord::verify`], and ENRs
+/// that are not signed correctly.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VerifiedRecordFilter;
+
+impl NodeFilter for VerifiedRecordFilter {
+    fn allow(&self, record: &NodeRecord) -> bool {
+        record.verify().is_ok()
+    }
+
+    fn allow_enr(&self, enr: &Enr) -> bool {
+        enr.verify()
+    }
+}
+
+/// Rejects banned node IDs. The list can be updated while discovery is running.
+#[derive(Debug, Default)]
+pub struct DenyList {
+    ids: RwLock<HashSet<NodeId>>,
+}
+
+impl DenyList {
+    pub fn new(ids: impl IntoIterator<Item = NodeId>) -> Self {
+        Self {
+            ids: RwLock::new(ids.into_iter().collect()),
+        }
+    }
+
+    pub fn ban(&self, id: NodeId) {
+        self.ids.write().insert(id);
+    }
+
+    pub fn unban(&self, id: NodeId) {
+        self.ids.write().remove(&id);
+    }
+
+    pub fn is_banned(&self, id: NodeId) -> bool {
+        self.ids.read().contains(&id)
+    }
+}
+
+impl NodeFilter for DenyList {
+    fn allow(&self, record: &NodeRecord) -> bool {
+        !self.is_banned(record.id)
+    }
+}
+
+/// Rejects nodes whose ENR announces a fork ID incompatible with our chain, or an `eth`
+/// entry that cannot be decoded. Nodes with no `eth` entry and stale nodes are allowed.
+#[derive(Debug)]
+pub struct ForkIdFilter {
+    fork_filter: RwLock<ForkFilter>,
+}
+
+impl ForkIdFilter {
+    pub fn new(fork_filter: ForkFilter) -> Self {
+        Self {
+            fork_filter: RwLock::new(fork_filter),
+        }
+    }
+
+    /// Update our head block as the chain progresses, see [`ForkFilter::set_head`].
+    pub fn set_head(&self, head: u64) {
+        self.fork_filter.write().set_head(head);
+    }
+}
+
+impl NodeFilter for ForkIdFilter {
+    fn allow(&self, _: &NodeRecord) -> bool {
+        true
+    }
+
+    fn allow_enr(&self, enr: &Enr) -> bool {
+        match enr.fork_id() {
+            Some(Ok(fork_id)) => {
+                self.fork_filter.read().validate(fork_id) != ForkIdValidation::Incompatible
+            }
+            Some(Err(_)) => false,
+            None => true,
+        }
+    }
+}
+
+/// Filters consulted in order, a node is allowed only if all of them allow it.
+#[derive(Clone, Default)]
+pub struct FilterChain(pub Vec<Arc<dyn NodeFilter>>);
+
+impl FilterChain {
+    pub fn with(mut self, filter: impl NodeFilter) -> Self {
+        self.0.push(Arc::new(filter));
+        self
+    }
+}
+
+impl NodeFilter for FilterChain {
+    fn allow(&self, record: &NodeRecord) -> bool {
+        self.0.iter().all(|filter| filter.allow(record))
+    }
+
+    fn allow_enr(&self, enr: &Enr) -> bool {
+        self.0.iter().all(|filter| filter.allow_enr(enr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::v4::{message::Ip, node::VerifyError};
+
+    fn record(address: impl Into<IpAddr>) -> NodeRecord {
+        NodeRecord {
+            address: Ip(address.into()),
+            tcp_port: 30303,
+            udp_port: 30303,
+            id: NodeId::random(),
+        }
+    }
+
+    #[test]
+    fn public_address_filter() {
+        for address in [
+            IpAddr::from([0, 0, 0, 0]),
+            IpAddr::from([127, 0, 0, 1]),
+            IpAddr::from([10, 1, 2, 3]),
+            IpAddr::from([172, 16, 0, 1]),
+            IpAddr::from([192, 168, 1, 1]),
+            IpAddr::from([169, 254, 0, 1]),
+            IpAddr::from(Ipv6Addr::LOCALHOST),
+            IpAddr::from([0xfd00, 0, 0, 0, 0, 0, 0, 1]),
+            IpAddr::from([0xfe80, 0, 0, 0, 0, 0, 0, 1]),
+        ] {
+            assert!(!PublicAddressFilter.allow(&record(address)), "{}", address);
+        }
+
+        for address in [
+            IpAddr::from([18, 138, 108, 67]),
+            IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]),
+        ] {
+            assert!(PublicAddressFilter.allow(&record(address)), "{}", address);
+        }
+    }
+
+    #[test]
+    fn verified_record_filter() {
+        use crate::disc::v4::util::pk2id;
+        use secp256k1::{PublicKey, SecretKey, SECP256K1};
+
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let mut node = record([18, 138, 108, 67]);
+        node.id = pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key));
+        assert!(VerifiedRecordFilter.allow(&node));
+        assert!(node
+            .verify_with_enr(&node.to_enr(&secret_key).unwrap())
+            .is_ok());
+
+        let other_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let mut other = node;
+        other.id = pk2id(&PublicKey::from_secret_key(SECP256K1, &other_key));
+        assert!(matches!(
+            node.verify_with_enr(&other.to_enr(&other_key).unwrap()),
+            Err(VerifyError::EnrIdMismatch { .. })
+        ));
+
+        // Not a point on the curve.
+        node.id = NodeId::zero();
+        assert!(matches!(node.verify(), Err(VerifyError::InvalidId(_))));
+        assert!(!VerifiedRecordFilter.allow(&node));
+    }
+
+    #[test]
+    fn filter_chain() {
+        let deny_list = Arc::new(DenyList::default());
+        let chain = FilterChain::default()
+            .with(PublicAddressFilter)
+            .with(deny_list.clone());
+
+        let node = record([18, 138, 108, 67]);
+        assert!(chain.allow(&node));
+        assert!(!chain.allow(&record([10, 0, 0, 1])));
+
+        deny_list.ban(node.id);
+        assert!(!chain.allow(&node));
+
+        deny_list.unban(node.id);
+        assert!(chain.allow(&node));
+
+        assert!(FilterChain::default().allow(&node));
+    }
+
+    #[test]
+    fn fork_id_filter() {
+        use crate::forkid::{EnrForkIdEntry, ForkId, ENR_KEY};
+        use ethereum_types::H256;
+        use secp256k1::SecretKey;
+
+        let fork_filter = ForkFilter::new(H256::random(), 100, [50, 150]);
+        let current = fork_filter.current();
+        let chain = FilterChain::default().with(ForkIdFilter::new(fork_filter));
+
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let enr = |fork_id: ForkId| {
+            enr::EnrBuilder::new("v4")
+                .add_value(ENR_KEY, &EnrForkIdEntry(fork_id))
+                .build(&secret_key)
+                .unwrap()
+        };
+
+        assert!(chain.allow_enr(&enr(current)));
+        assert!(!chain.allow_enr(&enr(ForkId::default())));
+        assert!(chain.allow_enr(&enr::EnrBuilder::new("v4").build(&secret_key).unwrap()));
+        assert!(chain.allow(&record([18, 138, 108, 67])));
+    }
+}
@@ -1,10 +1,17 @@
-use super::{message::*, util::*, NodeId, NodeRecord};
+use super::{
+    clock::*, filter::is_public, message::*, metrics, rng::*, util::*, NodeId, NodeRecord,
+};
 use array_init::array_init;
-use arrayvec::ArrayVec;
 use ethereum_types::H256;
+use fastrlp::{Decodable, DecodeError, Encodable, RlpDecodable, RlpEncodable};
+use parking_lot::Mutex;
+use rand::RngCore;
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{hash_map, BTreeMap, HashMap, VecDeque},
     convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
 };
 use tracing::*;
 
@@ -14,11 +21,156 @@ pub const REPLACEMENTS_SIZE: usize = 16;
 const ADDRESS_BYTES_SIZE: usize = 32;
 pub const ADDRESS_BITS: usize = 8 * ADDRESS_BYTES_SIZE;
 
+/// Random IDs drawn per target by [`Table::refresh_targets`].
+const REFRESH_TARGET_DRAWS: usize = 16;
+
 pub fn distance(n1: NodeId, n2: NodeId) -> H256 {
-    keccak256(n1) ^ keccak256(n2)
+    distance_with(&Keccak256Hasher, n1, n2)
+}
+
+/// [`distance`] with another [`Hasher`] than Keccak-256.
+pub fn distance_with(hasher: &dyn Hasher, n1: NodeId, n2: NodeId) -> H256 {
+    hasher.hash(n1.as_bytes()) ^ hasher.hash(n2.as_bytes())
+}
+
+/// Logarithmic distance between two nodes: index of the highest set bit of [`distance`],
+/// counting from 1, or 0 if the nodes are equal.
+pub fn log2_distance(n1: NodeId, n2: NodeId) -> u16 {
+    log2(distance(n1, n2))
+}
+
+/// [`log2_distance`] with another [`Hasher`] than Keccak-256.
+pub fn log2_distance_with(hasher: &dyn Hasher, n1: NodeId, n2: NodeId) -> u16 {
+    log2(distance_with(hasher, n1, n2))
+}
+
+fn log2(distance: H256) -> u16 {
+    distance
+        .as_bytes()
+        .iter()
+        .position(|b| *b != 0)
+        .map_or(0, |i| {
+            ((ADDRESS_BYTES_SIZE - i) * 8 - distance[i].leading_zeros() as usize) as u16
+        })
+}
+
+/// [`random_at_distance_with`], drawing from the thread RNG.
+pub fn random_at_distance(from: H256, log2_distance: u16) -> H256 {
+    random_at_distance_with(&mut rand::thread_rng(), from, log2_distance)
+}
+
+/// Random hash at exactly `log2_distance` from `from`: the bit `log2_distance - 1` is flipped,
+/// higher bits are kept intact and lower bits are random.
+///
+/// Node IDs are hashed before measuring the distance, so the result is a point in the hashed
+/// keyspace, e.g. a bucket refresh target to compare [`Table::id_hash`] distances against.
+///
+/// # Panics
+///
+/// If `log2_distance` is not within `1..=ADDRESS_BITS`.
+pub fn random_at_distance_with(rng: &mut dyn RngCore, from: H256, log2_distance: u16) -> H256 {
+    assert!(
+        (1..=ADDRESS_BITS as u16).contains(&log2_distance),
+        "log2 distance out of range: {log2_distance}"
+    );
+
+    let bit = usize::from(log2_distance) - 1;
+    let byte = ADDRESS_BYTES_SIZE - 1 - bit / 8;
+    let mask = 1_u8 << (bit % 8);
+
+    let mut distance = H256::random_using(rng);
+    let bytes = distance.as_bytes_mut();
+    bytes[..byte].fill(0);
+    bytes[byte] = (bytes[byte] & (mask - 1)) | mask;
+
+    from ^ distance
+}
+
+pub type NodeBucket = Vec<NodeRecord>;
+
+/// Limits on the bucket entries of a single /24 IPv4 or /64 IPv6 subnet, so that an attacker
+/// with few addresses can't fill the table with its nodes to eclipse us. Nodes over a limit
+/// are rejected, not even kept as replacements.
+///
+/// Same as in Geth, addresses of local networks are exempt, see [`subnet`]. `None` disables
+/// a limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubnetLimits {
+    /// Entries of a subnet in a single bucket.
+    pub bucket: Option<usize>,
+    /// Entries of a subnet across the whole table.
+    pub table: Option<usize>,
+}
+
+impl SubnetLimits {
+    pub const DEFAULT_BUCKET: usize = 2;
+    pub const DEFAULT_TABLE: usize = 10;
+    pub const UNLIMITED: Self = Self {
+        bucket: None,
+        table: None,
+    };
+}
+
+impl Default for SubnetLimits {
+    fn default() -> Self {
+        Self {
+            bucket: Some(Self::DEFAULT_BUCKET),
+            table: Some(Self::DEFAULT_TABLE),
+        }
+    }
+}
+
+/// The /24 IPv4 or /64 IPv6 subnet of `address` that [`SubnetLimits`] apply to, `None` for
+/// unspecified, loopback, private and link-local addresses.
+pub fn subnet(address: IpAddr) -> Option<IpAddr> {
+    let address = Ip(address).normalized().0;
+    if !is_public(address) {
+        return None;
+    }
+    Some(match address {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ipv4Addr::new(a, b, c, 0).into()
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, d, ..] = ip.segments();
+            Ipv6Addr::new(a, b, c, d, 0, 0, 0, 0).into()
+        }
+    })
 }
 
-pub type NodeBucket = ArrayVec<NodeRecord, BUCKET_SIZE>;
+/// Version of the [`Table::serialize`] format. Records of version 1 have their ports in
+/// the wrong order.
+const SNAPSHOT_VERSION: u64 = 2;
+
+#[derive(RlpEncodable, RlpDecodable)]
+struct SnapshotEntry {
+    record: NodeRecord,
+    /// Unix timestamp of the last verification, 0 if the node was only seen.
+    last_verified: u64,
+}
+
+#[derive(RlpEncodable, RlpDecodable)]
+struct Snapshot {
+    version: u64,
+    entries: Vec<SnapshotEntry>,
+}
+
+/// Bucket entry along with what is known about it, see [`Table::entries`].
+#[derive(Clone, Copy, Debug)]
+pub struct TableEntry {
+    pub record: NodeRecord,
+    /// Log2 distance of the node, as in [`Table::buckets`].
+    pub bucket: u16,
+    /// Unix timestamp of the last verification, `None` if the node was only seen.
+    pub last_verified: Option<u64>,
+    /// Whether the node answered our Ping recently, so that it is sent Neighbours on request.
+    ///
+    /// Only the discovery node tracks endpoint proofs: this is always `false` in the entries
+    /// of [`Table::entries`], and set in the ones of
+    /// [`Node::table_entries`](super::Node::table_entries).
+    pub endpoint_proven: bool,
+}
 
 #[derive(Debug, Default)]
 pub struct KBucket {
@@ -44,45 +196,209 @@ impl KBucket {
     }
 }
 
+/// Kademlia routing table of [`ADDRESS_BITS`] k-buckets, indexed by log distance to the local node.
+///
+/// Buckets hold up to [`BUCKET_SIZE`] nodes by default, most recently verified first. Nodes that do not fit
+/// into a full bucket are kept as replacements until one of the bucket entries is [removed],
+/// which happens when the least recently seen entry fails to answer a ping.
+///
+/// [removed]: Table::remove
 #[derive(Debug)]
 pub struct Table {
+    hasher: Arc<dyn Hasher>,
+    clock: Arc<dyn Clock>,
+    rng: SharedRng,
     id_hash: H256,
     kbuckets: [KBucket; ADDRESS_BITS],
+    bucket_size: usize,
+    subnet_limits: SubnetLimits,
+    /// Bucket entries of each [`subnet`], for the table-wide limit.
+    subnets: HashMap<IpAddr, usize>,
+    /// Unix timestamps of the last verification of the bucket entries.
+    last_verified: HashMap<NodeId, u64>,
 }
 
 impl Table {
     /// Construct a new [`Table`]
     pub fn new(id: NodeId) -> Self {
+        Self::with_bucket_size(id, BUCKET_SIZE)
+    }
+
+    /// Table with buckets of `bucket_size` nodes instead of [`BUCKET_SIZE`], at least one.
+    pub fn with_bucket_size(id: NodeId, bucket_size: usize) -> Self {
+        Self::with_hasher(id, bucket_size, Arc::new(Keccak256Hasher))
+    }
+
+    /// Table that measures distances with `hasher` instead of Keccak-256.
+    pub fn with_hasher(id: NodeId, bucket_size: usize, hasher: Arc<dyn Hasher>) -> Self {
         Self {
-            id_hash: keccak256(id),
+            id_hash: hasher.hash(id.as_bytes()),
+            hasher,
+            clock: Arc::new(SystemClock),
+            rng: SharedRng::default(),
             kbuckets: array_init(|_| Default::default()),
+            bucket_size: bucket_size.max(1),
+            subnet_limits: SubnetLimits::default(),
+            subnets: HashMap::new(),
+            last_verified: HashMap::new(),
         }
     }
 
-    fn logdistance(&self, peer: NodeId) -> Option<usize> {
-        let remote_hash = keccak256(peer);
-        for i in (0..ADDRESS_BYTES_SIZE).rev() {
-            let byte_index = ADDRESS_BYTES_SIZE - i - 1;
-            let d = self.id_hash[byte_index] ^ remote_hash[byte_index];
-            if d != 0 {
-                let high_bit_index = 7 - d.leading_zeros() as usize;
-                return Some(i * 8 + high_bit_index);
+    /// Take the times nodes are verified at from `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Reject nodes over `limits` instead of the default [`SubnetLimits`].
+    pub fn with_subnet_limits(mut self, limits: SubnetLimits) -> Self {
+        self.subnet_limits = limits;
+        self
+    }
+
+    /// Draw [refresh targets](Self::refresh_targets) from `rng`.
+    pub fn with_rng(mut self, rng: SharedRng) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    pub fn bucket_size(&self) -> usize {
+        self.bucket_size
+    }
+
+    /// Encode the bucket entries, most recently verified first, along with the time
+    /// they were last verified. Replacements are not included.
+    pub fn serialize(&self) -> Vec<u8> {
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            entries: self
+                .kbuckets
+                .iter()
+                .flat_map(|bucket| &bucket.bucket)
+                .map(|record| SnapshotEntry {
+                    record: *record,
+                    last_verified: self.last_verified.get(&record.id).copied().unwrap_or(0),
+                })
+                .collect(),
+        };
+
+        let mut out = Vec::with_capacity(snapshot.length());
+        snapshot.encode(&mut out);
+        out
+    }
+
+    /// Restore the table of node `id` from a [`Table::serialize`] snapshot, skipping the
+    /// entries that were not verified within `max_age` seconds before `now`.
+    ///
+    /// The entries are not verified again, it is up to the caller to ping them.
+    pub fn deserialize(
+        id: NodeId,
+        data: &[u8],
+        now: u64,
+        max_age: u64,
+    ) -> Result<Self, DecodeError> {
+        Self::deserialize_with_bucket_size(id, BUCKET_SIZE, data, now, max_age)
+    }
+
+    /// [`Table::deserialize`] into a [`Table::with_bucket_size`].
+    pub fn deserialize_with_bucket_size(
+        id: NodeId,
+        bucket_size: usize,
+        data: &[u8],
+        now: u64,
+        max_age: u64,
+    ) -> Result<Self, DecodeError> {
+        Self::deserialize_with_hasher(
+            id,
+            bucket_size,
+            Arc::new(Keccak256Hasher),
+            data,
+            now,
+            max_age,
+        )
+    }
+
+    /// [`Table::deserialize`] into a [`Table::with_hasher`].
+    pub fn deserialize_with_hasher(
+        id: NodeId,
+        bucket_size: usize,
+        hasher: Arc<dyn Hasher>,
+        data: &[u8],
+        now: u64,
+        max_age: u64,
+    ) -> Result<Self, DecodeError> {
+        let snapshot = Snapshot::decode(&mut &*data)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(DecodeError::Custom("unsupported table snapshot version"));
+        }
+
+        let mut table = Self::with_hasher(id, bucket_size, hasher);
+        let oldest = now.saturating_sub(max_age);
+        for SnapshotEntry {
+            record,
+            last_verified,
+        } in snapshot.entries
+        {
+            if last_verified < oldest {
+                continue;
+            }
+            if table.add_seen(record) {
+                table.last_verified.insert(record.id, last_verified);
             }
         }
-        None // n1 and n2 are equal, so logdistance is -inf
+        Ok(table)
     }
 
-    fn bucket(&self, peer: NodeId) -> Option<(usize, &KBucket)> {
-        self.logdistance(peer)
-            .map(|bucket_idx| (bucket_idx, &self.kbuckets[bucket_idx]))
+    /// Hash of the local node ID, which distances to other nodes are measured from.
+    pub fn id_hash(&self) -> H256 {
+        self.id_hash
     }
 
-    fn bucket_mut(&mut self, peer: NodeId) -> Option<(usize, &mut KBucket)> {
-        if let Some(bucket_idx) = self.logdistance(peer) {
-            return Some((bucket_idx, &mut self.kbuckets[bucket_idx]));
+    /// Random lookup targets in `count` different buckets, where that takes a reasonable
+    /// number of draws: each bucket closer to us is half as likely to be hit.
+    pub fn refresh_targets(&self, count: usize) -> Vec<NodeId> {
+        let mut buckets = Vec::with_capacity(count);
+        let mut targets = Vec::with_capacity(count);
+        let mut duplicates = Vec::new();
+        let mut rng = self.rng.clone();
+        for _ in 0..count.saturating_mul(REFRESH_TARGET_DRAWS) {
+            if targets.len() == count {
+                break;
+            }
+            let target = NodeId::random_using(&mut rng);
+            match self.logdistance(target) {
+                Some(bucket) if !buckets.contains(&bucket) => {
+                    buckets.push(bucket);
+                    targets.push(target);
+                }
+                _ => duplicates.push(target),
+            }
         }
+        let missing = count - targets.len();
+        targets.extend(duplicates.into_iter().take(missing));
+        targets
+    }
 
-        None
+    /// Distance between two nodes, measured with the hasher of the table.
+    pub fn distance(&self, n1: NodeId, n2: NodeId) -> H256 {
+        distance_with(&*self.hasher, n1, n2)
+    }
+
+    /// [`log2_distance`] of `peer` from the table's own ID, measured with its hasher.
+    pub fn log2_distance(&self, peer: NodeId) -> u16 {
+        log2(self.id_hash ^ self.hasher.hash(peer.as_bytes()))
+    }
+
+    fn logdistance(&self, peer: NodeId) -> Option<usize> {
+        match self.log2_distance(peer) {
+            0 => None, // n1 and n2 are equal, so logdistance is -inf
+            d => Some(usize::from(d) - 1),
+        }
+    }
+
+    fn bucket(&self, peer: NodeId) -> Option<(usize, &KBucket)> {
+        self.logdistance(peer)
+            .map(|bucket_idx| (bucket_idx, &self.kbuckets[bucket_idx]))
     }
 
     /// Get the [`Endpoint`] for the requested peer.
@@ -102,7 +418,7 @@ impl Table {
             .iter()
             .enumerate()
             .filter_map(|(i, kbucket)| {
-                if kbucket.bucket.len() >= BUCKET_SIZE {
+                if kbucket.bucket.len() >= self.bucket_size {
                     Some(u8::try_from(i).expect("there are only 255 kbuckets"))
                 } else {
                     None
@@ -111,6 +427,30 @@ impl Table {
             .collect()
     }
 
+    /// Iterate over all buckets, closest first, along with the log2 distance of their nodes.
+    pub fn buckets(&self) -> impl Iterator<Item = (u16, &VecDeque<NodeRecord>)> + '_ {
+        self.kbuckets
+            .iter()
+            .enumerate()
+            .map(|(i, kbucket)| (i as u16 + 1, &kbucket.bucket))
+    }
+
+    /// All bucket entries, closest bucket first and most recently verified first within
+    /// a bucket. Replacements are not included.
+    ///
+    /// The table knows nothing of endpoint proofs, so [`TableEntry::endpoint_proven`] is
+    /// always `false`.
+    pub fn entries(&self) -> impl Iterator<Item = TableEntry> + '_ {
+        self.buckets().flat_map(move |(bucket, nodes)| {
+            nodes.iter().map(move |record| TableEntry {
+                record: *record,
+                bucket,
+                last_verified: self.last_verified.get(&record.id).copied(),
+                endpoint_proven: false,
+            })
+        })
+    }
+
     pub fn oldest(&self, bucket_no: u8) -> Option<NodeRecord> {
         self.kbuckets[bucket_no as usize]
             .bucket
@@ -119,78 +459,208 @@ impl Table {
             .copied()
     }
 
-    /// Add verified node if there is space.
+    /// Whether the node fits into the bucket without exceeding the [`SubnetLimits`].
+    fn within_subnet_limits(&self, bucket_idx: usize, node: &NodeRecord) -> bool {
+        let subnet = match subnet(node.address.0) {
+            Some(subnet) => subnet,
+            None => return true,
+        };
+        let SubnetLimits { bucket, table } = self.subnet_limits;
+        table.map_or(true, |limit| {
+            self.subnets.get(&subnet).copied().unwrap_or(0) < limit
+        }) && bucket.map_or(true, |limit| {
+            self.kbuckets[bucket_idx]
+                .bucket
+                .iter()
+                .filter(|entry| self::subnet(entry.address.0) == Some(subnet))
+                .count()
+                < limit
+        })
+    }
+
+    /// [`Table::within_subnet_limits`], counting the rejection of a new node otherwise.
+    fn admits(&self, bucket_idx: usize, node: &NodeRecord) -> bool {
+        if self.within_subnet_limits(bucket_idx, node) {
+            return true;
+        }
+        trace!("Rejecting {:?}: subnet limit reached", node);
+        metrics::record_subnet_limit_rejection();
+        false
+    }
+
+    fn insert_entry(&mut self, bucket_idx: usize, pos: usize, node: NodeRecord) {
+        if let Some(subnet) = subnet(node.address.0) {
+            *self.subnets.entry(subnet).or_default() += 1;
+        }
+        self.kbuckets[bucket_idx].bucket.insert(pos, node);
+    }
+
+    fn remove_entry(&mut self, bucket_idx: usize, pos: usize) -> NodeRecord {
+        let node = self.kbuckets[bucket_idx]
+            .bucket
+            .remove(pos)
+            .expect("position of an entry");
+        if let Some(subnet) = subnet(node.address.0) {
+            if let hash_map::Entry::Occupied(mut entry) = self.subnets.entry(subnet) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+        node
+    }
+
+    /// Move the first replacement within the [`SubnetLimits`] to the back of the bucket.
+    fn promote_replacement(&mut self, bucket_idx: usize) -> Option<NodeRecord> {
+        let pos = self.kbuckets[bucket_idx]
+            .replacements
+            .iter()
+            .position(|replacement| self.within_subnet_limits(bucket_idx, replacement))?;
+        let replacement = self.kbuckets[bucket_idx]
+            .replacements
+            .remove(pos)
+            .expect("position of a replacement");
+        trace!("Replacing in bucket {bucket_idx} with {:?}", replacement);
+        let len = self.kbuckets[bucket_idx].bucket.len();
+        self.insert_entry(bucket_idx, len, replacement);
+        Some(replacement)
+    }
+
+    /// Add verified node if there is space. Returns whether the node entered the bucket,
+    /// rather than being already there or added to the replacements.
+    ///
+    /// Nodes over the [`SubnetLimits`] are rejected.
     #[instrument(skip_all, fields(node = &*node.id.to_string()))]
-    pub fn add_verified(&mut self, node: NodeRecord) {
+    pub fn add_verified(&mut self, node: NodeRecord) -> bool {
         trace!("Adding peer");
-        if node.address.is_ipv6() {
-            return;
-        }
 
-        if let Some((bucket_idx, bucket)) = self.bucket_mut(node.id) {
+        if let Some(bucket_idx) = self.logdistance(node.id) {
             trace!("Adding to bucket: {bucket_idx}");
-            if let Some(pos) = bucket.find_peer_pos(node.id) {
-                bucket.bucket.remove(pos);
+            let existing = self.kbuckets[bucket_idx]
+                .find_peer_pos(node.id)
+                .map(|pos| (pos, self.remove_entry(bucket_idx, pos)));
+            if !self.admits(bucket_idx, &node) {
+                // Keep the entry at the endpoint it had.
+                if let Some((pos, entry)) = existing {
+                    self.insert_entry(bucket_idx, pos, entry);
+                }
+                return false;
             }
 
-            // Push to front of bucket if we have less than BUCKET_SIZE peers, or we are shuffling existing peer...
-            if bucket.bucket.len() < BUCKET_SIZE {
-                bucket.bucket.push_front(node);
+            // Push to front of bucket if we have less than bucket_size peers, or we are shuffling existing peer...
+            if self.kbuckets[bucket_idx].bucket.len() < self.bucket_size {
+                self.insert_entry(bucket_idx, 0, node);
+                self.last_verified
+                    .insert(node.id, self.clock.unix_timestamp());
+                return existing.is_none();
             } else {
                 // ...add to replacements otherwise
-                bucket.push_replacement(node);
+                self.kbuckets[bucket_idx].push_replacement(node);
             }
         }
+        false
     }
 
-    /// Add seen node if there is space.
+    /// Add seen node if there is space. Returns whether the node entered the bucket.
+    ///
+    /// Nodes over the [`SubnetLimits`] are rejected.
     #[instrument(skip_all, fields(node = &*node.id.to_string()))]
-    pub fn add_seen(&mut self, node: NodeRecord) {
+    pub fn add_seen(&mut self, node: NodeRecord) -> bool {
         trace!("Adding peer");
-        if node.address.is_ipv6() {
-            return;
-        }
 
-        if let Some((bucket_idx, bucket)) = self.bucket_mut(node.id) {
+        if let Some(bucket_idx) = self.logdistance(node.id) {
             trace!("Adding peer to bucket {bucket_idx}");
-            if bucket.find_peer_pos(node.id).is_some() {
+            if self.kbuckets[bucket_idx].find_peer_pos(node.id).is_some() {
                 // Peer exists already, do nothing
-                return;
+                return false;
+            }
+            if !self.admits(bucket_idx, &node) {
+                return false;
             }
 
-            // Push to back of bucket if we have less than BUCKET_SIZE peers...
-            if bucket.bucket.len() < BUCKET_SIZE {
-                bucket.bucket.push_back(node);
+            // Push to back of bucket if we have less than bucket_size peers...
+            let len = self.kbuckets[bucket_idx].bucket.len();
+            if len < self.bucket_size {
+                self.insert_entry(bucket_idx, len, node);
+                return true;
             } else {
                 // ...add to replacements otherwise
-                bucket.push_replacement(node);
+                self.kbuckets[bucket_idx].push_replacement(node);
             }
         }
+        false
     }
 
-    /// Remove node from the bucket
+    /// Remove node from the bucket. Returns the replacement that took its place, or `None`
+    /// if the node was kept, as there is no replacement within the [`SubnetLimits`].
     #[instrument(skip_all, fields(node = &*node.to_string()))]
-    pub fn remove(&mut self, node: NodeId) {
-        if let Some((bucket_idx, bucket)) = self.bucket_mut(node) {
-            if bucket.replacements.is_empty() {
+    pub fn remove(&mut self, node: NodeId) -> Option<NodeRecord> {
+        if let Some(bucket_idx) = self.logdistance(node) {
+            if self.kbuckets[bucket_idx].replacements.is_empty() {
                 trace!("Not removing from bucket {bucket_idx}: no replacements");
-                return;
+                return None;
             }
 
-            for i in 0..bucket.bucket.len() {
-                if bucket.bucket[i].id == node {
-                    let replacement = bucket
-                        .replacements
-                        .pop_front()
-                        .expect("already returned if no replacement");
-                    trace!("Replacing in bucket {bucket_idx} with {:?}", replacement);
-                    bucket.bucket.remove(i);
-                    bucket.bucket.push_back(replacement);
-
-                    return;
+            if let Some(pos) = self.kbuckets[bucket_idx].find_peer_pos(node) {
+                let entry = self.remove_entry(bucket_idx, pos);
+                match self.promote_replacement(bucket_idx) {
+                    Some(replacement) => {
+                        self.last_verified.remove(&node);
+                        return Some(replacement);
+                    }
+                    None => {
+                        trace!("Not removing from bucket {bucket_idx}: no replacement fits");
+                        self.insert_entry(bucket_idx, pos, entry);
+                    }
                 }
             }
         }
+        None
+    }
+
+    /// Record that a bucket entry answered our Ping, keeping its place in the bucket.
+    pub fn record_contact(&mut self, node: NodeId) {
+        if self
+            .bucket(node)
+            .and_then(|(_, bucket)| bucket.find_peer_pos(node))
+            .is_some()
+        {
+            self.last_verified.insert(node, self.clock.unix_timestamp());
+        }
+    }
+
+    /// Bucket entries last verified longer than `ttl` ago, least recently verified first.
+    /// Nodes that were only seen have no contact to measure from and are not included.
+    pub fn stale(&self, ttl: Duration) -> Vec<NodeRecord> {
+        let deadline = self.clock.unix_timestamp().saturating_sub(ttl.as_secs());
+        let mut stale = self
+            .entries()
+            .filter_map(|entry| {
+                entry
+                    .last_verified
+                    .filter(|last_verified| *last_verified < deadline)
+                    .map(|last_verified| (last_verified, entry.record))
+            })
+            .collect::<Vec<_>>();
+        stale.sort_by_key(|(last_verified, _)| *last_verified);
+        stale.into_iter().map(|(_, record)| record).collect()
+    }
+
+    /// Remove node from the bucket even if there is no replacement to take its place, unlike
+    /// [`remove`](Self::remove). Returns whether the node was there, and the replacement.
+    #[instrument(skip_all, fields(node = &*node.to_string()))]
+    pub fn evict(&mut self, node: NodeId) -> (bool, Option<NodeRecord>) {
+        if let Some(bucket_idx) = self.logdistance(node) {
+            if let Some(pos) = self.kbuckets[bucket_idx].find_peer_pos(node) {
+                self.remove_entry(bucket_idx, pos);
+                let replacement = self.promote_replacement(bucket_idx);
+                self.last_verified.remove(&node);
+
+                return (true, replacement);
+            }
+        }
+        (false, None)
     }
 
     pub fn neighbours(&self, peer: NodeId) -> Option<NodeBucket> {
@@ -210,10 +680,24 @@ impl Table {
     }
 
     pub fn nearest_node_entries(&self, target: NodeId) -> BTreeMap<H256, NodeRecord> {
+        self.nearest_to_hash(self.hasher.hash(target.as_bytes()))
+    }
+
+    /// Bucket entries keyed by their distance to `point` of the hashed keyspace, such as a
+    /// [`random_at_distance_with`] target.
+    pub fn nearest_to_hash(&self, point: H256) -> BTreeMap<H256, NodeRecord> {
         self.kbuckets
             .iter()
             .flat_map(|bucket| &bucket.bucket)
-            .map(|n| (distance(n.id, target), *n))
+            .map(|n| (point ^ self.hasher.hash(n.id.as_bytes()), *n))
+            .collect()
+    }
+
+    /// Up to `count` nodes from the table, closest to `target` first.
+    pub fn closest(&self, target: NodeId, count: usize) -> Vec<NodeRecord> {
+        self.nearest_node_entries(target)
+            .into_values()
+            .take(count)
             .collect()
     }
 
@@ -228,3 +712,463 @@ impl Table {
         self.kbuckets.iter().all(|bucket| bucket.bucket.is_empty())
     }
 }
+
+/// Cloneable read-only view of the [`Table`] maintained by the discovery node.
+///
+/// Every call locks the table only for as long as it takes to copy the answer out,
+/// so the lock is never held across an await point and cannot stall the node.
+#[derive(Clone, Debug)]
+pub struct TableHandle(Arc<Mutex<Table>>);
+
+impl TableHandle {
+    pub(crate) fn new(table: Arc<Mutex<Table>>) -> Self {
+        Self(table)
+    }
+
+    /// See [`Table::closest`].
+    pub fn closest(&self, target: NodeId, count: usize) -> Vec<NodeRecord> {
+        self.0.lock().closest(target, count)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().is_empty()
+    }
+
+    /// Whether `peer` is one of the bucket entries.
+    pub fn contains(&self, peer: NodeId) -> bool {
+        self.0.lock().get(peer).is_some()
+    }
+
+    /// Snapshot of up to `count` nodes closest to `target`, taken off the async worker thread.
+    pub async fn request_closest(&self, target: NodeId, count: usize) -> Vec<NodeRecord> {
+        let table = self.0.clone();
+        tokio::task::spawn_blocking(move || table.lock().closest(target, count))
+            .await
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log2_distance_bounds() {
+        let id = NodeId::random();
+        assert_eq!(log2_distance(id, id), 0);
+
+        for _ in 0..100 {
+            let other = NodeId::random();
+            let d = log2_distance(id, other);
+            assert_eq!(d, log2_distance(other, id));
+            assert!((1..=ADDRESS_BITS as u16).contains(&d));
+            assert_eq!(Table::new(id).logdistance(other), Some(usize::from(d) - 1));
+        }
+    }
+
+    fn random_node() -> NodeRecord {
+        NodeRecord {
+            address: Ip(std::net::Ipv4Addr::LOCALHOST.into()),
+            tcp_port: 30303,
+            udp_port: 30303,
+            id: NodeId::random(),
+        }
+    }
+
+    #[test]
+    fn closest() {
+        let mut table = Table::new(NodeId::random());
+        for _ in 0..10_000 {
+            table.add_verified(random_node());
+        }
+        assert!(!table.is_empty());
+
+        let target = NodeId::random();
+        let closest = table.closest(target, BUCKET_SIZE);
+        assert_eq!(closest.len(), BUCKET_SIZE);
+        for pair in closest.windows(2) {
+            assert!(distance(pair[0].id, target) < distance(pair[1].id, target));
+        }
+        for node in &closest {
+            assert!(table.get(node.id).is_some());
+        }
+
+        assert_eq!(table.closest(target, usize::MAX).len(), table.len());
+
+        // Points at a distance of no node ID hash are targeted just the same.
+        let point = random_at_distance(table.id_hash(), 200);
+        let nearest = table.nearest_to_hash(point);
+        assert_eq!(nearest.len(), table.len());
+        for (distance, node) in nearest {
+            assert_eq!(distance, point ^ Keccak256Hasher.hash(node.id.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn stale_entries() {
+        let clock = MockClock::new();
+        let mut table = Table::new(NodeId::random()).with_clock(Arc::new(clock.clone()));
+        let (old, fresh, seen) = (random_node(), random_node(), random_node());
+        table.add_verified(old);
+        table.add_verified(fresh);
+        table.add_seen(seen);
+
+        clock.advance(Duration::from_secs(100));
+        table.record_contact(fresh.id);
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(
+            table
+                .stale(Duration::from_secs(150))
+                .iter()
+                .map(|record| record.id)
+                .collect::<Vec<_>>(),
+            vec![old.id]
+        );
+        assert!(table.stale(Duration::from_secs(250)).is_empty());
+
+        assert!(matches!(table.evict(old.id), (true, None)));
+        assert!(table.get(old.id).is_none());
+        assert!(matches!(table.evict(old.id), (false, None)));
+    }
+
+    #[test]
+    fn entries() {
+        let mut table = Table::new(NodeId::random());
+        let seen = random_node();
+        table.add_seen(seen);
+        for _ in 0..100 {
+            table.add_verified(random_node());
+        }
+
+        let entries = table.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), table.len());
+        for pair in entries.windows(2) {
+            assert!(pair[0].bucket <= pair[1].bucket);
+        }
+        for entry in &entries {
+            assert_eq!(
+                usize::from(entry.bucket),
+                table.logdistance(entry.record.id).unwrap() + 1
+            );
+            assert_eq!(entry.last_verified.is_none(), entry.record.id == seen.id);
+        }
+    }
+
+    #[test]
+    fn full_bucket_keeps_replacements() {
+        let mut table = Table::new(NodeId::random());
+        let mut bucket = Vec::new();
+        while bucket.len() <= BUCKET_SIZE {
+            let node = random_node();
+            // Half of random ids fall into the furthest bucket.
+            if table.logdistance(node.id) == Some(ADDRESS_BITS - 1) {
+                assert_eq!(table.add_verified(node), bucket.len() < BUCKET_SIZE);
+                bucket.push(node);
+            }
+        }
+
+        let replacement = bucket.pop().unwrap();
+        assert!(table.get(replacement.id).is_none());
+        assert!(!table.add_verified(bucket[1]));
+
+        assert_eq!(table.remove(bucket[0].id).unwrap().id, replacement.id);
+        assert!(table.remove(bucket[0].id).is_none());
+        assert!(table.get(bucket[0].id).is_none());
+        assert!(table.get(replacement.id).is_some());
+    }
+
+    #[test]
+    fn custom_bucket_size() {
+        let id = NodeId::random();
+        let mut table = Table::with_bucket_size(id, 2);
+        let mut added = 0;
+        while added < 3 {
+            let node = random_node();
+            if table.logdistance(node.id) == Some(ADDRESS_BITS - 1) {
+                assert_eq!(table.add_verified(node), added < 2);
+                added += 1;
+            }
+        }
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.filled_buckets(), [(ADDRESS_BITS - 1) as u8]);
+
+        let restored =
+            Table::deserialize_with_bucket_size(id, 1, &table.serialize(), unix_timestamp(), 60)
+                .unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(Table::with_bucket_size(id, 0).bucket_size(), 1);
+    }
+
+    #[test]
+    fn random_at_exact_distance() {
+        let from = H256::random();
+        for d in 1..=ADDRESS_BITS as u16 {
+            assert_eq!(log2(from ^ random_at_distance(from, d)), d);
+        }
+        assert_eq!(random_at_distance(from, 1), from ^ H256::from_low_u64_be(1));
+        // Lower bits are random
+        assert_ne!(
+            random_at_distance(from, ADDRESS_BITS as u16),
+            random_at_distance(from, ADDRESS_BITS as u16)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn random_at_zero_distance() {
+        random_at_distance(H256::random(), 0);
+    }
+
+    #[test]
+    fn buckets_by_distance() {
+        let id = NodeId::random();
+        let mut table = Table::new(id);
+        for _ in 0..1000 {
+            table.add_verified(random_node());
+        }
+
+        let mut total = 0;
+        for (d, bucket) in table.buckets() {
+            for node in bucket {
+                assert_eq!(log2_distance(id, node.id), d);
+            }
+            total += bucket.len();
+        }
+        assert_eq!(table.buckets().count(), ADDRESS_BITS);
+        assert_eq!(total, table.len());
+    }
+
+    /// First half of the ID, so that distances can be read off the IDs.
+    #[derive(Debug)]
+    struct PrefixHasher;
+
+    impl Hasher for PrefixHasher {
+        fn hash(&self, data: &[u8]) -> H256 {
+            H256::from_slice(&data[..32])
+        }
+    }
+
+    #[test]
+    fn custom_hasher() {
+        let at = |byte: u8| {
+            let mut node = random_node();
+            node.id = NodeId::zero();
+            node.id[0] = byte;
+            node
+        };
+
+        let mut table = Table::with_hasher(NodeId::zero(), BUCKET_SIZE, Arc::new(PrefixHasher));
+        for byte in [0x01, 0x80, 0x03] {
+            assert!(table.add_verified(at(byte)));
+        }
+
+        let buckets = table
+            .buckets()
+            .filter(|(_, bucket)| !bucket.is_empty())
+            .map(|(d, bucket)| (d, bucket.iter().map(|node| node.id[0]).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            buckets,
+            [(249, vec![0x01]), (250, vec![0x03]), (256, vec![0x80])]
+        );
+
+        let closest = table.closest(at(0x02).id, 3);
+        assert_eq!(
+            closest.iter().map(|node| node.id[0]).collect::<Vec<_>>(),
+            [0x03, 0x01, 0x80]
+        );
+    }
+
+    #[test]
+    fn refresh_targets_in_different_buckets() {
+        let table = Table::new(NodeId::random());
+        assert!(table.refresh_targets(0).is_empty());
+
+        let targets = table.refresh_targets(4);
+        assert_eq!(targets.len(), 4);
+        let mut buckets = targets
+            .iter()
+            .map(|target| table.logdistance(*target))
+            .collect::<Vec<_>>();
+        buckets.sort_unstable();
+        buckets.dedup();
+        // Four buckets take 30 draws on average, out of the 64 allowed.
+        assert!(buckets.len() >= 3, "{buckets:?}");
+    }
+
+    #[test]
+    fn subnet_limits() {
+        let node = |address: [u8; 4], i: u8| NodeRecord {
+            address: Ip(IpAddr::from([address[0], address[1], address[2], i])),
+            tcp_port: 30303,
+            udp_port: 30303,
+            id: NodeId::random(),
+        };
+
+        let mut table = Table::new(NodeId::random());
+        for i in 0..100 {
+            if i % 2 == 0 {
+                table.add_verified(node([81, 2, 3, 0], i));
+            } else {
+                table.add_seen(node([81, 2, 3, 0], i));
+            }
+        }
+        assert!(table.len() <= SubnetLimits::DEFAULT_TABLE);
+        // Two in each of the farthest buckets are enough to hit the table-wide limit.
+        assert!(table.len() >= SubnetLimits::DEFAULT_BUCKET * 3);
+        for (_, bucket) in table.buckets() {
+            assert!(bucket.len() <= SubnetLimits::DEFAULT_BUCKET);
+        }
+        assert!(table
+            .kbuckets
+            .iter()
+            .all(|bucket| bucket.replacements.is_empty()));
+
+        // Other subnets and local networks are not affected.
+        let len = table.len();
+        assert!(table.add_seen(node([81, 2, 4, 0], 1)));
+        assert_eq!(table.len(), len + 1);
+        let mut lan = Table::new(NodeId::random());
+        let mut unlimited =
+            Table::new(NodeId::random()).with_subnet_limits(SubnetLimits::UNLIMITED);
+        for i in 0..100 {
+            lan.add_seen(node([10, 0, 0, 0], i));
+            unlimited.add_seen(node([81, 2, 3, 0], i));
+        }
+        assert!(lan.len() > SubnetLimits::DEFAULT_TABLE);
+        assert!(unlimited.len() > SubnetLimits::DEFAULT_TABLE);
+
+        assert_eq!(
+            subnet("2001:db8:1:2:3:4:5:6".parse().unwrap()),
+            Some("2001:db8:1:2::".parse().unwrap())
+        );
+        assert_eq!(
+            subnet("::ffff:81.2.3.4".parse().unwrap()),
+            Some("81.2.3.0".parse().unwrap())
+        );
+        assert_eq!(subnet("fd00::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn seeded_refresh_targets() {
+        let id = NodeId::random();
+        let targets = |seed| {
+            Table::new(id)
+                .with_rng(SharedRng::seeded(seed))
+                .refresh_targets(REFRESH_TARGET_DRAWS)
+        };
+        assert_eq!(targets(1), targets(1));
+        assert_ne!(targets(1), targets(2));
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let id = NodeId::random();
+        let mut table = Table::new(id);
+        for _ in 0..1000 {
+            table.add_verified(random_node());
+        }
+
+        let restored = Table::deserialize(id, &table.serialize(), unix_timestamp(), 60).unwrap();
+        assert_eq!(restored.len(), table.len());
+        for _ in 0..10 {
+            let target = NodeId::random();
+            assert_eq!(
+                restored
+                    .closest(target, BUCKET_SIZE)
+                    .iter()
+                    .map(|node| node.id)
+                    .collect::<Vec<_>>(),
+                table
+                    .closest(target, BUCKET_SIZE)
+                    .iter()
+                    .map(|node| node.id)
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn snapshot_skips_stale_entries() {
+        let id = NodeId::random();
+        let mut table = Table::new(id);
+        let verified = random_node();
+        table.add_verified(verified);
+        table.add_seen(random_node());
+
+        let snapshot = table.serialize();
+        let now = unix_timestamp();
+
+        let restored = Table::deserialize(id, &snapshot, now, 60).unwrap();
+        assert_eq!(restored.len(), 1);
+        assert!(restored.get(verified.id).is_some());
+
+        let restored = Table::deserialize(id, &snapshot, now + 120, 60).unwrap();
+        assert!(restored.is_empty());
+
+        assert!(Table::deserialize(id, &snapshot[..snapshot.len() - 1], now, 60).is_err());
+    }
+
+    #[test]
+    fn log2_of_distance() {
+        assert_eq!(log2(H256::zero()), 0);
+        assert_eq!(log2(H256::from_low_u64_be(1)), 1);
+        assert_eq!(log2(H256::from_low_u64_be(0x80)), 8);
+        assert_eq!(log2(H256::from_low_u64_be(0x100)), 9);
+        assert_eq!(log2(H256::repeat_byte(0xff)), 256);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn table_handle_concurrent_access() {
+        let table = Arc::new(Mutex::new(Table::new(NodeId::random())));
+        let handle = TableHandle::new(table.clone());
+
+        let writer = tokio::spawn(async move {
+            let mut added = Vec::new();
+            for i in 0..2_000 {
+                let node = random_node();
+                table.lock().add_verified(node);
+                added.push(node);
+                if i % 3 == 0 {
+                    table.lock().remove(added[i / 2].id);
+                }
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let readers = (0..8)
+            .map(|_| {
+                let handle = handle.clone();
+                tokio::spawn(async move {
+                    for _ in 0..500 {
+                        let target = NodeId::random();
+                        let closest = handle.request_closest(target, BUCKET_SIZE).await;
+                        assert!(closest.len() <= BUCKET_SIZE);
+                        for pair in closest.windows(2) {
+                            assert!(distance(pair[0].id, target) < distance(pair[1].id, target));
+                        }
+                        assert!(handle.closest(target, BUCKET_SIZE).len() <= BUCKET_SIZE);
+                        // Entries may be removed by the writer at any moment.
+                        handle.contains(target);
+                        handle.len();
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        writer.await.unwrap();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+
+        assert!(!handle.is_empty());
+        for node in handle.closest(NodeId::random(), usize::MAX) {
+            assert!(handle.contains(node.id));
+        }
+    }
+}
@@ -1,13 +1,124 @@
-use super::{NodeId, NodeRecord};
-use bytes::BufMut;
+use super::{proto::MessageId, NodeId, NodeRecord};
+use crate::util::decode_enr;
+use bytes::{BufMut, Bytes};
 use derive_more::*;
+use enr::Enr;
 use ethereum_types::H256;
-use fastrlp::{Decodable, DecodeError, Encodable, Header, RlpDecodable, RlpEncodable};
-use std::net::IpAddr;
+use fastrlp::{Decodable, DecodeError, Encodable, Header, RlpEncodable};
+use num_traits::FromPrimitive;
+use secp256k1::SecretKey;
+use std::{
+    iter::FusedIterator,
+    net::{IpAddr, SocketAddr},
+};
+use thiserror::Error;
 
+/// Reason a discovery message failed to decode.
+#[derive(Clone, Copy, Debug, PartialEq, Error)]
+pub enum MessageError {
+    #[error("empty IP address")]
+    Empty,
+    #[error("wrong IP address length")]
+    WrongIpLength,
+    #[error("link-local or multicast IPv6 address")]
+    ScopedAddress,
+    #[error("more than {} neighbours", MAX_NEIGHBOURS)]
+    TooManyNeighbours,
+    #[error("unknown packet type: {0}")]
+    UnknownPacketType(u8),
+    #[error("legacy topic discovery packet: {0:?}")]
+    TopicPacket(TopicPacket),
+    #[error("message expired at {0}")]
+    Expired(u64),
+    #[error("unsupported protocol version: {0}")]
+    UnsupportedVersion(u64),
+    #[error("RLP decoding failed: {0}")]
+    Rlp(DecodeError),
+}
+
+impl MessageError {
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::WrongIpLength => "wrong IP address length",
+            Self::ScopedAddress => "scoped IPv6 address",
+            Self::TooManyNeighbours => "too many neighbours",
+            Self::UnknownPacketType(_) => "unknown packet type",
+            Self::TopicPacket(_) => "topic packet",
+            Self::Expired(_) => "expired",
+            Self::UnsupportedVersion(_) => "unsupported version",
+            Self::Rlp(_) => "RLP decoding failed",
+        }
+    }
+
+    /// Whether the message violates the encoding, as opposed to being well-formed but
+    /// expired, unsupported or of a packet type added by a later protocol version.
+    pub fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            Self::WrongIpLength | Self::TooManyNeighbours | Self::Rlp(_)
+        )
+    }
+}
+
+impl From<MessageError> for DecodeError {
+    fn from(e: MessageError) -> Self {
+        match e {
+            MessageError::Rlp(e) => e,
+            other => Self::Custom(other.reason()),
+        }
+    }
+}
+
+impl From<DecodeError> for MessageError {
+    fn from(e: DecodeError) -> Self {
+        Self::Rlp(e)
+    }
+}
+
+/// Decoding that keeps the [`MessageError`] of nested fields, which [`Decodable`] can only
+/// pass on as an untyped [`DecodeError::Custom`].
+pub(crate) trait DecodeMessage: Sized {
+    fn decode_message(buf: &mut &[u8]) -> Result<Self, MessageError>;
+}
+
+/// IP address of a discovery endpoint.
+///
+/// Any IPv4 address and any IPv6 address of global scope is accepted, including loopback and
+/// private ones, which are up to a [`NodeFilter`](super::filter::NodeFilter) to keep out.
+/// IPv6 link-local unicast (`fe80::/10`) and multicast (`ff00::/8`) addresses are only
+/// meaningful along with the zone of the interface, which the wire format has no place for,
+/// so they are rejected with [`MessageError::ScopedAddress`] and never advertised.
+///
+/// IPv4-mapped IPv6 addresses (`::ffff:0:0/96`) are decoded as the IPv4 addresses they map,
+/// so that the same node is not taken for another one of a different address family.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deref, DerefMut, From)]
 pub struct Ip(pub IpAddr);
 
+impl Ip {
+    /// IPv4 address that an IPv4-mapped IPv6 address maps, any other address as it is.
+    #[must_use]
+    pub fn normalized(self) -> Self {
+        match self.0 {
+            IpAddr::V6(addr) => match addr.octets() {
+                [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                    Self(IpAddr::from([a, b, c, d]))
+                }
+                _ => self,
+            },
+            IpAddr::V4(_) => self,
+        }
+    }
+
+    /// Whether this is an IPv6 address that is only valid within a zone.
+    pub fn is_scoped(&self) -> bool {
+        match self.0 {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(addr) => addr.is_multicast() || (addr.segments()[0] & 0xffc0) == 0xfe80,
+        }
+    }
+}
+
 impl Encodable for Ip {
     fn encode(&self, out: &mut dyn BufMut) {
         match self.0 {
@@ -24,27 +135,107 @@ impl Encodable for Ip {
     }
 }
 
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Ip {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let ip = Self(if u.arbitrary()? {
+            IpAddr::from(u.arbitrary::<[u8; 16]>()?)
+        } else {
+            IpAddr::from(u.arbitrary::<[u8; 4]>()?)
+        });
+        // Not encodable in the sense that it does not decode back, or not as itself.
+        if ip.is_scoped() || ip.normalized() != ip {
+            return Err(arbitrary::Error::IncorrectFormat);
+        }
+        Ok(ip)
+    }
+}
+
 impl Decodable for Ip {
     fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_message(buf).map_err(DecodeError::from)
+    }
+}
+
+impl DecodeMessage for Ip {
+    fn decode_message(buf: &mut &[u8]) -> Result<Self, MessageError> {
         match Header::decode(&mut &**buf)?.payload_length {
-            0 => Err(DecodeError::Custom("empty")),
+            0 => Err(MessageError::Empty),
             4 => Ok(Self(IpAddr::from(<[u8; 4]>::decode(buf)?))),
-            16 => Ok(Self(IpAddr::from(<[u8; 16]>::decode(buf)?))),
+            16 => {
+                let b = &mut &**buf;
+                let ip = Self(IpAddr::from(<[u8; 16]>::decode(b)?));
+                if ip.is_scoped() {
+                    return Err(MessageError::ScopedAddress);
+                }
+                *buf = *b;
+                Ok(ip.normalized())
+            }
+            len @ 1..=3 => {
+                // Some implementations encode IPv4 address as an integer, without leading zeroes.
+                let header = Header::decode(buf)?;
+                if header.list {
+                    return Err(DecodeError::UnexpectedList.into());
+                }
+                if buf.len() < len {
+                    return Err(DecodeError::InputTooShort.into());
+                }
+                let mut octets = [0_u8; 4];
+                octets[4 - len..].copy_from_slice(&buf[..len]);
+                *buf = &buf[len..];
+                Ok(Self(IpAddr::from(octets)))
+            }
             other => {
                 tracing::debug!("ip_addr_rlp_decode: wrong address length {other}");
-                Err(DecodeError::Custom("wrong IP address length"))
+                Err(MessageError::WrongIpLength)
             }
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, RlpEncodable, RlpDecodable)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, RlpEncodable)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Endpoint {
     pub address: Ip,
     pub udp_port: u16,
     pub tcp_port: u16,
 }
 
+impl Endpoint {
+    pub fn udp_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address.0, self.udp_port)
+    }
+}
+
+impl Decodable for Endpoint {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_message(buf).map_err(DecodeError::from)
+    }
+}
+
+impl DecodeMessage for Endpoint {
+    fn decode_message(buf: &mut &[u8]) -> Result<Self, MessageError> {
+        let payload = &mut list_payload(buf)?;
+        Ok(Self {
+            address: Ip::decode_message(payload)?,
+            udp_port: decode_port(payload)?,
+            tcp_port: decode_port(payload)?,
+        })
+    }
+}
+
+/// Port of an endpoint or node record.
+///
+/// Some clients encode port 0 as the integer byte `0x00` rather than the canonical empty
+/// string, both are accepted.
+pub(crate) fn decode_port(buf: &mut &[u8]) -> Result<u16, DecodeError> {
+    if let [0, rest @ ..] = *buf {
+        *buf = rest;
+        return Ok(0);
+    }
+    u16::decode(buf)
+}
+
 impl From<NodeRecord> for Endpoint {
     fn from(
         NodeRecord {
@@ -62,23 +253,273 @@ impl From<NodeRecord> for Endpoint {
     }
 }
 
-#[derive(Clone, Copy, Debug, RlpEncodable, RlpDecodable)]
+#[derive(Clone, Copy, Debug, RlpEncodable)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct FindNodeMessage {
     pub id: NodeId,
     pub expire: u64,
 }
 
-#[derive(Clone, Debug, RlpEncodable, RlpDecodable)]
+impl Decodable for FindNodeMessage {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let payload = &mut list_payload(buf)?;
+        Ok(Self {
+            id: NodeId::decode(payload)?,
+            expire: u64::decode(payload)?,
+        })
+    }
+}
+
+/// Maximum number of nodes accepted in a single Neighbours message.
+///
+/// The spec allows for a full bucket of 16 nodes, although only 12 of them
+/// fit into a [`MAX_PACKET_SIZE`](super::packet::MAX_PACKET_SIZE) datagram.
+pub const MAX_NEIGHBOURS: usize = 16;
+
+#[derive(Clone, Debug, RlpEncodable)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct NeighboursMessage {
     pub nodes: Vec<NodeRecord>,
     pub expire: u64,
 }
 
+impl NeighboursMessage {
+    /// Split into as few messages as possible with encoded length of at most `max_length`
+    /// each, keeping the order of nodes. A message always carries at least one node, even
+    /// if it does not fit.
+    pub fn split(self, max_length: usize) -> Vec<Self> {
+        let mut messages = Vec::new();
+        let mut current = Self {
+            nodes: Vec::new(),
+            expire: self.expire,
+        };
+        for node in self.nodes {
+            current.nodes.push(node);
+            if current.nodes.len() > 1 && current.length() > max_length {
+                current.nodes.pop();
+                messages.push(std::mem::replace(
+                    &mut current,
+                    Self {
+                        nodes: vec![node],
+                        expire: self.expire,
+                    },
+                ));
+            }
+        }
+        if !current.nodes.is_empty() || messages.is_empty() {
+            messages.push(current);
+        }
+        messages
+    }
+}
+
+/// Neighbours message decoded by [`NeighboursMessage::decode_lenient`], possibly partially.
+#[derive(Clone, Debug)]
+pub struct PartialNeighboursMessage {
+    /// Nodes decoded before the truncation point.
+    pub nodes: Vec<NodeRecord>,
+    /// Expiration, unless cut off.
+    pub expire: Option<u64>,
+    /// Whether all nodes of the list were decoded.
+    pub complete: bool,
+}
+
+impl NeighboursMessage {
+    /// Decode as many nodes as possible from a possibly truncated message, rather than failing
+    /// the whole message as the strict [`Decodable`] implementation does. Nodes past a malformed
+    /// or a truncated record, or past [`MAX_NEIGHBOURS`], are left out.
+    ///
+    /// Packet signature covers the whole datagram, so this is only of use for message data
+    /// which was not verified by [`decode_packet`](super::packet::decode_packet).
+    pub fn decode_lenient(buf: &mut &[u8]) -> Result<PartialNeighboursMessage, DecodeError> {
+        let b = &mut &**buf;
+        let header = Header::decode(b)?;
+        if !header.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+        let nodes_header = Header::decode(b)?;
+        if !nodes_header.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+        let mut complete = b.len() >= nodes_header.payload_length;
+        let (nodes, mut rest) = b.split_at(nodes_header.payload_length.min(b.len()));
+
+        let mut records = Vec::new();
+        for node in (NeighboursIter { nodes, expire: 0 }) {
+            match node {
+                Err(e) if is_scoped_address(&e) => continue,
+                Ok(node) if records.len() < MAX_NEIGHBOURS => records.push(node),
+                _ => {
+                    complete = false;
+                    break;
+                }
+            }
+        }
+
+        let expire = if nodes.len() == nodes_header.payload_length {
+            u64::decode(&mut rest).ok()
+        } else {
+            None
+        };
+        *buf = match expire {
+            Some(_) => rest,
+            None => &[],
+        };
+
+        Ok(PartialNeighboursMessage {
+            nodes: records,
+            expire,
+            complete,
+        })
+    }
+}
+
+impl Decodable for NeighboursMessage {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_message(buf).map_err(DecodeError::from)
+    }
+}
+
+impl DecodeMessage for NeighboursMessage {
+    fn decode_message(buf: &mut &[u8]) -> Result<Self, MessageError> {
+        let iter = NeighboursIter::new(buf)?;
+        let expire = iter.expire();
+
+        // Decode nodes one by one instead of collecting the whole list, so that
+        // oversized lists are rejected before allocating for them.
+        let mut nodes = Vec::new();
+        for node in iter {
+            if nodes.len() == MAX_NEIGHBOURS {
+                return Err(MessageError::TooManyNeighbours);
+            }
+            match node {
+                Err(e) if is_scoped_address(&e) => continue,
+                node => nodes.push(node?),
+            }
+        }
+
+        Ok(Self { nodes, expire })
+    }
+}
+
+/// Neighbours message data with the node list decoded lazily, one record at a time.
+///
+/// Unlike [`NeighboursMessage`], it does not limit the number of nodes, so that the
+/// caller can stop as soon as it has enough of them.
+#[derive(Clone, Debug)]
+pub struct NeighboursIter<'a> {
+    nodes: &'a [u8],
+    expire: u64,
+}
+
+impl<'a> NeighboursIter<'a> {
+    /// Decode the message envelope, advancing `buf` past the whole message.
+    pub fn new(buf: &mut &'a [u8]) -> Result<Self, DecodeError> {
+        let b = &mut &**buf;
+        let payload = &mut list_payload(b)?;
+        let nodes = list_payload(payload)?;
+        let expire = u64::decode(payload)?;
+
+        *buf = *b;
+
+        Ok(Self { nodes, expire })
+    }
+
+    pub fn expire(&self) -> u64 {
+        self.expire
+    }
+}
+
+impl Iterator for NeighboursIter<'_> {
+    type Item = Result<NodeRecord, MessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let record = self.nodes;
+        let node = NodeRecord::decode_message(&mut self.nodes);
+        match &node {
+            // Well-formed record of a node we can't reach, the rest of the list is fine.
+            Err(e) if is_scoped_address(e) => match skip_item(record) {
+                Ok(rest) => self.nodes = rest,
+                Err(_) => self.nodes = &[],
+            },
+            // The rest of the list can't be trusted after a malformed record.
+            Err(_) => self.nodes = &[],
+            Ok(_) => {}
+        }
+        Some(node)
+    }
+}
+
+impl FusedIterator for NeighboursIter<'_> {}
+
+fn is_scoped_address(e: &MessageError) -> bool {
+    *e == MessageError::ScopedAddress
+}
+
+/// Remainder of `buf` after the RLP item it starts with.
+fn skip_item(mut buf: &[u8]) -> Result<&[u8], DecodeError> {
+    let header = Header::decode(&mut buf)?;
+    if buf.len() < header.payload_length {
+        return Err(DecodeError::InputTooShort);
+    }
+    Ok(&buf[header.payload_length..])
+}
+
+/// Payload of the list `buf` starts with, advancing `buf` past the list.
+///
+/// Per EIP-8, decoders take the elements they know from the payload and ignore the rest,
+/// which later protocol versions may add. Missing elements still fail their decoding, so
+/// lists too short for the mandatory fields are rejected.
+pub(crate) fn list_payload<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], DecodeError> {
+    let b = &mut &**buf;
+    let header = Header::decode(b)?;
+    if !header.list {
+        return Err(DecodeError::UnexpectedString);
+    }
+    if b.len() < header.payload_length {
+        return Err(DecodeError::InputTooShort);
+    }
+    let (payload, rest) = b.split_at(header.payload_length);
+    *buf = rest;
+    Ok(payload)
+}
+
+/// EIP-868 ENR sequence number following the known elements of Ping and Pong, if the
+/// element there is one rather than an addition of a later protocol version.
+fn decode_enr_seq(payload: &[u8]) -> Option<u64> {
+    if payload.is_empty() {
+        return None;
+    }
+    u64::decode(&mut &*payload).ok()
+}
+
+/// Discovery protocol version sent in Ping.
+pub const PROTOCOL_VERSION: u64 = 4;
+
+/// How to treat Pings with a version other than [`PROTOCOL_VERSION`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VersionPolicy {
+    /// Accept them, only logging the version.
+    #[default]
+    Lenient,
+    /// Reject them with [`MessageError::UnsupportedVersion`].
+    Strict,
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct PingMessage {
+    /// Protocol version of the sender, [`PROTOCOL_VERSION`] unless it speaks another one.
+    pub version: u64,
     pub from: Endpoint,
     pub to: Endpoint,
     pub expire: u64,
+    /// EIP-868 ENR sequence number of the sender, if advertised.
+    pub enr_seq: Option<u64>,
 }
 
 #[derive(RlpEncodable)]
@@ -89,118 +530,1054 @@ struct PingMessageE<'s> {
     expire: &'s u64,
 }
 
+#[derive(RlpEncodable)]
+struct PingMessageEEnr<'s> {
+    version: u64,
+    from: &'s Endpoint,
+    to: &'s Endpoint,
+    expire: &'s u64,
+    enr_seq: &'s u64,
+}
+
+impl PingMessage {
+    /// Check the sender's protocol version against `policy`.
+    pub fn check_version(&self, policy: VersionPolicy) -> Result<(), MessageError> {
+        if self.version == PROTOCOL_VERSION {
+            return Ok(());
+        }
+
+        match policy {
+            VersionPolicy::Lenient => {
+                tracing::debug!("ping_version_check: unexpected version {}", self.version);
+                Ok(())
+            }
+            VersionPolicy::Strict => Err(MessageError::UnsupportedVersion(self.version)),
+        }
+    }
+}
+
 impl Encodable for PingMessage {
     fn encode(&self, out: &mut dyn BufMut) {
-        let Self { from, to, expire } = self;
-
-        PingMessageE {
-            version: 4,
+        let Self {
+            version,
             from,
             to,
             expire,
+            enr_seq,
+        } = self;
+
+        if let Some(enr_seq) = enr_seq {
+            PingMessageEEnr {
+                version: *version,
+                from,
+                to,
+                expire,
+                enr_seq,
+            }
+            .encode(out)
+        } else {
+            PingMessageE {
+                version: *version,
+                from,
+                to,
+                expire,
+            }
+            .encode(out)
         }
-        .encode(out)
     }
     fn length(&self) -> usize {
-        let Self { from, to, expire } = self;
-
-        PingMessageE {
-            version: 4,
+        let Self {
+            version,
             from,
             to,
             expire,
+            enr_seq,
+        } = self;
+
+        if let Some(enr_seq) = enr_seq {
+            PingMessageEEnr {
+                version: *version,
+                from,
+                to,
+                expire,
+                enr_seq,
+            }
+            .length()
+        } else {
+            PingMessageE {
+                version: *version,
+                from,
+                to,
+                expire,
+            }
+            .length()
         }
-        .length()
     }
 }
 
-#[derive(RlpDecodable)]
-struct PingMessageD {
-    _version: u64,
-    from: Endpoint,
-    to: Endpoint,
-    expire: u64,
-}
-
-#[derive(RlpDecodable)]
-struct PingMessageDEnr {
-    _version: u64,
-    from: Endpoint,
-    to: Endpoint,
-    expire: u64,
-    _enr_seq: u64,
-}
-
 impl Decodable for PingMessage {
     fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
-        let (from, to, expire) = {
-            PingMessageD::decode(buf)
-                .map(
-                    |PingMessageD {
-                         from, to, expire, ..
-                     }| (from, to, expire),
-                )
-                .or_else(|e| {
-                    if let DecodeError::ListLengthMismatch { .. } = e {
-                        PingMessageDEnr::decode(buf).map(
-                            |PingMessageDEnr {
-                                 from, to, expire, ..
-                             }| (from, to, expire),
-                        )
-                    } else {
-                        Err(e)
-                    }
-                })?
-        };
+        Self::decode_message(buf).map_err(DecodeError::from)
+    }
+}
 
-        Ok(Self { from, to, expire })
+impl DecodeMessage for PingMessage {
+    fn decode_message(buf: &mut &[u8]) -> Result<Self, MessageError> {
+        let payload = &mut list_payload(buf)?;
+        Ok(Self {
+            version: u64::decode(payload)?,
+            from: Endpoint::decode_message(payload)?,
+            to: Endpoint::decode_message(payload)?,
+            expire: u64::decode(payload)?,
+            enr_seq: decode_enr_seq(payload),
+        })
     }
 }
 
-#[derive(Debug, Clone, RlpEncodable)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct PongMessage {
     pub to: Endpoint,
     pub echo: H256,
     pub expire: u64,
+    /// EIP-868 ENR sequence number of the sender, if advertised.
+    pub enr_seq: Option<u64>,
 }
 
-#[derive(RlpDecodable)]
-struct PongMessageD {
-    to: Endpoint,
-    echo: H256,
-    expire: u64,
+impl PongMessage {
+    /// Answer to the Ping with packet hash `ping_hash`, sent from the endpoint `from`.
+    ///
+    /// Pass the endpoint the Ping was actually received from, so that the remote learns
+    /// its external address, and an expiration of our own rather than the one of the Ping,
+    /// which may be close to passing already.
+    pub fn respond_to(ping_hash: H256, from: Endpoint, expire: u64) -> Self {
+        Self {
+            to: from,
+            echo: ping_hash,
+            expire,
+            enr_seq: None,
+        }
+    }
 }
 
-#[derive(RlpDecodable)]
-struct PongMessageDEnr {
-    to: Endpoint,
-    echo: H256,
-    expire: u64,
-    _enr_seq: u64,
+#[derive(RlpEncodable)]
+struct PongMessageE<'s> {
+    to: &'s Endpoint,
+    echo: &'s H256,
+    expire: &'s u64,
+}
+
+#[derive(RlpEncodable)]
+struct PongMessageEEnr<'s> {
+    to: &'s Endpoint,
+    echo: &'s H256,
+    expire: &'s u64,
+    enr_seq: &'s u64,
+}
+
+impl Encodable for PongMessage {
+    fn encode(&self, out: &mut dyn BufMut) {
+        let Self {
+            to,
+            echo,
+            expire,
+            enr_seq,
+        } = self;
+
+        if let Some(enr_seq) = enr_seq {
+            PongMessageEEnr {
+                to,
+                echo,
+                expire,
+                enr_seq,
+            }
+            .encode(out)
+        } else {
+            PongMessageE { to, echo, expire }.encode(out)
+        }
+    }
+    fn length(&self) -> usize {
+        let Self {
+            to,
+            echo,
+            expire,
+            enr_seq,
+        } = self;
+
+        if let Some(enr_seq) = enr_seq {
+            PongMessageEEnr {
+                to,
+                echo,
+                expire,
+                enr_seq,
+            }
+            .length()
+        } else {
+            PongMessageE { to, echo, expire }.length()
+        }
+    }
 }
 
 impl Decodable for PongMessage {
     fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
-        let (to, echo, expire) = {
-            PongMessageD::decode(buf)
-                .map(
-                    |PongMessageD {
-                         to, echo, expire, ..
-                     }| (to, echo, expire),
-                )
-                .or_else(|e| {
-                    if let DecodeError::ListLengthMismatch { .. } = e {
-                        PongMessageDEnr::decode(buf).map(
-                            |PongMessageDEnr {
-                                 to, echo, expire, ..
-                             }| (to, echo, expire),
-                        )
-                    } else {
-                        Err(e)
-                    }
-                })?
+        Self::decode_message(buf).map_err(DecodeError::from)
+    }
+}
+
+impl DecodeMessage for PongMessage {
+    fn decode_message(buf: &mut &[u8]) -> Result<Self, MessageError> {
+        let payload = &mut list_payload(buf)?;
+        Ok(Self {
+            to: Endpoint::decode_message(payload)?,
+            echo: H256::decode(payload)?,
+            expire: u64::decode(payload)?,
+            enr_seq: decode_enr_seq(payload),
+        })
+    }
+}
+
+/// EIP-868 ENRRequest packet data.
+#[derive(Clone, Copy, Debug, RlpEncodable)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct EnrRequestMessage {
+    pub expire: u64,
+}
+
+impl Decodable for EnrRequestMessage {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let payload = &mut list_payload(buf)?;
+        Ok(Self {
+            expire: u64::decode(payload)?,
+        })
+    }
+}
+
+/// EIP-868 ENRResponse packet data.
+///
+/// The record is kept alongside its encoding, so that it is serialized only once.
+#[derive(Clone, Debug)]
+pub struct EnrResponseMessage {
+    /// Hash of the ENRRequest packet this message is replying to.
+    pub request_hash: H256,
+    enr: Enr<SecretKey>,
+    encoded_enr: Bytes,
+}
+
+impl EnrResponseMessage {
+    pub fn new(request_hash: H256, enr: Enr<SecretKey>) -> Self {
+        let encoded_enr = rlp::encode(&enr).freeze();
+        Self {
+            request_hash,
+            enr,
+            encoded_enr,
+        }
+    }
+
+    pub fn enr(&self) -> &Enr<SecretKey> {
+        &self.enr
+    }
+
+    pub fn into_enr(self) -> Enr<SecretKey> {
+        self.enr
+    }
+}
+
+impl Encodable for EnrResponseMessage {
+    fn encode(&self, out: &mut dyn BufMut) {
+        Header {
+            list: true,
+            payload_length: self.request_hash.length() + self.encoded_enr.len(),
+        }
+        .encode(out);
+        self.request_hash.encode(out);
+        out.put_slice(&self.encoded_enr);
+    }
+
+    fn length(&self) -> usize {
+        let payload_length = self.request_hash.length() + self.encoded_enr.len();
+        payload_length + fastrlp::length_of_length(payload_length)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for EnrResponseMessage {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let secret_key = SecretKey::from_slice(&u.arbitrary::<[u8; 32]>()?)
+            .map_err(|_| arbitrary::Error::IncorrectFormat)?;
+        let enr = enr::EnrBuilder::new("v4")
+            .ip4(u.arbitrary::<[u8; 4]>()?.into())
+            .udp4(u.arbitrary()?)
+            .build(&secret_key)
+            .map_err(|_| arbitrary::Error::IncorrectFormat)?;
+        Ok(Self::new(u.arbitrary()?, enr))
+    }
+}
+
+impl Decodable for EnrResponseMessage {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let payload = &mut list_payload(buf)?;
+        let request_hash = H256::decode(payload)?;
+        let record = *payload;
+        let enr = decode_enr(payload)?;
+        Ok(Self {
+            request_hash,
+            enr,
+            encoded_enr: Bytes::copy_from_slice(&record[..record.len() - payload.len()]),
+        })
+    }
+}
+
+/// Packet of the experimental topic discovery that some older clients still send.
+///
+/// These are only recognized, to tell them apart from garbage, and never acted on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopicPacket {
+    /// TopicRegister, of the same packet type as [`EnrResponseMessage`].
+    Register,
+    Query,
+    Nodes,
+}
+
+impl TopicPacket {
+    pub const REGISTER: u8 = 6;
+    pub const QUERY: u8 = 7;
+    pub const NODES: u8 = 8;
+
+    /// Recognize a topic packet by its packet type and the shape of its data.
+    ///
+    /// TopicRegister starts with the list of topics, which the request hash that starts
+    /// ENRResponse never is.
+    pub fn recognize(packet_type: u8, data: &[u8]) -> Option<Self> {
+        let packet = match packet_type {
+            Self::REGISTER => Self::Register,
+            Self::QUERY => Self::Query,
+            Self::NODES => Self::Nodes,
+            _ => return None,
+        };
+        let b = &mut &*data;
+        if !Header::decode(b).ok()?.list {
+            return None;
+        }
+        if packet == Self::Register && !Header::decode(b).ok()?.list {
+            return None;
+        }
+        Some(packet)
+    }
+
+    pub fn packet_type(self) -> u8 {
+        match self {
+            Self::Register => Self::REGISTER,
+            Self::Query => Self::QUERY,
+            Self::Nodes => Self::NODES,
+        }
+    }
+}
+
+/// What to do with [`TopicPacket`]s received.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TopicPacketPolicy {
+    /// Drop them, only counting them in [metrics](super::metrics).
+    #[default]
+    Drop,
+    /// Drop them and log each one.
+    Log,
+}
+
+/// Any discovery v4 message, tagged by its packet type.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Message {
+    Ping(PingMessage),
+    Pong(PongMessage),
+    FindNode(FindNodeMessage),
+    Neighbours(NeighboursMessage),
+    EnrRequest(EnrRequestMessage),
+    EnrResponse(EnrResponseMessage),
+}
+
+impl Message {
+    /// Packet type byte that precedes the message data on the wire.
+    pub fn packet_type(&self) -> u8 {
+        let id = match self {
+            Self::Ping(_) => MessageId::Ping,
+            Self::Pong(_) => MessageId::Pong,
+            Self::FindNode(_) => MessageId::FindNode,
+            Self::Neighbours(_) => MessageId::Neighbours,
+            Self::EnrRequest(_) => MessageId::EnrRequest,
+            Self::EnrResponse(_) => MessageId::EnrResponse,
+        };
+        id as u8
+    }
+
+    pub fn decode(packet_type: u8, buf: &mut &[u8]) -> Result<Self, MessageError> {
+        Ok(match MessageId::from_u8(packet_type) {
+            Some(MessageId::Ping) => Self::Ping(PingMessage::decode_message(buf)?),
+            Some(MessageId::Pong) => Self::Pong(PongMessage::decode_message(buf)?),
+            Some(MessageId::FindNode) => Self::FindNode(FindNodeMessage::decode(buf)?),
+            Some(MessageId::Neighbours) => {
+                Self::Neighbours(NeighboursMessage::decode_message(buf)?)
+            }
+            Some(MessageId::EnrRequest) => Self::EnrRequest(EnrRequestMessage::decode(buf)?),
+            Some(MessageId::EnrResponse) => match TopicPacket::recognize(packet_type, buf) {
+                Some(topic) => return Err(MessageError::TopicPacket(topic)),
+                None => Self::EnrResponse(EnrResponseMessage::decode(buf)?),
+            },
+            None => {
+                return Err(match TopicPacket::recognize(packet_type, buf) {
+                    Some(topic) => MessageError::TopicPacket(topic),
+                    None => MessageError::UnknownPacketType(packet_type),
+                })
+            }
+        })
+    }
+
+    /// Same as [`Message::decode`], but rejects messages that expired
+    /// more than `grace` seconds before `now`.
+    pub fn decode_checked(
+        packet_type: u8,
+        buf: &mut &[u8],
+        now: u64,
+        grace: u64,
+    ) -> Result<Self, MessageError> {
+        let message = Self::decode(packet_type, buf)?;
+        match message.expire() {
+            Some(expire) if expire < now.saturating_sub(grace) => {
+                Err(MessageError::Expired(expire))
+            }
+            _ => Ok(message),
+        }
+    }
+
+    /// Expiration Unix timestamp of the message, if it carries one.
+    pub fn expire(&self) -> Option<u64> {
+        match self {
+            Self::Ping(message) => Some(message.expire),
+            Self::Pong(message) => Some(message.expire),
+            Self::FindNode(message) => Some(message.expire),
+            Self::Neighbours(message) => Some(message.expire),
+            Self::EnrRequest(message) => Some(message.expire),
+            Self::EnrResponse(_) => None,
+        }
+    }
+
+    /// Whether the message expired before `now`. A message expiring exactly at `now` is still valid.
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expire(), Some(expire) if expire < now)
+    }
+}
+
+impl Encodable for Message {
+    fn encode(&self, out: &mut dyn BufMut) {
+        match self {
+            Self::Ping(message) => message.encode(out),
+            Self::Pong(message) => message.encode(out),
+            Self::FindNode(message) => message.encode(out),
+            Self::Neighbours(message) => message.encode(out),
+            Self::EnrRequest(message) => message.encode(out),
+            Self::EnrResponse(message) => message.encode(out),
+        }
+    }
+
+    fn length(&self) -> usize {
+        match self {
+            Self::Ping(message) => message.length(),
+            Self::Pong(message) => message.length(),
+            Self::FindNode(message) => message.length(),
+            Self::Neighbours(message) => message.length(),
+            Self::EnrRequest(message) => message.length(),
+            Self::EnrResponse(message) => message.length(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::v4::packet::{MAX_PACKET_SIZE, MIN_PACKET_SIZE};
+    use enr::EnrBuilder;
+    use hex_literal::hex;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn ip_integer_encoding() {
+        assert_eq!(
+            Ip::decode(&mut &hex!("05")[..]).unwrap(),
+            Ip(Ipv4Addr::new(0, 0, 0, 5).into())
+        );
+        assert_eq!(
+            Ip::decode(&mut &hex!("83010203")[..]).unwrap(),
+            Ip(Ipv4Addr::new(0, 1, 2, 3).into())
+        );
+        assert_eq!(
+            Ip::decode(&mut &hex!("847f000001")[..]).unwrap(),
+            Ip(Ipv4Addr::new(127, 0, 0, 1).into())
+        );
+        assert!(Ip::decode(&mut &hex!("850102030405")[..]).is_err());
+    }
+
+    #[test]
+    fn ip_integer_encoding_too_short() {
+        assert_eq!(
+            Ip::decode(&mut &hex!("830102")[..]).unwrap_err(),
+            DecodeError::InputTooShort
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses() {
+        let mapped = Ip("::ffff:1.2.3.4".parse().unwrap());
+        let plain = Ip(Ipv4Addr::new(1, 2, 3, 4).into());
+        assert_eq!(mapped.normalized(), plain);
+        assert_eq!(plain.normalized(), plain);
+        let v6 = Ip("2001:db8::ffff:102:304".parse().unwrap());
+        assert_eq!(v6.normalized(), v6);
+
+        let mut data = Vec::new();
+        mapped.encode(&mut data);
+        assert_eq!(data.len(), 17);
+        let decoded = Ip::decode(&mut &data[..]).unwrap();
+        assert_eq!(decoded, plain);
+
+        // Once decoded, the address is encoded as IPv4 and stays that way.
+        let mut again = Vec::new();
+        decoded.encode(&mut again);
+        assert_eq!(again, hex!("8401020304"));
+        assert_eq!(Ip::decode(&mut &again[..]).unwrap(), plain);
+    }
+
+    #[test]
+    fn zero_ports() {
+        let localhost = Ip(Ipv4Addr::LOCALHOST.into());
+        for data in [hex!("c7847f0000018000"), hex!("c7847f0000010080")] {
+            let endpoint = Endpoint::decode(&mut &data[..]).unwrap();
+            assert_eq!(endpoint.address, localhost);
+            assert_eq!((endpoint.udp_port, endpoint.tcp_port), (0, 0));
+        }
+
+        let id = NodeId::repeat_byte(1);
+        let mut data = hex!("f84b847f00000182765f80b840").to_vec();
+        data.extend_from_slice(id.as_bytes());
+        let record = NodeRecord::decode(&mut &data[..]).unwrap();
+        assert_eq!(record.tcp_port, 0);
+        assert_eq!(record.udp_port, 30303);
+        assert_eq!(record.id, id);
+        assert!(!record.is_dialable());
+
+        // Canonical otherwise: other integers with leading zeros are still rejected.
+        assert!(Endpoint::decode(&mut &hex!("c9847f00000182000180")[..]).is_err());
+    }
+
+    #[test]
+    fn node_record_port_order() {
+        let record = NodeRecord {
+            address: Ip(Ipv4Addr::LOCALHOST.into()),
+            udp_port: 30301,
+            tcp_port: 30303,
+            id: NodeId::repeat_byte(1),
+        };
+        let mut data = Vec::new();
+        record.encode(&mut data);
+        // UDP port first, as in endpoints.
+        let mut expected = hex!("f84d847f00000182765d82765fb840").to_vec();
+        expected.extend_from_slice(record.id.as_bytes());
+        assert_eq!(data, expected);
+
+        let decoded = NodeRecord::decode(&mut &data[..]).unwrap();
+        assert_eq!(decoded.udp_addr(), "127.0.0.1:30301".parse().unwrap());
+        assert_eq!(decoded.tcp_port, 30303);
+    }
+
+    #[test]
+    fn structured_errors() {
+        for (data, error) in [
+            (&hex!("80")[..], MessageError::Empty),
+            (&hex!("850102030405")[..], MessageError::WrongIpLength),
+            (
+                &hex!("90fe800000000000000000000000000001")[..],
+                MessageError::ScopedAddress,
+            ),
+            (
+                &hex!("c0")[..],
+                MessageError::Rlp(DecodeError::UnexpectedList),
+            ),
+        ] {
+            assert_eq!(Ip::decode_message(&mut &*data).unwrap_err(), error);
+            assert_eq!(
+                DecodeError::from(error),
+                Ip::decode(&mut &*data).unwrap_err()
+            );
+        }
+
+        // Typed through the nested decoders too: Pong to an endpoint with an empty address.
+        let mut pong = hex!("e6c3808080a0").to_vec();
+        pong.extend_from_slice(&[0; 32]);
+        pong.push(0x01);
+        assert_eq!(
+            Message::decode(MessageId::Pong as u8, &mut &pong[..]).unwrap_err(),
+            MessageError::Empty
+        );
+
+        assert_eq!(
+            Message::decode(0xff, &mut &hex!("c0")[..]).unwrap_err(),
+            MessageError::UnknownPacketType(0xff)
+        );
+    }
+
+    fn test_enr() -> Enr<SecretKey> {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        EnrBuilder::new("v4")
+            .ip4(Ipv4Addr::new(127, 0, 0, 1))
+            .udp4(30303)
+            .build(&secret_key)
+            .unwrap()
+    }
+
+    fn neighbours(count: usize) -> NeighboursMessage {
+        NeighboursMessage {
+            nodes: (0..count)
+                .map(|i| NodeRecord {
+                    address: Ip(Ipv4Addr::new(10, 0, 0, i as u8).into()),
+                    tcp_port: 30303,
+                    udp_port: 30303,
+                    id: NodeId::random(),
+                })
+                .collect(),
+            expire: 1_000_000,
+        }
+    }
+
+    #[test]
+    fn neighbours_max_nodes() {
+        let message = neighbours(MAX_NEIGHBOURS);
+        let mut data = Vec::new();
+        message.encode(&mut data);
+
+        let buf = &mut &data[..];
+        let decoded = NeighboursMessage::decode(buf).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(decoded.nodes.len(), MAX_NEIGHBOURS);
+        assert_eq!(decoded.expire, message.expire);
+        for (decoded, node) in decoded.nodes.iter().zip(&message.nodes) {
+            assert_eq!(decoded.id, node.id);
+            assert_eq!(decoded.address, node.address);
+        }
+    }
+
+    #[test]
+    fn neighbours_too_many_nodes() {
+        let mut data = Vec::new();
+        neighbours(MAX_NEIGHBOURS + 1).encode(&mut data);
+
+        assert_eq!(
+            NeighboursMessage::decode_message(&mut &data[..]).unwrap_err(),
+            MessageError::TooManyNeighbours
+        );
+        assert_eq!(
+            Message::decode(MessageId::Neighbours as u8, &mut &data[..]).unwrap_err(),
+            MessageError::TooManyNeighbours
+        );
+    }
+
+    #[test]
+    fn neighbours_split() {
+        let mut message = neighbours(MAX_NEIGHBOURS);
+        for node in &mut message.nodes[MAX_NEIGHBOURS / 2..] {
+            node.address = Ip(std::net::Ipv6Addr::LOCALHOST.into());
+        }
+        let max_length = MAX_PACKET_SIZE - MIN_PACKET_SIZE;
+        assert!(message.length() > max_length);
+
+        let ids = message.nodes.iter().map(|node| node.id).collect::<Vec<_>>();
+        let split = message.clone().split(max_length);
+        assert!(split.len() > 1);
+        for part in &split {
+            assert!(part.length() <= max_length);
+            assert_eq!(part.expire, message.expire);
+        }
+        assert_eq!(
+            split
+                .iter()
+                .flat_map(|part| part.nodes.iter().map(|node| node.id))
+                .collect::<Vec<_>>(),
+            ids
+        );
+
+        // Fits already, or nothing to split.
+        assert_eq!(neighbours(3).split(max_length).len(), 1);
+        assert_eq!(neighbours(0).split(max_length).len(), 1);
+        // Every message carries a node, even if oversized.
+        assert_eq!(neighbours(3).split(0).len(), 3);
+    }
+
+    #[test]
+    fn neighbours_iter() {
+        let message = neighbours(MAX_NEIGHBOURS + 1);
+        let mut data = Vec::new();
+        message.encode(&mut data);
+
+        let buf = &mut &data[..];
+        let iter = NeighboursIter::new(buf).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(iter.expire(), message.expire);
+        assert_eq!(
+            iter.take(2)
+                .map(|node| node.unwrap().id)
+                .collect::<Vec<_>>(),
+            message.nodes[..2]
+                .iter()
+                .map(|node| node.id)
+                .collect::<Vec<_>>()
+        );
+
+        let mut iter = NeighboursIter::new(&mut &data[..]).unwrap();
+        assert_eq!(iter.by_ref().count(), MAX_NEIGHBOURS + 1);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn scoped_addresses() {
+        for (address, scoped) in [
+            ("fe80::1", true),
+            ("febf::1", true),
+            ("ff02::1", true),
+            ("fec0::1", false),
+            ("2001:db8::1", false),
+            ("::1", false),
+            ("169.254.0.1", false),
+        ] {
+            let ip = Ip(address.parse().unwrap());
+            assert_eq!(ip.is_scoped(), scoped, "{address}");
+
+            let mut data = Vec::new();
+            ip.encode(&mut data);
+            assert_eq!(Ip::decode(&mut &data[..]).is_err(), scoped, "{address}");
+        }
+
+        // Such nodes are left out of Neighbours, the rest are kept.
+        let mut message = neighbours(3);
+        message.nodes[1].address = Ip("fe80::1".parse().unwrap());
+        let mut data = Vec::new();
+        message.encode(&mut data);
+        let decoded = NeighboursMessage::decode(&mut &data[..]).unwrap();
+        assert_eq!(
+            decoded.nodes.iter().map(|node| node.id).collect::<Vec<_>>(),
+            [message.nodes[0].id, message.nodes[2].id]
+        );
+    }
+
+    #[test]
+    fn neighbours_lenient() {
+        let message = neighbours(3);
+        let mut data = Vec::new();
+        message.encode(&mut data);
+        let ids = message.nodes.iter().map(|node| node.id).collect::<Vec<_>>();
+
+        let buf = &mut &data[..];
+        let decoded = NeighboursMessage::decode_lenient(buf).unwrap();
+        assert!(buf.is_empty());
+        assert!(decoded.complete);
+        assert_eq!(decoded.expire, Some(message.expire));
+        assert_eq!(decoded.nodes.len(), 3);
+
+        // Expiration cut off.
+        let truncated = &data[..data.len() - 1];
+        assert!(NeighboursMessage::decode(&mut &*truncated).is_err());
+        let decoded = NeighboursMessage::decode_lenient(&mut &*truncated).unwrap();
+        assert!(decoded.complete);
+        assert_eq!(decoded.expire, None);
+        assert_eq!(decoded.nodes.len(), 3);
+
+        // Last node cut off.
+        let truncated = &data[..data.len() - 20];
+        assert!(NeighboursMessage::decode(&mut &*truncated).is_err());
+        let decoded = NeighboursMessage::decode_lenient(&mut &*truncated).unwrap();
+        assert!(!decoded.complete);
+        assert_eq!(decoded.expire, None);
+        assert_eq!(
+            decoded.nodes.iter().map(|node| node.id).collect::<Vec<_>>(),
+            ids[..2]
+        );
+
+        // Oversized lists are cut at the limit.
+        let mut data = Vec::new();
+        neighbours(MAX_NEIGHBOURS + 1).encode(&mut data);
+        let decoded = NeighboursMessage::decode_lenient(&mut &data[..]).unwrap();
+        assert!(!decoded.complete);
+        assert_eq!(decoded.nodes.len(), MAX_NEIGHBOURS);
+
+        assert!(NeighboursMessage::decode_lenient(&mut &hex!("80")[..]).is_err());
+    }
+
+    #[test]
+    fn neighbours_iter_malformed_node() {
+        let mut data = Vec::new();
+        Header {
+            list: true,
+            payload_length: 3 + 1,
+        }
+        .encode(&mut data);
+        // Node list holding a single string instead of node records.
+        data.extend_from_slice(&hex!("c2 8100"));
+        1_u8.encode(&mut data);
+
+        let mut iter = NeighboursIter::new(&mut &data[..]).unwrap();
+        assert_eq!(iter.expire(), 1);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn enr_seq_roundtrip() {
+        let from = Endpoint {
+            address: Ip(Ipv4Addr::new(10, 0, 0, 1).into()),
+            udp_port: 30301,
+            tcp_port: 30303,
+        };
+        let to = Endpoint {
+            address: Ip(Ipv4Addr::new(10, 0, 0, 2).into()),
+            ..from
+        };
+
+        for enr_seq in [Some(7), Some(0), None] {
+            let ping = PingMessage {
+                version: PROTOCOL_VERSION,
+                from,
+                to,
+                expire: 1_000_000,
+                enr_seq,
+            };
+            let mut data = Vec::new();
+            ping.encode(&mut data);
+            assert_eq!(data.len(), ping.length());
+            let buf = &mut &data[..];
+            let decoded = PingMessage::decode(buf).unwrap();
+            assert!(buf.is_empty());
+            assert_eq!(decoded.version, PROTOCOL_VERSION);
+            assert_eq!(decoded.from, from);
+            assert_eq!(decoded.to, to);
+            assert_eq!(decoded.expire, 1_000_000);
+            assert_eq!(decoded.enr_seq, enr_seq);
+
+            let pong = PongMessage {
+                to,
+                echo: H256::random(),
+                expire: 1_000_000,
+                enr_seq,
+            };
+            let mut data = Vec::new();
+            pong.encode(&mut data);
+            assert_eq!(data.len(), pong.length());
+            let buf = &mut &data[..];
+            let decoded = PongMessage::decode(buf).unwrap();
+            assert!(buf.is_empty());
+            assert_eq!(decoded.to, to);
+            assert_eq!(decoded.echo, pong.echo);
+            assert_eq!(decoded.expire, 1_000_000);
+            assert_eq!(decoded.enr_seq, enr_seq);
+        }
+    }
+
+    #[test]
+    fn ping_version() {
+        let endpoint = Endpoint {
+            address: Ip(Ipv4Addr::LOCALHOST.into()),
+            udp_port: 30303,
+            tcp_port: 30303,
+        };
+        let message = PingMessage {
+            version: 5,
+            from: endpoint,
+            to: endpoint,
+            expire: 1_000_000,
+            enr_seq: Some(1),
+        };
+
+        let mut data = Vec::new();
+        message.encode(&mut data);
+        let decoded = PingMessage::decode(&mut &data[..]).unwrap();
+        assert_eq!(decoded.version, 5);
+        assert_eq!(decoded.enr_seq, Some(1));
+
+        assert!(decoded.check_version(VersionPolicy::Lenient).is_ok());
+        assert_eq!(
+            decoded.check_version(VersionPolicy::Strict),
+            Err(MessageError::UnsupportedVersion(5))
+        );
+
+        let message = PingMessage {
+            version: PROTOCOL_VERSION,
+            ..message
+        };
+        assert!(message.check_version(VersionPolicy::Strict).is_ok());
+    }
+
+    #[test]
+    fn pong_respond_to() {
+        let from = Endpoint {
+            address: Ip(Ipv4Addr::new(10, 0, 0, 1).into()),
+            udp_port: 30301,
+            tcp_port: 30303,
+        };
+        let ping_hash = H256::random();
+        let pong = PongMessage::respond_to(ping_hash, from, 1_000_000);
+        assert_eq!(pong.to, from);
+        assert_eq!(pong.echo, ping_hash);
+        assert_eq!(pong.expire, 1_000_000);
+        assert_eq!(pong.enr_seq, None);
+
+        let mut data = Vec::new();
+        pong.encode(&mut data);
+        let buf = &mut &data[..];
+        assert!(Header::decode(buf).unwrap().list);
+        Endpoint::decode(buf).unwrap();
+        // The echo is the full 32 byte hash.
+        let echo = Header::decode(buf).unwrap();
+        assert!(!echo.list);
+        assert_eq!(echo.payload_length, 32);
+        assert_eq!(&buf[..32], ping_hash.as_bytes());
+
+        let decoded = PongMessage::decode(&mut &data[..]).unwrap();
+        assert_eq!(decoded.echo, ping_hash);
+    }
+
+    /// RLP list of the already encoded `elements`.
+    fn rlp_list(elements: &[&[u8]]) -> Vec<u8> {
+        let payload = elements.concat();
+        let mut out = Vec::new();
+        Header {
+            list: true,
+            payload_length: payload.len(),
+        }
+        .encode(&mut out);
+        out.extend_from_slice(&payload);
+        out
+    }
+
+    fn rlp<T: Encodable>(value: T) -> Vec<u8> {
+        let mut out = Vec::new();
+        value.encode(&mut out);
+        out
+    }
+
+    #[test]
+    fn trailing_elements() {
+        let endpoint = Endpoint {
+            address: Ip(Ipv4Addr::new(10, 0, 0, 1).into()),
+            udp_port: 30301,
+            tcp_port: 30303,
         };
+        let extra_list = hex!("c20102");
+        let extra_string = hex!("83abcdef");
+        let to = rlp_list(&[
+            &rlp(endpoint.address),
+            &rlp(endpoint.udp_port),
+            &rlp(endpoint.tcp_port),
+            &extra_string,
+        ]);
+        assert_eq!(Endpoint::decode(&mut &to[..]).unwrap(), endpoint);
+
+        let echo = H256::repeat_byte(0xaa);
+        let pong =
+            |enr_seq: &[u8]| rlp_list(&[&to, &rlp(echo), &rlp(1_000u64), enr_seq, &extra_list]);
+
+        // Messages are still delimited by their list, whatever they end with.
+        let data = [pong(&rlp(5u64)), pong(&extra_list)].concat();
+        let buf = &mut &data[..];
+        let decoded = PongMessage::decode(buf).unwrap();
+        assert_eq!(decoded.to, endpoint);
+        assert_eq!(decoded.echo, echo);
+        assert_eq!(decoded.enr_seq, Some(5));
+        // Some later addition in place of the ENR sequence number.
+        assert_eq!(PongMessage::decode(buf).unwrap().enr_seq, None);
+        assert!(buf.is_empty());
+
+        // Mandatory fields can't be missing.
+        assert!(PongMessage::decode(&mut &rlp_list(&[&to, &rlp(echo)])[..]).is_err());
+        assert!(Endpoint::decode(&mut &rlp_list(&[&rlp(endpoint.address)])[..]).is_err());
+    }
+
+    #[test]
+    fn expiration() {
+        let message = Message::FindNode(FindNodeMessage {
+            id: NodeId::zero(),
+            expire: 100,
+        });
+        assert!(!message.is_expired(99));
+        assert!(!message.is_expired(100));
+        assert!(message.is_expired(101));
+
+        let mut data = Vec::new();
+        message.encode(&mut data);
+        let packet_type = message.packet_type();
+
+        assert!(Message::decode_checked(packet_type, &mut &data[..], 100, 0).is_ok());
+        assert!(Message::decode_checked(packet_type, &mut &data[..], 105, 5).is_ok());
+        assert_eq!(
+            Message::decode_checked(packet_type, &mut &data[..], 106, 5).unwrap_err(),
+            MessageError::Expired(100)
+        );
+    }
+
+    #[test]
+    fn enr_response_roundtrip() {
+        let message = EnrResponseMessage::new(H256::random(), test_enr());
+
+        let mut out = Vec::new();
+        message.encode(&mut out);
+        assert_eq!(out.len(), message.length());
+
+        let decoded = EnrResponseMessage::decode(&mut &out[..]).unwrap();
+        assert_eq!(decoded.request_hash, message.request_hash);
+        assert_eq!(decoded.enr().to_base64(), message.enr().to_base64());
+
+        let mut reencoded = Vec::new();
+        decoded.encode(&mut reencoded);
+        assert_eq!(reencoded, out);
+    }
+
+    #[test]
+    fn enr_response_short_request_hash() {
+        let enr = rlp::encode(&test_enr());
+        let request_hash = [0xab_u8; 20];
+
+        let mut out = Vec::new();
+        Header {
+            list: true,
+            payload_length: request_hash.length() + enr.len(),
+        }
+        .encode(&mut out);
+        request_hash.encode(&mut out);
+        out.extend_from_slice(&enr);
+
+        assert!(matches!(
+            EnrResponseMessage::decode(&mut &out[..]),
+            Err(DecodeError::UnexpectedLength)
+        ));
+    }
+
+    #[test]
+    fn topic_packets() {
+        let query = hex!("c583666f6f01");
+        let register = hex!("c7c483666f6f8080");
+        assert_eq!(
+            Message::decode(TopicPacket::QUERY, &mut &query[..]).unwrap_err(),
+            MessageError::TopicPacket(TopicPacket::Query)
+        );
+        assert_eq!(
+            Message::decode(TopicPacket::REGISTER, &mut &register[..]).unwrap_err(),
+            MessageError::TopicPacket(TopicPacket::Register)
+        );
+        assert!(!MessageError::TopicPacket(TopicPacket::Nodes).is_malformed());
 
-        Ok(Self { to, echo, expire })
+        // Not a list, or ENRResponse-shaped for the shared packet type.
+        assert_eq!(
+            Message::decode(TopicPacket::NODES, &mut &hex!("80")[..]).unwrap_err(),
+            MessageError::UnknownPacketType(TopicPacket::NODES)
+        );
+        assert_eq!(
+            TopicPacket::recognize(TopicPacket::REGISTER, &hex!("c3808080")),
+            None
+        );
+        assert_eq!(TopicPacket::recognize(9, &query), None);
     }
 }
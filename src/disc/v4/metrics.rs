@@ -0,0 +1,111 @@
+//! Discovery metrics, reported through the [`metrics`](::metrics) facade.
+//!
+//! Recording is compiled in only with the `metrics` feature, otherwise these functions are no-ops.
+
+use super::{message::TopicPacket, proto::MessageId};
+use num_traits::FromPrimitive;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+impl Direction {
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Ingress => "ingress",
+            Self::Egress => "egress",
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+fn packet_type_name(packet_type: u8) -> &'static str {
+    match MessageId::from_u8(packet_type) {
+        Some(MessageId::Ping) => "ping",
+        Some(MessageId::Pong) => "pong",
+        Some(MessageId::FindNode) => "find_node",
+        Some(MessageId::Neighbours) => "neighbours",
+        Some(MessageId::EnrRequest) => "enr_request",
+        Some(MessageId::EnrResponse) => "enr_response",
+        None => "unknown",
+    }
+}
+
+/// Count a packet sent or received.
+#[inline]
+pub fn record_packet(direction: Direction, packet_type: u8) {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!(
+        "discv4_packets_total",
+        "direction" => direction.as_str(),
+        "type" => packet_type_name(packet_type),
+    );
+    #[cfg(not(feature = "metrics"))]
+    let _ = (direction, packet_type);
+}
+
+/// Count a [`TopicPacket`] received, which is dropped rather than counted as an error.
+#[inline]
+pub fn record_topic_packet(topic: TopicPacket) {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!(
+        "discv4_topic_packets_total",
+        "type" => match topic {
+            TopicPacket::Register => "register",
+            TopicPacket::Query => "query",
+            TopicPacket::Nodes => "nodes",
+        },
+    );
+    #[cfg(not(feature = "metrics"))]
+    let _ = topic;
+}
+
+/// Record the time between sending a Ping and receiving the Pong that proves the endpoint.
+#[inline]
+pub fn record_ping_rtt(rtt: Duration) {
+    #[cfg(feature = "metrics")]
+    ::metrics::histogram!("discv4_ping_rtt_seconds", rtt);
+    #[cfg(not(feature = "metrics"))]
+    let _ = rtt;
+}
+
+/// Count a Ping whose sender reported another IP than the one it came from.
+#[inline]
+pub fn record_reported_address_mismatch() {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!("discv4_reported_address_mismatch_total");
+}
+
+/// Count a table entry evicted for not answering after its liveness TTL ran out.
+#[inline]
+pub fn record_stale_eviction() {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!("discv4_stale_evictions_total");
+}
+
+/// Count a node kept out of the table for exceeding the subnet limits.
+#[inline]
+pub fn record_subnet_limit_rejection() {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!("discv4_subnet_limit_rejections_total");
+}
+
+/// Count a node relayed in Neighbours without an address or UDP port to ping it at.
+#[inline]
+pub fn record_invalid_neighbour() {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!("discv4_invalid_neighbours_total");
+}
+
+/// Count a Ping received with a protocol version other than the one we speak.
+///
+/// Remotes can claim any version, so the version is not a label.
+#[inline]
+pub fn record_unexpected_ping_version() {
+    #[cfg(feature = "metrics")]
+    ::metrics::increment_counter!("discv4_ping_unexpected_version_total");
+}
@@ -2,21 +2,62 @@
 
 #![allow(clippy::type_complexity)]
 
+pub mod clock;
+pub mod crawler;
+pub mod enr_cache;
+pub mod events;
+pub mod filter;
 pub mod kad;
 pub mod message;
+pub mod metrics;
+pub mod nat;
 pub mod node;
+pub mod packet;
+pub mod proof;
 pub mod proto;
+pub mod ratelimit;
+pub mod reputation;
+pub mod rng;
+pub mod seed;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testutil;
+pub mod transport;
 pub mod util;
 
 use educe::Educe;
 use ethereum_types::H512;
-use std::{pin::Pin, sync::Arc};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+};
 use task_group::TaskGroup;
-use tokio::sync::mpsc::{channel, Receiver};
+use tokio::sync::Notify;
 use tokio_stream::Stream;
 
 pub type NodeId = H512;
-pub use self::node::{Node, NodeRecord};
+pub use self::node::{
+    BootstrapState, FindNodePolicy, LookupResult, MaintenanceConfig, Node, NodeBuilder, NodeConfig,
+    NodeConfigError, NodeRecord, NodeRecordBuildError, NodeRecordBuilder, PingError,
+    RequestTimeouts, WarmStart,
+};
+
+/// What to do with a discovered node when the [`Discv4`] stream consumer lags behind
+/// and [`Discv4Builder::with_cache`] nodes are queued already.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Make room by dropping the node discovered the longest ago, which is the most likely
+    /// to be stale by now.
+    #[default]
+    DropOldest,
+    /// Drop the node just discovered.
+    DropNewest,
+}
 
 #[derive(Educe)]
 #[educe(Default)]
@@ -25,6 +66,7 @@ pub struct Discv4Builder {
     concurrent_lookups: usize,
     #[educe(Default(20))]
     cache: usize,
+    overflow_policy: OverflowPolicy,
 }
 
 impl Discv4Builder {
@@ -33,63 +75,210 @@ impl Discv4Builder {
         self
     }
 
+    /// Number of discovered nodes queued for the consumer, 20 by default and at least 1.
     pub fn with_cache(mut self, cache: usize) -> Self {
         self.cache = cache;
         self
     }
 
+    pub fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
     pub fn build(self, node: Arc<Node>) -> Discv4 {
-        Discv4::new(node, self.concurrent_lookups, self.cache)
+        Discv4::new(
+            node,
+            self.concurrent_lookups,
+            self.cache,
+            self.overflow_policy,
+        )
     }
 }
 
+#[derive(Debug, Default)]
+struct QueueInner {
+    records: VecDeque<crate::NodeRecord>,
+    waker: Option<Waker>,
+}
+
+/// Bounded queue of discovered nodes. Pushing never blocks, the lookups feeding it wait for
+/// room before starting the next lookup instead.
+#[derive(Debug)]
+struct Queue {
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+    inner: Mutex<QueueInner>,
+    room: Notify,
+    dropped: AtomicU64,
+}
+
+impl Queue {
+    fn new(capacity: usize, overflow_policy: OverflowPolicy) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            overflow_policy,
+            inner: Mutex::default(),
+            room: Notify::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, record: crate::NodeRecord) {
+        let mut inner = self.inner.lock();
+        if inner.records.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.overflow_policy {
+                OverflowPolicy::DropOldest => {
+                    inner.records.pop_front();
+                }
+                OverflowPolicy::DropNewest => return,
+            }
+        }
+        inner.records.push_back(record);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Wait until the queue is not full.
+    async fn wait_for_room(&self) {
+        while self.inner.lock().records.len() >= self.capacity {
+            self.room.notified().await;
+        }
+    }
+
+    fn poll_pop(&self, cx: &mut Context<'_>) -> Poll<crate::NodeRecord> {
+        let mut inner = self.inner.lock();
+        match inner.records.pop_front() {
+            Some(record) => {
+                self.room.notify_one();
+                Poll::Ready(record)
+            }
+            None => {
+                inner.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Endless stream of dialable nodes found by random lookups.
+///
+/// Lookups pause while the consumer is slow to take the nodes queued already. The nodes that a
+/// running lookup finds with no room left for them are dropped according to the
+/// [`OverflowPolicy`], see [`Discv4::dropped`].
 pub struct Discv4 {
     #[allow(unused)]
     tasks: TaskGroup,
-    receiver: Receiver<crate::NodeRecord>,
+    queue: Arc<Queue>,
 }
 
 impl Discv4 {
     #[must_use]
-    fn new(node: Arc<Node>, concurrent_lookups: usize, cache: usize) -> Self {
+    fn new(
+        node: Arc<Node>,
+        concurrent_lookups: usize,
+        cache: usize,
+        overflow_policy: OverflowPolicy,
+    ) -> Self {
         let tasks = TaskGroup::default();
+        // Lookups run next to the tasks of the node.
+        let _runtime = node.runtime().map(tokio::runtime::Handle::enter);
 
-        let (tx, receiver) = channel(cache);
+        let queue = Arc::new(Queue::new(cache, overflow_policy));
 
         for i in 0..concurrent_lookups {
             let node = node.clone();
-            let tx = tx.clone();
+            let queue = queue.clone();
             tasks.spawn_with_name(format!("discv4 lookup #{}", i), {
                 async move {
-                    let node = node.clone();
-                    let tx = tx.clone();
                     loop {
-                        for record in node.lookup(rand::random()).await {
-                            let _ = tx
-                                .send(crate::NodeRecord {
-                                    addr: record.tcp_addr(),
-                                    id: record.id,
-                                })
-                                .await;
+                        queue.wait_for_room().await;
+                        for record in node
+                            .lookup(node.random_target())
+                            .await
+                            .into_iter()
+                            .filter(NodeRecord::is_dialable)
+                        {
+                            queue.push(crate::NodeRecord {
+                                addr: record.tcp_addr(),
+                                id: record.id,
+                            });
                         }
                     }
                 }
             });
         }
 
-        Self { tasks, receiver }
+        Self { tasks, queue }
+    }
+
+    /// Number of discovered nodes dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
     }
 }
 
 impl Stream for Discv4 {
     type Item = anyhow::Result<crate::NodeRecord>;
 
-    fn poll_next(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Self::Item>> {
-        Pin::new(&mut self.receiver)
-            .poll_recv(cx)
-            .map(|opt| opt.map(Ok))
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.queue.poll_pop(cx).map(|record| Some(Ok(record)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::net::SocketAddr;
+    use tokio_stream::StreamExt;
+
+    fn record(port: u16) -> crate::NodeRecord {
+        crate::NodeRecord {
+            addr: SocketAddr::from(([10, 0, 0, 1], port)),
+            id: NodeId::random(),
+        }
+    }
+
+    async fn drain(queue: Arc<Queue>) -> Vec<u16> {
+        let mut ports = Vec::new();
+        let mut stream = futures::stream::poll_fn(move |cx| queue.poll_pop(cx).map(Some));
+        while let Some(Some(record)) = stream.next().now_or_never() {
+            ports.push(record.addr.port());
+        }
+        ports
+    }
+
+    #[tokio::test]
+    async fn overflow_policies() {
+        for (policy, kept) in [
+            (OverflowPolicy::DropOldest, [3, 4]),
+            (OverflowPolicy::DropNewest, [1, 2]),
+        ] {
+            let queue = Arc::new(Queue::new(2, policy));
+            for port in 1..=4 {
+                queue.push(record(port));
+            }
+            assert_eq!(queue.dropped.load(Ordering::Relaxed), 2);
+            assert_eq!(drain(queue.clone()).await, kept);
+
+            // Room again.
+            queue.push(record(5));
+            assert_eq!(drain(queue).await, [5]);
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups_wait_for_room() {
+        let queue = Arc::new(Queue::new(1, OverflowPolicy::DropOldest));
+        assert!(queue.wait_for_room().now_or_never().is_some());
+
+        queue.push(record(1));
+        let mut room = Box::pin(queue.wait_for_room());
+        assert!((&mut room).now_or_never().is_none());
+        assert_eq!(drain(queue.clone()).await, [1]);
+        assert!(room.now_or_never().is_some());
     }
 }
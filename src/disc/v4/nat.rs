@@ -0,0 +1,179 @@
+//! External address detection.
+//!
+//! Behind NAT, the address we are bound to is not the one other nodes see. Every Pong carries
+//! the address its sender observed our Ping coming from, so once enough distinct nodes agree
+//! on it, it is safe to advertise that address instead. If the external address is known
+//! out of band, it can be supplied with an [`ExternalIpResolver`] instead.
+
+use super::NodeId;
+use auto_impl::auto_impl;
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+/// Source of the external IP address advertised to other nodes.
+#[auto_impl(&, Box, Arc)]
+pub trait ExternalIpResolver: Send + Sync + 'static {
+    /// Current external address, if known.
+    fn resolve(&self) -> Option<IpAddr>;
+}
+
+/// Statically known external address.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedIp(pub IpAddr);
+
+impl ExternalIpResolver for FixedIp {
+    fn resolve(&self) -> Option<IpAddr> {
+        Some(self.0)
+    }
+}
+
+/// Resolvers consulted in order, first resolved address wins.
+#[derive(Default)]
+pub struct ResolverChain(pub Vec<Box<dyn ExternalIpResolver>>);
+
+impl ResolverChain {
+    /// Add `resolver` with a lower priority than the ones added before.
+    pub fn with(mut self, resolver: impl ExternalIpResolver) -> Self {
+        self.0.push(Box::new(resolver));
+        self
+    }
+}
+
+impl ExternalIpResolver for ResolverChain {
+    fn resolve(&self) -> Option<IpAddr> {
+        self.0.iter().find_map(|resolver| resolver.resolve())
+    }
+}
+
+/// Address inferred from Pongs, shared with the discovery node that records the votes.
+#[derive(Clone, Debug)]
+pub struct PongExternalIp {
+    votes: Arc<Mutex<ExternalAddressVotes>>,
+    threshold: usize,
+}
+
+impl PongExternalIp {
+    /// Resolve once at least `threshold` nodes agree on the address.
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            votes: Default::default(),
+            threshold,
+        }
+    }
+
+    pub fn record(&self, voter: NodeId, address: IpAddr) {
+        self.votes.lock().record(voter, address)
+    }
+}
+
+impl ExternalIpResolver for PongExternalIp {
+    fn resolve(&self) -> Option<IpAddr> {
+        self.votes.lock().majority(self.threshold)
+    }
+}
+
+/// Number of most recent voters remembered, so that stale observations are phased out.
+pub const MAX_EXTERNAL_ADDRESS_VOTES: usize = 64;
+
+/// Observations of our external address, one vote per remote node.
+#[derive(Debug)]
+pub struct ExternalAddressVotes {
+    votes: LruCache<NodeId, IpAddr>,
+}
+
+impl Default for ExternalAddressVotes {
+    fn default() -> Self {
+        Self {
+            votes: LruCache::new(MAX_EXTERNAL_ADDRESS_VOTES),
+        }
+    }
+}
+
+impl ExternalAddressVotes {
+    /// Record that `voter` observed us at `address`, replacing its previous vote.
+    pub fn record(&mut self, voter: NodeId, address: IpAddr) {
+        self.votes.put(voter, address);
+    }
+
+    /// Address with the most votes, if it got at least `threshold` of them and no other
+    /// address got as many.
+    pub fn majority(&self, threshold: usize) -> Option<IpAddr> {
+        let mut tally = HashMap::<IpAddr, usize>::new();
+        for (_, address) in self.votes.iter() {
+            *tally.entry(*address).or_default() += 1;
+        }
+
+        let mut best = None;
+        let mut tie = false;
+        for (address, count) in tally {
+            match best {
+                Some((_, best_count)) if count < best_count => {}
+                Some((_, best_count)) if count == best_count => tie = true,
+                _ => {
+                    best = Some((address, count));
+                    tie = false;
+                }
+            }
+        }
+
+        best.filter(|(_, count)| !tie && *count >= threshold)
+            .map(|(address, _)| address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2));
+
+    #[test]
+    fn majority_with_threshold() {
+        let mut votes = ExternalAddressVotes::default();
+        assert_eq!(votes.majority(1), None);
+
+        let voter = NodeId::random();
+        votes.record(voter, A);
+        assert_eq!(votes.majority(1), Some(A));
+        assert_eq!(votes.majority(2), None);
+
+        // Repeated vote by the same node does not count twice.
+        votes.record(voter, A);
+        assert_eq!(votes.majority(2), None);
+
+        votes.record(NodeId::random(), A);
+        votes.record(NodeId::random(), B);
+        assert_eq!(votes.majority(2), Some(A));
+
+        // Tie
+        votes.record(NodeId::random(), B);
+        assert_eq!(votes.majority(1), None);
+    }
+
+    #[test]
+    fn resolver_chain_priority() {
+        let pong = PongExternalIp::new(1);
+        let chain = ResolverChain::default().with(pong.clone()).with(FixedIp(B));
+        assert_eq!(chain.resolve(), Some(B));
+
+        pong.record(NodeId::random(), A);
+        assert_eq!(chain.resolve(), Some(A));
+
+        assert_eq!(ResolverChain::default().resolve(), None);
+    }
+
+    #[test]
+    fn old_votes_are_evicted() {
+        let mut votes = ExternalAddressVotes::default();
+        for _ in 0..MAX_EXTERNAL_ADDRESS_VOTES {
+            votes.record(NodeId::random(), A);
+        }
+        for _ in 0..MAX_EXTERNAL_ADDRESS_VOTES / 2 + 1 {
+            votes.record(NodeId::random(), B);
+        }
+        assert_eq!(votes.majority(1), Some(B));
+    }
+}
@@ -1,23 +1,46 @@
-use super::{kad::*, message::*, proto::*, util::*, NodeId};
+use super::{
+    clock::*,
+    crawler::*,
+    enr_cache::*,
+    events::*,
+    filter::*,
+    kad::*,
+    message::*,
+    metrics::{self, Direction},
+    nat::*,
+    packet::*,
+    proof::*,
+    proto::*,
+    ratelimit::*,
+    reputation::*,
+    rng::*,
+    seed::normalize_ip,
+    transport::*,
+    util::*,
+    NodeId,
+};
+use crate::{
+    types::Enr,
+    util::{short_id_debug, NodeIdExt},
+};
 use anyhow::{anyhow, bail, Context};
-use bytes::{BufMut, BytesMut};
-use chrono::Utc;
+use bytes::BytesMut;
+use educe::Educe;
 use ethereum_types::H256;
 use fastrlp::*;
-use futures::future::join_all;
+use futures::{future::join_all, stream, Stream, StreamExt};
 use igd::aio::search_gateway;
-use num_traits::FromPrimitive;
+use lru::LruCache;
 use parking_lot::{Mutex, RwLock};
-use rand::{distributions::Standard, prelude::SliceRandom, thread_rng, Rng};
-use secp256k1::{
-    ecdsa::{RecoverableSignature, RecoveryId},
-    Message, PublicKey, SecretKey, SECP256K1,
-};
-use sha3::{Digest, Keccak256};
+use rand::{distributions::Standard, prelude::SliceRandom, Rng};
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
 use std::{
-    collections::{btree_map, hash_map, BTreeMap, HashMap},
+    collections::{btree_map, hash_map, BTreeMap, HashMap, HashSet},
     convert::TryFrom,
-    net::{IpAddr, SocketAddr},
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
     str::FromStr,
     sync::Arc,
     time::Duration,
@@ -26,50 +49,642 @@ use task_group::TaskGroup;
 use thiserror::Error;
 use tokio::{
     net::UdpSocket,
+    runtime::Handle,
+    select,
     sync::{
-        mpsc::{channel, Sender},
-        oneshot::{channel as oneshot, Sender as OneshotSender},
+        mpsc::{channel, Receiver, Sender},
+        oneshot::{channel as oneshot, Receiver as OneshotReceiver, Sender as OneshotSender},
+        Mutex as AsyncMutex, Semaphore,
     },
-    time::{sleep, timeout},
+    time::{sleep, timeout, timeout_at, Instant},
 };
+use tokio_util::sync::CancellationToken;
 use tracing::*;
 use url::{Host, Url};
 
 pub type RequestId = u64;
 
-pub const MAX_PACKET_SIZE: usize = 1280;
-
 pub const UPNP_INTERVAL: Duration = Duration::from_secs(60);
 pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
 pub const REFRESH_TIMEOUT: Duration = Duration::from_secs(60);
+pub const REFRESH_TARGETS: usize = 3;
+pub const MAX_OUTSTANDING_QUERIES: usize = 16;
+pub const RESTORE_CONCURRENCY: usize = 32;
 pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+pub const LIVENESS_TTL: Duration = Duration::from_secs(60 * 60);
+/// Stale nodes re-pinged at once by the liveness check.
+const LIVENESS_CHECK_CONCURRENCY: usize = 16;
 pub const FIND_NODE_TIMEOUT: Duration = Duration::from_secs(10);
 pub const QUERY_AWAIT_PING_TIME: Duration = Duration::from_secs(2);
 pub const NEIGHBOURS_WAIT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Tolerated clock skew when checking expiration of incoming messages.
+pub const EXPIRATION_GRACE: Duration = Duration::from_secs(5);
+pub const EXTERNAL_ADDRESS_THRESHOLD: usize = 3;
+pub const BOOTSTRAP_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+pub const BOOTSTRAP_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+pub const RANDOM_WALK_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+pub const RANDOM_WALK_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+/// Ids a [`Node::random_walk`] remembers as yielded, the ones seen least recently are
+/// forgotten first and may be yielded again.
+pub const RANDOM_WALK_MAX_SEEN: usize = 100_000;
+
+/// Expiration timestamp set on outgoing Ping and FindNode messages.
+///
+/// Recipients drop messages that expired by the time they arrive, the same way
+/// [`Message::decode_checked`] does here with [`EXPIRATION_GRACE`] for the clock skew.
+/// The lifetime should therefore cover both the network latency and the difference between
+/// the clocks. It is clamped to [`ExpiryPolicy::MIN_LIFETIME`]..=[`ExpiryPolicy::MAX_LIFETIME`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExpiryPolicy {
+    lifetime: Duration,
+}
+
+impl Default for ExpiryPolicy {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_LIFETIME)
+    }
+}
+
+impl ExpiryPolicy {
+    pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(20);
+    pub const MIN_LIFETIME: Duration = Duration::from_secs(1);
+    /// Peers are free to reject messages expiring too far in the future.
+    pub const MAX_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            lifetime: lifetime.clamp(Self::MIN_LIFETIME, Self::MAX_LIFETIME),
+        }
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Expiration of a message sent at `now`, as Unix timestamp.
+    pub fn expire_at(&self, now: u64) -> u64 {
+        now.saturating_add(self.lifetime.as_secs())
+    }
+
+    /// Expiration of a message sent right now.
+    pub fn expire(&self) -> u64 {
+        self.expire_at(unix_timestamp())
+    }
+}
+
+/// Which FindNode requests get a Neighbours response. Denied ones are dropped silently,
+/// the same way as requests over the [response rate limit](NodeConfig::response_rate_limit).
+///
+/// The default follows the spec: only nodes with a valid endpoint proof are answered, at the
+/// rate of [`NodeConfig::response_rate_limit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FindNodePolicy {
+    /// Answer only nodes that proved their endpoint by a recent Pong. Without it, a forged
+    /// source address turns every FindNode into an amplified response to the victim.
+    pub require_proof: bool,
+    /// Limit on FindNode requests answered per IP, on top of the response rate limit and
+    /// without its exemptions for bootstrap and table nodes, `None` to disable.
+    pub rate_limit: Option<RateLimit>,
+    /// Answer only lookups for targets within this [log2 distance](log2_distance) of
+    /// our own ID, e.g. to serve the neighbourhood of the node only.
+    pub max_target_distance: Option<u16>,
+}
+
+impl Default for FindNodePolicy {
+    fn default() -> Self {
+        Self {
+            require_proof: true,
+            rate_limit: None,
+            max_target_distance: None,
+        }
+    }
+}
+
+impl FindNodePolicy {
+    /// Why a FindNode for a target at `target_distance` is denied to a node that has
+    /// `proven` its endpoint or not, before the rate limit is checked.
+    fn denial(&self, proven: bool, target_distance: u16) -> Option<&'static str> {
+        if self.require_proof && !proven {
+            Some("unproofed")
+        } else if matches!(self.max_target_distance, Some(max) if target_distance > max) {
+            Some("target too far")
+        } else {
+            None
+        }
+    }
+}
+
+/// Timeouts of single requests to other nodes, and whether to send them once more before
+/// giving up, e.g. for high-latency links.
+///
+/// The defaults are [`PING_TIMEOUT`] and [`FIND_NODE_TIMEOUT`], without retries. A retried
+/// Ping expires later than the first one, so that it has a hash and a Pong of its own.
+///
+/// A Ping takes up to `2 * ping + retry_backoff` with retries. A query of a node, bonding
+/// included, takes up to `find_node`, which should be raised along with the ping timeout for
+/// the retries to fit into it. With no node answering, a lookup takes
+/// `lookup_result_count / lookup_concurrency` rounds of `find_node`, rounded up: 60 seconds
+/// with the defaults, plus the waits for [`NodeConfig::max_outstanding_queries`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestTimeouts {
+    /// Wait for the Pong to a Ping.
+    pub ping: Duration,
+    /// Wait for the whole query of a node: bonding, its Ping and the Neighbours.
+    pub find_node: Duration,
+    /// Send an unanswered Ping or FindNode once more, after a random pause of half of this
+    /// up to all of it, `None` to give up right away.
+    pub retry_backoff: Option<Duration>,
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        Self {
+            ping: PING_TIMEOUT,
+            find_node: FIND_NODE_TIMEOUT,
+            retry_backoff: None,
+        }
+    }
+}
+
+impl RequestTimeouts {
+    /// Pause before the retry, `None` if requests are not retried.
+    fn retry_delay(&self, rng: &mut SharedRng) -> Option<Duration> {
+        self.retry_backoff
+            .map(|backoff| backoff.mul_f32(rng.gen_range(0.5..=1.0)))
+    }
+}
+
+/// Intervals of the background table maintenance, tuned independently of each other.
+///
+/// Shorter intervals find more nodes sooner at the cost of more traffic, e.g. for a crawler,
+/// longer ones keep the node quiet. None of them may be below [`MaintenanceConfig::MIN_INTERVAL`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaintenanceConfig {
+    /// Pause between the rounds of lookups refreshing the buckets.
+    pub refresh_interval: Duration,
+    /// Random targets looked up in a refresh round, concurrently along with our own ID.
+    /// They fall into different buckets where possible, see [`Table::refresh_targets`].
+    ///
+    /// All of the lookups share the [`NodeConfig::max_outstanding_queries`].
+    pub refresh_targets: usize,
+    /// Longest pause between pings of the least recently seen node of a random bucket,
+    /// the actual one is picked at random below it.
+    pub ping_interval: Duration,
+    /// Time since the last contact after which a table entry is pinged again, and evicted
+    /// if it does not answer. Checked every [`ping_interval`](Self::ping_interval).
+    pub liveness_ttl: Duration,
+    /// Pause before bonding with the bootstrap nodes again after no one answered,
+    /// doubled with every failed round up to [`bootstrap_backoff_max`](Self::bootstrap_backoff_max).
+    pub bootstrap_backoff_initial: Duration,
+    pub bootstrap_backoff_max: Duration,
+    /// Pause between UPnP lookups of our public IP.
+    pub upnp_interval: Duration,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: REFRESH_TIMEOUT,
+            refresh_targets: REFRESH_TARGETS,
+            ping_interval: PING_INTERVAL,
+            liveness_ttl: LIVENESS_TTL,
+            bootstrap_backoff_initial: BOOTSTRAP_BACKOFF_INITIAL,
+            bootstrap_backoff_max: BOOTSTRAP_BACKOFF_MAX,
+            upnp_interval: UPNP_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MaintenanceConfigError {
+    #[error(
+        "{name} {interval:?} is below the minimum of {:?}",
+        MaintenanceConfig::MIN_INTERVAL
+    )]
+    TooShort {
+        name: &'static str,
+        interval: Duration,
+    },
+    #[error("bootstrap backoff max {max:?} is below the initial backoff {initial:?}")]
+    BackoffRange { initial: Duration, max: Duration },
+}
+
+impl MaintenanceConfig {
+    /// Guards against intervals short enough to busy-loop.
+    pub const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub fn with_refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+
+    pub fn with_refresh_targets(mut self, targets: usize) -> Self {
+        self.refresh_targets = targets;
+        self
+    }
+
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    pub fn with_liveness_ttl(mut self, ttl: Duration) -> Self {
+        self.liveness_ttl = ttl;
+        self
+    }
+
+    pub fn with_bootstrap_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.bootstrap_backoff_initial = initial;
+        self.bootstrap_backoff_max = max;
+        self
+    }
+
+    pub fn with_upnp_interval(mut self, interval: Duration) -> Self {
+        self.upnp_interval = interval;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), MaintenanceConfigError> {
+        for (name, interval) in [
+            ("refresh interval", self.refresh_interval),
+            ("ping interval", self.ping_interval),
+            ("liveness TTL", self.liveness_ttl),
+            ("initial bootstrap backoff", self.bootstrap_backoff_initial),
+            ("max bootstrap backoff", self.bootstrap_backoff_max),
+            ("UPnP interval", self.upnp_interval),
+        ] {
+            if interval < Self::MIN_INTERVAL {
+                return Err(MaintenanceConfigError::TooShort { name, interval });
+            }
+        }
+        if self.bootstrap_backoff_max < self.bootstrap_backoff_initial {
+            return Err(MaintenanceConfigError::BackoffRange {
+                initial: self.bootstrap_backoff_initial,
+                max: self.bootstrap_backoff_max,
+            });
+        }
+        Ok(())
+    }
+
+    /// Delay before the next round of bootstrap pings after `attempts` failed ones.
+    fn bootstrap_backoff(&self, attempts: u32) -> Duration {
+        self.bootstrap_backoff_initial
+            .saturating_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.bootstrap_backoff_max)
+    }
+}
 
-fn expiry(timeout: Duration) -> u64 {
-    u64::try_from(Utc::now().timestamp()).expect("this would predate the protocol inception")
-        + timeout.as_secs()
+/// Run `task` to completion, dropping `done` only after the task and everything it holds.
+async fn tracked(done: Sender<()>, task: impl Future<Output = ()>) {
+    task.await;
+    drop(done);
 }
 
-fn ping_expiry() -> u64 {
-    expiry(PING_TIMEOUT)
+/// Run `task` until it completes or `shutdown` is cancelled, see [`tracked`].
+async fn until_shutdown(
+    shutdown: CancellationToken,
+    done: Sender<()>,
+    task: impl Future<Output = ()>,
+) {
+    select! {
+        _ = shutdown.cancelled() => {}
+        _ = task => {}
+    }
+    drop(done);
 }
 
-fn find_node_expiry() -> u64 {
-    expiry(FIND_NODE_TIMEOUT)
+/// Whether `node_filter` allows the node, judging by its cached ENR as well if there is one.
+fn is_allowed(node_filter: &FilterChain, enr_cache: &Mutex<EnrCache>, record: &NodeRecord) -> bool {
+    if !node_filter.allow(record) {
+        return false;
+    }
+    match enr_cache.lock().get(&record.id) {
+        Some(enr) => node_filter.allow_enr(enr),
+        None => true,
+    }
 }
 
 pub const ALPHA: usize = 3;
 
-#[derive(Clone, Copy, Debug, RlpEncodable, RlpDecodable)]
+/// Tunables of the discovery [`Node`].
+#[derive(Clone, Educe)]
+#[educe(Debug, Default)]
+pub struct NodeConfig {
+    /// For how long after sending FindNode to accept Neighbours packets in response.
+    #[educe(Default(expression = "NEIGHBOURS_WAIT_TIMEOUT"))]
+    pub neighbours_wait_timeout: Duration,
+    /// Additional IPv6 address to bind to if the main address is IPv4, for dual-stack operation.
+    ///
+    /// Use a specific address or another port than the IPv4 socket, unless the system binds
+    /// IPv6 sockets as IPv6-only: otherwise the wildcard address conflicts with the IPv4 one.
+    pub ipv6_addr: Option<SocketAddr>,
+    /// Public IPv6 address advertised to other nodes, defaults to [`ipv6_addr`](Self::ipv6_addr).
+    pub ipv6_public_address: Option<Ipv6Addr>,
+    /// How many nodes have to agree on our address in their Pongs before it is advertised
+    /// in place of the configured one.
+    #[educe(Default(expression = "EXTERNAL_ADDRESS_THRESHOLD"))]
+    pub external_address_threshold: usize,
+    /// Out of band source of our external address, preferred over the one inferred from Pongs.
+    ///
+    /// Resolved addresses are only advertised to nodes of the same address family.
+    #[educe(Debug(ignore))]
+    pub external_ip_resolver: Option<Arc<dyn ExternalIpResolver>>,
+    /// How many nodes are queried in parallel during a lookup, Kademlia's alpha.
+    /// At least one, and at most [`bucket_size`](Self::bucket_size).
+    #[educe(Default(expression = "ALPHA"))]
+    pub lookup_concurrency: usize,
+    /// How many nodes a bucket of the table holds, Kademlia's k. At least one.
+    ///
+    /// Neighbours responses still carry at most [`MAX_NEIGHBOURS`] nodes, which is what
+    /// other nodes accept.
+    #[educe(Default(expression = "BUCKET_SIZE"))]
+    pub bucket_size: usize,
+    /// Table entries allowed per subnet, against eclipse attacks.
+    pub subnet_limits: SubnetLimits,
+    /// How many closest nodes a lookup converges on and returns.
+    #[educe(Default(expression = "BUCKET_SIZE"))]
+    pub lookup_result_count: usize,
+    /// Limit on FindNode queries awaiting their Neighbours at once, across all lookups.
+    /// At least one.
+    #[educe(Default(expression = "MAX_OUTSTANDING_QUERIES"))]
+    pub max_outstanding_queries: usize,
+    /// Limit on Pongs and Neighbours sent to a single IP, `None` to disable.
+    ///
+    /// Packets over the limit are dropped, unless they come from a bootstrap node or
+    /// from the endpoint of a node in the table that answered our Ping from it.
+    #[educe(Default(expression = "Some(RateLimit::default())"))]
+    pub response_rate_limit: Option<RateLimit>,
+    /// Nodes that are kept out of the table, not answered and not advertised to others.
+    #[educe(Debug(ignore))]
+    pub node_filter: FilterChain,
+    /// Whether to reject Pings of other protocol versions.
+    pub ping_version_policy: VersionPolicy,
+    /// Whether to log the legacy topic discovery packets that are dropped.
+    pub topic_packet_policy: TopicPacketPolicy,
+    /// Table snapshot to restore once started, before bonding with the bootstrap nodes.
+    #[educe(Debug(ignore))]
+    pub warm_start: Option<WarmStart>,
+    /// How many nodes of a table snapshot are pinged at once while it is
+    /// [restored](Node::restore_table). At least one.
+    #[educe(Default(expression = "RESTORE_CONCURRENCY"))]
+    pub restore_concurrency: usize,
+    /// Source of the current time, see [`MockClock`] for tests.
+    #[educe(Default(expression = "Arc::new(SystemClock)"))]
+    pub clock: Arc<dyn Clock>,
+    /// Source of lookup targets and jitter, see [`SharedRng::seeded`] for reproducible tests.
+    pub rng: SharedRng,
+    /// Hash of node IDs that distances are measured with, Keccak-256 unless testing.
+    #[educe(Default(expression = "Arc::new(Keccak256Hasher)"))]
+    pub hasher: Arc<dyn Hasher>,
+    /// Expiration of outgoing Ping and FindNode messages.
+    pub expiry: ExpiryPolicy,
+    /// Which FindNode requests are answered.
+    pub find_node_policy: FindNodePolicy,
+    /// Timeouts and retries of our Pings and FindNode queries.
+    pub timeouts: RequestTimeouts,
+    /// Whether to reject packets with non-canonical signatures.
+    pub signature_policy: SignaturePolicy,
+    /// Largest datagram sent or accepted, Neighbours responses are split to fit into it.
+    ///
+    /// The default is safe for any IPv6 path, raise it only on networks known to carry more.
+    #[educe(Default(expression = "MAX_PACKET_SIZE"))]
+    pub max_packet_size: usize,
+    /// Time for [reputation](Node::reputation) scores to decay to half of their value.
+    #[educe(Default(expression = "REPUTATION_HALF_LIFE"))]
+    pub reputation_half_life: Duration,
+    /// Whether lookups query the nodes of better reputation first among the closest
+    /// ones, instead of strictly by distance.
+    pub reputation_weighted_lookups: bool,
+    /// Intervals of the background tasks, checked when the node is started.
+    pub maintenance: MaintenanceConfig,
+    /// Stops the node once cancelled, same as [`Node::shutdown`] but without waiting.
+    pub cancellation_token: Option<CancellationToken>,
+    /// Runtime to run the background tasks of the node on, and to register its sockets with,
+    /// instead of the one the node is started from.
+    ///
+    /// Tasks are spawned with [`tokio::spawn`], so they are `Send + 'static` and can't be
+    /// run on a [`LocalSet`](tokio::task::LocalSet). The runtime must have both the IO and
+    /// the time driver enabled, and outlive the node.
+    pub runtime: Option<Handle>,
+}
+
+/// [`Node::table_snapshot`] to restore on start, see [`NodeConfig::warm_start`].
+#[derive(Clone, Debug)]
+pub struct WarmStart {
+    pub snapshot: Vec<u8>,
+    /// Entries not verified within this long before the snapshot was restored are skipped.
+    pub max_age: Duration,
+    /// Sequence number of the [`Node::local_enr`] when the snapshot was taken, 0 if unknown,
+    /// for the ENR to continue above it.
+    pub enr_seq: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum NodeConfigError {
+    #[error("bucket size must be at least 1")]
+    ZeroBucketSize,
+    #[error("at least one outstanding query must be allowed")]
+    ZeroOutstandingQueries,
+    #[error("restore concurrency must be at least 1")]
+    ZeroRestoreConcurrency,
+    #[error("{0} timeout must not be zero")]
+    ZeroTimeout(&'static str),
+    #[error("lookup concurrency {concurrency} is not within 1..={bucket_size}")]
+    LookupConcurrency {
+        concurrency: usize,
+        bucket_size: usize,
+    },
+    #[error(transparent)]
+    Maintenance(#[from] MaintenanceConfigError),
+}
+
+/// Failure of [`Node::ping`].
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum PingError {
+    #[error("no socket bound for the address family of the node")]
+    UnsupportedAddressFamily,
+    #[error("node is shut down")]
+    Shutdown,
+    /// No matching Pong in time, or the Ping could not be sent at all.
+    #[error("no Pong in time")]
+    Timeout,
+}
+
+impl NodeConfig {
+    /// Check the tunables that can't be used as they are, done when the node is started.
+    pub fn validate(&self) -> Result<(), NodeConfigError> {
+        if self.bucket_size == 0 {
+            return Err(NodeConfigError::ZeroBucketSize);
+        }
+        if !(1..=self.bucket_size).contains(&self.lookup_concurrency) {
+            return Err(NodeConfigError::LookupConcurrency {
+                concurrency: self.lookup_concurrency,
+                bucket_size: self.bucket_size,
+            });
+        }
+        if self.max_outstanding_queries == 0 {
+            return Err(NodeConfigError::ZeroOutstandingQueries);
+        }
+        if self.restore_concurrency == 0 {
+            return Err(NodeConfigError::ZeroRestoreConcurrency);
+        }
+        for (name, timeout) in [
+            ("ping", self.timeouts.ping),
+            ("FindNode", self.timeouts.find_node),
+        ] {
+            if timeout.is_zero() {
+                return Err(NodeConfigError::ZeroTimeout(name));
+            }
+        }
+        self.maintenance.validate()?;
+        Ok(())
+    }
+
+    /// Set the lifetime of outgoing Ping and FindNode messages, see [`ExpiryPolicy`].
+    pub fn with_expiry(mut self, lifetime: Duration) -> Self {
+        self.expiry = ExpiryPolicy::new(lifetime);
+        self
+    }
+}
+
+/// Values kept separately for each address family.
+#[derive(Clone, Copy, Debug, Default)]
+struct DualStack<T> {
+    v4: Option<T>,
+    v6: Option<T>,
+}
+
+impl<T> DualStack<T> {
+    fn single(ip: IpAddr, value: T) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self {
+                v4: Some(value),
+                v6: None,
+            },
+            IpAddr::V6(_) => Self {
+                v4: None,
+                v6: Some(value),
+            },
+        }
+    }
+
+    fn get(&self, ip: IpAddr) -> Option<&T> {
+        match ip {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+
+    fn get_mut(&mut self, ip: IpAddr) -> Option<&mut T> {
+        match ip {
+            IpAddr::V4(_) => self.v4.as_mut(),
+            IpAddr::V6(_) => self.v6.as_mut(),
+        }
+    }
+
+    fn supports(&self, ip: IpAddr) -> bool {
+        self.get(ip).is_some()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        self.v4.iter().chain(self.v6.iter())
+    }
+}
+
+/// Our endpoint as advertised in Ping `from`.
+struct LocalEndpoint {
+    /// Bound or configured endpoint.
+    endpoint: RwLock<DualStack<Endpoint>>,
+    pong_external_ip: DualStack<PongExternalIp>,
+    resolver: Option<Arc<dyn ExternalIpResolver>>,
+}
+
+impl LocalEndpoint {
+    /// External address: resolved out of band, or inferred from Pongs otherwise.
+    fn external_address(&self, family: IpAddr) -> Option<IpAddr> {
+        self.resolver
+            .as_ref()
+            .and_then(|resolver| resolver.resolve())
+            .filter(|address| address.is_ipv6() == family.is_ipv6())
+            .or_else(|| self.pong_external_ip.get(family)?.resolve())
+    }
+
+    /// Whether `addr` is one of our own UDP endpoints, bound or external.
+    fn is_local(&self, addr: SocketAddr) -> bool {
+        let ip = normalize_ip(addr.ip());
+        self.endpoint.read().iter().any(|endpoint| {
+            endpoint.udp_port == addr.port()
+                && (normalize_ip(endpoint.address.0) == ip || self.external_address(ip) == Some(ip))
+        })
+    }
+
+    /// Endpoint to advertise to the node at `destination`, if its address family is supported.
+    fn for_destination(&self, destination: IpAddr) -> Option<Endpoint> {
+        let mut endpoint = *self.endpoint.read().get(destination)?;
+        if let Some(address) = self.external_address(destination) {
+            endpoint.address = Ip(address);
+        }
+        Some(endpoint)
+    }
+
+    /// Record of node `id` at the endpoint advertised over IPv4, or over IPv6 without an
+    /// IPv4 socket.
+    fn record(&self, id: NodeId) -> NodeRecord {
+        let endpoint = [
+            IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            Ipv6Addr::UNSPECIFIED.into(),
+        ]
+        .into_iter()
+        .find_map(|family| self.for_destination(family))
+        .expect("a socket is bound");
+        NodeRecord {
+            address: endpoint.address,
+            tcp_port: endpoint.tcp_port,
+            udp_port: endpoint.udp_port,
+            id,
+        }
+    }
+}
+
+/// Our `record`, signed with `secret_key` under the sequence number `seq`.
+fn local_enr(record: NodeRecord, secret_key: &SecretKey, seq: u64) -> Enr {
+    let mut enr = record.to_enr(secret_key).expect("record of our own key");
+    enr.set_seq(seq, secret_key).expect("minimal ENR fits");
+    enr
+}
+
+/// Node as carried in Neighbours, `[ip, udp-port, tcp-port, node-id]` on the wire.
+#[derive(Clone, Copy, Educe, RlpEncodable)]
+#[educe(Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct NodeRecord {
     pub address: Ip,
-    pub tcp_port: u16,
     pub udp_port: u16,
+    pub tcp_port: u16,
+    #[educe(Debug(method = "short_id_debug"))]
     pub id: NodeId,
 }
 
+impl Decodable for NodeRecord {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Self::decode_message(buf).map_err(DecodeError::from)
+    }
+}
+
+impl DecodeMessage for NodeRecord {
+    /// Zero ports are accepted in either encoding, see [`decode_port`]. Records with TCP
+    /// port 0 are kept, as discovery-only nodes that are not [dialable](Self::is_dialable).
+    fn decode_message(buf: &mut &[u8]) -> Result<Self, MessageError> {
+        let payload = &mut list_payload(buf)?;
+        Ok(Self {
+            address: Ip::decode_message(payload)?,
+            udp_port: decode_port(payload)?,
+            tcp_port: decode_port(payload)?,
+            id: NodeId::decode(payload)?,
+        })
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum NodeRecordParseError {
     #[error("failed to parse url")]
@@ -78,7 +693,21 @@ pub enum NodeRecordParseError {
     InvalidId(#[source] anyhow::Error),
 }
 
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("node id is not a valid public key")]
+    InvalidId(#[source] secp256k1::Error),
+    #[error("ENR signature does not verify")]
+    EnrSignature,
+    #[error("ENR belongs to node {enr}, not {record}")]
+    EnrIdMismatch { record: NodeId, enr: NodeId },
+}
+
 impl NodeRecord {
+    pub fn builder() -> NodeRecordBuilder {
+        NodeRecordBuilder::default()
+    }
+
     /// The TCP socket address of this node
     #[must_use]
     pub fn tcp_addr(&self) -> SocketAddr {
@@ -90,14 +719,56 @@ impl NodeRecord {
     pub fn udp_addr(&self) -> SocketAddr {
         SocketAddr::new(self.address.0, self.udp_port)
     }
-}
 
-impl FromStr for NodeRecord {
-    type Err = NodeRecordParseError;
+    /// Whether the node accepts RLPx connections. Discovery-only nodes advertise TCP port 0.
+    #[must_use]
+    pub fn is_dialable(&self) -> bool {
+        self.tcp_port != 0
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    /// Whether the node can be pinged at all: its address is specified and so is its UDP port.
+    /// TCP port 0 is fine, see [`NodeRecord::is_dialable`].
+    #[must_use]
+    pub fn has_udp_endpoint(&self) -> bool {
+        !self.address.normalized().0.is_unspecified() && self.udp_port != 0
+    }
+
+    /// Check that the ID is a public key the node could sign packets with. Records relayed
+    /// in Neighbours are not signed by the node itself, so nothing else can be verified.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        self.id.to_public_key().map_err(VerifyError::InvalidId)?;
+        Ok(())
+    }
+
+    /// [`NodeRecord::verify`], and check that `enr` is a correctly signed record of this node.
+    pub fn verify_with_enr(&self, enr: &Enr) -> Result<(), VerifyError> {
+        self.verify()?;
+        if !enr.verify() {
+            return Err(VerifyError::EnrSignature);
+        }
+        let enr_id = pk2id(&enr.public_key());
+        if enr_id != self.id {
+            return Err(VerifyError::EnrIdMismatch {
+                record: self.id,
+                enr: enr_id,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl NodeRecord {
+    /// Parse `enode://<hex node id>@<ip>:<tcp port>[?discport=<udp port>]` URL.
+    pub fn from_enode_url(s: &str) -> Result<Self, NodeRecordParseError> {
         let url = Url::parse(s).map_err(|e| NodeRecordParseError::InvalidUrl(e.into()))?;
 
+        if url.scheme() != "enode" {
+            return Err(NodeRecordParseError::InvalidUrl(anyhow!(
+                "invalid scheme: {}",
+                url.scheme()
+            )));
+        }
+
         let address = match url.host() {
             Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
             Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
@@ -112,23 +783,321 @@ impl FromStr for NodeRecord {
             }
         }
         .into();
-        let port = url
+        let tcp_port = url
             .port()
             .ok_or_else(|| NodeRecordParseError::InvalidUrl(anyhow!("no port specified")))?;
-        let id = url
-            .username()
+        let udp_port = match url.query_pairs().find(|(key, _)| key == "discport") {
+            Some((_, port)) => port
+                .parse()
+                .map_err(|e| NodeRecordParseError::InvalidUrl(anyhow::Error::from(e)))?,
+            None => tcp_port,
+        };
+
+        let id = url.username();
+        if id.len() != NodeId::len_bytes() * 2 {
+            return Err(NodeRecordParseError::InvalidId(anyhow!(
+                "expected {} hex characters, got {}",
+                NodeId::len_bytes() * 2,
+                id.len()
+            )));
+        }
+        let id = id
             .parse()
             .map_err(|e| NodeRecordParseError::InvalidId(anyhow::Error::from(e)))?;
 
         Ok(Self {
             address,
             id,
-            tcp_port: port,
-            udp_port: port,
+            tcp_port,
+            udp_port,
+        })
+    }
+
+    /// Extract node record from ENR, preferring IPv4 endpoint if both are present.
+    ///
+    /// Returns `None` if the record has neither IP address with UDP port set. If TCP port
+    /// is absent, the node is taken as discovery-only, with TCP port 0.
+    pub fn from_enr(enr: &Enr) -> Option<Self> {
+        let (address, udp_port, tcp_port) = match (enr.ip4(), enr.udp4()) {
+            (Some(ip), Some(udp_port)) => (IpAddr::V4(ip), udp_port, enr.tcp4()),
+            _ => (IpAddr::V6(enr.ip6()?), enr.udp6()?, enr.tcp6()),
+        };
+
+        Some(Self {
+            address: Ip(address),
+            tcp_port: tcp_port.unwrap_or(0),
+            udp_port,
+            id: pk2id(&enr.public_key()),
+        })
+    }
+
+    /// Build a minimal ENR with sequence number 1 for this node, signed with its `secret_key`.
+    pub fn to_enr(&self, secret_key: &SecretKey) -> anyhow::Result<Enr> {
+        if pk2id(&PublicKey::from_secret_key(SECP256K1, secret_key)) != self.id {
+            bail!("secret key does not match node id");
+        }
+
+        let mut builder = enr::EnrBuilder::new("v4");
+        match self.address.0 {
+            IpAddr::V4(ip) => builder.ip4(ip).tcp4(self.tcp_port).udp4(self.udp_port),
+            IpAddr::V6(ip) => builder.ip6(ip).tcp6(self.tcp_port).udp6(self.udp_port),
+        };
+
+        Ok(builder.build(secret_key)?)
+    }
+
+    /// Format as `enode://` URL, adding `discport` if the UDP port differs from TCP.
+    #[must_use]
+    pub fn to_enode_url(&self) -> String {
+        let mut url = format!("enode://{:x}@{}", self.id, self.tcp_addr());
+        if self.udp_port != self.tcp_port {
+            url.push_str(&format!("?discport={}", self.udp_port));
+        }
+        url
+    }
+
+    /// Parse a JSON array of `enode://` URLs, the format of Geth's `static-nodes.json` and
+    /// `trusted-nodes.json`. Malformed URLs are logged and skipped.
+    pub fn parse_enode_list(s: &str) -> Result<Vec<Self>, serde_json::Error> {
+        let mut deserializer = serde_json::Deserializer::from_str(s);
+        let records = deserialize_enode_list(&mut deserializer)?;
+        deserializer.end()?;
+        Ok(records)
+    }
+
+    /// [`NodeRecord::parse_enode_list`] of the file at `path`.
+    pub fn load_enode_list(path: impl AsRef<Path>) -> anyhow::Result<Vec<Self>> {
+        let path = path.as_ref();
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse_enode_list(&s).with_context(|| format!("failed to parse {}", path.display()))
+    }
+}
+
+/// Deserialize an array of `enode://` URLs with `#[serde(deserialize_with)]`, logging and
+/// skipping the malformed ones.
+pub fn deserialize_enode_list<'de, D>(deserializer: D) -> Result<Vec<NodeRecord>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let urls = <Vec<String> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(urls
+        .iter()
+        .filter_map(|url| match NodeRecord::from_enode_url(url.trim()) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                warn!("Skipping malformed enode URL {url}: {e:?}");
+                None
+            }
+        })
+        .collect())
+}
+
+/// Port assumed by [`NodeRecordBuilder`] if neither TCP nor UDP port is set.
+pub const DEFAULT_PORT: u16 = 30303;
+
+#[derive(Debug, Error)]
+pub enum NodeRecordBuildError {
+    #[error("node id is not set")]
+    MissingId,
+    #[error("invalid public key length: expected {}, got {0}", NodeId::len_bytes())]
+    InvalidIdLength(usize),
+    #[error("IP address is not set")]
+    MissingAddress,
+    #[error("unspecified IP address {0}")]
+    UnspecifiedAddress(IpAddr),
+    #[error("link-local or multicast IPv6 address {0}")]
+    ScopedAddress(IpAddr),
+    #[error("loopback IP address {0} in a public record")]
+    LoopbackAddress(IpAddr),
+    #[error("zero UDP port")]
+    ZeroUdpPort,
+}
+
+/// Builder for [`NodeRecord`] that rejects inconsistent records.
+///
+/// If only one of the ports is set, the other one is assumed to be the same.
+/// If neither is set, both default to [`DEFAULT_PORT`]. TCP port 0 makes a discovery-only
+/// record, see [`NodeRecord::is_dialable`], while the UDP port has to be set to be pinged at.
+#[derive(Clone, Debug, Default)]
+pub struct NodeRecordBuilder {
+    id: Option<Vec<u8>>,
+    address: Option<IpAddr>,
+    tcp_port: Option<u16>,
+    udp_port: Option<u16>,
+    allow_loopback: bool,
+}
+
+impl NodeRecordBuilder {
+    pub fn with_id(self, id: NodeId) -> Self {
+        self.with_raw_id(id)
+    }
+
+    /// Uncompressed public key without the `0x04` prefix, validated on [`build`](Self::build).
+    pub fn with_raw_id(mut self, id: impl AsRef<[u8]>) -> Self {
+        self.id = Some(id.as_ref().to_vec());
+        self
+    }
+
+    pub fn with_public_key(self, public_key: &PublicKey) -> Self {
+        self.with_id(pk2id(public_key))
+    }
+
+    pub fn with_address(mut self, address: impl Into<IpAddr>) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    pub fn with_tcp_port(mut self, port: u16) -> Self {
+        self.tcp_port = Some(port);
+        self
+    }
+
+    pub fn with_udp_port(mut self, port: u16) -> Self {
+        self.udp_port = Some(port);
+        self
+    }
+
+    /// Accept loopback addresses, for records that never leave the local machine.
+    pub fn allow_loopback(mut self) -> Self {
+        self.allow_loopback = true;
+        self
+    }
+
+    pub fn build(self) -> Result<NodeRecord, NodeRecordBuildError> {
+        let id = self.id.ok_or(NodeRecordBuildError::MissingId)?;
+        if id.len() != NodeId::len_bytes() {
+            return Err(NodeRecordBuildError::InvalidIdLength(id.len()));
+        }
+
+        let address = Ip(self.address.ok_or(NodeRecordBuildError::MissingAddress)?)
+            .normalized()
+            .0;
+        if address.is_unspecified() {
+            return Err(NodeRecordBuildError::UnspecifiedAddress(address));
+        }
+        if address.is_loopback() && !self.allow_loopback {
+            return Err(NodeRecordBuildError::LoopbackAddress(address));
+        }
+        if Ip(address).is_scoped() {
+            return Err(NodeRecordBuildError::ScopedAddress(address));
+        }
+
+        let (tcp_port, udp_port) = match (self.tcp_port, self.udp_port) {
+            (Some(tcp_port), Some(udp_port)) => (tcp_port, udp_port),
+            (Some(port), None) | (None, Some(port)) => (port, port),
+            (None, None) => (DEFAULT_PORT, DEFAULT_PORT),
+        };
+        if udp_port == 0 {
+            return Err(NodeRecordBuildError::ZeroUdpPort);
+        }
+
+        Ok(NodeRecord {
+            address: Ip(address),
+            tcp_port,
+            udp_port,
+            id: NodeId::from_slice(&id),
         })
     }
 }
 
+impl FromStr for NodeRecord {
+    type Err = NodeRecordParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_enode_url(s)
+    }
+}
+
+/// Move the node from `old` endpoint to the one of `record` once `proven` by its Pong,
+/// unless it was evicted or moved elsewhere in the meantime.
+async fn verify_move(
+    table: Arc<Mutex<Table>>,
+    events: Arc<EventListeners>,
+    moving: Arc<Mutex<HashSet<NodeId>>>,
+    old: Endpoint,
+    record: NodeRecord,
+    proven: OneshotReceiver<()>,
+    ping_timeout: Duration,
+) {
+    let proven = matches!(timeout(ping_timeout, proven).await, Ok(Ok(())));
+    moving.lock().remove(&record.id);
+    if !proven {
+        trace!("New endpoint of {} was not proven", record.id);
+        return;
+    }
+
+    let moved = {
+        let mut table = table.lock();
+        if table.get(record.id) == Some(old) {
+            table.add_verified(record);
+            true
+        } else {
+            false
+        }
+    };
+    if moved {
+        events.emit(DiscoveryEvent::AddressChanged {
+            node_id: record.id,
+            old,
+            new: record.into(),
+        });
+    }
+}
+
+/// Bind the socket, registering it with `runtime` if given rather than the current one.
+async fn bind_udp(addr: SocketAddr, runtime: Option<&Handle>) -> io::Result<UdpSocket> {
+    match runtime {
+        Some(runtime) => {
+            let socket = std::net::UdpSocket::bind(addr)?;
+            socket.set_nonblocking(true)?;
+            let _runtime = runtime.enter();
+            UdpSocket::from_std(socket)
+        }
+        None => UdpSocket::bind(addr).await,
+    }
+}
+
+/// Whether the record relayed in Neighbours can be pinged, counting it as invalid if not.
+fn is_valid_neighbour(record: &NodeRecord) -> bool {
+    if record.has_udp_endpoint() {
+        return true;
+    }
+    trace!("Dropping neighbour without endpoint: {:?}", record);
+    metrics::record_invalid_neighbour();
+    false
+}
+
+/// Whether the record is ours: of our ID, or of another one at one of our endpoints.
+fn is_self(id: NodeId, node_endpoint: &LocalEndpoint, record: &NodeRecord) -> bool {
+    record.id == id || node_endpoint.is_local(record.udp_addr())
+}
+
+/// Remove the node from the table, reporting its eviction and the replacement taking its place.
+fn remove_node(table: &Mutex<Table>, events: &EventListeners, node_id: NodeId) {
+    let replacement = table.lock().remove(node_id);
+    if let Some(replacement) = replacement {
+        events.emit(DiscoveryEvent::NodeRemoved(node_id));
+        events.emit(DiscoveryEvent::NodeAdded(replacement));
+    }
+}
+
+/// Nodes to answer FindNode of `requester` with, closest to `target` first: the ones that
+/// proved their endpoint, other than the requester itself.
+fn find_node_candidates(
+    table: &Table,
+    endpoint_proofs: &EndpointProofs,
+    requester: NodeId,
+    target: NodeId,
+    now: u64,
+) -> Vec<NodeRecord> {
+    table
+        .nearest_node_entries(target)
+        .into_values()
+        .filter(|node| node.id != requester && endpoint_proofs.has_valid_proof(&node.id, now))
+        .collect()
+}
+
 type InflightFindNodeInner = HashMap<NodeId, HashMap<RequestId, Sender<NeighboursMessage>>>;
 
 #[derive(Default)]
@@ -193,27 +1162,214 @@ impl Drop for InflightFindNodeRequestGuard {
     }
 }
 
+/// Discovery v4 service bound to a UDP socket.
+///
+/// Spawns tasks that receive and answer incoming packets, maintain the routing [`Table`]
+/// by periodically looking up random targets and re-pinging the oldest table entries,
+/// and send outgoing packets. All tasks are stopped on [`Node::shutdown`], or aborted
+/// when the node is dropped.
+///
+/// Wrap it into [`Discv4`](super::Discv4) to get a stream of discovered nodes.
 pub struct Node {
     task_group: Arc<TaskGroup>,
+    config: NodeConfig,
     connected: Arc<Mutex<Table>>,
 
     id: NodeId,
-    node_endpoint: Arc<RwLock<Endpoint>>,
+    secret_key: SecretKey,
+    node_endpoint: Arc<LocalEndpoint>,
+    /// Latest [`Node::local_enr`], re-signed once the endpoint changes.
+    local_enr: Mutex<Enr>,
 
     egress_requests_tx: Sender<(SocketAddr, NodeId, EgressMessage)>,
     expected_pings: Arc<Mutex<HashMap<SocketAddr, HashMap<RequestId, OneshotSender<()>>>>>,
     inflight_find_node_requests: Arc<InflightFindNode>,
-}
+    /// Permits of [`NodeConfig::max_outstanding_queries`].
+    query_permits: Semaphore,
+    enr_cache: Arc<Mutex<EnrCache>>,
+    reputation: Arc<Mutex<Reputation>>,
+    events: Arc<EventListeners>,
+    /// Set in the crawler mode.
+    crawler: Option<Arc<Mutex<Crawler>>>,
+    endpoint_proofs: Arc<Mutex<EndpointProofs>>,
 
-enum PreTrigger {
-    Ping(Option<OneshotSender<()>>),
+    bootstrap_nodes: Vec<NodeRecord>,
+    bootstrap_state: Mutex<BootstrapState>,
+
+    shutdown: CancellationToken,
+    /// Closed once all tasks are finished, each of them holding a sender.
+    tasks_done: AsyncMutex<Receiver<()>>,
 }
 
-enum PostSendTrigger {
-    Ping,
+/// Node found by [`Node::lookup_enrs`], with its latest ENR if we have it.
+#[derive(Clone, Debug)]
+pub struct LookupResult {
+    pub record: NodeRecord,
+    pub enr: Option<Enr>,
+}
+
+impl LookupResult {
+    /// Endpoint advertised in the ENR, or the one the node was found at without one.
+    pub fn endpoint(&self) -> NodeRecord {
+        self.enr
+            .as_ref()
+            .and_then(NodeRecord::from_enr)
+            .unwrap_or(self.record)
+    }
+}
+
+/// Progress of bonding with the bootstrap nodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootstrapState {
+    /// No bootstrap node answered yet, after `attempts` rounds of pings.
+    Bonding { attempts: u32 },
+    /// A bootstrap node has proven its endpoint, or there were none to bond with.
+    Ready,
+}
+
+/// Builder of a [`Node`], so that options can be added without changing the signature of
+/// [`Node::new`].
+///
+/// Only the secret key is required. By default the node listens on `0.0.0.0:30303` without
+/// bootstrap nodes or UPnP, advertising the bound address and the TCP port [`DEFAULT_PORT`].
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct NodeBuilder {
+    #[educe(Debug(ignore))]
+    secret_key: SecretKey,
+    addr: SocketAddr,
+    bootstrap_nodes: Vec<NodeRecord>,
+    public_address: Option<IpAddr>,
+    enable_upnp: bool,
+    tcp_port: u16,
+    config: NodeConfig,
+}
+
+impl NodeBuilder {
+    pub fn new(secret_key: SecretKey) -> Self {
+        Self {
+            secret_key,
+            addr: SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)),
+            bootstrap_nodes: Vec::new(),
+            public_address: None,
+            enable_upnp: false,
+            tcp_port: DEFAULT_PORT,
+            config: NodeConfig::default(),
+        }
+    }
+
+    pub fn with_addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    pub fn with_bootstrap_nodes(mut self, bootstrap_nodes: Vec<NodeRecord>) -> Self {
+        self.bootstrap_nodes = bootstrap_nodes;
+        self
+    }
+
+    /// Address advertised instead of the bound one, e.g. of the NAT gateway.
+    pub fn with_public_address(mut self, public_address: IpAddr) -> Self {
+        self.public_address = Some(public_address);
+        self
+    }
+
+    /// Refresh the advertised IPv4 address from the UPnP gateway.
+    pub fn with_upnp(mut self, enable_upnp: bool) -> Self {
+        self.enable_upnp = enable_upnp;
+        self
+    }
+
+    /// TCP port advertised to other nodes, 0 for a discovery-only node.
+    pub fn with_tcp_port(mut self, tcp_port: u16) -> Self {
+        self.tcp_port = tcp_port;
+        self
+    }
+
+    /// Replace all of the tunables at once, see the other setters for the common ones.
+    pub fn with_config(mut self, config: NodeConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn with_maintenance(mut self, maintenance: MaintenanceConfig) -> Self {
+        self.config.maintenance = maintenance;
+        self
+    }
+
+    pub fn with_response_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.config.response_rate_limit = limit;
+        self
+    }
+
+    pub fn with_node_filter(mut self, node_filter: FilterChain) -> Self {
+        self.config.node_filter = node_filter;
+        self
+    }
+
+    /// Validate the configuration before binding anything, and start the node, see
+    /// [`Node::new`].
+    pub async fn build(self) -> anyhow::Result<Arc<Node>> {
+        self.config.validate()?;
+        Node::new_with_config(
+            self.addr,
+            self.secret_key,
+            self.bootstrap_nodes,
+            self.public_address,
+            self.enable_upnp,
+            self.tcp_port,
+            self.config,
+        )
+        .await
+    }
+
+    /// Start the node on top of `transport` instead of binding the address, see
+    /// [`Node::with_transport`].
+    pub async fn build_with_transport(
+        self,
+        transport: impl Transport,
+    ) -> anyhow::Result<Arc<Node>> {
+        self.config.validate()?;
+        if self.enable_upnp {
+            bail!("UPnP is not supported with a custom transport");
+        }
+        Node::with_transport(
+            transport,
+            self.secret_key,
+            self.bootstrap_nodes,
+            self.public_address,
+            self.tcp_port,
+            self.config,
+        )
+        .await
+    }
+}
+
+enum PreTrigger {
+    Ping(Option<OneshotSender<()>>),
+    EnrRequest,
+}
+
+enum PostSendTrigger {
+    Ping { probe: bool },
 }
 
 impl Node {
+    /// [`NodeBuilder`] of a node with `secret_key`.
+    pub fn builder(secret_key: SecretKey) -> NodeBuilder {
+        NodeBuilder::new(secret_key)
+    }
+
+    /// Bind to `addr` and start the service, bootstrapping from `bootstrap_nodes`.
+    ///
+    /// Bootstrap nodes are pinged with exponential backoff until one of them answers, and
+    /// only then lookups start. They are not part of the routing table, so they are never
+    /// evicted, but every lookup considers them.
+    ///
+    /// `public_address` and `tcp_port` are advertised to other nodes; with `enable_upnp`,
+    /// the public IPv4 address is periodically refreshed from the UPnP gateway.
+    ///
+    /// Tunables are left at their defaults, see [`Node::new_with_config`].
     pub async fn new(
         addr: SocketAddr,
         secret_key: SecretKey,
@@ -222,41 +1378,226 @@ impl Node {
         enable_upnp: bool,
         tcp_port: u16,
     ) -> anyhow::Result<Arc<Self>> {
-        let node_endpoint = Arc::new(RwLock::new(Endpoint {
-            address: Ip(public_address.unwrap_or_else(|| addr.ip())),
+        Self::new_with_config(
+            addr,
+            secret_key,
+            bootstrap_nodes,
+            public_address,
+            enable_upnp,
+            tcp_port,
+            NodeConfig::default(),
+        )
+        .await
+    }
+
+    /// Same as [`Node::new`], with the tunables of `config`.
+    ///
+    /// With [`NodeConfig::ipv6_addr`] set, IPv6 socket is bound as well, and each node is
+    /// contacted over the socket of its address family. Nodes of the family with no socket bound
+    /// are ignored.
+    pub async fn new_with_config(
+        addr: SocketAddr,
+        secret_key: SecretKey,
+        bootstrap_nodes: Vec<NodeRecord>,
+        public_address: Option<IpAddr>,
+        enable_upnp: bool,
+        tcp_port: u16,
+        config: NodeConfig,
+    ) -> anyhow::Result<Arc<Self>> {
+        let mut addrs = DualStack::default();
+        *match addr {
+            SocketAddr::V4(_) => &mut addrs.v4,
+            SocketAddr::V6(_) => &mut addrs.v6,
+        } = Some((addr, public_address.unwrap_or_else(|| addr.ip())));
+        if let Some(ipv6_addr) = config.ipv6_addr {
+            if !ipv6_addr.is_ipv6() || addrs.v6.is_some() {
+                bail!("dual-stack requires IPv4 main address and IPv6 additional address");
+            }
+            addrs.v6 = Some((
+                ipv6_addr,
+                config
+                    .ipv6_public_address
+                    .map_or_else(|| ipv6_addr.ip(), IpAddr::V6),
+            ));
+        }
+
+        let runtime = config.runtime.as_ref();
+        let sockets = DualStack {
+            v4: match addrs.v4 {
+                Some((addr, _)) => {
+                    Some(Arc::new(bind_udp(addr, runtime).await?) as Arc<dyn Transport>)
+                }
+                None => None,
+            },
+            v6: match addrs.v6 {
+                Some((addr, _)) => {
+                    Some(Arc::new(bind_udp(addr, runtime).await?) as Arc<dyn Transport>)
+                }
+                None => None,
+            },
+        };
+
+        Self::start(
+            addrs,
+            sockets,
+            secret_key,
+            bootstrap_nodes,
+            enable_upnp,
+            tcp_port,
+            config,
+            None,
+        )
+        .await
+    }
+
+    /// Start the service on top of an already bound `transport`, see [`Node::new`].
+    ///
+    /// Its local address is advertised unless `public_address` is given. UPnP and dual-stack
+    /// operation are not available.
+    pub async fn with_transport(
+        transport: impl Transport,
+        secret_key: SecretKey,
+        bootstrap_nodes: Vec<NodeRecord>,
+        public_address: Option<IpAddr>,
+        tcp_port: u16,
+        config: NodeConfig,
+    ) -> anyhow::Result<Arc<Self>> {
+        if config.ipv6_addr.is_some() {
+            bail!("dual-stack is not supported with a custom transport");
+        }
+
+        let addr = transport.local_addr()?;
+        Self::start(
+            DualStack::single(
+                addr.ip(),
+                (addr, public_address.unwrap_or_else(|| addr.ip())),
+            ),
+            DualStack::single(addr.ip(), Arc::new(transport) as Arc<dyn Transport>),
+            secret_key,
+            bootstrap_nodes,
+            false,
+            tcp_port,
+            config,
+            None,
+        )
+        .await
+    }
+
+    /// Start the service in the crawler mode, for a census of the network rather than for
+    /// finding peers.
+    ///
+    /// Every node heard of, in Neighbours whether or not we asked for them or in Pings,
+    /// is emitted to the returned stream once, and again whenever its endpoint changes. All of
+    /// them are queried with FindNode in turn, over and over again. Unsolicited Neighbours are
+    /// only taken from nodes with a valid endpoint proof. Up to [`MAX_CRAWLED_NODES`] are kept
+    /// track of, and nodes are dropped from the stream while [`CRAWL_STREAM_CAPACITY`] of them
+    /// wait to be consumed. Nodes are never evicted from the table for not answering. The node
+    /// is discovery-only, advertising no TCP port.
+    pub async fn crawler(
+        transport: impl Transport,
+        secret_key: SecretKey,
+        bootstrap_nodes: Vec<NodeRecord>,
+        config: NodeConfig,
+    ) -> anyhow::Result<(Arc<Self>, CrawlStream)> {
+        if config.ipv6_addr.is_some() {
+            bail!("dual-stack is not supported with a custom transport");
+        }
+
+        let addr = transport.local_addr()?;
+        let (crawler, stream) = Crawler::new();
+        let this = Self::start(
+            DualStack::single(addr.ip(), (addr, addr.ip())),
+            DualStack::single(addr.ip(), Arc::new(transport) as Arc<dyn Transport>),
+            secret_key,
+            bootstrap_nodes,
+            false,
+            0,
+            config,
+            Some(crawler),
+        )
+        .await?;
+        Ok((this, stream))
+    }
+
+    async fn start(
+        addrs: DualStack<(SocketAddr, IpAddr)>,
+        sockets: DualStack<Arc<dyn Transport>>,
+        secret_key: SecretKey,
+        mut bootstrap_nodes: Vec<NodeRecord>,
+        enable_upnp: bool,
+        tcp_port: u16,
+        config: NodeConfig,
+        crawler: Option<Crawler>,
+    ) -> anyhow::Result<Arc<Self>> {
+        config.validate()?;
+        // Tasks spawned from within the tasks end up on the same runtime.
+        let runtime = config.runtime.clone();
+        let _runtime = runtime.as_ref().map(Handle::enter);
+        for (_, public_address) in addrs.iter() {
+            if Ip(*public_address).is_scoped() {
+                bail!("link-local or multicast address {public_address} can't be advertised");
+            }
+        }
+
+        let endpoint = |(addr, public_address): (SocketAddr, IpAddr)| Endpoint {
+            address: Ip(public_address),
             udp_port: addr.port(),
             tcp_port,
-        }));
+        };
+        let pong_external_ip = |_| PongExternalIp::new(config.external_address_threshold);
+        let node_endpoint = Arc::new(LocalEndpoint {
+            endpoint: RwLock::new(DualStack {
+                v4: addrs.v4.map(endpoint),
+                v6: addrs.v6.map(endpoint),
+            }),
+            pong_external_ip: DualStack {
+                v4: addrs.v4.map(pong_external_ip),
+                v6: addrs.v6.map(pong_external_ip),
+            },
+            resolver: config.external_ip_resolver.clone(),
+        });
 
         let task_group = Arc::new(TaskGroup::new());
+        let shutdown = config
+            .cancellation_token
+            .as_ref()
+            .map_or_else(CancellationToken::new, CancellationToken::child_token);
+        let (done_tx, tasks_done) = channel(1);
 
         if enable_upnp {
             task_group.spawn_with_name("discv4 - UPnP", {
                 let node_endpoint = node_endpoint.clone();
-                async move {
-                    loop {
-                        match async {
-                            Ok::<_, anyhow::Error>(
-                                search_gateway(Default::default())
-                                    .await?
-                                    .get_external_ip()
-                                    .await?,
-                            )
-                        }
-                        .await
-                        {
-                            Ok(v) => {
-                                debug!("Discovered public IP: {}", v);
-                                node_endpoint.write().address = Ip(IpAddr::V4(v));
+                let upnp_interval = config.maintenance.upnp_interval;
+                until_shutdown(
+                    shutdown.clone(),
+                    done_tx.clone(),
+                    async move {
+                        loop {
+                            match async {
+                                Ok::<_, anyhow::Error>(
+                                    search_gateway(Default::default())
+                                        .await?
+                                        .get_external_ip()
+                                        .await?,
+                                )
                             }
-                            Err(e) => {
-                                debug!("Failed to get public IP: {}", e);
+                            .await
+                            {
+                                Ok(v) => {
+                                    debug!("Discovered public IP: {}", v);
+                                    if let Some(endpoint) = &mut node_endpoint.endpoint.write().v4 {
+                                        endpoint.address = Ip(IpAddr::V4(v));
+                                    }
+                                }
+                                Err(e) => {
+                                    debug!("Failed to get public IP: {}", e);
+                                }
                             }
+                            sleep(upnp_interval).await;
                         }
-                        sleep(UPNP_INTERVAL).await;
                     }
-                }
-                .instrument(span!(Level::TRACE, "UPNP",))
+                    .instrument(span!(Level::TRACE, "UPNP",)),
+                )
             });
         }
 
@@ -264,20 +1605,52 @@ impl Node {
 
         debug!("Starting node with id: {}", id);
 
-        let udp = Arc::new(UdpSocket::bind(&addr).await?);
+        let local_enr = Mutex::new(local_enr(
+            node_endpoint.record(id),
+            &secret_key,
+            config
+                .warm_start
+                .as_ref()
+                .map_or(0, |warm_start| warm_start.enr_seq + 1)
+                .max(config.clock.unix_timestamp()),
+        ));
 
         let (egress_requests_tx, mut egress_requests) = channel(1);
 
-        let mut table = Table::new(id);
-        for node in bootstrap_nodes.iter().copied() {
-            debug!("Adding bootstrap node: {:?}", node);
-            table.add_verified(node);
-        }
+        bootstrap_nodes.retain(|node| {
+            let supported = sockets.supports(node.address.0);
+            if !supported {
+                debug!(
+                    "Skipping bootstrap node of unsupported address family: {:?}",
+                    node
+                );
+            }
+            supported
+        });
 
-        let connected = Arc::new(Mutex::new(table));
+        let clock = config.clock.clone();
+        let connected = Arc::new(Mutex::new(
+            Table::with_hasher(id, config.bucket_size, config.hasher.clone())
+                .with_clock(clock.clone())
+                .with_rng(config.rng.clone())
+                .with_subnet_limits(config.subnet_limits),
+        ));
 
         let inflight_find_node_requests = Arc::new(InflightFindNode::default());
-        let inflight_ping_requests = Arc::new(Mutex::new(HashMap::<H256, Vec<_>>::default()));
+        let ping_timeout = config.timeouts.ping;
+        let pending_pings = Arc::new(Mutex::new(PendingPings::new(ping_timeout)));
+        let enr_cache = Arc::new(Mutex::new(EnrCache::default()));
+        let reputation = Arc::new(Mutex::new(Reputation::new(config.reputation_half_life)));
+        let events = Arc::new(EventListeners::default());
+        // Nodes seen at a new endpoint, which is being verified.
+        let moving = Arc::new(Mutex::new(HashSet::<NodeId>::new()));
+        let crawler = crawler.map(|mut crawler| {
+            for node in &bootstrap_nodes {
+                crawler.observe(*node, clock.unix_timestamp());
+            }
+            Arc::new(Mutex::new(crawler))
+        });
+        let endpoint_proofs = Arc::new(Mutex::new(EndpointProofs::default()));
         let expected_pings = Arc::new(Mutex::new(HashMap::<
             SocketAddr,
             HashMap<RequestId, OneshotSender<_>>,
@@ -286,10 +1659,45 @@ impl Node {
         task_group.spawn_with_name("discv4 egress router", {
             let task_group = Arc::downgrade(&task_group);
             let connected = connected.clone();
-            let inflight_ping_requests = inflight_ping_requests.clone();
-            let udp = udp.clone();
-            async move {
-                while let Some((addr, peer, message)) = egress_requests.recv().await {
+            let pending_pings = pending_pings.clone();
+            let enr_cache = enr_cache.clone();
+            let reputation = reputation.clone();
+            let events = events.clone();
+            let evict = crawler.is_none();
+            let sockets = sockets.clone();
+            let clock = clock.clone();
+            let max_packet_size = config.max_packet_size;
+            let shutdown = shutdown.clone();
+            let done = done_tx.clone();
+            let done_tx = done_tx.clone();
+            tracked(done, async move {
+                let mut flushing = false;
+                // Reused for every datagram, one at a time.
+                let mut datagram = BytesMut::with_capacity(max_packet_size);
+                loop {
+                    let (addr, peer, message) = if flushing {
+                        match egress_requests.recv().await {
+                            Some(request) => request,
+                            None => break,
+                        }
+                    } else {
+                        select! {
+                            _ = shutdown.cancelled() => {
+                                // Send out the responses already queued, but nothing else.
+                                egress_requests.close();
+                                flushing = true;
+                                continue;
+                            }
+                            request = egress_requests.recv() => match request {
+                                Some(request) => request,
+                                None => break,
+                            },
+                        }
+                    };
+                    if flushing && !message.is_response() {
+                        continue;
+                    }
+
                     async {
                         if peer == id {
                             return;
@@ -300,88 +1708,115 @@ impl Node {
                         let mut pre_trigger = None;
                         let mut post_trigger = None;
 
-                        let mut datagram = BytesMut::with_capacity(MAX_PACKET_SIZE);
-                        let mut sig_bytes = datagram.split_off(H256::len_bytes());
-                        let mut payload =
-                            sig_bytes.split_off(secp256k1::constants::COMPACT_SIGNATURE_SIZE + 1);
-                        match message {
+                        let message = match message {
                             EgressMessage::Ping(message, sender) => {
                                 pre_trigger = Some(PreTrigger::Ping(sender));
-                                post_trigger = Some(PostSendTrigger::Ping);
-                                payload.put_u8(1);
-                                message.encode(&mut payload);
+                                post_trigger = Some(PostSendTrigger::Ping { probe: false });
+                                Message::Ping(message)
                             }
-                            EgressMessage::Pong(message) => {
-                                payload.put_u8(2);
-                                message.encode(&mut payload);
+                            EgressMessage::Probe(message, sender) => {
+                                pre_trigger = Some(PreTrigger::Ping(Some(sender)));
+                                post_trigger = Some(PostSendTrigger::Ping { probe: true });
+                                Message::Ping(message)
                             }
-                            EgressMessage::FindNode(message) => {
-                                payload.put_u8(3);
-                                message.encode(&mut payload);
+                            EgressMessage::Pong(message) => Message::Pong(message),
+                            EgressMessage::FindNode(message) => Message::FindNode(message),
+                            EgressMessage::Neighbours(message) => Message::Neighbours(message),
+                            EgressMessage::EnrRequest(message) => {
+                                pre_trigger = Some(PreTrigger::EnrRequest);
+                                Message::EnrRequest(message)
                             }
-                            EgressMessage::Neighbours(message) => {
-                                payload.put_u8(4);
-                                message.encode(&mut payload);
-                            }
-                        }
+                        };
 
-                        let signature: RecoverableSignature = SECP256K1.sign_ecdsa_recoverable(
-                            &Message::from_slice(&Keccak256::digest(&payload)).unwrap(),
+                        if let Err(e) = encode_packet_checked_into(
+                            &message,
                             &secret_key,
-                        );
-
-                        let (rec, sig) = signature.serialize_compact();
-                        sig_bytes.extend_from_slice(&sig);
-                        sig_bytes.put_u8(rec.to_i32() as u8);
-
-                        sig_bytes.unsplit(payload);
-
-                        let hash = keccak256(&sig_bytes);
-
-                        datagram.extend_from_slice(hash.as_bytes());
-
-                        datagram.unsplit(sig_bytes);
+                            max_packet_size,
+                            &mut datagram,
+                        ) {
+                            debug!("Not sending packet: {}", e);
+                            return;
+                        }
+                        let hash = H256::from_slice(&datagram[..H256::len_bytes()]);
 
-                        let mut do_send = false;
-                        match pre_trigger {
+                        let is_ping = matches!(pre_trigger, Some(PreTrigger::Ping(_)));
+                        let do_send = match pre_trigger {
                             Some(PreTrigger::Ping(sender)) => {
-                                let mut inflight_ping_requests = inflight_ping_requests.lock();
-                                let cbs = inflight_ping_requests.entry(hash).or_insert_with(|| {
-                                    do_send = true;
-                                    Vec::new()
-                                });
-                                if let Some(sender) = sender {
-                                    cbs.push(sender);
-                                }
+                                pending_pings.lock().insert(hash, peer, clock.now(), sender)
                             }
-                            None => {
-                                do_send = true;
+                            Some(PreTrigger::EnrRequest) => {
+                                enr_cache.lock().request_sent(hash, peer, clock.now());
+                                true
                             }
-                        }
+                            None => true,
+                        };
 
                         if !do_send {
                             return;
                         }
 
-                        if let Err(e) = udp.send_to(&datagram, addr).await {
-                            debug!("UDP socket send failure: {}", e);
-                        } else if let Some(trigger) = post_trigger {
+                        let sent = match sockets.get(addr.ip()) {
+                            Some(udp) => match udp.send_to(&datagram, addr).await {
+                                Ok(_) => true,
+                                Err(e) => {
+                                    debug!("UDP socket send failure: {}", e);
+                                    false
+                                }
+                            },
+                            None => {
+                                debug!("No socket bound for the address family of {}", addr);
+                                false
+                            }
+                        };
+                        if !sent {
+                            if is_ping {
+                                // No Pong is coming for a Ping that was never sent, drop the
+                                // callbacks waiting for it right away.
+                                pending_pings.lock().expire(hash);
+                            }
+                            return;
+                        }
+
+                        metrics::record_packet(Direction::Egress, message.packet_type());
+
+                        if let Some(trigger) = post_trigger {
                             match trigger {
-                                PostSendTrigger::Ping => {
+                                PostSendTrigger::Ping { probe } => {
                                     if let Some(task_group) = task_group.upgrade() {
                                         task_group.spawn({
                                             let connected = connected.clone();
-                                            let inflight_ping_requests =
-                                                inflight_ping_requests.clone();
-                                            async move {
-                                                sleep(PING_TIMEOUT).await;
-                                                let mut connected = connected.lock();
-                                                let mut inflight_ping_requests =
-                                                    inflight_ping_requests.lock();
-                                                if inflight_ping_requests.remove(&hash).is_some() {
-                                                    connected.remove(peer);
-                                                }
-                                            }
+                                            let pending_pings = pending_pings.clone();
+                                            let reputation = reputation.clone();
+                                            let events = events.clone();
+                                            let clock = clock.clone();
+                                            until_shutdown(
+                                                shutdown.clone(),
+                                                done_tx.clone(),
+                                                async move {
+                                                    sleep(ping_timeout).await;
+                                                    let expired = pending_pings.lock().expire(hash);
+                                                    // Unanswered Ping to another address than the
+                                                    // stored one, e.g. verifying that the node moved,
+                                                    // says nothing about the node.
+                                                    let at_stored = match connected.lock().get(peer)
+                                                    {
+                                                        Some(endpoint) => {
+                                                            endpoint.udp_addr() == addr
+                                                        }
+                                                        None => true,
+                                                    };
+                                                    if expired.is_some() && at_stored && !probe {
+                                                        reputation.lock().record(
+                                                            peer,
+                                                            ReputationEvent::Timeout,
+                                                            clock.now(),
+                                                        );
+                                                        if evict {
+                                                            remove_node(&connected, &events, peer);
+                                                        }
+                                                    }
+                                                },
+                                            )
                                         });
                                     }
                                 }
@@ -397,265 +1832,767 @@ impl Node {
                     ))
                     .await;
                 }
-            }
+            })
         });
 
-        task_group.spawn_with_name("discv4 ingress router", {
-            let egress_requests_tx = egress_requests_tx.clone();
-            let connected = connected.clone();
-            let node_endpoint = node_endpoint.clone();
-            let expected_pings = expected_pings.clone();
-            let inflight_find_node_requests = inflight_find_node_requests.clone();
-            async move {
-                loop {
-                    let mut buf = [0; MAX_PACKET_SIZE];
-                    let res = udp.recv_from(&mut buf).await;
-                    match res {
-                        Err(e) => {
-                            warn!("UDP socket recv failure: {}", e);
-                            break;
-                        }
-                        Ok((len, addr)) => {
-                            let buf = &buf[..len];
-                            if let Err(e) = async {
-                                if addr.is_ipv6() {
-                                    bail!("IPv6 is unsupported");
-                                }
-
-                                let min_len = 32 + 65 + 1;
-
-                                if buf.len() < min_len {
-                                    bail!("Packet too short: {} < {}", buf.len(), min_len);
-                                }
+        let bootstrap_addrs = bootstrap_nodes
+            .iter()
+            .map(NodeRecord::udp_addr)
+            .collect::<HashSet<_>>();
+        let rate_limiter = config
+            .response_rate_limit
+            .map(|limit| Arc::new(Mutex::new(RateLimiter::new(limit))));
+        let find_node_policy = config.find_node_policy;
+        let find_node_rate_limiter = find_node_policy
+            .rate_limit
+            .map(|limit| Arc::new(Mutex::new(RateLimiter::new(limit))));
 
-                                let hash = keccak256(&buf[32..]);
-                                let check_hash = H256::from_slice(&buf[0..32]);
-                                if check_hash != hash {
-                                    bail!(
-                                        "Hash check failed: computed {}, prefix {}",
+        for udp in sockets.iter().cloned() {
+            task_group.spawn_with_name(format!("discv4 ingress router {}", udp.local_addr()?), {
+                let egress_requests_tx = egress_requests_tx.clone();
+                let connected = connected.clone();
+                let node_endpoint = node_endpoint.clone();
+                let sockets = sockets.clone();
+                let expected_pings = expected_pings.clone();
+                let pending_pings = pending_pings.clone();
+                let enr_cache = enr_cache.clone();
+                let reputation = reputation.clone();
+                let events = events.clone();
+                let moving = moving.clone();
+                let crawler = crawler.clone();
+                let inflight_find_node_requests = inflight_find_node_requests.clone();
+                let endpoint_proofs = endpoint_proofs.clone();
+                let bootstrap_addrs = bootstrap_addrs.clone();
+                let rate_limiter = rate_limiter.clone();
+                let find_node_rate_limiter = find_node_rate_limiter.clone();
+                let clock = clock.clone();
+                let node_filter = config.node_filter.clone();
+                let ping_version_policy = config.ping_version_policy;
+                let topic_packet_policy = config.topic_packet_policy;
+                let expiry = config.expiry;
+                let max_packet_size = config.max_packet_size;
+                let signature_policy = config.signature_policy;
+                let task_group = Arc::downgrade(&task_group);
+                let shutdown = shutdown.clone();
+                let done_tx = done_tx.clone();
+                until_shutdown(shutdown.clone(), done_tx.clone(), async move {
+                    // One byte more than accepted, to tell oversized datagrams from the rest.
+                    let mut buf = vec![0; max_packet_size + 1];
+                    loop {
+                        let res = udp.recv_from(&mut buf).await;
+                        match res {
+                            Err(e) => {
+                                warn!("UDP socket recv failure: {}", e);
+                                break;
+                            }
+                            Ok((len, addr)) => {
+                                let buf = &buf[..len];
+                                if let Err(e) = async {
+                                    let packet =
+                                        decode_datagram(buf, max_packet_size, signature_policy)?;
+                                    metrics::record_packet(Direction::Ingress, packet.packet_type);
+                                    let Packet {
                                         hash,
-                                        check_hash
-                                    );
-                                }
-
-                                let rec_id = RecoveryId::from_i32(buf[96] as i32)?;
-                                let rec_sig =
-                                    RecoverableSignature::from_compact(&buf[32..96], rec_id)?;
-                                let public_key =
-                                    SECP256K1.recover_ecdsa(&keccak256_message(&buf[97..]), &rec_sig)?;
-                                let remote_id = pk2id(&public_key);
+                                        node_id: remote_id,
+                                        ..
+                                    } = packet;
 
-                                if remote_id == id {
-                                    return Ok(());
-                                }
+                                    // Our own packets, looped back or replayed.
+                                    if remote_id == id || node_endpoint.is_local(addr) {
+                                        trace!("Ignoring packet from ourselves");
+                                        return Ok(());
+                                    }
+                                    events.emit(DiscoveryEvent::PacketReceived {
+                                        node_id: remote_id,
+                                        addr,
+                                        packet_type: packet.packet_type,
+                                    });
 
-                                let typ = buf[97];
-                                let mut data = &buf[98..];
-
-                                async {
-                                    match MessageId::from_u8(typ) {
-                                        Some(MessageId::Ping) => {
-                                            let ping_data_result = PingMessage::decode(&mut data);
-                                            let ping_data = match ping_data_result {
-                                                Err(DecodeError::Custom("from:empty")) => {
-                                                    trace!("PING (ignore) due to an empty 'from' IP");
-                                                    return Ok(())
-                                                },
-                                                other => other.context("RLP decoding of incoming Ping message data")?,
-                                            };
+                                    async {
+                                        let message = match Message::decode_checked(
+                                            packet.packet_type,
+                                            &mut &*packet.data,
+                                            clock.unix_timestamp(),
+                                            EXPIRATION_GRACE.as_secs(),
+                                        ) {
+                                            Err(MessageError::Empty)
+                                                if packet.packet_type == MessageId::Ping as u8 =>
+                                            {
+                                                trace!("PING (ignore) due to an empty 'from' IP");
+                                                return Ok(());
+                                            }
+                                            Err(MessageError::TopicPacket(topic)) => {
+                                                metrics::record_topic_packet(topic);
+                                                if topic_packet_policy == TopicPacketPolicy::Log {
+                                                    debug!("Dropping legacy topic discovery packet {:?}", topic);
+                                                }
+                                                return Ok(());
+                                            }
+                                            Err(e) if e.is_malformed() => {
+                                                reputation.lock().record(
+                                                    remote_id,
+                                                    ReputationEvent::Malformed,
+                                                    clock.now(),
+                                                );
+                                                return Err(e).with_context(|| {
+                                                    format!(
+                                                        "Malformed incoming message data of type {}",
+                                                        packet.packet_type
+                                                    )
+                                                });
+                                            }
+                                            other => other.with_context(|| {
+                                                format!(
+                                                "RLP decoding of incoming message data of type {}",
+                                                packet.packet_type
+                                            )
+                                            })?,
+                                        };
 
-                                            trace!("PING");
+                                        if let Message::Ping(ping) = &message {
+                                            if ping.version != PROTOCOL_VERSION {
+                                                metrics::record_unexpected_ping_version();
+                                            }
+                                            ping.check_version(ping_version_policy)?;
+                                        }
 
-                                            connected.lock().add_verified(NodeRecord {
-                                                address: ping_data.from.address,
-                                                udp_port: ping_data.from.udp_port,
-                                                tcp_port: ping_data.from.udp_port,
-                                                id: remote_id,
-                                            });
+                                        // Sender as it is recorded, IPv4-mapped addresses
+                                        // from a dual-stack socket included. Replies still
+                                        // go to `addr`, the one the socket can send to.
+                                        let source =
+                                            SocketAddr::new(normalize_ip(addr.ip()), addr.port());
 
-                                            let _ = egress_requests_tx
-                                                .send((
-                                                    addr,
-                                                    remote_id,
-                                                    EgressMessage::Pong(PongMessage {
-                                                        to: ping_data.from,
-                                                        echo: hash,
-                                                        expire: ping_data.expire,
-                                                    }),
-                                                ))
-                                                .await;
-
-                                            if let Some(cbs) = expected_pings.lock().remove(&addr) {
-                                                for (_, cb) in cbs {
-                                                    let _ = cb.send(());
-                                                }
+                                        if let (
+                                            Message::Ping(_) | Message::FindNode(_),
+                                            Some(rate_limiter),
+                                        ) = (&message, &rate_limiter)
+                                        {
+                                            // Pings from unknown nodes are taken into the
+                                            // table right away, so only a Pong proves the
+                                            // endpoint is not spoofed.
+                                            let exempt = bootstrap_addrs.contains(&source)
+                                                || (endpoint_proofs
+                                                    .lock()
+                                                    .has_valid_proof(&remote_id, clock.unix_timestamp())
+                                                    && matches!(
+                                                        connected.lock().get(remote_id),
+                                                        Some(endpoint) if endpoint.udp_addr() == source
+                                                    ));
+                                            if !exempt
+                                                && !rate_limiter.lock().check(source.ip(), clock.now())
+                                            {
+                                                trace!("Response rate limit exceeded, dropping");
+                                                return Ok(());
                                             }
                                         }
-                                        Some(MessageId::Pong) => {
-                                            let message = PongMessage::decode(&mut data)
-                                                .context("RLP decoding of incoming Pong message data")?;
 
-                                            // Did we actually ask for this? Ignore message if not.
-                                            if let Some(cbs) =
-                                                inflight_ping_requests.lock().remove(&message.echo)
-                                            {
-                                                trace!("PONG - our endpoint is: {:?}", message.to);
-                                                {
-                                                    let mut node_endpoint = node_endpoint.write();
-                                                    node_endpoint.address = message.to.address;
-                                                    node_endpoint.udp_port = message.to.udp_port;
+                                        match message {
+                                            Message::Ping(ping_data) => {
+                                                // Where the Ping came from, not where the
+                                                // remote thinks it is, e.g. behind a NAT: the
+                                                // Pong and our own Ping go there, and the Pong
+                                                // tells the remote what we see. Only the TCP
+                                                // port can't be observed and is taken as reported.
+                                                let record = NodeRecord {
+                                                    address: Ip(source.ip()),
+                                                    udp_port: source.port(),
+                                                    tcp_port: ping_data.from.tcp_port,
+                                                    id: remote_id,
+                                                };
+                                                if !is_allowed(&node_filter, &enr_cache, &record) {
+                                                    trace!("PING (filtered, ignoring)");
+                                                    return Ok(());
                                                 }
-                                                for cb in cbs {
-                                                    let _ = cb.send(());
+
+                                                trace!("PING");
+
+                                                if normalize_ip(ping_data.from.address.0)
+                                                    != source.ip()
+                                                {
+                                                    trace!(
+                                                        "PING reports {}, came from {}",
+                                                        ping_data.from.address.0,
+                                                        addr
+                                                    );
+                                                    metrics::record_reported_address_mismatch();
+                                                    events.emit(
+                                                        DiscoveryEvent::ReportedAddressMismatch {
+                                                            node_id: remote_id,
+                                                            reported: ping_data.from,
+                                                            source: addr,
+                                                        },
+                                                    );
                                                 }
-                                            } else {
-                                                trace!("PONG (unsolicited, ignoring)")
-                                            }
-                                        }
-                                        Some(MessageId::FindNode) => {
-                                            let message =
-                                                FindNodeMessage::decode(&mut data)
-                                                    .context("RLP decoding of incoming FindNode message data")?;
 
-                                            let mut neighbours = None;
-                                            {
-                                                let connected = connected.lock();
-
-                                                // Only send to nodes that have been proofed.
-                                                if connected.get(remote_id).is_some() {
-                                                    trace!("FINDNODE");
-                                                    neighbours = connected
-                                                        .neighbours(message.id)
-                                                        .map(Box::new);
-                                                } else {
-                                                    trace!("FINDNODE (unproofed, ignoring)");
+                                                if let Some(crawler) = &crawler {
+                                                    crawler.lock().observe(record, clock.unix_timestamp());
+                                                }
+                                                let stored = connected.lock().get(remote_id);
+                                                match stored {
+                                                    _ if !sockets.supports(record.address.0) => {}
+                                                    // Source address can be spoofed, so the node
+                                                    // keeps its endpoint until the new one is proven.
+                                                    Some(old) if old.udp_addr() != source => {
+                                                        let from = node_endpoint
+                                                            .for_destination(addr.ip());
+                                                        if let (Some(task_group), Some(from)) =
+                                                            (task_group.upgrade(), from)
+                                                        {
+                                                            if moving.lock().insert(remote_id) {
+                                                                trace!(
+                                                                    "PING from a new endpoint, verifying"
+                                                                );
+                                                                let (tx, rx) = oneshot();
+                                                                let _ = egress_requests_tx
+                                                                    .send((
+                                                                        addr,
+                                                                        remote_id,
+                                                                        EgressMessage::Ping(
+                                                                            PingMessage {
+                                                                                version:
+                                                                                    PROTOCOL_VERSION,
+                                                                                from,
+                                                                                to: record.into(),
+                                                                                expire: expiry
+                                                                                    .expire(),
+                                                                                enr_seq: None,
+                                                                            },
+                                                                            Some(tx),
+                                                                        ),
+                                                                    ))
+                                                                    .await;
+                                                                task_group.spawn(until_shutdown(
+                                                                    shutdown.clone(),
+                                                                    done_tx.clone(),
+                                                                    verify_move(
+                                                                        connected.clone(),
+                                                                        events.clone(),
+                                                                        moving.clone(),
+                                                                        old,
+                                                                        record,
+                                                                        rx,
+                                                                        ping_timeout,
+                                                                    ),
+                                                                ));
+                                                            }
+                                                        }
+                                                    }
+                                                    _ => {
+                                                        if connected.lock().add_verified(record) {
+                                                            events.emit(DiscoveryEvent::NodeAdded(
+                                                                record,
+                                                            ));
+                                                        }
+                                                    }
                                                 }
-                                            }
 
-                                            if let Some(nodes) = neighbours {
                                                 let _ = egress_requests_tx
                                                     .send((
                                                         addr,
                                                         remote_id,
-                                                        EgressMessage::Neighbours(
-                                                            NeighboursMessage {
-                                                                nodes: nodes.into_iter().collect(),
-                                                                expire: message.expire,
-                                                            },
+                                                        EgressMessage::Pong(
+                                                            PongMessage::respond_to(
+                                                                hash,
+                                                                record.into(),
+                                                                expiry.expire_at(
+                                                                    clock.unix_timestamp(),
+                                                                ),
+                                                            ),
                                                         ),
                                                     ))
                                                     .await;
+
+                                                // Prove our endpoint to the remote as well, unless
+                                                // it has done so already or is about to: Pings
+                                                // repeated before our Ping is answered are not
+                                                // answered by more Pings.
+                                                let from = node_endpoint.for_destination(addr.ip());
+                                                let has_valid_proof = endpoint_proofs
+                                                    .lock()
+                                                    .has_valid_proof(&remote_id, clock.unix_timestamp());
+                                                let bonding = !has_valid_proof
+                                                    && !pending_pings
+                                                        .lock()
+                                                        .is_pending(remote_id, clock.now());
+                                                if let (Some(from), true) = (from, bonding) {
+                                                    let _ = egress_requests_tx
+                                                        .send((
+                                                            addr,
+                                                            remote_id,
+                                                            EgressMessage::Ping(
+                                                                PingMessage {
+                                                                    version: PROTOCOL_VERSION,
+                                                                    from,
+                                                                    to: record.into(),
+                                                                    expire: expiry.expire_at(clock.unix_timestamp()),
+                                                                    enr_seq: None,
+                                                                },
+                                                                None,
+                                                            ),
+                                                        ))
+                                                        .await;
+                                                }
+
+                                                if let Some(enr_seq) = ping_data.enr_seq {
+                                                    let outdated = has_valid_proof
+                                                        && enr_cache.lock().is_outdated(
+                                                            remote_id,
+                                                            enr_seq,
+                                                            clock.now(),
+                                                        );
+                                                    if outdated {
+                                                        let _ = egress_requests_tx
+                                                            .send((
+                                                                addr,
+                                                                remote_id,
+                                                                EgressMessage::EnrRequest(
+                                                                    EnrRequestMessage {
+                                                                        expire: expiry.expire_at(clock.unix_timestamp()),
+                                                                    },
+                                                                ),
+                                                            ))
+                                                            .await;
+                                                    }
+                                                }
+
+                                                if let Some(cbs) =
+                                                    expected_pings.lock().remove(&addr)
+                                                {
+                                                    for (_, cb) in cbs {
+                                                        let _ = cb.send(());
+                                                    }
+                                                }
                                             }
-                                        }
-                                        Some(MessageId::Neighbours) => {
-                                            // Did we actually ask for this? Ignore message if not.
-                                            let cbs = inflight_find_node_requests.get(remote_id);
-                                            if cbs.is_empty() {
-                                                trace!("NEIGHBOURS (ignore)");
-                                            } else {
-                                                trace!("NEIGHBOURS");
-
-                                                // OK, so we did ask, let's handle the message.
-                                                let message =
-                                                    NeighboursMessage::decode(&mut data)
-                                                        .context("RLP decoding of incoming Neighbours message data")?;
+                                            Message::Pong(message) => {
+                                                // Did we actually ask for this? Ignore message if not.
+                                                let pending = pending_pings.lock().take(
+                                                    message.echo,
+                                                    remote_id,
+                                                    clock.now(),
+                                                );
+                                                if let Some(PendingPing {
+                                                    sent_at,
+                                                    callbacks,
+                                                    ..
+                                                }) = pending
+                                                {
+                                                    metrics::record_ping_rtt(sent_at.elapsed());
+                                                    reputation.lock().record(
+                                                        remote_id,
+                                                        ReputationEvent::Pong,
+                                                        clock.now(),
+                                                    );
+                                                    trace!(
+                                                        "PONG - our endpoint is: {:?}",
+                                                        message.to
+                                                    );
+                                                    endpoint_proofs.lock().record_pong(
+                                                        remote_id,
+                                                        message.echo,
+                                                        clock.unix_timestamp(),
+                                                    );
+                                                    connected.lock().record_contact(remote_id);
+                                                    events.emit(DiscoveryEvent::EndpointProven {
+                                                        node_id: remote_id,
+                                                        addr,
+                                                    });
+                                                    if let Some(pong_external_ip) = node_endpoint
+                                                        .pong_external_ip
+                                                        .get(addr.ip())
+                                                        .filter(|_| {
+                                                            addr.is_ipv6()
+                                                                == message.to.address.is_ipv6()
+                                                        })
+                                                    {
+                                                        pong_external_ip.record(
+                                                            remote_id,
+                                                            message.to.address.0,
+                                                        );
+                                                    }
+                                                    for cb in callbacks {
+                                                        let _ = cb.send(());
+                                                    }
 
+                                                    // The endpoint is proven now.
+                                                    let outdated = match message.enr_seq {
+                                                        Some(enr_seq) => {
+                                                            enr_cache.lock().is_outdated(
+                                                                remote_id,
+                                                                enr_seq,
+                                                                clock.now(),
+                                                            )
+                                                        }
+                                                        None => false,
+                                                    };
+                                                    if outdated {
+                                                        let _ = egress_requests_tx
+                                                            .send((
+                                                                addr,
+                                                                remote_id,
+                                                                EgressMessage::EnrRequest(
+                                                                    EnrRequestMessage {
+                                                                        expire: expiry.expire_at(clock.unix_timestamp()),
+                                                                    },
+                                                                ),
+                                                            ))
+                                                            .await;
+                                                    }
+                                                } else {
+                                                    trace!("PONG (unsolicited, ignoring)")
+                                                }
+                                            }
+                                            Message::FindNode(message) => {
+                                                let mut neighbours = None;
                                                 {
-                                                    let mut connected = connected.lock();
+                                                    let connected = connected.lock();
+                                                    let endpoint_proofs = endpoint_proofs.lock();
+                                                    let now = clock.unix_timestamp();
 
-                                                    for peer in message.nodes.iter() {
-                                                        connected.add_seen(*peer);
+                                                    let denial = find_node_policy
+                                                        .denial(
+                                                            endpoint_proofs
+                                                                .has_valid_proof(&remote_id, now),
+                                                            connected.log2_distance(message.id),
+                                                        )
+                                                        .or_else(|| {
+                                                            find_node_rate_limiter
+                                                                .as_ref()
+                                                                .filter(|limiter| {
+                                                                    !limiter.lock().check(
+                                                                        source.ip(),
+                                                                        clock.now(),
+                                                                    )
+                                                                })
+                                                                .map(|_| "rate limited")
+                                                        });
+                                                    if let Some(reason) = denial {
+                                                        trace!("FINDNODE ({reason}, ignoring)");
+                                                    } else {
+                                                        trace!("FINDNODE");
+                                                        neighbours = Some(find_node_candidates(
+                                                            &connected,
+                                                            &endpoint_proofs,
+                                                            remote_id,
+                                                            message.id,
+                                                            now,
+                                                        ));
                                                     }
                                                 }
 
-                                                for cb in cbs {
-                                                    let _ = cb.send(message.clone()).await;
+                                                if let Some(nodes) = neighbours {
+                                                    let neighbours = NeighboursMessage {
+                                                        nodes: nodes
+                                                            .into_iter()
+                                                            .filter(|node| {
+                                                                is_allowed(
+                                                                    &node_filter,
+                                                                    &enr_cache,
+                                                                    node,
+                                                                )
+                                                            })
+                                                            .take(MAX_NEIGHBOURS)
+                                                            .collect(),
+                                                        expire: message.expire,
+                                                    };
+                                                    for message in neighbours.split(
+                                                        max_packet_size
+                                                            .saturating_sub(MIN_PACKET_SIZE),
+                                                    ) {
+                                                        let _ = egress_requests_tx
+                                                            .send((
+                                                                addr,
+                                                                remote_id,
+                                                                EgressMessage::Neighbours(message),
+                                                            ))
+                                                            .await;
+                                                    }
+                                                }
+                                            }
+                                            Message::Neighbours(mut message) => {
+                                                // Did we actually ask for this? Ignore message if not.
+                                                let cbs =
+                                                    inflight_find_node_requests.get(remote_id);
+                                                // Every node a crawler hears of is queried in
+                                                // turn, so not on behalf of spoofed sources.
+                                                let proven = crawler.is_some()
+                                                    && endpoint_proofs
+                                                        .lock()
+                                                        .has_valid_proof(&remote_id, clock.unix_timestamp());
+                                                if let (true, Some(crawler), true) =
+                                                    (cbs.is_empty(), &crawler, proven)
+                                                {
+                                                    // Crawlers take whatever they hear of, without
+                                                    // adding it to the table.
+                                                    trace!("NEIGHBOURS (unsolicited, crawling)");
+                                                    let mut crawler = crawler.lock();
+                                                    for node in message.nodes {
+                                                        if is_valid_neighbour(&node)
+                                                            && !is_self(id, &node_endpoint, &node)
+                                                            && is_allowed(&node_filter, &enr_cache, &node)
+                                                        {
+                                                            crawler.observe(node, clock.unix_timestamp());
+                                                        }
+                                                    }
+                                                } else if cbs.is_empty() {
+                                                    trace!("NEIGHBOURS (ignore)");
+                                                } else {
+                                                    trace!("NEIGHBOURS");
+                                                    reputation.lock().record(
+                                                        remote_id,
+                                                        ReputationEvent::Neighbours,
+                                                        clock.now(),
+                                                    );
+
+                                                    let mut seen = HashSet::new();
+                                                    message.nodes.retain(|node| {
+                                                        is_valid_neighbour(node)
+                                                            && !is_self(id, &node_endpoint, node)
+                                                            && is_allowed(&node_filter, &enr_cache, node)
+                                                            && seen.insert(node.id)
+                                                    });
+
+                                                    if let Some(crawler) = &crawler {
+                                                        let mut crawler = crawler.lock();
+                                                        for node in message.nodes.iter() {
+                                                            crawler.observe(*node, clock.unix_timestamp());
+                                                        }
+                                                    }
+                                                    {
+                                                        let mut connected = connected.lock();
+
+                                                        for peer in message.nodes.iter() {
+                                                            if sockets.supports(peer.address.0)
+                                                                && connected.add_seen(*peer)
+                                                            {
+                                                                events.emit(
+                                                                    DiscoveryEvent::NodeAdded(*peer),
+                                                                );
+                                                            }
+                                                        }
+                                                    }
+
+                                                    for cb in cbs {
+                                                        let _ = cb.send(message.clone()).await;
+                                                    }
+                                                }
+                                            }
+                                            Message::EnrRequest(_) => {
+                                                trace!("ENRREQUEST (ignore)");
+                                            }
+                                            Message::EnrResponse(message) => {
+                                                let allowed = node_filter.allow_enr(message.enr());
+                                                let updated = enr_cache.lock().insert_response(
+                                                    remote_id,
+                                                    message.request_hash,
+                                                    message.into_enr(),
+                                                    clock.now(),
+                                                );
+                                                if updated && !allowed {
+                                                    trace!("ENRRESPONSE (filtered, removing)");
+                                                    remove_node(&connected, &events, remote_id);
+                                                } else if updated {
+                                                    trace!("ENRRESPONSE");
+                                                } else {
+                                                    trace!("ENRRESPONSE (unsolicited or stale, ignoring)");
                                                 }
                                             }
                                         }
-                                        None => bail!("Invalid message type: {}", typ),
-                                    };
 
-                                    Ok(())
+                                        Ok(())
+                                    }
+                                    .instrument(span!(
+                                        Level::TRACE,
+                                        "HANDLER",
+                                        "remote_id={}",
+                                        &*remote_id.to_string()
+                                    ))
+                                    .await
                                 }
                                 .instrument(span!(
                                     Level::TRACE,
-                                    "HANDLER",
-                                    "remote_id={}",
-                                    &*remote_id.to_string()
+                                    "IN",
+                                    "addr={}",
+                                    &*addr.to_string()
                                 ))
                                 .await
-                            }
-                            .instrument(span!(Level::TRACE, "IN", "addr={}", &*addr.to_string()))
-                            .await
-                            {
-                                trace!("Failed to handle message from {}: {}", addr, e);
+                                {
+                                    trace!("Failed to handle message from {}: {}", addr, e);
+                                }
                             }
                         }
                     }
-                }
-            }
-        });
+                })
+            });
+        }
 
+        let query_permits = Semaphore::new(config.max_outstanding_queries);
         let this = Arc::new(Self {
             task_group,
+            config,
             connected,
             id,
+            secret_key,
             node_endpoint,
+            local_enr,
             egress_requests_tx,
             expected_pings,
             inflight_find_node_requests,
+            query_permits,
+            enr_cache,
+            reputation,
+            events,
+            crawler,
+            endpoint_proofs,
+            bootstrap_state: Mutex::new(if bootstrap_nodes.is_empty() {
+                BootstrapState::Ready
+            } else {
+                BootstrapState::Bonding { attempts: 0 }
+            }),
+            bootstrap_nodes,
+            shutdown,
+            tasks_done: AsyncMutex::new(tasks_done),
         });
 
         this.task_group.spawn_with_name("discv4 refresher", {
+            let shutdown = this.shutdown.clone();
             let this = Arc::downgrade(&this);
-            async move {
-                while let Some(this) = this.upgrade() {
-                    {
-                        let mut connected = this.connected.lock();
-                        for node in bootstrap_nodes.iter().copied() {
-                            connected.add_seen(node);
+            until_shutdown(shutdown, done_tx.clone(), async move {
+                if let Some(this) = this.upgrade() {
+                    if let Some(warm_start) = &this.config.warm_start {
+                        if let Err(e) = this
+                            .restore_table(&warm_start.snapshot, warm_start.max_age)
+                            .await
+                        {
+                            warn!("Failed to restore the table snapshot: {}", e);
                         }
                     }
+                }
 
-                    this.lookup_self().await;
-                    for _ in 0..3 {
-                        this.lookup(rand::random()).await;
+                while let Some(this) = this.upgrade() {
+                    let attempts = match this.bootstrap_state() {
+                        BootstrapState::Bonding { attempts } => attempts,
+                        BootstrapState::Ready => break,
+                    };
+                    if this.bond_bootstrap_nodes().await {
+                        debug!("Bonded with bootstrap nodes");
+                        *this.bootstrap_state.lock() = BootstrapState::Ready;
+                        break;
                     }
+
+                    let attempts = attempts + 1;
+                    let backoff = this.config.maintenance.bootstrap_backoff(attempts);
+                    debug!("No bootstrap node answered, retrying in {:?}", backoff);
+                    *this.bootstrap_state.lock() = BootstrapState::Bonding { attempts };
                     drop(this);
 
-                    sleep(REFRESH_TIMEOUT).await;
+                    sleep(backoff).await;
                 }
-            }
+
+                while let Some(this) = this.upgrade() {
+                    this.endpoint_proofs
+                        .lock()
+                        .prune(this.config.clock.unix_timestamp());
+
+                    let targets = this
+                        .connected
+                        .lock()
+                        .refresh_targets(this.config.maintenance.refresh_targets);
+                    join_all(
+                        std::iter::once(this.id)
+                            .chain(targets)
+                            .map(|target| this.lookup_inner(target)),
+                    )
+                    .await;
+                    let refresh_interval = this.config.maintenance.refresh_interval;
+                    drop(this);
+
+                    sleep(refresh_interval).await;
+                }
+            })
         });
 
-        this.task_group
-            .spawn_with_name("discv4 oldest node pinger", {
-                let connected = this.connected.clone();
-                let egress_requests_tx = this.egress_requests_tx.clone();
-                let node_endpoint = this.node_endpoint.clone();
-                async move {
+        if this.crawler.is_some() {
+            this.task_group.spawn_with_name("discv4 crawler", {
+                let shutdown = this.shutdown.clone();
+                let this = Arc::downgrade(&this);
+                until_shutdown(shutdown, done_tx.clone(), async move {
+                    while let Some(this) = this.upgrade() {
+                        let batch = this
+                            .crawler
+                            .as_ref()
+                            .expect("crawler mode")
+                            .lock()
+                            .next_to_query(this.config.lookup_concurrency);
+                        if batch.is_empty() {
+                            let refresh_interval = this.config.maintenance.refresh_interval;
+                            drop(this);
+                            sleep(refresh_interval).await;
+                            continue;
+                        }
+
+                        // Neighbours are picked up by the ingress router.
+                        join_all(
+                            batch
+                                .into_iter()
+                                .map(|record| this.query(record, this.random_target())),
+                        )
+                        .await;
+                    }
+                })
+            });
+        }
+
+        this.task_group.spawn_with_name("discv4 liveness checker", {
+            let shutdown = this.shutdown.clone();
+            let this = Arc::downgrade(&this);
+            until_shutdown(shutdown, done_tx.clone(), async move {
+                while let Some(this) = this.upgrade() {
+                    this.evict_stale().await;
+                    let ping_interval = this.config.maintenance.ping_interval;
+                    drop(this);
+
+                    sleep(ping_interval).await;
+                }
+            })
+        });
+
+        this.task_group
+            .spawn_with_name("discv4 oldest node pinger", {
+                let connected = this.connected.clone();
+                let egress_requests_tx = this.egress_requests_tx.clone();
+                let node_endpoint = this.node_endpoint.clone();
+                let expiry = this.config.expiry;
+                let clock = this.config.clock.clone();
+                let mut rng = this.config.rng.clone();
+                let ping_interval = this.config.maintenance.ping_interval;
+                until_shutdown(this.shutdown.clone(), done_tx, async move {
                     loop {
                         let oldest = {
                             let connected = connected.lock();
                             connected
                                 .filled_buckets()
-                                .choose(&mut thread_rng())
+                                .choose(&mut rng)
                                 .and_then(|bucket_no| connected.oldest(*bucket_no))
                         };
 
-                        if let Some(node) = oldest {
+                        let from = oldest.and_then(|node| {
+                            node_endpoint
+                                .for_destination(node.address.0)
+                                .map(|from| (node, from))
+                        });
+                        if let Some((node, from)) = from {
                             let (tx, rx) = oneshot();
-                            let from = *node_endpoint.read();
                             if egress_requests_tx
                                 .send((
                                     node.udp_addr(),
-                                    id,
+                                    node.id,
                                     EgressMessage::Ping(
                                         PingMessage {
+                                            version: PROTOCOL_VERSION,
                                             from,
                                             to: node.into(),
-                                            expire: ping_expiry(),
+                                            expire: expiry.expire_at(clock.unix_timestamp()),
+                                            enr_seq: None,
                                         },
                                         Some(tx),
                                     ),
@@ -670,26 +2607,536 @@ impl Node {
                         }
 
                         let sleep_duration = Duration::from_secs_f32(
-                            PING_INTERVAL.as_secs_f32() * thread_rng().sample::<f32, _>(Standard),
+                            ping_interval.as_secs_f32() * rng.sample::<f32, _>(Standard),
                         );
 
                         sleep(sleep_duration).await;
                     }
-                }
+                })
             });
 
         Ok(this)
     }
 
+    /// Ping the table entries not heard of for the [liveness TTL](MaintenanceConfig::liveness_ttl)
+    /// and evict the ones that don't answer, so that temporarily unreachable nodes get another
+    /// chance first. Answering nodes are marked as contacted again by their Pong.
+    async fn evict_stale(&self) {
+        let stale = self
+            .connected
+            .lock()
+            .stale(self.config.maintenance.liveness_ttl);
+        let unanswered = stream::iter(stale)
+            .map(|node| async move { (node, self.ping(&node).await) })
+            .buffer_unordered(LIVENESS_CHECK_CONCURRENCY)
+            .filter_map(|(node, result)| async move {
+                matches!(result, Err(PingError::Timeout)).then_some(node)
+            })
+            .collect::<Vec<_>>()
+            .await;
+
+        for node in unanswered {
+            let (evicted, replacement) = self.connected.lock().evict(node.id);
+            if evicted {
+                debug!("Evicting stale node {:?}", node);
+                metrics::record_stale_eviction();
+                self.events.emit(DiscoveryEvent::NodeRemoved(node.id));
+                if let Some(replacement) = replacement {
+                    self.events.emit(DiscoveryEvent::NodeAdded(replacement));
+                }
+            }
+        }
+    }
+
+    /// Stop receiving packets and all background tasks, sending out the responses that are
+    /// already queued, and wait until the tasks are finished and the sockets are closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.cancel();
+        let mut tasks_done = self.tasks_done.lock().await;
+        while tasks_done.recv().await.is_some() {}
+    }
+
+    /// See [`NodeConfig::runtime`].
+    pub(crate) fn runtime(&self) -> Option<&Handle> {
+        self.config.runtime.as_ref()
+    }
+
+    pub fn bootstrap_state(&self) -> BootstrapState {
+        *self.bootstrap_state.lock()
+    }
+
+    /// Ping all bootstrap nodes, returning whether any of them answered.
+    async fn bond_bootstrap_nodes(&self) -> bool {
+        join_all(self.bootstrap_nodes.iter().map(|node| self.bond(*node)))
+            .await
+            .into_iter()
+            .any(|bonded| bonded)
+    }
+
+    /// Ping the node and wait for its Pong. Nodes of unsupported address family are never pinged.
+    async fn bond(&self, node: NodeRecord) -> bool {
+        self.ping_with_retry(node, false).await.is_ok()
+    }
+
+    /// Check whether the node is alive, returning the round-trip time of a Ping to it.
+    ///
+    /// Unlike the Pings of table maintenance, this leaves the table and the reputation of
+    /// the node alone if it does not answer within the [ping timeout](RequestTimeouts::ping),
+    /// retries included.
+    pub async fn ping(&self, record: &NodeRecord) -> Result<Duration, PingError> {
+        let sent_at = Instant::now();
+        self.ping_with_retry(*record, true).await?;
+        Ok(sent_at.elapsed())
+    }
+
+    /// Ping the node until it answers, once more after a pause if
+    /// [retries](RequestTimeouts::retry_backoff) are enabled.
+    ///
+    /// The retry expires later than the first Ping, so that it gets a hash of its own rather
+    /// than being taken for the expired one. Only the last attempt of a non-`probe` Ping
+    /// counts against the node if it is not answered.
+    async fn ping_with_retry(&self, node: NodeRecord, probe: bool) -> Result<(), PingError> {
+        let timeouts = self.config.timeouts;
+        let mut retry_delay = timeouts.retry_delay(&mut self.config.rng.clone());
+        let mut last_expire = 0;
+        loop {
+            let expire = self
+                .config
+                .expiry
+                .expire_at(self.config.clock.unix_timestamp())
+                .max(last_expire + 1);
+            last_expire = expire;
+
+            let last = retry_delay.is_none();
+            let (tx, rx) = oneshot();
+            self.send_ping(node, expire, |message| {
+                if probe || !last {
+                    EgressMessage::Probe(message, tx)
+                } else {
+                    EgressMessage::Ping(message, Some(tx))
+                }
+            })
+            .await?;
+            // The callback is dropped once the Ping expires unanswered.
+            if let Ok(Ok(())) = timeout(timeouts.ping, rx).await {
+                return Ok(());
+            }
+
+            match retry_delay.take() {
+                Some(delay) => {
+                    trace!("No Pong from {:?}, retrying in {:?}", node.id, delay);
+                    sleep(delay).await;
+                }
+                None => return Err(PingError::Timeout),
+            }
+        }
+    }
+
+    async fn send_ping(
+        &self,
+        node: NodeRecord,
+        expire: u64,
+        egress: impl FnOnce(PingMessage) -> EgressMessage,
+    ) -> Result<(), PingError> {
+        let from = self
+            .node_endpoint
+            .for_destination(node.address.0)
+            .ok_or(PingError::UnsupportedAddressFamily)?;
+
+        self.egress_requests_tx
+            .send((
+                node.udp_addr(),
+                node.id,
+                egress(PingMessage {
+                    version: PROTOCOL_VERSION,
+                    from,
+                    to: node.into(),
+                    expire,
+                    enr_seq: None,
+                }),
+            ))
+            .await
+            .map_err(|_| PingError::Shutdown)
+    }
+
+    /// Latest node record received from the node, see [`EnrCache`].
+    pub fn enr(&self, node_id: NodeId) -> Option<Enr> {
+        self.enr_cache.lock().get(&node_id).cloned()
+    }
+
+    /// Stream of [`DiscoveryEvent`]s from now on, buffering up to `capacity` of them.
+    ///
+    /// Events never wait for a lagging listener, the ones that do not fit are dropped
+    /// and counted in [`Node::dropped_events`].
+    pub fn subscribe_events(&self, capacity: usize) -> EventStream {
+        self.events.subscribe(capacity)
+    }
+
+    /// Number of events dropped so far because of listeners lagging behind.
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped()
+    }
+
+    /// Current reputation score of the node, see [`Reputation`].
+    pub fn reputation(&self, node_id: NodeId) -> f64 {
+        self.reputation
+            .lock()
+            .score(node_id, self.config.clock.now())
+    }
+
+    /// Reputation scores of all nodes scored recently, to be persisted along with the
+    /// [`Node::table_snapshot`] and passed to [`Node::restore_reputation`] after a restart.
+    pub fn reputation_scores(&self) -> Vec<(NodeId, f64)> {
+        self.reputation.lock().scores(self.config.clock.now())
+    }
+
+    pub fn restore_reputation(&self, scores: impl IntoIterator<Item = (NodeId, f64)>) {
+        self.reputation
+            .lock()
+            .restore(scores, self.config.clock.now());
+    }
+
+    /// Shared read-only view of the routing table, e.g. to pick peers to dial.
+    pub fn table(&self) -> TableHandle {
+        TableHandle::new(self.connected.clone())
+    }
+
+    /// All routing table entries, see [`Table::entries`].
+    pub fn table_entries(&self) -> Vec<TableEntry> {
+        let mut entries = self.connected.lock().entries().collect::<Vec<_>>();
+        let endpoint_proofs = self.endpoint_proofs.lock();
+        let now = self.config.clock.unix_timestamp();
+        for entry in &mut entries {
+            entry.endpoint_proven = endpoint_proofs.has_valid_proof(&entry.record.id, now);
+        }
+        entries
+    }
+
+    /// Snapshot of the routing table, to be passed to [`Node::restore_table`] after a restart.
+    pub fn table_snapshot(&self) -> Vec<u8> {
+        self.connected.lock().serialize()
+    }
+
+    /// Restore the table from a [`Node::table_snapshot`].
+    ///
+    /// Entries not verified within `max_age` are skipped, and the rest are pinged again,
+    /// [`NodeConfig::restore_concurrency`] at a time, so that only the nodes that answer
+    /// within the [ping timeout](RequestTimeouts::ping) are added to the table. Returns the number of them, which is
+    /// reported with [`DiscoveryEvent::TableRestored`] as well.
+    pub async fn restore_table(
+        &self,
+        snapshot: &[u8],
+        max_age: Duration,
+    ) -> Result<usize, DecodeError> {
+        let snapshot = Table::deserialize_with_hasher(
+            self.id,
+            self.config.bucket_size,
+            self.config.hasher.clone(),
+            snapshot,
+            self.config.clock.unix_timestamp(),
+            max_age.as_secs(),
+        )?;
+        let nodes = snapshot
+            .buckets()
+            .flat_map(|(_, bucket)| bucket.iter().copied())
+            .filter(|node| {
+                !is_self(self.id, &self.node_endpoint, node)
+                    && is_allowed(&self.config.node_filter, &self.enr_cache, node)
+            })
+            .collect::<Vec<_>>();
+
+        let total = nodes.len();
+        let pinged = stream::iter(nodes)
+            .map(|node| async move {
+                let alive = self.bond(node).await;
+                (node, alive)
+            })
+            .buffer_unordered(self.config.restore_concurrency)
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut restored = 0;
+        {
+            let mut connected = self.connected.lock();
+            for (node, alive) in pinged {
+                if alive {
+                    if connected.add_verified(node) {
+                        self.events.emit(DiscoveryEvent::NodeAdded(node));
+                    }
+                    restored += 1;
+                }
+            }
+        }
+        info!("Restored {} of {} table snapshot nodes", restored, total);
+        self.events
+            .emit(DiscoveryEvent::TableRestored { restored, total });
+        Ok(restored)
+    }
+
+    /// Look up the nodes closest to ourselves, which populates the table with our neighbourhood.
     pub async fn lookup_self(&self) -> Vec<NodeRecord> {
         self.lookup_inner(self.id).await
     }
 
+    /// Iterative Kademlia lookup of the nodes closest to `target`.
+    ///
+    /// Starting from the closest known nodes, queries [`NodeConfig::lookup_concurrency`] of
+    /// them at a time with FindNode, each after bonding with it, and continues with the closer
+    /// nodes learned from the responses until the [`NodeConfig::lookup_result_count`] closest
+    /// ones have all been queried. Returns the closest nodes that responded, closest first.
     pub async fn lookup(&self, target: NodeId) -> Vec<NodeRecord> {
         self.lookup_inner(target).await
     }
 
+    /// [`Node::lookup`], attaching the cached ENR to every node that has one, e.g. to pass
+    /// the nodes on to discovery v5.
+    ///
+    /// ENRs are requested from the nodes that advertise a newer one as they answer the
+    /// lookup, so ones that have just been asked for may still be missing.
+    pub async fn lookup_enrs(&self, target: NodeId) -> Vec<LookupResult> {
+        let records = self.lookup_inner(target).await;
+        let enr_cache = self.enr_cache.lock();
+        records
+            .into_iter()
+            .map(|record| LookupResult {
+                record,
+                enr: enr_cache.get(&record.id).cloned(),
+            })
+            .collect()
+    }
+
+    /// Nodes at the given log2 `distances` from `target`, as in the FINDNODE of discovery v5,
+    /// closest first.
+    ///
+    /// FindNode of v4 only takes a target, so besides `target` itself, a random point at each
+    /// of the distances is looked up, see [`random_at_distance_with`]. Only the nodes that have
+    /// proven their endpoint are returned. Distances beyond [`ADDRESS_BITS`] are ignored.
+    pub async fn lookup_at_distances(&self, target: NodeId, distances: &[u16]) -> Vec<NodeRecord> {
+        let hasher = &*self.config.hasher;
+        let distances = distances
+            .iter()
+            .copied()
+            .filter(|distance| usize::from(*distance) <= ADDRESS_BITS)
+            .collect::<HashSet<_>>();
+        if distances.is_empty() {
+            return vec![];
+        }
+
+        let target_hash = hasher.hash(target.as_bytes());
+        let mut rng = self.config.rng.clone();
+        let points = std::iter::once(target_hash)
+            .chain(
+                distances
+                    .iter()
+                    .filter(|distance| **distance > 0)
+                    .map(|distance| random_at_distance_with(&mut rng, target_hash, *distance)),
+            )
+            .collect::<Vec<_>>();
+
+        let results = join_all(
+            points
+                .into_iter()
+                .map(|point| self.lookup_point(target, point)),
+        )
+        .await;
+
+        let now = self.config.clock.unix_timestamp();
+        let endpoint_proofs = self.endpoint_proofs.lock();
+        let mut found = BTreeMap::new();
+        for record in results.into_iter().flatten() {
+            if distances.contains(&log2_distance_with(hasher, target, record.id))
+                && endpoint_proofs.has_valid_proof(&record.id, now)
+            {
+                found.insert(distance_with(hasher, target, record.id), record);
+            }
+        }
+        found.into_values().collect()
+    }
+
+    /// Random lookup target, drawn from [`NodeConfig::rng`].
+    pub fn random_target(&self) -> NodeId {
+        NodeId::random_using(&mut self.config.rng.clone())
+    }
+
+    /// Endless walk over the DHT: lookups of random targets, one after another, yielding
+    /// every node they turn up that has not been yielded before.
+    ///
+    /// Once the lookups stop finding new nodes, the walk waits between them, twice as long
+    /// after every fruitless one, from [`RANDOM_WALK_BACKOFF_INITIAL`] up to
+    /// [`RANDOM_WALK_BACKOFF_MAX`], and speeds up again with the next new node. It only ends
+    /// on [`Node::shutdown`], or once the node has been dropped. Up to
+    /// [`RANDOM_WALK_MAX_SEEN`] of the ids yielded so far are kept for the lifetime of the
+    /// stream.
+    pub fn random_walk(self: &Arc<Self>) -> impl Stream<Item = NodeRecord> + Send + 'static {
+        let node = Arc::downgrade(self);
+        let shutdown = self.shutdown.clone();
+        async_stream::stream! {
+            let mut seen = LruCache::new(RANDOM_WALK_MAX_SEEN);
+            let mut backoff = RANDOM_WALK_BACKOFF_INITIAL;
+            loop {
+                // Only hold the node during the lookup, so that a paused stream doesn't
+                // keep it alive.
+                let found = {
+                    let node = match node.upgrade() {
+                        Some(node) => node,
+                        None => break,
+                    };
+                    tokio::select! {
+                        found = node.lookup_inner(node.random_target()) => found,
+                        _ = shutdown.cancelled() => break,
+                    }
+                };
+
+                let mut new = false;
+                for record in found {
+                    if seen.put(record.id, ()).is_none() {
+                        new = true;
+                        yield record;
+                    }
+                }
+
+                if new {
+                    backoff = RANDOM_WALK_BACKOFF_INITIAL;
+                } else {
+                    trace!("Random walk found no new nodes, waiting {:?}", backoff);
+                    tokio::select! {
+                        _ = sleep(backoff) => {}
+                        _ = shutdown.cancelled() => break,
+                    }
+                    backoff = (backoff * 2).min(RANDOM_WALK_BACKOFF_MAX);
+                }
+            }
+        }
+    }
+
+    /// Bond with the node and ask it for the nodes closest to `target`, waiting for
+    /// all Neighbours packets of the response, within the
+    /// [FindNode timeout](RequestTimeouts::find_node).
+    async fn query(&self, record: NodeRecord, target: NodeId) -> Option<Vec<NodeRecord>> {
+        let egress_requests_tx = &self.egress_requests_tx;
+        let expected_pings = &self.expected_pings;
+        let node_endpoint = &self.node_endpoint;
+        let inflight_find_node_requests = &self.inflight_find_node_requests;
+        let neighbours_wait_timeout = self.config.neighbours_wait_timeout;
+        let expiry = self.config.expiry;
+        let expected_ping_id = self.config.rng.clone().gen();
+
+        let addr = SocketAddr::new(record.address.0, record.udp_port);
+
+        let _permit = self.query_permits.acquire().await.ok()?;
+        let res = timeout(self.config.timeouts.find_node, async {
+            if node_endpoint.for_destination(addr.ip()).is_none() {
+                bail!("Address family is not supported");
+            }
+
+            let (expected_ping_tx, expected_ping_rx) = oneshot();
+            expected_pings
+                .lock()
+                .entry(addr)
+                .or_default()
+                .insert(expected_ping_id, expected_ping_tx);
+
+            // Make sure our endpoint is proven.
+            if !self.bond(record).await {
+                bail!("Pong timeout");
+            }
+
+            trace!("Our endpoint is proven");
+
+            // In case the node wants to ping us, give it an opportunity to do so
+            let _ = timeout(QUERY_AWAIT_PING_TIME, expected_ping_rx).await;
+
+            let (tx, mut rx) = channel(1);
+            let _guard = inflight_find_node_requests.add(record.id, tx);
+            let mut retry_delay = self
+                .config
+                .timeouts
+                .retry_delay(&mut self.config.rng.clone());
+            let mut seen = HashSet::new();
+            let mut received_neighbours = Vec::new();
+            loop {
+                egress_requests_tx
+                    .send((
+                        addr,
+                        record.id,
+                        EgressMessage::FindNode(FindNodeMessage {
+                            id: target,
+                            expire: expiry.expire_at(self.config.clock.unix_timestamp()),
+                        }),
+                    ))
+                    .await
+                    .map_err(|_| anyhow!("Sender shutdown"))?;
+
+                debug!("Awaiting neighbours");
+
+                // ...and await for Neighbours response, possibly split into several packets
+                let deadline = Instant::now() + neighbours_wait_timeout;
+                while let Ok(neighbours) = timeout_at(deadline, rx.recv()).await {
+                    let neighbours =
+                        neighbours.expect("we drop the sending channel, not the ingress router");
+                    received_neighbours.extend(
+                        neighbours
+                            .nodes
+                            .into_iter()
+                            .filter(|node| seen.insert(node.id)),
+                    );
+                }
+                if !received_neighbours.is_empty() {
+                    break;
+                }
+                match retry_delay.take() {
+                    Some(delay) => {
+                        trace!("No Neighbours, retrying FindNode in {:?}", delay);
+                        sleep(delay).await;
+                    }
+                    None => bail!("No neigbours received"),
+                }
+            }
+
+            debug!("Received neighbours");
+
+            Ok::<_, anyhow::Error>(received_neighbours)
+        })
+        .await;
+
+        if let hash_map::Entry::Occupied(mut entry) = expected_pings.lock().entry(addr) {
+            entry.get_mut().remove(&expected_ping_id);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+
+        match res {
+            Ok(Ok(v)) => {
+                return Some(v);
+            }
+            Ok(Err(e)) => {
+                debug!("Query error: {}", e);
+            }
+            Err(_) => {
+                debug!("Query timeout");
+                self.reputation.lock().record(
+                    record.id,
+                    ReputationEvent::Timeout,
+                    self.config.clock.now(),
+                );
+            }
+        }
+
+        None
+    }
+
     async fn lookup_inner(&self, target: NodeId) -> Vec<NodeRecord> {
+        self.lookup_point(target, self.config.hasher.hash(target.as_bytes()))
+            .await
+    }
+
+    /// Lookup of the nodes closest to `point` of the hashed keyspace, on behalf of `target`.
+    ///
+    /// FindNode only carries a node ID, whose hash the remote measures distances from. Unless
+    /// `point` is the hash of `target`, each round asks for the neighbours of the node closest
+    /// to `point` found so far instead, as the nearest to `point` an ID can get.
+    async fn lookup_point(&self, target: NodeId, point: H256) -> Vec<NodeRecord> {
         #[derive(Clone, Copy)]
         struct QueryNode {
             record: NodeRecord,
@@ -697,14 +3144,25 @@ impl Node {
             responded: bool,
         }
 
-        let node_endpoint = *self.node_endpoint.read();
-        let egress_requests_tx = self.egress_requests_tx.clone();
+        let NodeConfig {
+            lookup_concurrency,
+            lookup_result_count,
+            reputation_weighted_lookups,
+            ..
+        } = self.config;
 
-        // Get all nodes from local table sorted by distance
-        let mut nearest_nodes = self
-            .connected
-            .lock()
-            .nearest_node_entries(target)
+        let hasher = &*self.config.hasher;
+        let proxied = point != hasher.hash(target.as_bytes());
+        let distance = |id: NodeId| point ^ hasher.hash(id.as_bytes());
+
+        // Get all nodes from local table and bootstrap nodes sorted by distance
+        let mut nearest_nodes = self.connected.lock().nearest_to_hash(point);
+        nearest_nodes.extend(
+            self.bootstrap_nodes
+                .iter()
+                .map(|node| (distance(node.id), *node)),
+        );
+        let mut nearest_nodes = nearest_nodes
             .into_iter()
             .map(|(distance, record)| {
                 (
@@ -716,14 +3174,20 @@ impl Node {
                     },
                 )
             })
-            .take(ALPHA)
+            .take(lookup_result_count)
             .collect::<BTreeMap<_, _>>();
         let mut lookup_round = 0_usize;
         loop {
-            // For each node of ALPHA closest and not queried yet...
-            let picked_nodes = nearest_nodes
+            let query_target = match nearest_nodes.values().next() {
+                Some(closest) if proxied => closest.record.id,
+                _ => target,
+            };
+
+            // For each of the closest nodes not queried yet, skipping the ones that failed...
+            let mut picked_nodes = nearest_nodes
                 .iter_mut()
-                .take(ALPHA)
+                .filter(|(_, node)| !node.queried || node.responded)
+                .take(lookup_result_count)
                 .filter_map(|(distance, node)| {
                     if !node.queried {
                         Some((*distance, node))
@@ -732,145 +3196,60 @@ impl Node {
                     }
                 })
                 .collect::<Vec<_>>();
+            if reputation_weighted_lookups {
+                // ...preferring the ones of better reputation, and the closer ones among equals...
+                let reputation = self.reputation.lock();
+                let now = self.config.clock.now();
+                picked_nodes.sort_by(|(_, a), (_, b)| {
+                    reputation
+                        .score(b.record.id, now)
+                        .total_cmp(&reputation.score(a.record.id, now))
+                });
+            }
+            picked_nodes.truncate(lookup_concurrency);
 
             if picked_nodes.is_empty() {
                 break;
             }
 
-            debug!("Picked alpha ({ALPHA}) closest nodes that are not queried");
+            debug!("Picked {lookup_concurrency} closest nodes that are not queried");
 
             let fut = picked_nodes.into_iter().map(|(distance, node)| {
                 // ...send find node request...
                 node.queried = true;
-                let node = *node;
-                let egress_requests_tx = egress_requests_tx.clone();
-                let expected_pings = self.expected_pings.clone();
-                let inflight_find_node_requests = self.inflight_find_node_requests.clone();
-                let expected_ping_id = rand::random();
+                let record = node.record;
                 async move {
-                    let addr = SocketAddr::new(node.record.address.0, node.record.udp_port);
-
-                    let res = timeout(FIND_NODE_TIMEOUT, async {
-                        let (expected_ping_tx, expected_ping_rx) = oneshot();
-                        expected_pings
-                            .lock()
-                            .entry(addr)
-                            .or_default()
-                            .insert(expected_ping_id, expected_ping_tx);
-
-                        // Make sure our endpoint is proven.
-                        let (tx, rx) = oneshot();
-                        egress_requests_tx
-                            .send((
-                                addr,
-                                node.record.id,
-                                EgressMessage::Ping(
-                                    PingMessage {
-                                        from: node_endpoint,
-                                        to: node.record.into(),
-                                        expire: ping_expiry(),
-                                    },
-                                    Some(tx),
-                                ),
-                            ))
-                            .await
-                            .map_err(|_| anyhow!("Sender shutdown"))?;
-                        // ...and await for Pong response
-                        rx.await.map_err(|_| anyhow!("Pong timeout"))?;
-
-                        trace!("Our endpoint is proven");
-
-                        // In case the node wants to ping us, give it an opportunity to do so
-                        let _ = timeout(QUERY_AWAIT_PING_TIME, expected_ping_rx).await;
-
-                        let (tx, mut rx) = channel(1);
-                        let _guard = inflight_find_node_requests.add(node.record.id, tx);
-                        egress_requests_tx
-                            .send((
-                                addr,
-                                node.record.id,
-                                EgressMessage::FindNode(FindNodeMessage {
-                                    id: target,
-                                    expire: find_node_expiry(),
-                                }),
-                            ))
-                            .await
-                            .map_err(|_| anyhow!("Sender shutdown"))?;
-
-                        debug!("Awaiting neighbours");
-
-                        // ...and await for Neighbours response
-                        let mut received_neighbours = Vec::new();
-
-                        while let Ok(neighbours) =
-                            tokio::time::timeout(NEIGHBOURS_WAIT_TIMEOUT, rx.recv()).await
-                        {
-                            received_neighbours.push(
-                                neighbours
-                                    .expect("we drop the sending channel, not the ingress router"),
-                            );
-                        }
-                        if received_neighbours.is_empty() {
-                            bail!("No neigbours received");
-                        }
-
-                        debug!("Received neighbours");
-
-                        Ok::<_, anyhow::Error>(received_neighbours)
-                    })
-                    .await;
-
-                    if let hash_map::Entry::Occupied(mut entry) = expected_pings.lock().entry(addr)
-                    {
-                        entry.get_mut().remove(&expected_ping_id);
-                        if entry.get().is_empty() {
-                            entry.remove();
-                        }
-                    }
-
-                    match res {
-                        Ok(Ok(v)) => {
-                            return Some((distance, v));
-                        }
-                        Ok(Err(e)) => {
-                            debug!("Query error: {}", e);
-                        }
-                        Err(_) => {
-                            debug!("Query timeout");
-                        }
-                    }
-
-                    None
+                    self.query(record, query_target)
+                        .await
+                        .map(|records| (distance, records))
                 }
                 .instrument(span!(
                     Level::DEBUG,
                     "query",
                     "#{},node={}",
                     lookup_round,
-                    node.record.id
+                    record.id
                 ))
             });
 
-            for (d, messages) in join_all(fut).await.into_iter().flatten() {
+            for (d, records) in join_all(fut).await.into_iter().flatten() {
                 nearest_nodes
                     .get_mut(&d)
                     .expect("we just got this node from the nearest node set")
                     .responded = true;
-                for message in messages {
-                    // If we have a node...
-                    for record in message.nodes.into_iter() {
-                        // ...and it's not been seen yet...
-                        if let btree_map::Entry::Vacant(vacant) =
-                            nearest_nodes.entry(distance(target, record.id))
-                        {
-                            debug!("Adding unseen node to query: {:?}", record);
-                            // ...add to the set and continue the query
-                            vacant.insert(QueryNode {
-                                record,
-                                queried: false,
-                                responded: false,
-                            });
-                        }
+                // If we have a node...
+                for record in records {
+                    // ...and it's not been seen yet...
+                    if let btree_map::Entry::Vacant(vacant) =
+                        nearest_nodes.entry(distance(record.id))
+                    {
+                        debug!("Adding unseen node to query: {:?}", record);
+                        // ...add to the set and continue the query
+                        vacant.insert(QueryNode {
+                            record,
+                            queried: false,
+                            responded: false,
+                        });
                     }
                 }
             }
@@ -878,7 +3257,7 @@ impl Node {
             lookup_round += 1;
         }
 
-        nearest_nodes
+        let found = nearest_nodes
             .into_iter()
             .filter_map(|(_, node)| {
                 if node.responded {
@@ -887,10 +3266,1643 @@ impl Node {
                     None
                 }
             })
-            .collect()
+            .take(lookup_result_count)
+            .collect::<Vec<_>>();
+        self.events.emit(DiscoveryEvent::LookupCompleted {
+            target,
+            found: found.len(),
+        });
+        found
+    }
+
+    /// Our external address, IPv4 one if known for both families.
+    ///
+    /// This is the address advertised in outgoing Pings: either resolved by
+    /// [`NodeConfig::external_ip_resolver`], or agreed upon by enough nodes in their Pongs,
+    /// see [`NodeConfig::external_address_threshold`].
+    pub fn external_address(&self) -> Option<IpAddr> {
+        [
+            IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            Ipv6Addr::UNSPECIFIED.into(),
+        ]
+        .into_iter()
+        .filter(|family| self.node_endpoint.endpoint.read().supports(*family))
+        .find_map(|family| self.node_endpoint.external_address(family))
+    }
+
+    /// Our own record, as advertised in Pings: at the [external address](Node::external_address)
+    /// once known, at the bound or configured public address until then.
+    pub fn local_node_record(&self) -> NodeRecord {
+        self.node_endpoint.record(self.id)
+    }
+
+    /// Signed ENR of the [`Node::local_node_record`], e.g. to publish in a DNS tree.
+    ///
+    /// Every change of the endpoint re-signs it with the next sequence number. Sequence
+    /// numbers start at the Unix time the node was started at, or above
+    /// [`WarmStart::enr_seq`], so that they keep increasing across restarts even without a
+    /// snapshot, unless the endpoint changed more than once a second.
+    pub fn local_enr(&self) -> Enr {
+        let record = self.local_node_record();
+        let mut enr = self.local_enr.lock();
+        let current =
+            NodeRecord::from_enr(&enr).map(|current| (current.udp_addr(), current.tcp_port));
+        if current != Some((record.udp_addr(), record.tcp_port)) {
+            let seq = (enr.seq() + 1).max(self.config.clock.unix_timestamp());
+            debug!(
+                "Local endpoint changed to {:?}, ENR sequence {}",
+                record, seq
+            );
+            *enr = local_enr(record, &self.secret_key, seq);
+        }
+        enr.clone()
     }
 
     pub fn num_nodes(&self) -> usize {
         self.connected.lock().len()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "d860a01f9722d78051619d1e2351aba3f43f943f6f00718d1b9baa4101932a1f5011f16bb2b1bb35db20d6fe28fa0bf09636d26a87d31de9ec6203eeedb1f666";
+
+    #[test]
+    fn bootstrap_backoff_is_capped() {
+        let maintenance = MaintenanceConfig::default();
+        assert_eq!(maintenance.bootstrap_backoff(1), BOOTSTRAP_BACKOFF_INITIAL);
+        assert_eq!(
+            maintenance.bootstrap_backoff(2),
+            BOOTSTRAP_BACKOFF_INITIAL * 2
+        );
+        assert_eq!(
+            maintenance.bootstrap_backoff(3),
+            BOOTSTRAP_BACKOFF_INITIAL * 4
+        );
+        assert_eq!(maintenance.bootstrap_backoff(100), BOOTSTRAP_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn find_node_answers_closest_proven_nodes() {
+        let mut table = Table::new(NodeId::random());
+        let mut endpoint_proofs = EndpointProofs::default();
+        let now = unix_timestamp();
+        let records = (0..200)
+            .map(|i| NodeRecord {
+                address: Ip(Ipv4Addr::new(10, 0, 0, i).into()),
+                tcp_port: DEFAULT_PORT,
+                udp_port: DEFAULT_PORT,
+                id: NodeId::random(),
+            })
+            .filter(|record| table.add_verified(*record))
+            .collect::<Vec<_>>();
+        for record in records.iter().step_by(2) {
+            endpoint_proofs.record_pong(record.id, H256::random(), now);
+        }
+        let requester = records[0].id;
+        let target = NodeId::random();
+
+        let mut expected = records
+            .iter()
+            .filter(|record| record.id != requester)
+            .filter(|record| endpoint_proofs.has_valid_proof(&record.id, now))
+            .copied()
+            .collect::<Vec<_>>();
+        expected.sort_by_key(|record| distance(record.id, target));
+        expected.truncate(MAX_NEIGHBOURS);
+
+        let candidates = find_node_candidates(&table, &endpoint_proofs, requester, target, now);
+        assert_eq!(
+            candidates[..MAX_NEIGHBOURS]
+                .iter()
+                .map(|record| record.id)
+                .collect::<Vec<_>>(),
+            expected.iter().map(|record| record.id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn find_node_policy_denials() {
+        let default = FindNodePolicy::default();
+        assert_eq!(default.denial(true, 256), None);
+        assert_eq!(default.denial(false, 256), Some("unproofed"));
+
+        let open = FindNodePolicy {
+            require_proof: false,
+            max_target_distance: Some(250),
+            ..default
+        };
+        assert_eq!(open.denial(false, 250), None);
+        assert_eq!(open.denial(false, 251), Some("target too far"));
+    }
+
+    #[test]
+    fn maintenance_intervals_are_validated() {
+        assert!(MaintenanceConfig::default().validate().is_ok());
+        assert!(matches!(
+            MaintenanceConfig::default()
+                .with_refresh_interval(Duration::ZERO)
+                .validate(),
+            Err(MaintenanceConfigError::TooShort {
+                name: "refresh interval",
+                ..
+            })
+        ));
+        assert!(matches!(
+            MaintenanceConfig::default()
+                .with_bootstrap_backoff(Duration::from_secs(10), Duration::from_secs(1))
+                .validate(),
+            Err(MaintenanceConfigError::BackoffRange { .. })
+        ));
+
+        // Aggressive, but still valid.
+        let maintenance = MaintenanceConfig::default()
+            .with_refresh_interval(Duration::from_secs(1))
+            .with_ping_interval(MaintenanceConfig::MIN_INTERVAL);
+        assert!(maintenance.validate().is_ok());
+        assert_eq!(
+            maintenance.bootstrap_backoff(1),
+            maintenance.bootstrap_backoff_initial
+        );
+    }
+
+    #[test]
+    fn kademlia_parameters_are_validated() {
+        assert!(NodeConfig::default().validate().is_ok());
+        let config = |bucket_size, lookup_concurrency| NodeConfig {
+            bucket_size,
+            lookup_concurrency,
+            ..Default::default()
+        };
+        assert!(config(8, 8).validate().is_ok());
+        assert!(config(1, 1).validate().is_ok());
+        assert!(matches!(
+            config(0, 1).validate(),
+            Err(NodeConfigError::ZeroBucketSize)
+        ));
+        assert!(matches!(
+            config(8, 9).validate(),
+            Err(NodeConfigError::LookupConcurrency {
+                concurrency: 9,
+                bucket_size: 8
+            })
+        ));
+        assert!(matches!(
+            config(8, 0).validate(),
+            Err(NodeConfigError::LookupConcurrency { .. })
+        ));
+        assert!(matches!(
+            NodeConfig {
+                max_outstanding_queries: 0,
+                ..Default::default()
+            }
+            .validate(),
+            Err(NodeConfigError::ZeroOutstandingQueries)
+        ));
+        assert!(matches!(
+            NodeConfig {
+                maintenance: MaintenanceConfig::default().with_ping_interval(Duration::ZERO),
+                ..Default::default()
+            }
+            .validate(),
+            Err(NodeConfigError::Maintenance(_))
+        ));
+    }
+
+    #[test]
+    fn expiry_policy_is_clamped() {
+        assert_eq!(
+            ExpiryPolicy::new(Duration::ZERO).lifetime(),
+            ExpiryPolicy::MIN_LIFETIME
+        );
+        assert_eq!(
+            ExpiryPolicy::new(Duration::MAX).lifetime(),
+            ExpiryPolicy::MAX_LIFETIME
+        );
+
+        let expiry = ExpiryPolicy::new(Duration::from_secs(5));
+        assert_eq!(expiry.expire_at(100), 105);
+        assert_eq!(expiry.expire_at(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn enode_url_roundtrip() {
+        for url in [
+            format!("enode://{ID}@18.138.108.67:30303"),
+            format!("enode://{ID}@18.138.108.67:30303?discport=30301"),
+            format!("enode://{ID}@[::1]:30303"),
+        ] {
+            let record = NodeRecord::from_enode_url(&url).unwrap();
+            assert_eq!(record.to_enode_url(), url);
+        }
+
+        let record: NodeRecord = format!("enode://{ID}@18.138.108.67:30303?discport=30301")
+            .parse()
+            .unwrap();
+        assert_eq!(record.tcp_port, 30303);
+        assert_eq!(record.udp_port, 30301);
+        assert_eq!(record.id, ID.parse::<NodeId>().unwrap());
+    }
+
+    #[test]
+    fn discovery_only_enode_url() {
+        let url = format!("enode://{ID}@18.138.108.67:0?discport=30301");
+        let record = NodeRecord::from_enode_url(&url).unwrap();
+        assert_eq!(record.udp_addr(), "18.138.108.67:30301".parse().unwrap());
+        assert_eq!(record.tcp_addr(), "18.138.108.67:0".parse().unwrap());
+        assert!(!record.is_dialable());
+        assert_eq!(record.to_enode_url(), url);
+
+        let record =
+            NodeRecord::from_enode_url(&format!("enode://{ID}@18.138.108.67:30303")).unwrap();
+        assert_eq!(record.udp_addr(), record.tcp_addr());
+        assert!(record.is_dialable());
+    }
+
+    #[test]
+    fn enr_conversion() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let id = pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key));
+
+        for address in [
+            IpAddr::from([10, 0, 0, 1]),
+            IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]),
+        ] {
+            let record = NodeRecord {
+                address: Ip(address),
+                tcp_port: 30303,
+                udp_port: 30301,
+                id,
+            };
+
+            let enr = record.to_enr(&secret_key).unwrap();
+            assert_eq!(enr.seq(), 1);
+            assert_eq!(address.is_ipv6(), enr.ip6().is_some());
+
+            let decoded = NodeRecord::from_enr(&enr).unwrap();
+            assert_eq!(decoded.address, record.address);
+            assert_eq!(decoded.tcp_port, record.tcp_port);
+            assert_eq!(decoded.udp_port, record.udp_port);
+            assert_eq!(decoded.id, record.id);
+        }
+
+        let record = NodeRecord {
+            address: Ip(IpAddr::from([10, 0, 0, 1])),
+            tcp_port: 30303,
+            udp_port: 30303,
+            id: NodeId::random(),
+        };
+        assert!(record.to_enr(&secret_key).is_err());
+    }
+
+    #[test]
+    fn enr_without_endpoint() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+
+        let enr = enr::EnrBuilder::new("v4")
+            .ip4([10, 0, 0, 1].into())
+            .tcp4(30303)
+            .build(&secret_key)
+            .unwrap();
+        assert!(NodeRecord::from_enr(&enr).is_none());
+
+        let enr = enr::EnrBuilder::new("v4")
+            .ip4([10, 0, 0, 1].into())
+            .udp4(30301)
+            .build(&secret_key)
+            .unwrap();
+        let record = NodeRecord::from_enr(&enr).unwrap();
+        assert_eq!(record.udp_port, 30301);
+        assert_eq!(record.tcp_port, 0);
+        assert!(!record.is_dialable());
+    }
+
+    async fn start_node(addr: SocketAddr, config: NodeConfig) -> Arc<Node> {
+        Node::builder(SecretKey::new(&mut secp256k1::rand::thread_rng()))
+            .with_addr(addr)
+            .with_config(config)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    /// Address of the `i`-th node on a [`MemoryNetwork`].
+    fn memory_addr(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i + 1], DEFAULT_PORT))
+    }
+
+    async fn start_memory_node(
+        network: &MemoryNetwork,
+        addr: SocketAddr,
+        bootstrap_nodes: Vec<NodeRecord>,
+        config: NodeConfig,
+    ) -> Arc<Node> {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        start_memory_node_with_key(network, addr, secret_key, bootstrap_nodes, config).await
+    }
+
+    async fn start_memory_node_with_key(
+        network: &MemoryNetwork,
+        addr: SocketAddr,
+        secret_key: SecretKey,
+        bootstrap_nodes: Vec<NodeRecord>,
+        config: NodeConfig,
+    ) -> Arc<Node> {
+        Node::builder(secret_key)
+            .with_bootstrap_nodes(bootstrap_nodes)
+            .with_tcp_port(addr.port())
+            .with_config(config)
+            .build_with_transport(network.bind(addr).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn builder_validates_before_binding() {
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let err = Node::builder(SecretKey::new(&mut secp256k1::rand::thread_rng()))
+            .with_addr(addr)
+            .with_maintenance(MaintenanceConfig::default().with_ping_interval(Duration::ZERO))
+            .build()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NodeConfigError>(),
+            Some(NodeConfigError::Maintenance(
+                MaintenanceConfigError::TooShort { .. }
+            ))
+        ));
+
+        let err = Node::builder(SecretKey::new(&mut secp256k1::rand::thread_rng()))
+            .with_upnp(true)
+            .build_with_transport(MemoryNetwork::default().bind(addr).unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("UPnP"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_socket() {
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // Rebinding the same port fails unless the previous node released it.
+        for _ in 0..3 {
+            let node = start_node(addr, NodeConfig::default()).await;
+            timeout(Duration::from_secs(5), node.shutdown())
+                .await
+                .unwrap();
+            // Repeated shutdown returns right away.
+            timeout(Duration::from_secs(5), node.shutdown())
+                .await
+                .unwrap();
+        }
+
+        let token = CancellationToken::new();
+        let node = start_node(
+            addr,
+            NodeConfig {
+                cancellation_token: Some(token.clone()),
+                ..Default::default()
+            },
+        )
+        .await;
+        token.cancel();
+        timeout(Duration::from_secs(5), node.shutdown())
+            .await
+            .unwrap();
+
+        // Dropping without shutdown aborts the tasks instead.
+        drop(start_node(addr, NodeConfig::default()).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn memory_network_converges() {
+        const NODES: u8 = 50;
+
+        let network = MemoryNetwork::default()
+            .with_latency(Duration::from_millis(20))
+            .with_drop_rate(0.05);
+
+        let mut nodes = Vec::new();
+        let mut bootstrap_nodes = Vec::new();
+        for i in 0..NODES {
+            let node = start_memory_node(
+                &network,
+                memory_addr(i),
+                bootstrap_nodes.clone(),
+                NodeConfig::default(),
+            )
+            .await;
+            if bootstrap_nodes.is_empty() {
+                bootstrap_nodes.push(node.local_node_record());
+            }
+            nodes.push(node);
+        }
+
+        sleep(REFRESH_TIMEOUT * 2).await;
+
+        let mut known = HashSet::new();
+        for node in &nodes {
+            assert_eq!(node.bootstrap_state(), BootstrapState::Ready);
+            let table = node.table();
+            assert!(!table.is_empty());
+            known.extend(
+                table
+                    .closest(node.id, usize::MAX)
+                    .into_iter()
+                    .map(|record| record.id),
+            );
+        }
+        for node in &nodes {
+            assert!(known.contains(&node.id));
+        }
+
+        for node in nodes {
+            node.shutdown().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn emits_events() {
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+
+        let bootstrap =
+            start_memory_node(&network, memory_addr(0), vec![], NodeConfig::default()).await;
+        let mut bootstrap_events = bootstrap.subscribe_events(1024).into_inner();
+        let node = start_memory_node(
+            &network,
+            memory_addr(1),
+            vec![bootstrap.local_node_record()],
+            NodeConfig::default(),
+        )
+        .await;
+        let mut events = node.subscribe_events(1024).into_inner();
+
+        node.lookup_self().await;
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert!(received.iter().any(|event| matches!(
+            event,
+            DiscoveryEvent::PacketReceived { node_id, packet_type, .. }
+                if *node_id == bootstrap.id && *packet_type == MessageId::Pong as u8
+        )));
+        assert!(received.iter().any(|event| matches!(
+            event,
+            DiscoveryEvent::EndpointProven { node_id, addr: from }
+                if *node_id == bootstrap.id && *from == memory_addr(0)
+        )));
+        assert!(received.iter().any(|event| matches!(
+            event,
+            DiscoveryEvent::LookupCompleted { target, .. } if *target == node.id
+        )));
+
+        let mut added = false;
+        while let Ok(event) = bootstrap_events.try_recv() {
+            added |= matches!(event, DiscoveryEvent::NodeAdded(record) if record.id == node.id);
+        }
+        assert!(added);
+        assert_eq!(node.dropped_events(), 0);
+
+        node.shutdown().await;
+        bootstrap.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn moved_node_is_verified() {
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+        let endpoint = |addr: SocketAddr| Endpoint {
+            address: Ip(addr.ip()),
+            udp_port: addr.port(),
+            tcp_port: addr.port(),
+        };
+
+        let bootstrap =
+            start_memory_node(&network, memory_addr(0), vec![], NodeConfig::default()).await;
+        let bootstrap_nodes = vec![bootstrap.local_node_record()];
+        let stored_addr = |id| {
+            bootstrap
+                .table_entries()
+                .into_iter()
+                .find(|entry| entry.record.id == id)
+                .map(|entry| entry.record.udp_addr())
+        };
+
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let node = start_memory_node_with_key(
+            &network,
+            memory_addr(1),
+            secret_key,
+            bootstrap_nodes.clone(),
+            NodeConfig::default(),
+        )
+        .await;
+        let id = node.id;
+        sleep(PING_TIMEOUT).await;
+        assert_eq!(stored_addr(id), Some(memory_addr(1)));
+
+        // Replayed Ping from another address does not move the node.
+        let spoofer = network.bind(memory_addr(2)).unwrap();
+        let ping = crate::disc::v4::testutil::build_ping(
+            &secret_key,
+            endpoint(memory_addr(2)),
+            endpoint(memory_addr(0)),
+            unix_timestamp() + 20,
+        );
+        spoofer.send_to(&ping, memory_addr(0)).await.unwrap();
+        sleep(PING_TIMEOUT * 2).await;
+        assert_eq!(stored_addr(id), Some(memory_addr(1)));
+
+        node.shutdown().await;
+        let mut events = bootstrap.subscribe_events(1024).into_inner();
+        let moved = start_memory_node_with_key(
+            &network,
+            memory_addr(3),
+            secret_key,
+            bootstrap_nodes,
+            NodeConfig::default(),
+        )
+        .await;
+        sleep(PING_TIMEOUT).await;
+        assert_eq!(stored_addr(id), Some(memory_addr(3)));
+
+        let mut changed = false;
+        while let Ok(event) = events.try_recv() {
+            changed |= matches!(
+                event,
+                DiscoveryEvent::AddressChanged { node_id, old, new }
+                    if node_id == id && old.udp_addr() == memory_addr(1) && new.udp_addr() == memory_addr(3)
+            );
+        }
+        assert!(changed);
+
+        moved.shutdown().await;
+        bootstrap.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn warm_start_keeps_answering_nodes() {
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+
+        let mut alive = Vec::new();
+        for i in 1..=2 {
+            alive.push(
+                start_memory_node(&network, memory_addr(i), vec![], NodeConfig::default()).await,
+            );
+        }
+        let mut table = Table::new(NodeId::random());
+        for node in &alive {
+            table.add_verified(node.local_node_record());
+        }
+        // Nothing listens there anymore.
+        table.add_verified(NodeRecord {
+            address: Ip(memory_addr(3).ip()),
+            id: NodeId::random(),
+            ..alive[0].local_node_record()
+        });
+
+        let node = start_memory_node(
+            &network,
+            memory_addr(0),
+            vec![],
+            NodeConfig {
+                warm_start: Some(WarmStart {
+                    snapshot: table.serialize(),
+                    max_age: Duration::from_secs(3600),
+                    enr_seq: 0,
+                }),
+                restore_concurrency: 2,
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut events = node.subscribe_events(16);
+
+        let restored = loop {
+            if let DiscoveryEvent::TableRestored { restored, total } = events.next().await.unwrap()
+            {
+                break (restored, total);
+            }
+        };
+        assert_eq!(restored, (2, 3));
+        let mut ids = node
+            .table_entries()
+            .iter()
+            .map(|entry| entry.record.id)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        let mut expected = alive.iter().map(|node| node.id).collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(ids, expected);
+
+        node.shutdown().await;
+        for node in alive {
+            node.shutdown().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn local_enr_follows_external_address() {
+        #[derive(Default)]
+        struct External(Mutex<Option<IpAddr>>);
+
+        impl ExternalIpResolver for External {
+            fn resolve(&self) -> Option<IpAddr> {
+                *self.0.lock()
+            }
+        }
+
+        let network = MemoryNetwork::default();
+        let addr = SocketAddr::from(([10, 0, 0, 1], DEFAULT_PORT));
+        let external = Arc::new(External::default());
+        let node = start_memory_node(
+            &network,
+            addr,
+            vec![],
+            NodeConfig {
+                clock: Arc::new(MockClock::starting_at(1_000_000)),
+                external_ip_resolver: Some(external.clone()),
+                // Signed before the restart with a sequence number above the clock.
+                warm_start: Some(WarmStart {
+                    snapshot: Table::new(NodeId::random()).serialize(),
+                    max_age: Duration::from_secs(3600),
+                    enr_seq: 2_000_000,
+                }),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let record = node.local_node_record();
+        assert_eq!(record.id, node.id);
+        assert_eq!(record.udp_addr(), addr);
+        let enr = node.local_enr();
+        assert!(enr.verify());
+        assert_eq!(enr.seq(), 2_000_001);
+        assert_eq!(enr.ip4(), Some([10, 0, 0, 1].into()));
+        assert_eq!(enr.udp4(), Some(DEFAULT_PORT));
+        // Unchanged endpoint, unchanged record.
+        assert_eq!(node.local_enr().seq(), 2_000_001);
+
+        *external.0.lock() = Some([1, 2, 3, 4].into());
+        assert_eq!(node.local_node_record().address, Ip([1, 2, 3, 4].into()));
+        let enr = node.local_enr();
+        assert!(enr.verify());
+        assert_eq!(enr.seq(), 2_000_002);
+        assert_eq!(enr.ip4(), Some([1, 2, 3, 4].into()));
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn endpoint_proof_expires_on_clock() {
+        let network = MemoryNetwork::default();
+        let clock = MockClock::new();
+        let config = || NodeConfig {
+            clock: Arc::new(clock.clone()),
+            maintenance: MaintenanceConfig::default()
+                .with_ping_interval(Duration::from_secs(3600))
+                .with_refresh_interval(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+
+        let bootstrap = start_memory_node(&network, memory_addr(0), vec![], config()).await;
+        let node = start_memory_node(
+            &network,
+            memory_addr(1),
+            vec![bootstrap.local_node_record()],
+            config(),
+        )
+        .await;
+
+        let proven = || {
+            node.table_entries()
+                .iter()
+                .any(|entry| entry.record.id == bootstrap.id && entry.endpoint_proven)
+        };
+        timeout(Duration::from_secs(5), async {
+            while !proven() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        clock.advance(Duration::from_secs(13 * 60 * 60));
+        assert!(!proven());
+        // Still in the table, only its proof is gone.
+        assert_eq!(node.num_nodes(), 1);
+
+        node.shutdown().await;
+        bootstrap.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_nodes_are_evicted_after_reping() {
+        let network = MemoryNetwork::default();
+        let clock = MockClock::new();
+        let config = || NodeConfig {
+            clock: Arc::new(clock.clone()),
+            maintenance: MaintenanceConfig::default()
+                .with_ping_interval(Duration::from_secs(1))
+                .with_liveness_ttl(Duration::from_secs(60))
+                .with_refresh_interval(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+
+        let bootstrap = start_memory_node(&network, memory_addr(0), vec![], config()).await;
+        let node = start_memory_node(
+            &network,
+            memory_addr(1),
+            vec![bootstrap.local_node_record()],
+            config(),
+        )
+        .await;
+
+        timeout(Duration::from_secs(5), async {
+            while node.num_nodes() == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Stale, but still answering.
+        clock.advance(Duration::from_secs(61));
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!(node.num_nodes(), 1);
+
+        bootstrap.shutdown().await;
+        clock.advance(Duration::from_secs(61));
+        timeout(Duration::from_secs(30), async {
+            while node.num_nodes() != 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ping_leaves_table_alone() {
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+
+        let bootstrap = start_memory_node(
+            &network,
+            memory_addr(0),
+            vec![],
+            NodeConfig {
+                // No maintenance Pings or lookups racing with ours.
+                maintenance: MaintenanceConfig::default()
+                    .with_ping_interval(Duration::from_secs(3600))
+                    .with_refresh_interval(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let node = start_memory_node(
+            &network,
+            memory_addr(1),
+            vec![bootstrap.local_node_record()],
+            NodeConfig::default(),
+        )
+        .await;
+        sleep(PING_TIMEOUT).await;
+        let record = node.local_node_record();
+        assert_eq!(bootstrap.num_nodes(), 1);
+
+        let rtt = bootstrap.ping(&record).await.unwrap();
+        assert!(rtt >= Duration::from_millis(40));
+
+        node.shutdown().await;
+        assert_eq!(bootstrap.ping(&record).await, Err(PingError::Timeout));
+        sleep(PING_TIMEOUT).await;
+        assert_eq!(bootstrap.num_nodes(), 1);
+
+        bootstrap.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn own_records_are_filtered() {
+        use crate::disc::v4::testutil::{self, build_neighbours, build_pong, secret_key};
+
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+        let record = |id, addr: SocketAddr| NodeRecord {
+            address: Ip(addr.ip()),
+            tcp_port: addr.port(),
+            udp_port: addr.port(),
+            id,
+        };
+
+        // Bootstrap node answering Pings and FindNodes, with our own records among others.
+        let fake_key = secret_key(1);
+        let fake = network.bind(memory_addr(0)).unwrap();
+        let node_key = secret_key(2);
+        let node_id = testutil::node_id(&node_key);
+        let other = record(NodeId::random(), memory_addr(2));
+        let nodes = vec![
+            record(node_id, memory_addr(1)),
+            record(node_id, memory_addr(3)),
+            record(NodeId::random(), memory_addr(1)),
+            other,
+        ];
+        tokio::spawn(async move {
+            let mut buf = [0; MAX_PACKET_SIZE];
+            loop {
+                let (len, from) = fake.recv_from(&mut buf).await.unwrap();
+                let packet = decode_packet(&buf[..len]).unwrap();
+                let expire = unix_timestamp() + 20;
+                let reply = match packet.message().unwrap() {
+                    Message::Ping(ping) => build_pong(&fake_key, packet.hash, ping.from, expire),
+                    Message::FindNode(_) => build_neighbours(&fake_key, nodes.clone(), expire),
+                    _ => continue,
+                };
+                fake.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        let node = start_memory_node_with_key(
+            &network,
+            memory_addr(1),
+            node_key,
+            vec![record(testutil::node_id(&fake_key), memory_addr(0))],
+            NodeConfig::default(),
+        )
+        .await;
+        let mut events = node.subscribe_events(1024).into_inner();
+        sleep(PING_TIMEOUT).await;
+
+        let mut added = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let DiscoveryEvent::NodeAdded(record) = event {
+                added.push(record.id);
+            }
+        }
+        assert_eq!(added, [other.id]);
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn neighbours_without_endpoint_are_dropped() {
+        use crate::disc::v4::testutil::{self, build_neighbours, build_pong, secret_key};
+
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+        let record = |addr: SocketAddr, tcp_port, udp_port| NodeRecord {
+            address: Ip(addr.ip()),
+            tcp_port,
+            udp_port,
+            id: NodeId::random(),
+        };
+
+        let fake_key = secret_key(1);
+        let fake = network.bind(memory_addr(0)).unwrap();
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT));
+        let mapped_unspecified =
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(), DEFAULT_PORT));
+        // Discovery-only nodes are kept.
+        let discovery_only = record(memory_addr(2), 0, DEFAULT_PORT);
+        let nodes = vec![
+            record(unspecified, DEFAULT_PORT, DEFAULT_PORT),
+            record(mapped_unspecified, DEFAULT_PORT, DEFAULT_PORT),
+            record(memory_addr(3), DEFAULT_PORT, 0),
+            discovery_only,
+        ];
+        tokio::spawn(async move {
+            let mut buf = [0; MAX_PACKET_SIZE];
+            loop {
+                let (len, from) = fake.recv_from(&mut buf).await.unwrap();
+                let packet = decode_packet(&buf[..len]).unwrap();
+                let expire = unix_timestamp() + 20;
+                let reply = match packet.message().unwrap() {
+                    Message::Ping(ping) => build_pong(&fake_key, packet.hash, ping.from, expire),
+                    Message::FindNode(_) => build_neighbours(&fake_key, nodes.clone(), expire),
+                    _ => continue,
+                };
+                fake.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        let node = start_memory_node_with_key(
+            &network,
+            memory_addr(1),
+            secret_key(2),
+            vec![NodeRecord {
+                address: Ip(memory_addr(0).ip()),
+                tcp_port: DEFAULT_PORT,
+                udp_port: DEFAULT_PORT,
+                id: testutil::node_id(&fake_key),
+            }],
+            NodeConfig::default(),
+        )
+        .await;
+        let mut events = node.subscribe_events(1024).into_inner();
+        sleep(PING_TIMEOUT).await;
+
+        let mut added = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let DiscoveryEvent::NodeAdded(record) = event {
+                added.push(record.id);
+            }
+        }
+        assert_eq!(added, [discovery_only.id]);
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ping_from_unknown_node_is_bonded() {
+        use crate::disc::v4::testutil::{build_ping, golden::endpoint, secret_key};
+
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+        let (peer_addr, node_addr) = (endpoint(1), endpoint(2));
+        let node = start_memory_node_with_key(
+            &network,
+            node_addr.udp_addr(),
+            secret_key(2),
+            vec![],
+            NodeConfig {
+                // No maintenance Pings besides the ones answering Pings.
+                maintenance: MaintenanceConfig::default()
+                    .with_ping_interval(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let peer_key = secret_key(1);
+        let peer = network.bind(peer_addr.udp_addr()).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let ping = build_ping(&peer_key, peer_addr, node_addr, unix_timestamp() + 20);
+            peer.send_to(&ping, node_addr.udp_addr()).await.unwrap();
+
+            let mut buf = [0; MAX_PACKET_SIZE];
+            let mut types = Vec::new();
+            while let Ok(Ok((len, _))) =
+                timeout(Duration::from_secs(1), peer.recv_from(&mut buf)).await
+            {
+                types.push(decode_packet(&buf[..len]).unwrap().packet_type);
+            }
+            types.sort_unstable();
+            received.push(types);
+        }
+        // Pong and our own Ping to the first Ping, only a Pong while our Ping is unanswered.
+        assert_eq!(
+            received,
+            [
+                vec![MessageId::Ping as u8, MessageId::Pong as u8],
+                vec![MessageId::Pong as u8]
+            ]
+        );
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered_at_source_address() {
+        use crate::disc::v4::testutil::{build_ping, golden::endpoint, node_id, secret_key};
+
+        let network = MemoryNetwork::default();
+        let (peer_addr, node_addr) = (endpoint(1), endpoint(2));
+        let node = start_memory_node_with_key(
+            &network,
+            node_addr.udp_addr(),
+            secret_key(2),
+            vec![],
+            NodeConfig {
+                maintenance: MaintenanceConfig::default()
+                    .with_ping_interval(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut events = node.subscribe_events(16);
+
+        // Private address and ports the peer believes it has behind a NAT.
+        let reported = Endpoint {
+            address: Ip(Ipv4Addr::new(192, 168, 1, 2).into()),
+            udp_port: 40404,
+            tcp_port: 40405,
+        };
+        let peer = network.bind(peer_addr.udp_addr()).unwrap();
+        let ping = build_ping(&secret_key(1), reported, node_addr, unix_timestamp() + 20);
+        peer.send_to(&ping, node_addr.udp_addr()).await.unwrap();
+
+        let observed = Endpoint {
+            tcp_port: reported.tcp_port,
+            ..peer_addr
+        };
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut answered = 0;
+        while let Ok(Ok((len, _))) = timeout(Duration::from_secs(1), peer.recv_from(&mut buf)).await
+        {
+            match decode_packet(&buf[..len]).unwrap().message().unwrap() {
+                Message::Pong(pong) => assert_eq!(pong.to, observed),
+                Message::Ping(ping) => assert_eq!(ping.to, observed),
+                other => panic!("unexpected message {other:?}"),
+            }
+            answered += 1;
+        }
+        assert_eq!(answered, 2);
+
+        let mismatch = loop {
+            match events.next().await.unwrap() {
+                DiscoveryEvent::ReportedAddressMismatch {
+                    node_id,
+                    reported,
+                    source,
+                } => break (node_id, reported, source),
+                _ => continue,
+            }
+        };
+        assert_eq!(
+            mismatch,
+            (node_id(&secret_key(1)), reported, peer_addr.udp_addr())
+        );
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn spoofed_pings_are_rate_limited() {
+        use crate::disc::v4::testutil::{build_ping, golden::endpoint, secret_key};
+
+        let network = MemoryNetwork::default();
+        let (spoofed_addr, node_addr) = (endpoint(1), endpoint(2));
+        let burst = 3;
+        let node = start_memory_node_with_key(
+            &network,
+            node_addr.udp_addr(),
+            secret_key(2),
+            vec![],
+            NodeConfig {
+                maintenance: MaintenanceConfig::default()
+                    .with_ping_interval(Duration::from_secs(3600)),
+                response_rate_limit: Some(RateLimit {
+                    per_second: 1,
+                    burst,
+                }),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // The victim at the spoofed address never answers the Pings of the node, yet the
+        // first spoofed Ping puts it into the table.
+        let victim = network.bind(spoofed_addr.udp_addr()).unwrap();
+        let attacker_key = secret_key(1);
+        for _ in 0..10 {
+            let ping = build_ping(
+                &attacker_key,
+                spoofed_addr,
+                node_addr,
+                unix_timestamp() + 20,
+            );
+            victim.send_to(&ping, node_addr.udp_addr()).await.unwrap();
+        }
+
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut pongs = 0;
+        while let Ok(Ok((len, _))) =
+            timeout(Duration::from_millis(500), victim.recv_from(&mut buf)).await
+        {
+            if decode_packet(&buf[..len]).unwrap().packet_type == MessageId::Pong as u8 {
+                pongs += 1;
+            }
+        }
+        assert_eq!(pongs, burst);
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn ping_from_mapped_address_is_recorded_as_ipv4() {
+        use crate::disc::v4::testutil::{build_ping, golden::endpoint, node_id, secret_key};
+
+        let network = MemoryNetwork::default();
+        let (peer_addr, node_addr) = (endpoint(1), endpoint(2));
+        let node = start_memory_node_with_key(
+            &network,
+            node_addr.udp_addr(),
+            secret_key(2),
+            vec![],
+            NodeConfig {
+                maintenance: MaintenanceConfig::default()
+                    .with_ping_interval(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut events = node.subscribe_events(16);
+
+        // As a dual-stack socket sees an IPv4 sender.
+        let mapped = match peer_addr.address.0 {
+            IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), peer_addr.udp_port),
+            IpAddr::V6(_) => unreachable!(),
+        };
+        let peer = network.bind(mapped).unwrap();
+        let ping = build_ping(&secret_key(1), peer_addr, node_addr, unix_timestamp() + 20);
+        peer.send_to(&ping, node_addr.udp_addr()).await.unwrap();
+
+        let added = loop {
+            match events.next().await.unwrap() {
+                DiscoveryEvent::NodeAdded(record) => break record,
+                _ => continue,
+            }
+        };
+        assert_eq!(added.id, node_id(&secret_key(1)));
+        assert_eq!(added.udp_addr(), peer_addr.udp_addr());
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_ping_is_retried() {
+        use crate::disc::v4::testutil::{build_pong, golden::endpoint, node_id, secret_key};
+
+        let network = MemoryNetwork::default();
+        let (peer_addr, node_addr) = (endpoint(1), endpoint(2));
+        // Standing still, so that the retry has to move its expiration on its own.
+        let clock = MockClock::new();
+        let node = start_memory_node_with_key(
+            &network,
+            node_addr.udp_addr(),
+            secret_key(2),
+            vec![],
+            NodeConfig {
+                clock: Arc::new(clock.clone()),
+                timeouts: RequestTimeouts {
+                    retry_backoff: Some(Duration::from_secs(1)),
+                    ..Default::default()
+                },
+                maintenance: MaintenanceConfig::default()
+                    .with_ping_interval(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let peer = network.bind(peer_addr.udp_addr()).unwrap();
+        let record = NodeRecord {
+            address: peer_addr.address,
+            tcp_port: peer_addr.tcp_port,
+            udp_port: peer_addr.udp_port,
+            id: node_id(&secret_key(1)),
+        };
+
+        let ping = tokio::spawn({
+            let node = node.clone();
+            async move { node.ping(&record).await }
+        });
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut pings = Vec::new();
+        while pings.len() < 2 {
+            let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+            let packet = decode_packet(&buf[..len]).unwrap();
+            if let Message::Ping(message) = packet.message().unwrap() {
+                pings.push((packet.hash, message.expire));
+            }
+        }
+        assert_ne!(pings[0].0, pings[1].0);
+        assert!(pings[1].1 > pings[0].1);
+
+        let pong = build_pong(
+            &secret_key(1),
+            pings[1].0,
+            node_addr,
+            clock.unix_timestamp() + 20,
+        );
+        peer.send_to(&pong, node_addr.udp_addr()).await.unwrap();
+        assert!(ping.await.unwrap().is_ok());
+
+        node.shutdown().await;
+    }
+
+    /// Transport that fails to send anything.
+    struct Unreachable(MemoryTransport);
+
+    #[async_trait::async_trait]
+    impl Transport for Unreachable {
+        async fn send_to(&self, _: &[u8], _: SocketAddr) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf).await
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unsent_ping_fails_right_away() {
+        use crate::disc::v4::testutil::{golden::endpoint, node_id, secret_key};
+
+        let network = MemoryNetwork::default();
+        let node_addr = endpoint(2);
+        let node = Node::with_transport(
+            Unreachable(network.bind(node_addr.udp_addr()).unwrap()),
+            secret_key(2),
+            vec![],
+            None,
+            node_addr.tcp_port,
+            NodeConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        let peer = endpoint(1);
+        let record = NodeRecord {
+            address: peer.address,
+            tcp_port: peer.tcp_port,
+            udp_port: peer.udp_port,
+            id: node_id(&secret_key(1)),
+        };
+        let started = Instant::now();
+        assert!(matches!(node.ping(&record).await, Err(PingError::Timeout)));
+        // Not waiting for the Pong to a Ping that never left.
+        assert!(started.elapsed() < PING_TIMEOUT);
+
+        node.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pong_expiry_is_fresh() {
+        use crate::disc::v4::testutil::{build_ping, golden::endpoint, secret_key};
+
+        let network = MemoryNetwork::default();
+        let (peer_addr, node_addr) = (endpoint(1), endpoint(2));
+        let clock = MockClock::starting_at(1_000_000);
+        let node = start_memory_node_with_key(
+            &network,
+            node_addr.udp_addr(),
+            secret_key(2),
+            vec![],
+            NodeConfig {
+                clock: Arc::new(clock.clone()),
+                maintenance: MaintenanceConfig::default()
+                    .with_ping_interval(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        )
+        .await;
+        let peer = network.bind(peer_addr.udp_addr()).unwrap();
+        let mut buf = [0; MAX_PACKET_SIZE];
+
+        // Expired longer ago than the clock skew tolerated.
+        let expired = 1_000_000 - EXPIRATION_GRACE.as_secs() - 1;
+        let ping = build_ping(&secret_key(1), peer_addr, node_addr, expired);
+        peer.send_to(&ping, node_addr.udp_addr()).await.unwrap();
+        assert!(timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+            .await
+            .is_err());
+
+        // About to expire, the Pong still gets a full lifetime.
+        let ping = build_ping(&secret_key(1), peer_addr, node_addr, 1_000_001);
+        peer.send_to(&ping, node_addr.udp_addr()).await.unwrap();
+        let pong = loop {
+            let (len, _) = timeout(Duration::from_secs(1), peer.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            if let Message::Pong(pong) = decode_packet(&buf[..len]).unwrap().message().unwrap() {
+                break pong;
+            }
+        };
+        assert_eq!(
+            pong.expire,
+            1_000_000 + ExpiryPolicy::DEFAULT_LIFETIME.as_secs()
+        );
+
+        node.shutdown().await;
+    }
+
+    #[test]
+    fn runs_on_provided_runtime() {
+        let dedicated = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let ambient = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        ambient.block_on(async {
+            let network = MemoryNetwork::default();
+            let node = start_memory_node(
+                &network,
+                memory_addr(0),
+                vec![],
+                NodeConfig {
+                    runtime: Some(dedicated.handle().clone()),
+                    ..Default::default()
+                },
+            )
+            .await;
+            let pinger =
+                start_memory_node(&network, memory_addr(1), vec![], NodeConfig::default()).await;
+            let record = node.local_node_record();
+
+            assert!(pinger.ping(&record).await.is_ok());
+            // The node is gone with its runtime, though the ambient one is still there.
+            dedicated.shutdown_background();
+            assert_eq!(pinger.ping(&record).await, Err(PingError::Timeout));
+
+            pinger.shutdown().await;
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn crawler_finds_every_node() {
+        const NODES: u8 = 20;
+
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+
+        let mut nodes = Vec::new();
+        let mut bootstrap_nodes = Vec::new();
+        for i in 0..NODES {
+            let node = start_memory_node(
+                &network,
+                memory_addr(i),
+                bootstrap_nodes.clone(),
+                NodeConfig::default(),
+            )
+            .await;
+            if bootstrap_nodes.is_empty() {
+                bootstrap_nodes.push(node.local_node_record());
+            }
+            nodes.push(node);
+        }
+        sleep(REFRESH_TIMEOUT).await;
+
+        let (crawler, stream) = Node::crawler(
+            network.bind(memory_addr(NODES)).unwrap(),
+            SecretKey::new(&mut secp256k1::rand::thread_rng()),
+            bootstrap_nodes,
+            NodeConfig::default(),
+        )
+        .await
+        .unwrap();
+        sleep(REFRESH_TIMEOUT * 2).await;
+
+        let mut rx = stream.into_inner();
+        let mut crawled = HashSet::new();
+        while let Ok(node) = rx.try_recv() {
+            // Nobody moved, so nobody is emitted twice.
+            assert!(crawled.insert(node.id));
+            assert_eq!(node.record.id, node.id);
+        }
+        for node in &nodes {
+            assert!(crawled.contains(&node.id));
+        }
+
+        crawler.shutdown().await;
+        for node in nodes {
+            node.shutdown().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn random_walk_yields_every_node_once() {
+        const NODES: u8 = 10;
+
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+
+        let mut nodes = Vec::new();
+        let mut bootstrap_nodes = Vec::new();
+        for i in 0..NODES {
+            let node = start_memory_node(
+                &network,
+                memory_addr(i),
+                bootstrap_nodes.clone(),
+                NodeConfig::default(),
+            )
+            .await;
+            if bootstrap_nodes.is_empty() {
+                bootstrap_nodes.push(node.local_node_record());
+            }
+            nodes.push(node);
+        }
+        sleep(REFRESH_TIMEOUT).await;
+
+        let walker = nodes.pop().unwrap();
+        let mut walk = Box::pin(walker.random_walk());
+        let mut walked = HashSet::new();
+        while walked.len() < nodes.len() {
+            let record = timeout(REFRESH_TIMEOUT, walk.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(walked.insert(record.id));
+        }
+        for node in &nodes {
+            assert!(walked.contains(&node.id));
+        }
+
+        // Nothing new is left to find, so the walk backs off, until shut down.
+        assert!(timeout(RANDOM_WALK_BACKOFF_MAX, walk.next()).await.is_err());
+        walker.shutdown().await;
+        assert!(walk.next().await.is_none());
+        for node in nodes {
+            node.shutdown().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lookup_at_distances_keeps_to_the_distances() {
+        const NODES: u8 = 20;
+
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(20));
+
+        let mut nodes = Vec::new();
+        let mut bootstrap_nodes = Vec::new();
+        for i in 0..NODES {
+            let node = start_memory_node(
+                &network,
+                memory_addr(i),
+                bootstrap_nodes.clone(),
+                NodeConfig::default(),
+            )
+            .await;
+            if bootstrap_nodes.is_empty() {
+                bootstrap_nodes.push(node.local_node_record());
+            }
+            nodes.push(node);
+        }
+        sleep(REFRESH_TIMEOUT).await;
+
+        let node = &nodes[NODES as usize - 1];
+        let target = NodeId::random();
+        // Half of the nodes are expected at the farthest distance.
+        let found = node
+            .lookup_at_distances(target, &[ADDRESS_BITS as u16])
+            .await;
+        assert!(!found.is_empty());
+        let now = unix_timestamp();
+        for record in &found {
+            assert_eq!(log2_distance(target, record.id), ADDRESS_BITS as u16);
+            assert!(node.endpoint_proofs.lock().has_valid_proof(&record.id, now));
+        }
+        assert!(node
+            .lookup_at_distances(target, &[u16::MAX])
+            .await
+            .is_empty());
+
+        for node in nodes {
+            node.shutdown().await;
+        }
+    }
+
+    #[test]
+    fn node_record_builder() {
+        let id = ID.parse::<NodeId>().unwrap();
+
+        let record = NodeRecord::builder()
+            .with_id(id)
+            .with_address([10, 0, 0, 1])
+            .build()
+            .unwrap();
+        assert_eq!(record.udp_addr(), "10.0.0.1:30303".parse().unwrap());
+        assert_eq!(record.tcp_port, DEFAULT_PORT);
+
+        let record = NodeRecord::builder()
+            .with_raw_id(id)
+            .with_address([10, 0, 0, 1])
+            .with_udp_port(30301)
+            .build()
+            .unwrap();
+        assert_eq!(record.tcp_port, 30301);
+        assert_eq!(record.udp_port, 30301);
+
+        let builder = NodeRecord::builder().with_address(Ipv4Addr::LOCALHOST);
+        assert!(matches!(
+            builder.clone().build(),
+            Err(NodeRecordBuildError::MissingId)
+        ));
+        assert!(matches!(
+            builder.clone().with_raw_id([0; 33]).build(),
+            Err(NodeRecordBuildError::InvalidIdLength(33))
+        ));
+        assert!(matches!(
+            builder.clone().with_id(id).build(),
+            Err(NodeRecordBuildError::LoopbackAddress(_))
+        ));
+        assert!(builder.with_id(id).allow_loopback().build().is_ok());
+
+        let builder = NodeRecord::builder().with_id(id);
+        assert!(matches!(
+            builder.clone().build(),
+            Err(NodeRecordBuildError::MissingAddress)
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .with_address(Ipv6Addr::UNSPECIFIED)
+                .allow_loopback()
+                .build(),
+            Err(NodeRecordBuildError::UnspecifiedAddress(_))
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .with_address("fe80::1".parse::<Ipv6Addr>().unwrap())
+                .build(),
+            Err(NodeRecordBuildError::ScopedAddress(_))
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .with_address([10, 0, 0, 1])
+                .with_tcp_port(30303)
+                .with_udp_port(0)
+                .build(),
+            Err(NodeRecordBuildError::ZeroUdpPort)
+        ));
+
+        let discovery_only = builder
+            .with_address([10, 0, 0, 1])
+            .with_udp_port(30301)
+            .with_tcp_port(0)
+            .build()
+            .unwrap();
+        assert_eq!(discovery_only.udp_port, 30301);
+        assert!(!discovery_only.is_dialable());
+    }
+
+    #[test]
+    fn lookup_result_endpoint() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let record = NodeRecord {
+            address: Ip([10, 0, 0, 1].into()),
+            tcp_port: DEFAULT_PORT,
+            udp_port: DEFAULT_PORT,
+            id: pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key)),
+        };
+        let mut result = LookupResult { record, enr: None };
+        assert_eq!(result.endpoint().udp_addr(), record.udp_addr());
+
+        let advertised = NodeRecord {
+            address: Ip([10, 0, 0, 2].into()),
+            ..record
+        };
+        result.enr = Some(advertised.to_enr(&secret_key).unwrap());
+        assert_eq!(result.endpoint().udp_addr(), advertised.udp_addr());
+        assert_eq!(result.endpoint().id, record.id);
+    }
+
+    #[test]
+    fn enode_url_invalid() {
+        for url in [
+            format!("enode://{}@18.138.108.67:30303", &ID[2..]),
+            format!("enode://{ID}@18.138.108.67"),
+            format!("enode://{ID}@example.org:30303"),
+            format!("enode://{ID}@18.138.108.67:30303?discport=foo"),
+            format!("enr://{ID}@18.138.108.67:30303"),
+        ] {
+            assert!(NodeRecord::from_enode_url(&url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn enode_list() {
+        let list = format!(
+            r#"[
+                "enode://{ID}@18.138.108.67:30303",
+                "enode://{ID}@example.org:30303",
+                " enode://{ID}@[::1]:30303?discport=30301 "
+            ]"#
+        );
+        let records = NodeRecord::parse_enode_list(&list).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].udp_addr(),
+            "18.138.108.67:30303".parse().unwrap()
+        );
+        assert_eq!(records[1].udp_addr(), "[::1]:30301".parse().unwrap());
+
+        assert!(NodeRecord::parse_enode_list("[").is_err());
+        assert!(NodeRecord::parse_enode_list(r#"{"nodes": []}"#).is_err());
+
+        let path = std::env::temp_dir().join(format!("static-nodes-{}.json", std::process::id()));
+        std::fs::write(&path, &list).unwrap();
+        assert_eq!(NodeRecord::load_enode_list(&path).unwrap().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+        assert!(NodeRecord::load_enode_list(&path).is_err());
+    }
+}
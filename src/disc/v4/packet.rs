@@ -0,0 +1,358 @@
+//! Discovery v4 packet framing: `hash || signature || packet-type || packet-data`.
+
+use super::{
+    message::{Message, MessageError},
+    util::*,
+    NodeId,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use ethereum_types::H256;
+use fastrlp::Encodable;
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    SecretKey, SECP256K1,
+};
+use thiserror::Error;
+
+pub const MAX_PACKET_SIZE: usize = 1280;
+
+pub const HASH_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = secp256k1::constants::COMPACT_SIGNATURE_SIZE + 1;
+pub const MIN_PACKET_SIZE: usize = HASH_SIZE + SIGNATURE_SIZE + 1;
+
+#[derive(Debug, Error)]
+pub enum PacketError {
+    #[error("packet too short: {0} < {}", MIN_PACKET_SIZE)]
+    TooShort(usize),
+    #[error("packet too large: {size} > {max}")]
+    TooLarge { size: usize, max: usize },
+    #[error("datagram truncated to {0} bytes")]
+    Truncated(usize),
+    #[error("hash check failed: computed {computed}, prefix {prefix}")]
+    HashMismatch { computed: H256, prefix: H256 },
+    #[error("invalid signature")]
+    InvalidSignature(#[from] secp256k1::Error),
+    #[error("non-canonical signature with high s")]
+    HighS,
+}
+
+/// Which signatures to accept in addition to the canonical ones.
+///
+/// For any signature `(r, s)` there is the equally valid `(r, n - s)`, so enforcing `s` in
+/// the lower half of the curve order leaves only one valid signature per message and key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Accept high-s signatures too, as other implementations may send them.
+    #[default]
+    Lenient,
+    /// Reject high-s signatures with [`PacketError::HighS`].
+    LowS,
+}
+
+/// Verified discovery v4 packet.
+#[derive(Clone, Copy, Debug)]
+pub struct Packet<'a> {
+    /// Hash prefix of the packet, echoed back in Pong.
+    pub hash: H256,
+    /// ID of the node that signed the packet.
+    pub node_id: NodeId,
+    pub packet_type: u8,
+    pub data: &'a [u8],
+}
+
+impl Packet<'_> {
+    /// Decode the packet data according to its packet type.
+    pub fn message(&self) -> Result<Message, MessageError> {
+        Message::decode(self.packet_type, &mut &*self.data)
+    }
+}
+
+/// Sign and encode the message into a datagram ready to be sent.
+pub fn encode_packet(message: &Message, secret_key: &SecretKey) -> Bytes {
+    let mut datagram = BytesMut::with_capacity(MAX_PACKET_SIZE);
+    encode_packet_into(message, secret_key, &mut datagram);
+    datagram.freeze()
+}
+
+/// [`encode_packet`] into `buf`, replacing its contents, so that one buffer can be reused
+/// for all datagrams sent. The packet is signed and hashed in place.
+pub fn encode_packet_into(message: &Message, secret_key: &SecretKey, buf: &mut BytesMut) {
+    const PAYLOAD: usize = HASH_SIZE + SIGNATURE_SIZE;
+
+    buf.clear();
+    buf.resize(PAYLOAD, 0);
+    buf.put_u8(message.packet_type());
+    message.encode(buf);
+
+    let signature: RecoverableSignature =
+        SECP256K1.sign_ecdsa_recoverable(&keccak256_message(&buf[PAYLOAD..]), secret_key);
+    let (rec, sig) = signature.serialize_compact();
+    buf[HASH_SIZE..PAYLOAD - 1].copy_from_slice(&sig);
+    buf[PAYLOAD - 1] = rec.to_i32() as u8;
+
+    let hash = keccak256(&buf[HASH_SIZE..]);
+    buf[..HASH_SIZE].copy_from_slice(hash.as_bytes());
+}
+
+/// [`encode_packet`], unless the datagram would exceed `max_packet_size`.
+pub fn encode_packet_checked(
+    message: &Message,
+    secret_key: &SecretKey,
+    max_packet_size: usize,
+) -> Result<Bytes, PacketError> {
+    let mut datagram = BytesMut::new();
+    encode_packet_checked_into(message, secret_key, max_packet_size, &mut datagram)?;
+    Ok(datagram.freeze())
+}
+
+/// [`encode_packet_into`], unless the datagram would exceed `max_packet_size`, in which
+/// case `buf` is left as it is.
+pub fn encode_packet_checked_into(
+    message: &Message,
+    secret_key: &SecretKey,
+    max_packet_size: usize,
+    buf: &mut BytesMut,
+) -> Result<(), PacketError> {
+    let size = packet_size(message);
+    if size > max_packet_size {
+        return Err(PacketError::TooLarge {
+            size,
+            max: max_packet_size,
+        });
+    }
+    encode_packet_into(message, secret_key, buf);
+    Ok(())
+}
+
+/// Size of the datagram carrying `message`.
+pub fn packet_size(message: &Message) -> usize {
+    MIN_PACKET_SIZE + message.length()
+}
+
+/// [`decode_packet_with_policy`] a datagram received into a buffer of `max_packet_size + 1`
+/// bytes, so that datagrams that did not fit into `max_packet_size` are detected as truncated.
+pub fn decode_datagram(
+    data: &[u8],
+    max_packet_size: usize,
+    signature_policy: SignaturePolicy,
+) -> Result<Packet<'_>, PacketError> {
+    if data.len() > max_packet_size {
+        return Err(PacketError::Truncated(max_packet_size));
+    }
+    decode_packet_with_policy(data, signature_policy)
+}
+
+/// Verify the packet hash and recover the sender from the signature.
+pub fn decode_packet(data: &[u8]) -> Result<Packet<'_>, PacketError> {
+    decode_packet_with_policy(data, SignaturePolicy::Lenient)
+}
+
+/// [`decode_packet`], accepting signatures according to `signature_policy`.
+pub fn decode_packet_with_policy(
+    data: &[u8],
+    signature_policy: SignaturePolicy,
+) -> Result<Packet<'_>, PacketError> {
+    if data.len() < MIN_PACKET_SIZE {
+        return Err(PacketError::TooShort(data.len()));
+    }
+
+    let hash = keccak256(&data[HASH_SIZE..]);
+    let prefix = H256::from_slice(&data[..HASH_SIZE]);
+    if prefix != hash {
+        return Err(PacketError::HashMismatch {
+            computed: hash,
+            prefix,
+        });
+    }
+
+    let signed = &data[HASH_SIZE + SIGNATURE_SIZE..];
+    let rec_id = RecoveryId::from_i32(data[HASH_SIZE + SIGNATURE_SIZE - 1] as i32)?;
+    let rec_sig = RecoverableSignature::from_compact(
+        &data[HASH_SIZE..HASH_SIZE + SIGNATURE_SIZE - 1],
+        rec_id,
+    )?;
+    if signature_policy == SignaturePolicy::LowS {
+        let signature = rec_sig.to_standard();
+        let mut normalized = signature;
+        normalized.normalize_s();
+        if normalized != signature {
+            return Err(PacketError::HighS);
+        }
+    }
+    let public_key = SECP256K1.recover_ecdsa(&keccak256_message(signed), &rec_sig)?;
+
+    Ok(Packet {
+        hash,
+        node_id: pk2id(&public_key),
+        packet_type: signed[0],
+        data: &signed[1..],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disc::v4::message::{EnrRequestMessage, FindNodeMessage};
+    use secp256k1::PublicKey;
+
+    #[test]
+    fn roundtrip() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let message = Message::FindNode(FindNodeMessage {
+            id: NodeId::random(),
+            expire: 1_000_000,
+        });
+
+        let datagram = encode_packet(&message, &secret_key);
+        let packet = decode_packet(&datagram).unwrap();
+
+        assert_eq!(packet.hash, H256::from_slice(&datagram[..HASH_SIZE]));
+        assert_eq!(
+            packet.node_id,
+            pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key))
+        );
+        assert_eq!(packet.packet_type, 3);
+
+        let mut data = Vec::new();
+        message.encode(&mut data);
+        assert_eq!(packet.data, &data[..]);
+
+        let (expected, decoded) = match (message, packet.message().unwrap()) {
+            (Message::FindNode(expected), Message::FindNode(decoded)) => (expected, decoded),
+            other => panic!("unexpected message: {:?}", other),
+        };
+        assert_eq!(decoded.id, expected.id);
+        assert_eq!(decoded.expire, expected.expire);
+    }
+
+    #[test]
+    fn encode_into_reused_buffer() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let long = Message::FindNode(FindNodeMessage {
+            id: NodeId::random(),
+            expire: 1_000_000,
+        });
+        let short = Message::EnrRequest(EnrRequestMessage { expire: 1_000_000 });
+
+        let mut buf = BytesMut::with_capacity(MAX_PACKET_SIZE);
+        for message in [&long, &short, &long] {
+            encode_packet_into(message, &secret_key, &mut buf);
+            // Signatures are deterministic, so the datagrams are identical.
+            assert_eq!(&buf[..], &encode_packet(message, &secret_key)[..]);
+        }
+    }
+
+    #[test]
+    fn unknown_packet_type() {
+        let packet = Packet {
+            hash: H256::zero(),
+            node_id: NodeId::zero(),
+            packet_type: 0xff,
+            data: &[0xc0],
+        };
+        assert!(matches!(
+            packet.message(),
+            Err(MessageError::UnknownPacketType(0xff))
+        ));
+    }
+
+    #[test]
+    fn too_short() {
+        assert!(matches!(
+            decode_packet(&[0; MIN_PACKET_SIZE - 1]),
+            Err(PacketError::TooShort(len)) if len == MIN_PACKET_SIZE - 1
+        ));
+    }
+
+    #[test]
+    fn size_limit() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let message = Message::FindNode(FindNodeMessage {
+            id: NodeId::random(),
+            expire: 1_000_000,
+        });
+
+        let size = packet_size(&message);
+        assert_eq!(
+            encode_packet_checked(&message, &secret_key, size)
+                .unwrap()
+                .len(),
+            size
+        );
+        assert!(matches!(
+            encode_packet_checked(&message, &secret_key, size - 1),
+            Err(PacketError::TooLarge { size: s, max }) if s == size && max == size - 1
+        ));
+
+        let datagram = encode_packet(&message, &secret_key);
+        assert!(decode_datagram(&datagram, size, SignaturePolicy::default()).is_ok());
+        assert!(matches!(
+            decode_datagram(&datagram, size - 1, SignaturePolicy::default()),
+            Err(PacketError::Truncated(max)) if max == size - 1
+        ));
+    }
+
+    /// Replace the signature `(r, s)` of the datagram with `(r, n - s)`, which is as valid.
+    fn malleate(datagram: &[u8]) -> Vec<u8> {
+        let mut datagram = datagram.to_vec();
+        let s = &mut datagram[HASH_SIZE + 32..HASH_SIZE + 64];
+        let mut borrow = 0;
+        for (s, n) in s.iter_mut().zip(secp256k1::constants::CURVE_ORDER).rev() {
+            let diff = i16::from(n) - i16::from(*s) - borrow;
+            borrow = i16::from(diff < 0);
+            *s = diff.rem_euclid(256) as u8;
+        }
+        // Negating s negates the nonce point, flipping the parity of its y coordinate.
+        datagram[HASH_SIZE + SIGNATURE_SIZE - 1] ^= 1;
+
+        let hash = keccak256(&datagram[HASH_SIZE..]);
+        datagram[..HASH_SIZE].copy_from_slice(hash.as_bytes());
+        datagram
+    }
+
+    #[test]
+    fn high_s_signature() {
+        let secret_key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+        let datagram = encode_packet(
+            &Message::FindNode(FindNodeMessage {
+                id: NodeId::repeat_byte(0x11),
+                expire: 1_000_000,
+            }),
+            &secret_key,
+        );
+        // Signatures we make are always canonical.
+        let packet = decode_packet_with_policy(&datagram, SignaturePolicy::LowS).unwrap();
+
+        let high_s = malleate(&datagram);
+        let malleated = decode_packet(&high_s).unwrap();
+        assert_eq!(malleated.node_id, packet.node_id);
+        assert_eq!(malleated.data, packet.data);
+        assert!(matches!(
+            decode_packet_with_policy(&high_s, SignaturePolicy::LowS),
+            Err(PacketError::HighS)
+        ));
+        assert!(matches!(
+            decode_datagram(&high_s, MAX_PACKET_SIZE, SignaturePolicy::LowS),
+            Err(PacketError::HighS)
+        ));
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let mut datagram = encode_packet(
+            &Message::FindNode(FindNodeMessage {
+                id: NodeId::random(),
+                expire: 1_000_000,
+            }),
+            &secret_key,
+        )
+        .to_vec();
+        *datagram.last_mut().unwrap() ^= 1;
+
+        assert!(matches!(
+            decode_packet(&datagram),
+            Err(PacketError::HashMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+//! Endpoint proof tracking.
+//!
+//! A remote node proves its endpoint by answering our Ping with a Pong echoing the Ping hash.
+//! Only nodes with a recent proof may be answered with Neighbours, which prevents using
+//! us for traffic amplification against a spoofed address.
+//!
+//! Pongs are matched to our Pings through [`PendingPings`], anything else is unsolicited.
+
+use super::NodeId;
+use ethereum_types::H256;
+use std::{collections::HashMap, time::Duration};
+use tokio::{sync::oneshot::Sender as OneshotSender, time::Instant};
+
+/// How long an endpoint proof stays valid after the Pong was received.
+pub const ENDPOINT_PROOF_EXPIRATION: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Clone, Copy, Debug)]
+struct EndpointProof {
+    last_pong_time: u64,
+    ping_hash: H256,
+}
+
+impl EndpointProof {
+    fn is_valid(&self, now: u64) -> bool {
+        self.last_pong_time + ENDPOINT_PROOF_EXPIRATION.as_secs() >= now
+    }
+}
+
+/// Ping we sent and expect a Pong for.
+#[derive(Debug)]
+pub struct PendingPing {
+    pub node_id: NodeId,
+    pub sent_at: Instant,
+    /// Notified once the Pong arrives, dropped if it does not in time.
+    pub callbacks: Vec<OneshotSender<()>>,
+}
+
+/// Pings we sent and expect a Pong for, by the Ping hash that the Pong has to echo.
+#[derive(Debug)]
+pub struct PendingPings {
+    pings: HashMap<H256, PendingPing>,
+    timeout: Duration,
+}
+
+impl PendingPings {
+    /// Pings expire if not answered within `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            pings: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Remember that a Ping with `hash` is being sent to `node_id` at `now`, or add the
+    /// `callback` to the identical Ping that is already pending.
+    ///
+    /// Returns `true` if the Ping is new and has to be sent.
+    pub fn insert(
+        &mut self,
+        hash: H256,
+        node_id: NodeId,
+        now: Instant,
+        callback: Option<OneshotSender<()>>,
+    ) -> bool {
+        let mut is_new = false;
+        let ping = self.pings.entry(hash).or_insert_with(|| {
+            is_new = true;
+            PendingPing {
+                node_id,
+                sent_at: now,
+                callbacks: Vec::new(),
+            }
+        });
+        ping.callbacks.extend(callback);
+        is_new
+    }
+
+    /// Take the Ping answered by Pong from `node_id` received at `now`.
+    ///
+    /// Returns `None` if `echo` does not match an unexpired Ping sent to `node_id`.
+    pub fn take(&mut self, echo: H256, node_id: NodeId, now: Instant) -> Option<PendingPing> {
+        match self.pings.get(&echo) {
+            Some(ping) if ping.node_id == node_id && now <= ping.sent_at + self.timeout => {
+                self.pings.remove(&echo)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a Ping sent to `node_id` is still waiting for its Pong at `now`.
+    pub fn is_pending(&self, node_id: NodeId, now: Instant) -> bool {
+        self.pings
+            .values()
+            .any(|ping| ping.node_id == node_id && now <= ping.sent_at + self.timeout)
+    }
+
+    /// Remove the Ping with `hash` after it timed out, if it is still pending.
+    pub fn expire(&mut self, hash: H256) -> Option<PendingPing> {
+        self.pings.remove(&hash)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct EndpointProofs {
+    proofs: HashMap<NodeId, EndpointProof>,
+}
+
+impl EndpointProofs {
+    /// Record the Pong from `id` received at `now`, echoing our Ping with hash `echo`.
+    ///
+    /// The Pong must be matched with the Ping through [`PendingPings::take`] first.
+    pub fn record_pong(&mut self, id: NodeId, echo: H256, now: u64) {
+        self.proofs.insert(
+            id,
+            EndpointProof {
+                last_pong_time: now,
+                ping_hash: echo,
+            },
+        );
+    }
+
+    /// Whether `id` answered our Ping within [`ENDPOINT_PROOF_EXPIRATION`] before `now`.
+    pub fn has_valid_proof(&self, id: &NodeId, now: u64) -> bool {
+        matches!(self.proofs.get(id), Some(proof) if proof.is_valid(now))
+    }
+
+    /// Hash of the Ping that the latest proof of `id` answered.
+    pub fn proof_ping_hash(&self, id: &NodeId) -> Option<H256> {
+        self.proofs.get(id).map(|proof| proof.ping_hash)
+    }
+
+    /// Drop proofs that expired before `now`.
+    pub fn prune(&mut self, now: u64) {
+        self.proofs.retain(|_, proof| proof.is_valid(now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn pong_must_echo_our_ping() {
+        let mut pings = PendingPings::new(TIMEOUT);
+        let id = NodeId::random();
+        let hash = H256::random();
+        let now = Instant::now();
+
+        assert!(pings.take(hash, id, now).is_none());
+
+        assert!(pings.insert(hash, id, now, None));
+        assert!(pings.take(hash, NodeId::random(), now).is_none());
+        assert_eq!(pings.take(hash, id, now).unwrap().node_id, id);
+
+        // Pong can only be used once.
+        assert!(pings.take(hash, id, now).is_none());
+    }
+
+    #[tokio::test]
+    async fn bogus_echo_is_ignored() {
+        let mut pings = PendingPings::new(TIMEOUT);
+        let id = NodeId::random();
+        let hash = H256::random();
+        let now = Instant::now();
+
+        let (tx, mut rx) = tokio::sync::oneshot::channel();
+        assert!(pings.insert(hash, id, now, Some(tx)));
+        // Identical Ping is not sent again, but also notified.
+        let (tx2, rx2) = tokio::sync::oneshot::channel();
+        assert!(!pings.insert(hash, id, now, Some(tx2)));
+
+        assert!(pings.take(H256::random(), id, now).is_none());
+        assert!(rx.try_recv().is_err());
+
+        for cb in pings.take(hash, id, now).unwrap().callbacks {
+            cb.send(()).unwrap();
+        }
+        assert!(rx.await.is_ok());
+        assert!(rx2.await.is_ok());
+    }
+
+    #[test]
+    fn ping_expires() {
+        let mut pings = PendingPings::new(TIMEOUT);
+        let id = NodeId::random();
+        let hash = H256::random();
+        let now = Instant::now();
+
+        pings.insert(hash, id, now, None);
+        assert!(pings.is_pending(id, now + TIMEOUT));
+        assert!(!pings.is_pending(NodeId::random(), now));
+        assert!(!pings.is_pending(id, now + TIMEOUT * 2));
+        assert!(pings.take(hash, id, now + TIMEOUT * 2).is_none());
+        assert!(pings.expire(hash).is_some());
+        assert!(pings.expire(hash).is_none());
+    }
+
+    #[test]
+    fn proof_expires() {
+        let mut proofs = EndpointProofs::default();
+        let id = NodeId::random();
+        let hash = H256::random();
+        let expiration = ENDPOINT_PROOF_EXPIRATION.as_secs();
+
+        assert!(!proofs.has_valid_proof(&id, 100));
+        proofs.record_pong(id, hash, 100);
+        assert_eq!(proofs.proof_ping_hash(&id), Some(hash));
+        assert!(proofs.has_valid_proof(&id, 100 + expiration));
+        assert!(!proofs.has_valid_proof(&id, 101 + expiration));
+
+        proofs.prune(101 + expiration);
+        assert_eq!(proofs.proof_ping_hash(&id), None);
+    }
+}
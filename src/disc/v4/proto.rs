@@ -8,12 +8,25 @@ pub enum MessageId {
     Pong = 2,
     FindNode = 3,
     Neighbours = 4,
+    EnrRequest = 5,
+    EnrResponse = 6,
 }
 
 #[derive(Debug)]
 pub enum EgressMessage {
     Ping(PingMessage, Option<OneshotSender<()>>),
+    /// Ping that only checks whether the node is alive: leaving it unanswered has no
+    /// consequences for the node.
+    Probe(PingMessage, OneshotSender<()>),
     Pong(PongMessage),
     FindNode(FindNodeMessage),
     Neighbours(NeighboursMessage),
+    EnrRequest(EnrRequestMessage),
+}
+
+impl EgressMessage {
+    /// Whether the message answers a request of the remote node.
+    pub fn is_response(&self) -> bool {
+        matches!(self, Self::Pong(_) | Self::Neighbours(_))
+    }
 }
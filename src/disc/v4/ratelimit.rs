@@ -0,0 +1,123 @@
+//! Per source IP rate limiting of responses.
+//!
+//! Pong and especially Neighbours packets are sent to the packet source address, which can be
+//! spoofed, so without a limit the node could be used to amplify traffic against a victim.
+
+use lru::LruCache;
+use std::{net::IpAddr, time::Duration};
+use tokio::time::Instant;
+
+/// Number of source IPs tracked at once.
+pub const MAX_TRACKED_IPS: usize = 10_000;
+
+/// Token bucket parameters.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Sustained number of responses per second.
+    pub per_second: u32,
+    /// Number of responses that can be sent at once after a period of inactivity.
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            per_second: 10,
+            burst: 20,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: LruCache<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: LruCache::new(MAX_TRACKED_IPS),
+        }
+    }
+
+    /// Take a token for a response to `ip` at `now`, returning `false` if there are none left.
+    pub fn check(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let RateLimit { per_second, burst } = self.limit;
+
+        let bucket = match self.buckets.get_mut(&ip) {
+            Some(bucket) => {
+                let elapsed = now.saturating_duration_since(bucket.updated_at);
+                bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * f64::from(per_second))
+                    .min(f64::from(burst));
+                bucket.updated_at = now;
+                bucket
+            }
+            None => {
+                self.buckets.put(
+                    ip,
+                    TokenBucket {
+                        tokens: f64::from(burst),
+                        updated_at: now,
+                    },
+                );
+                self.buckets.get_mut(&ip).expect("just inserted")
+            }
+        };
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time it takes to regain a single token.
+    pub fn refill_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.limit.per_second.max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LIMIT: RateLimit = RateLimit {
+        per_second: 5,
+        burst: 10,
+    };
+
+    #[test]
+    fn flood_from_single_ip_is_dropped() {
+        let mut limiter = RateLimiter::new(LIMIT);
+        let attacker = IpAddr::from(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::from(Ipv4Addr::new(10, 0, 0, 2));
+        let now = Instant::now();
+
+        let allowed = (0..1000).filter(|_| limiter.check(attacker, now)).count();
+        assert_eq!(allowed, LIMIT.burst as usize);
+
+        // Other sources are not affected
+        assert!(limiter.check(other, now));
+
+        // Tokens come back over time, but no more than the burst
+        let later = now + limiter.refill_interval();
+        assert!(limiter.check(attacker, later));
+        assert!(!limiter.check(attacker, later));
+
+        let much_later = later + Duration::from_secs(3600);
+        let allowed = (0..1000)
+            .filter(|_| limiter.check(attacker, much_later))
+            .count();
+        assert_eq!(allowed, LIMIT.burst as usize);
+    }
+}
@@ -0,0 +1,164 @@
+//! Reputation of remote nodes, by how well they follow the protocol.
+//!
+//! Scores go up with every solicited response and down with every malformed packet or
+//! request left unanswered. They decay towards zero, so that old behaviour is forgotten.
+
+use super::NodeId;
+use lru::LruCache;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Number of nodes scored at once, the least recently scored ones are forgotten first.
+pub const MAX_SCORED_NODES: usize = 10_000;
+/// Time it takes for a score to decay to half of its value.
+pub const REPUTATION_HALF_LIFE: Duration = Duration::from_secs(60 * 60);
+
+/// Observed behaviour of a remote node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReputationEvent {
+    /// Pong in response to our Ping.
+    Pong,
+    /// Neighbours in response to our FindNode.
+    Neighbours,
+    /// Correctly signed packet that failed to decode.
+    Malformed,
+    /// Ping or FindNode that was not answered in time.
+    Timeout,
+}
+
+impl ReputationEvent {
+    /// Change of the score caused by the event.
+    pub fn weight(self) -> f64 {
+        match self {
+            Self::Pong => 1.0,
+            Self::Neighbours => 1.0,
+            Self::Malformed => -5.0,
+            Self::Timeout => -2.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Score {
+    value: f64,
+    updated_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct Reputation {
+    half_life: Duration,
+    scores: LruCache<NodeId, Score>,
+}
+
+impl Default for Reputation {
+    fn default() -> Self {
+        Self::new(REPUTATION_HALF_LIFE)
+    }
+}
+
+impl Reputation {
+    /// Half-life below one second is rounded up to it.
+    pub fn new(half_life: Duration) -> Self {
+        Self {
+            half_life: half_life.max(Duration::from_secs(1)),
+            scores: LruCache::new(MAX_SCORED_NODES),
+        }
+    }
+
+    fn decayed(&self, score: &Score, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(score.updated_at);
+        score.value * 0.5_f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64())
+    }
+
+    pub fn record(&mut self, node_id: NodeId, event: ReputationEvent, now: Instant) {
+        let value = self.score(node_id, now) + event.weight();
+        self.scores.put(
+            node_id,
+            Score {
+                value,
+                updated_at: now,
+            },
+        );
+    }
+
+    /// Score of the node at `now`, 0 for nodes never scored.
+    pub fn score(&self, node_id: NodeId, now: Instant) -> f64 {
+        match self.scores.peek(&node_id) {
+            Some(score) => self.decayed(score, now),
+            None => 0.0,
+        }
+    }
+
+    /// All scores at `now`, e.g. to be persisted and passed to [`Reputation::restore`].
+    pub fn scores(&self, now: Instant) -> Vec<(NodeId, f64)> {
+        self.scores
+            .iter()
+            .map(|(node_id, score)| (*node_id, self.decayed(score, now)))
+            .collect()
+    }
+
+    /// Replace the scores of the given nodes, as if they were recorded at `now`.
+    pub fn restore(&mut self, scores: impl IntoIterator<Item = (NodeId, f64)>, now: Instant) {
+        for (node_id, value) in scores {
+            if value.is_finite() {
+                self.scores.put(
+                    node_id,
+                    Score {
+                        value,
+                        updated_at: now,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_decay() {
+        let node_id = NodeId::random();
+        let now = Instant::now();
+        let mut reputation = Reputation::new(Duration::from_secs(10));
+
+        assert_eq!(reputation.score(node_id, now), 0.0);
+        reputation.record(node_id, ReputationEvent::Pong, now);
+        reputation.record(node_id, ReputationEvent::Neighbours, now);
+        assert_eq!(reputation.score(node_id, now), 2.0);
+        assert_eq!(
+            reputation.score(node_id, now + Duration::from_secs(10)),
+            1.0
+        );
+
+        reputation.record(
+            node_id,
+            ReputationEvent::Malformed,
+            now + Duration::from_secs(20),
+        );
+        assert_eq!(
+            reputation.score(node_id, now + Duration::from_secs(20)),
+            -4.5
+        );
+    }
+
+    #[test]
+    fn restore() {
+        let node_id = NodeId::random();
+        let now = Instant::now();
+        let mut reputation = Reputation::new(Duration::from_secs(10));
+        reputation.record(node_id, ReputationEvent::Timeout, now);
+
+        let scores = reputation.scores(now + Duration::from_secs(10));
+        assert_eq!(scores, vec![(node_id, -1.0)]);
+
+        let mut restored = Reputation::default();
+        restored.restore(
+            scores.into_iter().chain([(NodeId::random(), f64::NAN)]),
+            now,
+        );
+        assert_eq!(restored.score(node_id, now), -1.0);
+        assert_eq!(restored.scores(now).len(), 1);
+    }
+}
@@ -0,0 +1,210 @@
+//! Merging of seed lists that mix enode URLs, ENRs and node records of the same nodes.
+
+use super::{
+    message::Ip,
+    node::{NodeRecord, NodeRecordParseError},
+    util::pk2id,
+    NodeId,
+};
+use crate::types::Enr;
+use std::{
+    collections::{hash_map, HashMap},
+    net::IpAddr,
+    str::FromStr,
+};
+use thiserror::Error;
+use tracing::*;
+
+/// Node as found in a seed list.
+#[derive(Clone, Debug)]
+pub enum SeedNode {
+    /// Bare endpoint, e.g. from an `enode://` URL.
+    Record(NodeRecord),
+    /// Signed record, which is authoritative about the endpoint of the node.
+    Enr(Enr),
+}
+
+impl From<NodeRecord> for SeedNode {
+    fn from(record: NodeRecord) -> Self {
+        Self::Record(record)
+    }
+}
+
+impl From<Enr> for SeedNode {
+    fn from(enr: Enr) -> Self {
+        Self::Enr(enr)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SeedNodeParseError {
+    #[error("invalid ENR: {0}")]
+    InvalidEnr(String),
+    #[error(transparent)]
+    InvalidEnode(#[from] NodeRecordParseError),
+}
+
+impl FromStr for SeedNode {
+    type Err = SeedNodeParseError;
+
+    /// Parse either `enr:` text encoding or `enode://` URL.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("enr:") {
+            Ok(Self::Enr(
+                s.parse().map_err(SeedNodeParseError::InvalidEnr)?,
+            ))
+        } else {
+            Ok(Self::Record(NodeRecord::from_enode_url(s)?))
+        }
+    }
+}
+
+/// Comparable identity of a node: its ID and normalized endpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeKey {
+    pub id: NodeId,
+    pub address: IpAddr,
+    pub udp_port: u16,
+    pub tcp_port: u16,
+}
+
+impl From<NodeRecord> for NodeKey {
+    fn from(record: NodeRecord) -> Self {
+        Self {
+            id: record.id,
+            address: normalize_ip(record.address.0),
+            udp_port: record.udp_port,
+            tcp_port: record.tcp_port,
+        }
+    }
+}
+
+/// IPv4-mapped IPv6 addresses are the IPv4 ones they map, see [`Ip::normalized`].
+pub fn normalize_ip(ip: IpAddr) -> IpAddr {
+    Ip(ip).normalized().0
+}
+
+impl SeedNode {
+    pub fn id(&self) -> NodeId {
+        match self {
+            Self::Record(record) => record.id,
+            Self::Enr(enr) => pk2id(&enr.public_key()),
+        }
+    }
+
+    /// Record with normalized address, `None` for ENRs without an endpoint.
+    pub fn record(&self) -> Option<NodeRecord> {
+        let mut record = match self {
+            Self::Record(record) => *record,
+            Self::Enr(enr) => NodeRecord::from_enr(enr)?,
+        };
+        record.address = Ip(normalize_ip(record.address.0));
+        Some(record)
+    }
+
+    pub fn key(&self) -> Option<NodeKey> {
+        self.record().map(NodeKey::from)
+    }
+
+    /// Entries of higher rank carry more complete information: ENRs over bare endpoints,
+    /// and later ENRs over earlier ones.
+    fn rank(&self) -> (bool, u64) {
+        match self {
+            Self::Record(_) => (false, 0),
+            Self::Enr(enr) => (true, enr.seq()),
+        }
+    }
+}
+
+/// Merge the entries of the same node into one, in the order nodes first appear.
+///
+/// Of the entries that disagree on the endpoint, the one of the highest rank wins: the ENR
+/// with the highest sequence number, or the first bare endpoint if there are no ENRs.
+/// ENRs without an endpoint are skipped.
+pub fn dedup_nodes(inputs: impl IntoIterator<Item = SeedNode>) -> Vec<NodeRecord> {
+    let mut order = Vec::new();
+    let mut best = HashMap::<NodeId, ((bool, u64), NodeRecord)>::new();
+    for input in inputs {
+        let record = match input.record() {
+            Some(record) => record,
+            None => {
+                debug!("Skipping ENR of {} without an endpoint", input.id());
+                continue;
+            }
+        };
+        let rank = input.rank();
+        match best.entry(record.id) {
+            hash_map::Entry::Vacant(entry) => {
+                order.push(record.id);
+                entry.insert((rank, record));
+            }
+            hash_map::Entry::Occupied(mut entry) => {
+                let (best_rank, best_record) = entry.get();
+                if NodeKey::from(*best_record) != NodeKey::from(record) {
+                    debug!(
+                        "Conflicting endpoints of {}: {} and {}",
+                        record.id,
+                        best_record.udp_addr(),
+                        record.udp_addr()
+                    );
+                }
+                if rank > *best_rank {
+                    entry.insert((rank, record));
+                }
+            }
+        }
+    }
+    order.into_iter().map(|id| best[&id].1).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{PublicKey, SecretKey, SECP256K1};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn dedup() {
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let id = pk2id(&PublicKey::from_secret_key(SECP256K1, &secret_key));
+        let other = NodeId::random();
+
+        let enode = format!("enode://{id:x}@10.0.0.1:30303");
+        let other_enode = format!("enode://{other:x}@[::ffff:10.0.0.2]:30303");
+        let enr = NodeRecord {
+            address: Ip(Ipv4Addr::new(10, 0, 0, 3).into()),
+            tcp_port: 30303,
+            udp_port: 30301,
+            id,
+        }
+        .to_enr(&secret_key)
+        .unwrap();
+        let no_endpoint = enr::EnrBuilder::new("v4")
+            .build(&SecretKey::new(&mut secp256k1::rand::thread_rng()))
+            .unwrap();
+
+        let inputs = [
+            enode.parse::<SeedNode>().unwrap(),
+            other_enode.parse().unwrap(),
+            enr.to_base64().parse().unwrap(),
+            enode.parse().unwrap(),
+            format!("enode://{other:x}@10.0.0.2:30303").parse().unwrap(),
+            no_endpoint.into(),
+        ];
+        assert_eq!(inputs[0].key(), inputs[3].key());
+        assert_eq!(inputs[1].key(), inputs[4].key());
+        assert_ne!(inputs[0].key(), inputs[2].key());
+        assert_eq!(inputs[0].id(), inputs[2].id());
+
+        let nodes = dedup_nodes(inputs);
+        assert_eq!(nodes.len(), 2);
+        // The ENR wins over the enode URL that disagrees on the endpoint.
+        assert_eq!(nodes[0].id, id);
+        assert_eq!(nodes[0].udp_addr(), "10.0.0.3:30301".parse().unwrap());
+        assert_eq!(nodes[1].id, other);
+        assert_eq!(nodes[1].udp_addr(), "10.0.0.2:30303".parse().unwrap());
+
+        assert!("enr:-----".parse::<SeedNode>().is_err());
+        assert!("enode://foo".parse::<SeedNode>().is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Fixtures for protocol tests: deterministic keys and fully-signed packets, ready to be fed
+//! into [`decode_packet`](super::packet::decode_packet) or a running node.
+//!
+//! Signatures are deterministic (RFC 6979), so the same inputs always produce the same bytes,
+//! see [`golden`].
+
+use super::{
+    message::{Endpoint, Message, NeighboursMessage, PingMessage, PongMessage, PROTOCOL_VERSION},
+    node::NodeRecord,
+    packet::{encode_packet, HASH_SIZE},
+    util::{keccak256, pk2id},
+    NodeId,
+};
+use bytes::Bytes;
+use ethereum_types::H256;
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
+
+/// Secret key derived from `seed`: Keccak-256 of its big-endian bytes, rehashed in the
+/// unlikely case it is not a valid key.
+pub fn secret_key(seed: u64) -> SecretKey {
+    let mut hash = keccak256(seed.to_be_bytes());
+    loop {
+        if let Ok(secret_key) = SecretKey::from_slice(hash.as_bytes()) {
+            return secret_key;
+        }
+        hash = keccak256(hash);
+    }
+}
+
+pub fn node_id(secret_key: &SecretKey) -> NodeId {
+    pk2id(&PublicKey::from_secret_key(SECP256K1, secret_key))
+}
+
+/// Hash prefix of a packet, echoed back in the Pong to a Ping.
+pub fn packet_hash(packet: &[u8]) -> H256 {
+    H256::from_slice(&packet[..HASH_SIZE])
+}
+
+pub fn build_ping(secret_key: &SecretKey, from: Endpoint, to: Endpoint, expire: u64) -> Bytes {
+    encode_packet(
+        &Message::Ping(PingMessage {
+            version: PROTOCOL_VERSION,
+            from,
+            to,
+            expire,
+            enr_seq: None,
+        }),
+        secret_key,
+    )
+}
+
+pub fn build_pong(secret_key: &SecretKey, ping_hash: H256, to: Endpoint, expire: u64) -> Bytes {
+    encode_packet(
+        &Message::Pong(PongMessage::respond_to(ping_hash, to, expire)),
+        secret_key,
+    )
+}
+
+pub fn build_neighbours(secret_key: &SecretKey, nodes: Vec<NodeRecord>, expire: u64) -> Bytes {
+    encode_packet(
+        &Message::Neighbours(NeighboursMessage { nodes, expire }),
+        secret_key,
+    )
+}
+
+/// Known-good encodings, see [`golden::packets`] for the inputs they are built from.
+pub mod golden {
+    use super::*;
+    use crate::disc::v4::message::Ip;
+    use std::net::Ipv4Addr;
+
+    /// Expiration of all golden packets, far enough in the future to be accepted.
+    pub const EXPIRE: u64 = 2_000_000_000;
+
+    /// Hex of `secret_key(1)`, the signer of [`PING`].
+    pub const SECRET_KEY_1: &str =
+        "6c31fc15422ebad28aaf9089c306702f67540b53c7eea8b7d2941044b027100f";
+    /// Hex of `secret_key(2)`, the signer of [`PONG`] and [`NEIGHBOURS`].
+    pub const SECRET_KEY_2: &str =
+        "859f11b75569a4eb0496c5138fd42cc52aee8cf5c4e7cfafe58c92b2ed138e04";
+
+    /// Ping from [`endpoint`]`(1)` to [`endpoint`]`(2)`.
+    pub const PING: &str = "51b1424c7886dbe49c9b0844f0003d71db63f24073b855da01fdeae21ab34e4d\
+        ef705ec64e44f4808c32866f0ba4787a2e16279199d9eecc16a0bd70433774ad54b8eef4b57016481e356dc5\
+        af26ec31694fefab5759c4f7044422093b05264c0001de04cb840a00000182765f82765fcb840a00000282\
+        765f82765f8477359400";
+    /// Pong to [`PING`].
+    pub const PONG: &str = "572a229dc2ec478a607bb12050008b1e0ff8ada79ee4058db04e869b7684bde9\
+        74ebf6ddb167126da0599115d840f22467c501b1fa87cab8f56124e2df90cfe4507c06462c2a93c806197e\
+        1dd1f40447e66e62575325f14b8b2be3b9660017c20102f2cb840a00000182765f82765fa051b1424c7886\
+        dbe49c9b0844f0003d71db63f24073b855da01fdeae21ab34e4d8477359400";
+    /// Neighbours carrying the record of the signer at [`endpoint`]`(2)`.
+    pub const NEIGHBOURS: &str = "c05bbacfe259f8f36d316330b1b89847be58bbf086a4f86d78837740afdf\
+        c4ffd326b50e41e0c9571d6aa215197ea0d85347d17469c5edb0e1a46e0daed724072f425eba46715c95d5\
+        24b3f539e94a4df50d2fecac30468dfd8b252babb215c30104f856f84ff84d840a00000282765f82765fb8\
+        40816f84073f78fe6988f08380c05fccdbe99767be8f27033747018ef34475cc26ec9cd1f2ed4fa63d7a24\
+        d2bca97206b710dec0ec15e0d02d4797dafc83d6c1788477359400";
+
+    /// `10.0.0.i`, with both ports set to 30303.
+    pub fn endpoint(i: u8) -> Endpoint {
+        Endpoint {
+            address: Ip(Ipv4Addr::new(10, 0, 0, i).into()),
+            udp_port: 30303,
+            tcp_port: 30303,
+        }
+    }
+
+    /// [`PING`], [`PONG`] and [`NEIGHBOURS`] built with the helpers of this module.
+    pub fn packets() -> [Bytes; 3] {
+        let (node_1, node_2) = (secret_key(1), secret_key(2));
+        let ping = build_ping(&node_1, endpoint(1), endpoint(2), EXPIRE);
+        let pong = build_pong(&node_2, packet_hash(&ping), endpoint(1), EXPIRE);
+        let neighbours = build_neighbours(
+            &node_2,
+            vec![NodeRecord {
+                address: endpoint(2).address,
+                tcp_port: 30303,
+                udp_port: 30303,
+                id: node_id(&node_2),
+            }],
+            EXPIRE,
+        );
+        [ping, pong, neighbours]
+    }
+}
+
+/// Packets signed by other implementations, to check interoperability with: the EIP-8
+/// vectors of the discovery v4 spec, which go-ethereum tests against as well, and a Ping and
+/// a Neighbours as go-ethereum encodes them.
+///
+/// EIP-8 vectors carry list elements of later protocol versions, which are ignored, so
+/// re-encoding their messages does not reproduce the packet data.
+pub mod interop {
+    /// Hex of the key all the packets are signed with.
+    pub const SECRET_KEY: &str = "b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291";
+
+    /// Ping with EIP-868 ENR sequence number 1, from `127.0.0.1:3322` (TCP port 5544) to
+    /// `[::1]:2222` (TCP port 3333), expiring at 1136239445.
+    pub const PING_ENR: &str = "e04089fbeb521b4e6a22622a79389f00c76f7eb93c2c046a0333832d83795363\
+        820b24a50e9a92ab6b54c29ec27415e4b1fb2e7221ae54df539e24eb7b0708ec5cd65263edbf18c639658308\
+        a5fb6cbe273b11231dc6db1eb8f0e91ebcd52e740101eb04cb847f000001820cfa8215a8d790000000000000\
+        000000000000000000018208ae820d058443b9a35501";
+    /// EIP-8 Ping of version 555 with an additional list element, from
+    /// `[2001:db8:3c4d:15::abcd:ef12]:3322` (TCP port 5544) to
+    /// `[2001:db8:85a3:8d3:1319:8a2e:370:7348]:2222` (TCP port 33338).
+    pub const PING_EIP8: &str = "577be4349c4dd26768081f58de4c6f375a7a22f3f7adda654d1428637412c3d7\
+        fe917cadc56d4e5e7ffae1dbe3efffb9849feb71b262de37977e7c7a44e677295680e9e38ab26bee2fcbae20\
+        7fba3ff3d74069a50b902a82c9903ed37cc993c50001f83e82022bd79020010db83c4d001500000000abcdef\
+        12820cfa8215a8d79020010db885a308d313198a2e037073488208ae82823a8443b9a355c5010203040531b9\
+        019afde696e582a78fa8d95ea13ce3297d4afb8ba6433e4154caa5ac6431af1b80ba76023fa4090c408f6b4b\
+        c3701562c031041d4702971d102c9ab7fa5eed4cd6bab8f7af956f7d565ee1917084a95398b6a21eac920fe3\
+        dd1345ec0a7ef39367ee69ddf092cbfe5b93e5e568ebc491983c09c76d922dc3";
+    /// EIP-8 Neighbours of four nodes with additional list elements.
+    pub const NEIGHBOURS_EIP8: &str =
+        "c679fc8fe0b8b12f06577f2e802d34f6fa257e6137a995f6f4cbfc9ee50ed371\
+        0faf6e66f932c4c8d81d64343f429651328758b47d3dbc02c4042f0fff6946a50f4a49037a72bb550f3a7872\
+        363a83e1b9ee6469856c24eb4ef80b7535bcf99c0004f9015bf90150f84d846321163782115c82115db84031\
+        55e1427f85f10a5c9a7755877748041af1bcd8d474ec065eb33df57a97babf54bfd2103575fa829115d224c5\
+        23596b401065a97f74010610fce76382c0bf32f84984010203040101b840312c55512422cf9b8a4097e9a6ad\
+        79402e87a15ae909a4bfefa22398f03d20951933beea1e4dfa6f968212385e829f04c2d314fc2d4e255e0d3b\
+        c08792b069dbf8599020010db83c4d001500000000abcdef12820d05820d05b84038643200b172dcfef85749\
+        2156971f0e6aa2c538d8b74010f8e140811d53b98c765dd2d96126051913f44582e8c199ad7c6d6819e9a564\
+        83f637feaac9448aacf8599020010db885a308d313198a2e037073488203e78203e8b8408dcab8618c3253b5\
+        58d459da53bd8fa68935a719aff8b811197101a4b2b47dd2d47295286fc00cc081bb542d760717d1bdd6bec2\
+        c37cd72eca367d6dd3b9df738443b9a355010203b525a138aa34383fec3d2719a0";
+    /// The nodes of [`NEIGHBOURS_EIP8`] without the additional list elements, expiring at
+    /// 1136239445.
+    pub const NEIGHBOURS: &str = "9c078e169838cd2f8025dc45e09f7657cc3fc998861acc123e6eb1f7d4110161\
+        2d52627f4a09b105655f6774a73a0ae3a337c883ac37a4b6ceb5ba14640cdfb15191a7635116c540b60e598f\
+        e01a44dd73626d336eee069fafbe798e3976c3d80104f90158f90150f84d846321163782115c82115db84031\
+        55e1427f85f10a5c9a7755877748041af1bcd8d474ec065eb33df57a97babf54bfd2103575fa829115d224c5\
+        23596b401065a97f74010610fce76382c0bf32f84984010203040101b840312c55512422cf9b8a4097e9a6ad\
+        79402e87a15ae909a4bfefa22398f03d20951933beea1e4dfa6f968212385e829f04c2d314fc2d4e255e0d3b\
+        c08792b069dbf8599020010db83c4d001500000000abcdef12820d05820d05b84038643200b172dcfef85749\
+        2156971f0e6aa2c538d8b74010f8e140811d53b98c765dd2d96126051913f44582e8c199ad7c6d6819e9a564\
+        83f637feaac9448aacf8599020010db885a308d313198a2e037073488203e78203e8b8408dcab8618c3253b5\
+        58d459da53bd8fa68935a719aff8b811197101a4b2b47dd2d47295286fc00cc081bb542d760717d1bdd6bec2\
+        c37cd72eca367d6dd3b9df738443b9a355";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{golden::*, *};
+    use crate::disc::v4::packet::decode_packet;
+    use fastrlp::Encodable;
+
+    #[test]
+    fn golden_packets() {
+        assert_eq!(hex::encode(secret_key(1).secret_bytes()), SECRET_KEY_1);
+        assert_eq!(hex::encode(secret_key(2).secret_bytes()), SECRET_KEY_2);
+
+        let [ping, pong, neighbours] = packets();
+        assert_eq!(hex::encode(&ping), PING);
+        assert_eq!(hex::encode(&pong), PONG);
+        assert_eq!(hex::encode(&neighbours), NEIGHBOURS);
+    }
+
+    #[test]
+    fn packets_decode() {
+        let [ping, pong, neighbours] = packets();
+
+        let packet = decode_packet(&ping).unwrap();
+        assert_eq!(packet.node_id, node_id(&secret_key(1)));
+        match packet.message().unwrap() {
+            Message::Ping(ping) => {
+                assert_eq!(ping.from, endpoint(1));
+                assert_eq!(ping.to, endpoint(2));
+                assert_eq!(ping.expire, EXPIRE);
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+
+        let packet = decode_packet(&pong).unwrap();
+        assert_eq!(packet.node_id, node_id(&secret_key(2)));
+        match packet.message().unwrap() {
+            Message::Pong(pong) => assert_eq!(pong.echo, packet_hash(&ping)),
+            other => panic!("unexpected message {other:?}"),
+        }
+
+        let packet = decode_packet(&neighbours).unwrap();
+        match packet.message().unwrap() {
+            Message::Neighbours(neighbours) => {
+                assert_eq!(neighbours.nodes.len(), 1);
+                assert_eq!(neighbours.nodes[0].id, packet.node_id);
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+
+    fn interop_packet(hex: &str) -> (Vec<u8>, NodeId) {
+        let secret_key = SecretKey::from_slice(&hex::decode(interop::SECRET_KEY).unwrap()).unwrap();
+        (hex::decode(hex).unwrap(), node_id(&secret_key))
+    }
+
+    /// Message of the packet must survive re-encoding, and re-signing.
+    fn reencode(message: &Message) -> Vec<u8> {
+        let mut data = Vec::new();
+        message.encode(&mut data);
+        let decoded = Message::decode(message.packet_type(), &mut &data[..]).unwrap();
+        let mut again = Vec::new();
+        decoded.encode(&mut again);
+        assert_eq!(again, data);
+
+        let datagram = encode_packet(message, &secret_key(1));
+        assert_eq!(decode_packet(&datagram).unwrap().data, &data[..]);
+        data
+    }
+
+    #[test]
+    fn interop_ping() {
+        let (datagram, id) = interop_packet(interop::PING_ENR);
+        let packet = decode_packet(&datagram).unwrap();
+        assert_eq!(packet.node_id, id);
+        let message = packet.message().unwrap();
+        match &message {
+            Message::Ping(ping) => {
+                assert_eq!(ping.version, PROTOCOL_VERSION);
+                assert_eq!(ping.from.udp_addr(), "127.0.0.1:3322".parse().unwrap());
+                assert_eq!(ping.from.tcp_port, 5544);
+                assert_eq!(ping.to.udp_addr(), "[::1]:2222".parse().unwrap());
+                assert_eq!(ping.to.tcp_port, 3333);
+                assert_eq!(ping.expire, 1136239445);
+                assert_eq!(ping.enr_seq, Some(1));
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+        // Byte for byte, as nothing is left out.
+        assert_eq!(reencode(&message), packet.data);
+    }
+
+    #[test]
+    fn interop_ping_eip8() {
+        let (datagram, id) = interop_packet(interop::PING_EIP8);
+        let packet = decode_packet(&datagram).unwrap();
+        assert_eq!(packet.node_id, id);
+        let message = packet.message().unwrap();
+        match &message {
+            Message::Ping(ping) => {
+                assert_eq!(ping.version, 555);
+                assert_eq!(
+                    ping.from.udp_addr(),
+                    "[2001:db8:3c4d:15::abcd:ef12]:3322".parse().unwrap()
+                );
+                assert_eq!(ping.from.tcp_port, 5544);
+                assert_eq!(
+                    ping.to.udp_addr(),
+                    "[2001:db8:85a3:8d3:1319:8a2e:370:7348]:2222"
+                        .parse()
+                        .unwrap()
+                );
+                assert_eq!(ping.to.tcp_port, 33338);
+                assert_eq!(ping.expire, 1136239445);
+                // The additional element is a list, not a sequence number.
+                assert_eq!(ping.enr_seq, None);
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+        reencode(&message);
+    }
+
+    /// Nodes of the interop Neighbours vectors.
+    fn assert_interop_neighbours(neighbours: &NeighboursMessage) {
+        assert_eq!(neighbours.expire, 1136239445);
+        let endpoints = neighbours
+            .nodes
+            .iter()
+            .map(|node| (node.udp_addr(), node.tcp_port))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            endpoints,
+            [
+                ("99.33.22.55:4444".parse().unwrap(), 4445),
+                ("1.2.3.4:1".parse().unwrap(), 1),
+                ("[2001:db8:3c4d:15::abcd:ef12]:3333".parse().unwrap(), 3333),
+                (
+                    "[2001:db8:85a3:8d3:1319:8a2e:370:7348]:999"
+                        .parse()
+                        .unwrap(),
+                    1000
+                ),
+            ]
+        );
+        assert!(hex::encode(neighbours.nodes[0].id).starts_with("3155e1427f85"));
+    }
+
+    #[test]
+    fn interop_neighbours() {
+        let (datagram, id) = interop_packet(interop::NEIGHBOURS);
+        let packet = decode_packet(&datagram).unwrap();
+        assert_eq!(packet.node_id, id);
+        let message = packet.message().unwrap();
+        match &message {
+            Message::Neighbours(neighbours) => assert_interop_neighbours(neighbours),
+            other => panic!("unexpected message {other:?}"),
+        }
+        assert_eq!(reencode(&message), packet.data);
+    }
+
+    #[test]
+    fn interop_neighbours_eip8() {
+        let (datagram, id) = interop_packet(interop::NEIGHBOURS_EIP8);
+        let packet = decode_packet(&datagram).unwrap();
+        assert_eq!(packet.node_id, id);
+        let message = packet.message().unwrap();
+        match &message {
+            Message::Neighbours(neighbours) => assert_interop_neighbours(neighbours),
+            other => panic!("unexpected message {other:?}"),
+        }
+        reencode(&message);
+    }
+}
@@ -0,0 +1,256 @@
+//! Datagram transport of the discovery node.
+//!
+//! [`UdpSocket`] is what the node binds by default. [`MemoryNetwork`] connects any number of
+//! nodes within one process, with configurable latency and packet loss, so that the protocol
+//! can be tested without real sockets.
+
+use super::rng::SharedRng;
+use async_trait::async_trait;
+use auto_impl::auto_impl;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::{
+    collections::{hash_map, HashMap},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Mutex as AsyncMutex,
+    },
+    time::sleep,
+};
+
+/// Unreliable datagram socket.
+#[async_trait]
+#[auto_impl(&, Box, Arc)]
+pub trait Transport: Send + Sync + 'static {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize>;
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+#[async_trait]
+impl Transport for UdpSocket {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, target).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+type Datagram = (Bytes, SocketAddr);
+
+#[derive(Debug, Default)]
+struct MemoryNetworkInner {
+    latency: Duration,
+    drop_rate: f64,
+    rng: SharedRng,
+    endpoints: HashMap<SocketAddr, UnboundedSender<Datagram>>,
+}
+
+/// In-process network routing datagrams between [`MemoryTransport`]s by their address.
+///
+/// Datagrams to addresses nobody is bound to are silently lost, same as with UDP.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryNetwork(Arc<Mutex<MemoryNetworkInner>>);
+
+impl MemoryNetwork {
+    /// Delay every datagram by `latency`.
+    pub fn with_latency(self, latency: Duration) -> Self {
+        self.0.lock().latency = latency;
+        self
+    }
+
+    /// Lose every datagram with the probability of `drop_rate`, clamped to `0.0..=1.0`.
+    pub fn with_drop_rate(self, drop_rate: f64) -> Self {
+        self.0.lock().drop_rate = drop_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Decide which datagrams are lost with `rng`, e.g. a [seeded](SharedRng::seeded) one to
+    /// lose the same datagrams on every run.
+    pub fn with_rng(self, rng: SharedRng) -> Self {
+        self.0.lock().rng = rng;
+        self
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.0.lock().latency = latency;
+    }
+
+    pub fn set_drop_rate(&self, drop_rate: f64) {
+        self.0.lock().drop_rate = drop_rate.clamp(0.0, 1.0);
+    }
+
+    /// Attach a transport at `addr`, which is released once the transport is dropped.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryTransport> {
+        let (tx, rx) = unbounded_channel();
+        match self.0.lock().endpoints.entry(addr) {
+            hash_map::Entry::Occupied(_) => return Err(io::ErrorKind::AddrInUse.into()),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(tx);
+            }
+        }
+
+        Ok(MemoryTransport {
+            addr,
+            network: self.clone(),
+            rx: AsyncMutex::new(rx),
+        })
+    }
+}
+
+/// Endpoint of a [`MemoryNetwork`].
+#[derive(Debug)]
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    rx: AsyncMutex<UnboundedReceiver<Datagram>>,
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.0.lock().endpoints.remove(&self.addr);
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let (latency, tx) = {
+            let mut network = self.network.0.lock();
+            let drop_rate = network.drop_rate;
+            if network.rng.gen_bool(drop_rate) {
+                return Ok(buf.len());
+            }
+            (network.latency, network.endpoints.get(&target).cloned())
+        };
+
+        if let Some(tx) = tx {
+            let datagram = (Bytes::copy_from_slice(buf), self.addr);
+            if latency.is_zero() {
+                let _ = tx.send(datagram);
+            } else {
+                tokio::spawn(async move {
+                    sleep(latency).await;
+                    let _ = tx.send(datagram);
+                });
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (datagram, from) = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        // Excess bytes are discarded, as with UDP.
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok((len, from))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i], 30303))
+    }
+
+    #[tokio::test]
+    async fn memory_routing() {
+        let network = MemoryNetwork::default();
+        let a = network.bind(addr(1)).unwrap();
+        let b = network.bind(addr(2)).unwrap();
+        assert_eq!(
+            network.bind(addr(1)).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        a.send_to(b"hello", addr(2)).await.unwrap();
+        // Nobody is listening there.
+        a.send_to(b"lost", addr(3)).await.unwrap();
+
+        let mut buf = [0; 16];
+        let (len, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(from, addr(1));
+
+        drop(b);
+        network.bind(addr(2)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_latency_and_loss() {
+        let network = MemoryNetwork::default().with_latency(Duration::from_millis(50));
+        let a = network.bind(addr(1)).unwrap();
+        let b = network.bind(addr(2)).unwrap();
+        let mut buf = [0; 16];
+
+        let sent_at = tokio::time::Instant::now();
+        a.send_to(b"late", addr(2)).await.unwrap();
+        b.recv_from(&mut buf).await.unwrap();
+        assert!(sent_at.elapsed() >= Duration::from_millis(50));
+
+        network.set_drop_rate(1.0);
+        a.send_to(b"dropped", addr(2)).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), b.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn seeded_loss() {
+        async fn delivered(seed: u64) -> Vec<u8> {
+            let network = MemoryNetwork::default()
+                .with_drop_rate(0.5)
+                .with_rng(SharedRng::seeded(seed));
+            let a = network.bind(addr(1)).unwrap();
+            let b = network.bind(addr(2)).unwrap();
+            for i in 0..32 {
+                a.send_to(&[i], addr(2)).await.unwrap();
+            }
+            drop(a);
+
+            let mut delivered = Vec::new();
+            let mut buf = [0; 1];
+            while let Ok(Ok(_)) =
+                tokio::time::timeout(Duration::from_millis(10), b.recv_from(&mut buf)).await
+            {
+                delivered.push(buf[0]);
+            }
+            delivered
+        }
+
+        let first = delivered(7).await;
+        assert!(!first.is_empty() && first.len() < 32);
+        assert_eq!(delivered(7).await, first);
+    }
+}
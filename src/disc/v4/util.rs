@@ -1,16 +1,42 @@
 use super::NodeId;
+use crate::util::NodeIdExt;
+use chrono::Utc;
 use ethereum_types::H256;
 use secp256k1::{Message, PublicKey};
 use sha3::{Digest, Keccak256};
+use std::fmt::Debug;
 
 pub fn keccak256<T: AsRef<[u8]>>(data: T) -> H256 {
     H256::from_slice(Keccak256::digest(data.as_ref()).as_slice())
 }
 
+/// Hash of node IDs that [distances](super::kad::distance) in the table and lookups are
+/// measured with.
+///
+/// Only [`Keccak256Hasher`] places nodes the way other implementations do, others are meant
+/// for tests and simulated networks. Packet hashes and signatures are always Keccak-256, as
+/// required by the wire format.
+pub trait Hasher: Debug + Send + Sync + 'static {
+    fn hash(&self, data: &[u8]) -> H256;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Keccak256Hasher;
+
+impl Hasher for Keccak256Hasher {
+    fn hash(&self, data: &[u8]) -> H256 {
+        keccak256(data)
+    }
+}
+
 pub fn keccak256_message<T: AsRef<[u8]>>(data: T) -> Message {
     Message::from_slice(Keccak256::digest(data.as_ref()).as_slice()).unwrap()
 }
 
+pub fn unix_timestamp() -> u64 {
+    u64::try_from(Utc::now().timestamp()).expect("this would predate the protocol inception")
+}
+
 pub fn pk2id(pk: &PublicKey) -> NodeId {
-    NodeId::from_slice(&pk.serialize_uncompressed()[1..])
+    NodeId::from_public_key(pk)
 }
@@ -0,0 +1,350 @@
+//! Discovery v5 protocol messages.
+//!
+//! Each message is encoded as `message-type || rlp(message-data)` in the plaintext of an
+//! ordinary packet. WHOAREYOU is not a message, but a packet of its own that starts the
+//! session handshake.
+
+use crate::{disc::v4::message::Ip, types::Enr, util::decode_enr};
+use bytes::{BufMut, Bytes};
+use derive_more::*;
+use enum_primitive_derive::Primitive;
+use fastrlp::{Decodable, DecodeError, Encodable, Header, RlpDecodable, RlpEncodable};
+use num_traits::FromPrimitive;
+
+/// Maximum length of a request ID, in bytes.
+pub const MAX_REQUEST_ID_SIZE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Primitive)]
+pub enum MessageType {
+    Ping = 0x01,
+    Pong = 0x02,
+    FindNode = 0x03,
+    Nodes = 0x04,
+    TalkReq = 0x05,
+    TalkResp = 0x06,
+}
+
+/// Request ID assigned by the requester and echoed back in responses.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deref, From)]
+pub struct RequestId(pub Bytes);
+
+impl Encodable for RequestId {
+    fn encode(&self, out: &mut dyn BufMut) {
+        self.0.encode(out)
+    }
+
+    fn length(&self) -> usize {
+        self.0.length()
+    }
+}
+
+impl Decodable for RequestId {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let id = Bytes::decode(buf)?;
+        if id.len() > MAX_REQUEST_ID_SIZE {
+            return Err(DecodeError::Custom("request id too long"));
+        }
+        Ok(Self(id))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct PingMessage {
+    pub request_id: RequestId,
+    /// ENR sequence number of the sender.
+    pub enr_seq: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct PongMessage {
+    pub request_id: RequestId,
+    /// ENR sequence number of the sender.
+    pub enr_seq: u64,
+    /// IP address of the Ping sender, as observed by the recipient.
+    pub recipient_ip: Ip,
+    /// UDP port of the Ping sender, as observed by the recipient.
+    pub recipient_port: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct FindNodeMessage {
+    pub request_id: RequestId,
+    /// Log2 distances of the requested nodes. Distance 0 requests the recipient's own record.
+    pub distances: Vec<u16>,
+}
+
+/// Response to FindNode, possibly split into several messages.
+#[derive(Clone, Debug)]
+pub struct NodesMessage {
+    pub request_id: RequestId,
+    /// Total number of Nodes messages sent in response to the request.
+    pub total: u8,
+    pub enrs: Vec<Enr>,
+}
+
+impl NodesMessage {
+    fn enrs_length(&self) -> usize {
+        self.enrs.iter().map(|enr| rlp::encode(enr).len()).sum()
+    }
+
+    fn payload_length(&self) -> usize {
+        let enrs_length = self.enrs_length();
+        self.request_id.length()
+            + self.total.length()
+            + fastrlp::length_of_length(enrs_length)
+            + enrs_length
+    }
+}
+
+impl Encodable for NodesMessage {
+    fn encode(&self, out: &mut dyn BufMut) {
+        Header {
+            list: true,
+            payload_length: self.payload_length(),
+        }
+        .encode(out);
+        self.request_id.encode(out);
+        self.total.encode(out);
+        Header {
+            list: true,
+            payload_length: self.enrs_length(),
+        }
+        .encode(out);
+        for enr in &self.enrs {
+            out.put_slice(&rlp::encode(enr));
+        }
+    }
+
+    fn length(&self) -> usize {
+        let payload_length = self.payload_length();
+        fastrlp::length_of_length(payload_length) + payload_length
+    }
+}
+
+impl Decodable for NodesMessage {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let b = &mut &**buf;
+        let header = Header::decode(b)?;
+        if !header.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+        let started_len = b.len();
+
+        let request_id = RequestId::decode(b)?;
+        let total = u8::decode(b)?;
+
+        let enrs_header = Header::decode(b)?;
+        if !enrs_header.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+        if b.len() < enrs_header.payload_length {
+            return Err(DecodeError::InputTooShort);
+        }
+        let (mut enrs_buf, rest) = b.split_at(enrs_header.payload_length);
+        let mut enrs = Vec::new();
+        while !enrs_buf.is_empty() {
+            enrs.push(decode_enr(&mut enrs_buf)?);
+        }
+        *b = rest;
+
+        let consumed = started_len - b.len();
+        if consumed != header.payload_length {
+            return Err(DecodeError::ListLengthMismatch {
+                expected: header.payload_length,
+                got: consumed,
+            });
+        }
+
+        *buf = *b;
+
+        Ok(Self {
+            request_id,
+            total,
+            enrs,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct TalkReqMessage {
+    pub request_id: RequestId,
+    pub protocol: Bytes,
+    pub request: Bytes,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct TalkRespMessage {
+    pub request_id: RequestId,
+    /// Empty if the recipient does not speak the requested protocol.
+    pub response: Bytes,
+}
+
+/// Any discovery v5 message, tagged by its message type.
+#[derive(Clone, Debug)]
+pub enum Message {
+    Ping(PingMessage),
+    Pong(PongMessage),
+    FindNode(FindNodeMessage),
+    Nodes(NodesMessage),
+    TalkReq(TalkReqMessage),
+    TalkResp(TalkRespMessage),
+}
+
+impl Message {
+    /// Message type byte that precedes the message data in the packet plaintext.
+    pub fn message_type(&self) -> u8 {
+        let message_type = match self {
+            Self::Ping(_) => MessageType::Ping,
+            Self::Pong(_) => MessageType::Pong,
+            Self::FindNode(_) => MessageType::FindNode,
+            Self::Nodes(_) => MessageType::Nodes,
+            Self::TalkReq(_) => MessageType::TalkReq,
+            Self::TalkResp(_) => MessageType::TalkResp,
+        };
+        message_type as u8
+    }
+
+    pub fn request_id(&self) -> &RequestId {
+        match self {
+            Self::Ping(message) => &message.request_id,
+            Self::Pong(message) => &message.request_id,
+            Self::FindNode(message) => &message.request_id,
+            Self::Nodes(message) => &message.request_id,
+            Self::TalkReq(message) => &message.request_id,
+            Self::TalkResp(message) => &message.request_id,
+        }
+    }
+
+    pub fn decode(message_type: u8, buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        match MessageType::from_u8(message_type) {
+            Some(MessageType::Ping) => PingMessage::decode(buf).map(Self::Ping),
+            Some(MessageType::Pong) => PongMessage::decode(buf).map(Self::Pong),
+            Some(MessageType::FindNode) => FindNodeMessage::decode(buf).map(Self::FindNode),
+            Some(MessageType::Nodes) => NodesMessage::decode(buf).map(Self::Nodes),
+            Some(MessageType::TalkReq) => TalkReqMessage::decode(buf).map(Self::TalkReq),
+            Some(MessageType::TalkResp) => TalkRespMessage::decode(buf).map(Self::TalkResp),
+            None => Err(DecodeError::Custom("unknown message type")),
+        }
+    }
+}
+
+impl Encodable for Message {
+    fn encode(&self, out: &mut dyn BufMut) {
+        match self {
+            Self::Ping(message) => message.encode(out),
+            Self::Pong(message) => message.encode(out),
+            Self::FindNode(message) => message.encode(out),
+            Self::Nodes(message) => message.encode(out),
+            Self::TalkReq(message) => message.encode(out),
+            Self::TalkResp(message) => message.encode(out),
+        }
+    }
+
+    fn length(&self) -> usize {
+        match self {
+            Self::Ping(message) => message.length(),
+            Self::Pong(message) => message.length(),
+            Self::FindNode(message) => message.length(),
+            Self::Nodes(message) => message.length(),
+            Self::TalkReq(message) => message.length(),
+            Self::TalkResp(message) => message.length(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enr::EnrBuilder;
+    use hex_literal::hex;
+    use secp256k1::SecretKey;
+    use std::net::Ipv4Addr;
+
+    fn roundtrip(message: Message) -> Message {
+        let mut data = Vec::new();
+        message.encode(&mut data);
+        assert_eq!(data.len(), message.length());
+
+        let buf = &mut &data[..];
+        let decoded = Message::decode(message.message_type(), buf).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(decoded.message_type(), message.message_type());
+        assert_eq!(decoded.request_id(), message.request_id());
+        decoded
+    }
+
+    #[test]
+    fn ping_encoding() {
+        let message = PingMessage {
+            request_id: RequestId(Bytes::from_static(&hex!("00000001"))),
+            enr_seq: 2,
+        };
+
+        let mut data = Vec::new();
+        message.encode(&mut data);
+        assert_eq!(data, hex!("c6840000000102"));
+
+        assert!(matches!(
+            roundtrip(Message::Ping(message.clone())),
+            Message::Ping(decoded) if decoded == message
+        ));
+    }
+
+    #[test]
+    fn find_node_roundtrip() {
+        let message = FindNodeMessage {
+            request_id: RequestId(Bytes::from_static(&[0xff; MAX_REQUEST_ID_SIZE])),
+            distances: vec![0, 255, 256],
+        };
+        assert!(matches!(
+            roundtrip(Message::FindNode(message.clone())),
+            Message::FindNode(decoded) if decoded == message
+        ));
+    }
+
+    #[test]
+    fn nodes_roundtrip() {
+        let enrs = (0..3)
+            .map(|i| {
+                EnrBuilder::new("v4")
+                    .ip4(Ipv4Addr::new(10, 0, 0, i))
+                    .udp4(30303)
+                    .build(&SecretKey::new(&mut secp256k1::rand::thread_rng()))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        for enrs in [vec![], enrs] {
+            let message = NodesMessage {
+                request_id: RequestId(Bytes::from_static(&[1])),
+                total: 1,
+                enrs,
+            };
+            match roundtrip(Message::Nodes(message.clone())) {
+                Message::Nodes(decoded) => {
+                    assert_eq!(decoded.total, message.total);
+                    assert_eq!(
+                        decoded.enrs.iter().map(Enr::to_base64).collect::<Vec<_>>(),
+                        message.enrs.iter().map(Enr::to_base64).collect::<Vec<_>>()
+                    );
+                }
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn request_id_too_long() {
+        let message = PingMessage {
+            request_id: RequestId(Bytes::from_static(&[0; MAX_REQUEST_ID_SIZE + 1])),
+            enr_seq: 1,
+        };
+        let mut data = Vec::new();
+        message.encode(&mut data);
+
+        assert!(matches!(
+            PingMessage::decode(&mut &data[..]),
+            Err(DecodeError::Custom("request id too long"))
+        ));
+    }
+}
@@ -0,0 +1,15 @@
+//! Ethereum Node Discovery v5 implementation.
+
+pub mod message;
+pub mod session;
+
+use crate::util::keccak256;
+use ethereum_types::H256;
+use secp256k1::PublicKey;
+
+/// Node ID in discovery v5 is the keccak256 hash of the uncompressed public key.
+pub type NodeId = H256;
+
+pub fn node_id(public_key: &PublicKey) -> NodeId {
+    keccak256(&public_key.serialize_uncompressed()[1..])
+}
@@ -0,0 +1,753 @@
+//! Discovery v5 packet encoding and session handshake.
+//!
+//! Every packet is `masking-iv || masked-header || message`, where the header is masked with
+//! AES-CTR keyed by the destination node ID. Messages are encrypted with AES-GCM using session
+//! keys, which are agreed upon in a handshake: the recipient of an undecryptable packet answers
+//! with a WHOAREYOU challenge, and the initiator replies with a handshake packet carrying an
+//! ephemeral public key and a signature proving its identity.
+
+use super::{node_id, NodeId};
+use crate::types::Enr;
+use aes::{
+    cipher::{KeyIvInit, StreamCipher},
+    Aes128,
+};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128Gcm,
+};
+use ctr::Ctr128BE;
+use educe::Educe;
+use hkdf::Hkdf;
+use secp256k1::{ecdsa::Signature, Message, PublicKey, SecretKey, SECP256K1};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+pub const PROTOCOL_ID: &[u8; 6] = b"discv5";
+pub const PROTOCOL_VERSION: u16 = 1;
+
+pub const MASKING_IV_SIZE: usize = 16;
+pub const NONCE_SIZE: usize = 12;
+const STATIC_HEADER_SIZE: usize = PROTOCOL_ID.len() + 2 + 1 + NONCE_SIZE + 2;
+const ID_NONCE_SIZE: usize = 16;
+const WHOAREYOU_AUTH_DATA_SIZE: usize = ID_NONCE_SIZE + 8;
+const SIGNATURE_SIZE: usize = 64;
+const PUBLIC_KEY_SIZE: usize = 33;
+
+pub const MIN_PACKET_SIZE: usize = MASKING_IV_SIZE + STATIC_HEADER_SIZE + WHOAREYOU_AUTH_DATA_SIZE;
+pub const MAX_PACKET_SIZE: usize = 1280;
+
+const KEY_AGREEMENT_INFO: &[u8] = b"discovery v5 key agreement";
+const ID_SIGNATURE_TEXT: &[u8] = b"discovery v5 identity proof";
+
+pub type Nonce = [u8; NONCE_SIZE];
+pub type Key = [u8; 16];
+
+#[derive(Debug, Error)]
+pub enum PacketError {
+    #[error("packet too short: {0}")]
+    TooShort(usize),
+    #[error("packet too large: {0}")]
+    TooLarge(usize),
+    #[error("invalid protocol id")]
+    InvalidProtocolId,
+    #[error("unsupported protocol version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("invalid packet flag: {0}")]
+    InvalidFlag(u8),
+    #[error("invalid auth data")]
+    InvalidAuthData,
+    #[error("invalid node record in handshake")]
+    InvalidRecord,
+    #[error("invalid ephemeral public key")]
+    InvalidPublicKey(#[source] secp256k1::Error),
+    #[error("invalid id signature")]
+    InvalidSignature,
+    #[error("message decryption failed")]
+    DecryptionFailed,
+}
+
+/// Flag-specific part of the packet header.
+#[derive(Clone, Debug)]
+pub enum AuthData {
+    /// Ordinary message, encrypted with an established session key.
+    Message { src_id: NodeId },
+    /// Challenge sent in reply to a packet that could not be decrypted.
+    WhoAreYou {
+        id_nonce: [u8; ID_NONCE_SIZE],
+        /// Highest ENR sequence number of the challenged node known to the sender, 0 if none.
+        enr_seq: u64,
+    },
+    /// Reply to WHOAREYOU, establishing a new session.
+    Handshake {
+        src_id: NodeId,
+        id_signature: [u8; SIGNATURE_SIZE],
+        ephemeral_public_key: PublicKey,
+        /// Present if the challenger's copy of the record is outdated.
+        record: Option<Enr>,
+    },
+}
+
+impl AuthData {
+    pub fn flag(&self) -> u8 {
+        match self {
+            Self::Message { .. } => 0,
+            Self::WhoAreYou { .. } => 1,
+            Self::Handshake { .. } => 2,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Message { src_id } => src_id.as_bytes().to_vec(),
+            Self::WhoAreYou { id_nonce, enr_seq } => {
+                [&id_nonce[..], &enr_seq.to_be_bytes()].concat()
+            }
+            Self::Handshake {
+                src_id,
+                id_signature,
+                ephemeral_public_key,
+                record,
+            } => {
+                let mut out = src_id.as_bytes().to_vec();
+                out.push(SIGNATURE_SIZE as u8);
+                out.push(PUBLIC_KEY_SIZE as u8);
+                out.extend_from_slice(id_signature);
+                out.extend_from_slice(&ephemeral_public_key.serialize());
+                if let Some(record) = record {
+                    out.extend_from_slice(&rlp::encode(record));
+                }
+                out
+            }
+        }
+    }
+
+    fn decode(flag: u8, data: &[u8]) -> Result<Self, PacketError> {
+        match flag {
+            0 => {
+                if data.len() != NodeId::len_bytes() {
+                    return Err(PacketError::InvalidAuthData);
+                }
+                Ok(Self::Message {
+                    src_id: NodeId::from_slice(data),
+                })
+            }
+            1 => {
+                if data.len() != WHOAREYOU_AUTH_DATA_SIZE {
+                    return Err(PacketError::InvalidAuthData);
+                }
+                let (id_nonce, enr_seq) = data.split_at(ID_NONCE_SIZE);
+                Ok(Self::WhoAreYou {
+                    id_nonce: id_nonce.try_into().unwrap(),
+                    enr_seq: u64::from_be_bytes(enr_seq.try_into().unwrap()),
+                })
+            }
+            2 => {
+                let head_size = NodeId::len_bytes() + 2;
+                if data.len() < head_size {
+                    return Err(PacketError::InvalidAuthData);
+                }
+                let src_id = NodeId::from_slice(&data[..NodeId::len_bytes()]);
+                let signature_size = data[head_size - 2] as usize;
+                let public_key_size = data[head_size - 1] as usize;
+                if signature_size != SIGNATURE_SIZE
+                    || public_key_size != PUBLIC_KEY_SIZE
+                    || data.len() < head_size + signature_size + public_key_size
+                {
+                    return Err(PacketError::InvalidAuthData);
+                }
+
+                let (id_signature, rest) = data[head_size..].split_at(signature_size);
+                let (ephemeral_public_key, record) = rest.split_at(public_key_size);
+                let record = if record.is_empty() {
+                    None
+                } else {
+                    Some(rlp::decode::<Enr>(record).map_err(|_| PacketError::InvalidRecord)?)
+                };
+
+                Ok(Self::Handshake {
+                    src_id,
+                    id_signature: id_signature.try_into().unwrap(),
+                    ephemeral_public_key: PublicKey::from_slice(ephemeral_public_key)
+                        .map_err(PacketError::InvalidPublicKey)?,
+                    record,
+                })
+            }
+            other => Err(PacketError::InvalidFlag(other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PacketHeader {
+    pub nonce: Nonce,
+    pub auth_data: AuthData,
+}
+
+impl PacketHeader {
+    /// Unmasked `static-header || authdata`.
+    pub fn encode(&self) -> Vec<u8> {
+        let auth_data = self.auth_data.encode();
+
+        let mut out = Vec::with_capacity(STATIC_HEADER_SIZE + auth_data.len());
+        out.extend_from_slice(PROTOCOL_ID);
+        out.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        out.push(self.auth_data.flag());
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&(auth_data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&auth_data);
+        out
+    }
+}
+
+/// Packet with unmasked header.
+#[derive(Clone, Debug)]
+pub struct Packet {
+    pub masking_iv: [u8; MASKING_IV_SIZE],
+    pub header: PacketHeader,
+    /// Encoded header, as it was received.
+    pub header_data: Vec<u8>,
+    /// Encrypted message, empty for WHOAREYOU.
+    pub message: Vec<u8>,
+}
+
+impl Packet {
+    /// `masking-iv || header`, the associated data of message encryption.
+    ///
+    /// For WHOAREYOU packet it is the challenge data used in the handshake.
+    pub fn authenticated_data(&self) -> Vec<u8> {
+        [&self.masking_iv[..], &self.header_data].concat()
+    }
+}
+
+fn mask(dest_id: &NodeId, masking_iv: &[u8; MASKING_IV_SIZE]) -> Ctr128BE<Aes128> {
+    Ctr128BE::<Aes128>::new(dest_id[..16].into(), masking_iv.into())
+}
+
+/// Mask the header and assemble the packet.
+pub fn encode_packet(
+    dest_id: &NodeId,
+    masking_iv: [u8; MASKING_IV_SIZE],
+    header: &PacketHeader,
+    message: &[u8],
+) -> Vec<u8> {
+    let mut header = header.encode();
+    mask(dest_id, &masking_iv).apply_keystream(&mut header);
+
+    [&masking_iv[..], &header, message].concat()
+}
+
+/// Unmask and decode the header of a packet addressed to `local_id`.
+pub fn decode_packet(local_id: &NodeId, data: &[u8]) -> Result<Packet, PacketError> {
+    if data.len() < MIN_PACKET_SIZE {
+        return Err(PacketError::TooShort(data.len()));
+    }
+    if data.len() > MAX_PACKET_SIZE {
+        return Err(PacketError::TooLarge(data.len()));
+    }
+
+    let (masking_iv, data) = data.split_at(MASKING_IV_SIZE);
+    let masking_iv: [u8; MASKING_IV_SIZE] = masking_iv.try_into().unwrap();
+    let mut cipher = mask(local_id, &masking_iv);
+
+    let mut header_data = data[..STATIC_HEADER_SIZE].to_vec();
+    cipher.apply_keystream(&mut header_data);
+
+    let (protocol_id, static_header) = header_data.split_at(PROTOCOL_ID.len());
+    if protocol_id != PROTOCOL_ID {
+        return Err(PacketError::InvalidProtocolId);
+    }
+    let version = u16::from_be_bytes([static_header[0], static_header[1]]);
+    if version != PROTOCOL_VERSION {
+        return Err(PacketError::UnsupportedVersion(version));
+    }
+    let flag = static_header[2];
+    let nonce: Nonce = static_header[3..3 + NONCE_SIZE].try_into().unwrap();
+    let auth_data_size =
+        u16::from_be_bytes([static_header[3 + NONCE_SIZE], static_header[4 + NONCE_SIZE]]) as usize;
+
+    let header_size = STATIC_HEADER_SIZE + auth_data_size;
+    if data.len() < header_size {
+        return Err(PacketError::TooShort(MASKING_IV_SIZE + data.len()));
+    }
+    let mut auth_data = data[STATIC_HEADER_SIZE..header_size].to_vec();
+    cipher.apply_keystream(&mut auth_data);
+
+    let auth_data_decoded = AuthData::decode(flag, &auth_data)?;
+    header_data.extend_from_slice(&auth_data);
+
+    Ok(Packet {
+        masking_iv,
+        header: PacketHeader {
+            nonce,
+            auth_data: auth_data_decoded,
+        },
+        header_data,
+        message: data[header_size..].to_vec(),
+    })
+}
+
+/// ECDH shared secret in the compressed point form.
+pub fn ecdh(public_key: &PublicKey, secret_key: &SecretKey) -> [u8; PUBLIC_KEY_SIZE] {
+    let point = secp256k1::ecdh::shared_secret_point(public_key, secret_key);
+
+    let mut secret = [0; PUBLIC_KEY_SIZE];
+    secret[0] = 0x02 | (point[63] & 1);
+    secret[1..].copy_from_slice(&point[..32]);
+    secret
+}
+
+/// Session keys agreed upon in the handshake.
+#[derive(Clone, Copy, Educe)]
+#[educe(Debug)]
+pub struct SessionKeys {
+    #[educe(Debug(ignore))]
+    pub initiator_key: Key,
+    #[educe(Debug(ignore))]
+    pub recipient_key: Key,
+}
+
+/// Derive session keys from the ECDH of one side's ephemeral key with the other side's static key.
+pub fn derive_keys(
+    secret_key: &SecretKey,
+    public_key: &PublicKey,
+    initiator_id: &NodeId,
+    recipient_id: &NodeId,
+    challenge_data: &[u8],
+) -> SessionKeys {
+    let info = [
+        KEY_AGREEMENT_INFO,
+        initiator_id.as_bytes(),
+        recipient_id.as_bytes(),
+    ]
+    .concat();
+
+    let mut key_data = [0; 32];
+    Hkdf::<Sha256>::new(Some(challenge_data), &ecdh(public_key, secret_key))
+        .expand(&info, &mut key_data)
+        .expect("32 bytes is a valid output length");
+
+    SessionKeys {
+        initiator_key: key_data[..16].try_into().unwrap(),
+        recipient_key: key_data[16..].try_into().unwrap(),
+    }
+}
+
+fn id_signature_input(
+    challenge_data: &[u8],
+    ephemeral_public_key: &PublicKey,
+    recipient_id: &NodeId,
+) -> Message {
+    let digest = Sha256::new()
+        .chain_update(ID_SIGNATURE_TEXT)
+        .chain_update(challenge_data)
+        .chain_update(ephemeral_public_key.serialize())
+        .chain_update(recipient_id)
+        .finalize();
+    Message::from_slice(&digest).expect("SHA256 digest is 32 bytes")
+}
+
+/// Sign the challenge, proving ownership of the static `secret_key` to the recipient.
+pub fn sign_id_nonce(
+    secret_key: &SecretKey,
+    challenge_data: &[u8],
+    ephemeral_public_key: &PublicKey,
+    recipient_id: &NodeId,
+) -> [u8; SIGNATURE_SIZE] {
+    SECP256K1
+        .sign_ecdsa(
+            &id_signature_input(challenge_data, ephemeral_public_key, recipient_id),
+            secret_key,
+        )
+        .serialize_compact()
+}
+
+pub fn verify_id_signature(
+    public_key: &PublicKey,
+    id_signature: &[u8; SIGNATURE_SIZE],
+    challenge_data: &[u8],
+    ephemeral_public_key: &PublicKey,
+    recipient_id: &NodeId,
+) -> bool {
+    let signature = match Signature::from_compact(id_signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    SECP256K1
+        .verify_ecdsa(
+            &id_signature_input(challenge_data, ephemeral_public_key, recipient_id),
+            &signature,
+            public_key,
+        )
+        .is_ok()
+}
+
+pub fn encrypt_message(key: &Key, nonce: &Nonce, message: &[u8], ad: &[u8]) -> Vec<u8> {
+    Aes128Gcm::new(key.into())
+        .encrypt(
+            nonce.into(),
+            Payload {
+                msg: message,
+                aad: ad,
+            },
+        )
+        .expect("message is shorter than AES-GCM limit")
+}
+
+pub fn decrypt_message(
+    key: &Key,
+    nonce: &Nonce,
+    message: &[u8],
+    ad: &[u8],
+) -> Result<Vec<u8>, PacketError> {
+    Aes128Gcm::new(key.into())
+        .decrypt(
+            nonce.into(),
+            Payload {
+                msg: message,
+                aad: ad,
+            },
+        )
+        .map_err(|_| PacketError::DecryptionFailed)
+}
+
+/// Established session with a remote node.
+#[derive(Clone, Educe)]
+#[educe(Debug)]
+pub struct Session {
+    #[educe(Debug(ignore))]
+    write_key: Key,
+    #[educe(Debug(ignore))]
+    read_key: Key,
+}
+
+impl Session {
+    /// Answer the WHOAREYOU `challenge_data` received from the remote node.
+    ///
+    /// Returns the session and auth data of the handshake packet to send the next message in.
+    /// `record` should be included if the challenge's `enr_seq` is lower than the local one.
+    pub fn initiate(
+        secret_key: &SecretKey,
+        remote_public_key: &PublicKey,
+        challenge_data: &[u8],
+        record: Option<Enr>,
+    ) -> (Self, AuthData) {
+        let local_id = node_id(&PublicKey::from_secret_key(SECP256K1, secret_key));
+        let remote_id = node_id(remote_public_key);
+
+        let ephemeral_secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let ephemeral_public_key = PublicKey::from_secret_key(SECP256K1, &ephemeral_secret_key);
+
+        let keys = derive_keys(
+            &ephemeral_secret_key,
+            remote_public_key,
+            &local_id,
+            &remote_id,
+            challenge_data,
+        );
+        let id_signature = sign_id_nonce(
+            secret_key,
+            challenge_data,
+            &ephemeral_public_key,
+            &remote_id,
+        );
+
+        (
+            Self {
+                write_key: keys.initiator_key,
+                read_key: keys.recipient_key,
+            },
+            AuthData::Handshake {
+                src_id: local_id,
+                id_signature,
+                ephemeral_public_key,
+                record,
+            },
+        )
+    }
+
+    /// Verify the handshake sent by `remote_public_key` in reply to our WHOAREYOU `challenge_data`.
+    pub fn accept(
+        secret_key: &SecretKey,
+        remote_public_key: &PublicKey,
+        challenge_data: &[u8],
+        id_signature: &[u8; SIGNATURE_SIZE],
+        ephemeral_public_key: &PublicKey,
+    ) -> Result<Self, PacketError> {
+        let local_id = node_id(&PublicKey::from_secret_key(SECP256K1, secret_key));
+        let remote_id = node_id(remote_public_key);
+
+        if !verify_id_signature(
+            remote_public_key,
+            id_signature,
+            challenge_data,
+            ephemeral_public_key,
+            &local_id,
+        ) {
+            return Err(PacketError::InvalidSignature);
+        }
+
+        let keys = derive_keys(
+            secret_key,
+            ephemeral_public_key,
+            &remote_id,
+            &local_id,
+            challenge_data,
+        );
+
+        Ok(Self {
+            write_key: keys.recipient_key,
+            read_key: keys.initiator_key,
+        })
+    }
+
+    /// Encrypt `message` (message type followed by RLP message data) into a packet.
+    pub fn encrypt(
+        &self,
+        dest_id: &NodeId,
+        masking_iv: [u8; MASKING_IV_SIZE],
+        header: &PacketHeader,
+        message: &[u8],
+    ) -> Vec<u8> {
+        let ad = [&masking_iv[..], &header.encode()].concat();
+        let message = encrypt_message(&self.write_key, &header.nonce, message, &ad);
+        encode_packet(dest_id, masking_iv, header, &message)
+    }
+
+    /// Decrypt the message of a packet received from the remote node.
+    pub fn decrypt(&self, packet: &Packet) -> Result<Vec<u8>, PacketError> {
+        decrypt_message(
+            &self.read_key,
+            &packet.header.nonce,
+            &packet.message,
+            &packet.authenticated_data(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    // Test vectors from https://github.com/ethereum/devp2p/blob/master/discv5/discv5-wire-test-vectors.md
+
+    const NODE_A_KEY: [u8; 32] =
+        hex!("eef77acb6c6a6eebc5b363a475ac583ec7eccdb42b6481424c60f59aa326547f");
+    const NODE_B_KEY: [u8; 32] =
+        hex!("66fb62bfbd66b9177a138c1e5cddbe4f7c30c343e94e68df8769459cb1cde628");
+    const NODE_A_ID: [u8; 32] =
+        hex!("aaaa8419e9f49d0083561b48287df592939a8d19947d8c0ef88f2a4856a69fbb");
+    const NODE_B_ID: [u8; 32] =
+        hex!("bbbb9d047f0488c0b5a93c1c3f2d8bafc7c8ff337024a55434a0d0555de64db9");
+
+    const CHALLENGE_DATA: [u8; 63] = hex!("000000000000000000000000000000006469736376350001010102030405060708090a0b0c00180102030405060708090a0b0c0d0e0f100000000000000000");
+
+    fn public_key(secret_key: &[u8]) -> PublicKey {
+        PublicKey::from_secret_key(SECP256K1, &SecretKey::from_slice(secret_key).unwrap())
+    }
+
+    #[test]
+    fn node_ids() {
+        assert_eq!(node_id(&public_key(&NODE_A_KEY)), NodeId::from(NODE_A_ID));
+        assert_eq!(node_id(&public_key(&NODE_B_KEY)), NodeId::from(NODE_B_ID));
+    }
+
+    #[test]
+    fn ecdh_vector() {
+        let public_key = PublicKey::from_slice(&hex!(
+            "039961e4c2356d61bedb83052c115d311acb3a96f5777296dcf297351130266231"
+        ))
+        .unwrap();
+        let secret_key = SecretKey::from_slice(&hex!(
+            "fb757dc581730490a1d7a00deea65e9b1936924caaea8f44d476014856b68736"
+        ))
+        .unwrap();
+
+        assert_eq!(
+            ecdh(&public_key, &secret_key),
+            hex!("033b11a2a1f214567e1537ce5e509ffd9b21373247f2a3ff6841f4976f53165e7e")
+        );
+    }
+
+    #[test]
+    fn key_derivation_vector() {
+        let ephemeral_key = SecretKey::from_slice(&hex!(
+            "fb757dc581730490a1d7a00deea65e9b1936924caaea8f44d476014856b68736"
+        ))
+        .unwrap();
+        let dest_public_key = PublicKey::from_slice(&hex!(
+            "0317931e6e0840220642f230037d285d122bc59063221ef3226b1f403ddc69ca91"
+        ))
+        .unwrap();
+
+        let keys = derive_keys(
+            &ephemeral_key,
+            &dest_public_key,
+            &NODE_A_ID.into(),
+            &NODE_B_ID.into(),
+            &CHALLENGE_DATA,
+        );
+        assert_eq!(keys.initiator_key, hex!("dccc82d81bd610f4f76d3ebe97a40571"));
+        assert_eq!(keys.recipient_key, hex!("ac74bb8773749920b0d3a8881c173ec5"));
+    }
+
+    #[test]
+    fn id_signature_vector() {
+        let static_key = SecretKey::from_slice(&hex!(
+            "fb757dc581730490a1d7a00deea65e9b1936924caaea8f44d476014856b68736"
+        ))
+        .unwrap();
+        let ephemeral_public_key = PublicKey::from_slice(&hex!(
+            "039961e4c2356d61bedb83052c115d311acb3a96f5777296dcf297351130266231"
+        ))
+        .unwrap();
+        let id_signature = hex!("94852a1e2318c4e5e9d422c98eaf19d1d90d876b29cd06ca7cb7546d0fff7b484fe86c09a064fe72bdbef73ba8e9c34df0cd2b53e9d65528c2c7f336d5dfc6e6");
+
+        assert_eq!(
+            sign_id_nonce(
+                &static_key,
+                &CHALLENGE_DATA,
+                &ephemeral_public_key,
+                &NODE_B_ID.into()
+            ),
+            id_signature
+        );
+        assert!(verify_id_signature(
+            &PublicKey::from_secret_key(SECP256K1, &static_key),
+            &id_signature,
+            &CHALLENGE_DATA,
+            &ephemeral_public_key,
+            &NODE_B_ID.into(),
+        ));
+        assert!(!verify_id_signature(
+            &PublicKey::from_secret_key(SECP256K1, &static_key),
+            &id_signature,
+            &CHALLENGE_DATA,
+            &ephemeral_public_key,
+            &NODE_A_ID.into(),
+        ));
+    }
+
+    #[test]
+    fn encryption_vector() {
+        let key = hex!("9f2d77db7004bf8a1a85107ac686990b");
+        let nonce = hex!("27b5af763c446acd2749fe8e");
+        let ad = hex!("93a7400fa0d6a694ebc24d5cf570f65d04215b6ac00757875e3f3a5f42107903");
+        let message = hex!("01c20101");
+        let ciphertext = hex!("a5d12a2d94b8ccb3ba55558229867dc13bfa3648");
+
+        assert_eq!(encrypt_message(&key, &nonce, &message, &ad), ciphertext);
+        assert_eq!(
+            decrypt_message(&key, &nonce, &ciphertext, &ad).unwrap(),
+            message
+        );
+        assert!(matches!(
+            decrypt_message(&key, &nonce, &ciphertext, &ad[1..]),
+            Err(PacketError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn whoareyou_challenge_data() {
+        let header = PacketHeader {
+            nonce: hex!("0102030405060708090a0b0c"),
+            auth_data: AuthData::WhoAreYou {
+                id_nonce: hex!("0102030405060708090a0b0c0d0e0f10"),
+                enr_seq: 0,
+            },
+        };
+        let dest_id = NodeId::from(NODE_A_ID);
+
+        let packet = encode_packet(&dest_id, [0; MASKING_IV_SIZE], &header, &[]);
+        let decoded = decode_packet(&dest_id, &packet).unwrap();
+        assert_eq!(decoded.authenticated_data(), CHALLENGE_DATA);
+        assert!(decoded.message.is_empty());
+
+        // Header masked for another node is garbage.
+        assert!(decode_packet(&NODE_B_ID.into(), &packet).is_err());
+    }
+
+    #[test]
+    fn handshake() {
+        let key_a = SecretKey::from_slice(&NODE_A_KEY).unwrap();
+        let key_b = SecretKey::from_slice(&NODE_B_KEY).unwrap();
+        let (id_a, id_b) = (NodeId::from(NODE_A_ID), NodeId::from(NODE_B_ID));
+
+        // B could not decrypt A's packet and challenges it.
+        let whoareyou = encode_packet(
+            &id_a,
+            rand::random(),
+            &PacketHeader {
+                nonce: rand::random(),
+                auth_data: AuthData::WhoAreYou {
+                    id_nonce: rand::random(),
+                    enr_seq: 0,
+                },
+            },
+            &[],
+        );
+        let challenge_data = decode_packet(&id_a, &whoareyou)
+            .unwrap()
+            .authenticated_data();
+
+        // A answers with handshake.
+        let (session_a, auth_data) =
+            Session::initiate(&key_a, &public_key(&NODE_B_KEY), &challenge_data, None);
+        let handshake = session_a.encrypt(
+            &id_b,
+            rand::random(),
+            &PacketHeader {
+                nonce: rand::random(),
+                auth_data,
+            },
+            &hex!("01c20101"),
+        );
+
+        // B verifies it and decrypts the message.
+        let packet = decode_packet(&id_b, &handshake).unwrap();
+        let (id_signature, ephemeral_public_key) = match &packet.header.auth_data {
+            AuthData::Handshake {
+                src_id,
+                id_signature,
+                ephemeral_public_key,
+                record: None,
+            } if *src_id == id_a => (*id_signature, *ephemeral_public_key),
+            other => panic!("unexpected auth data: {:?}", other),
+        };
+        let session_b = Session::accept(
+            &key_b,
+            &public_key(&NODE_A_KEY),
+            &challenge_data,
+            &id_signature,
+            &ephemeral_public_key,
+        )
+        .unwrap();
+        assert_eq!(session_b.decrypt(&packet).unwrap(), hex!("01c20101"));
+
+        // Signature made for another challenge is rejected.
+        assert!(matches!(
+            Session::accept(
+                &key_b,
+                &public_key(&NODE_A_KEY),
+                &CHALLENGE_DATA,
+                &id_signature,
+                &ephemeral_public_key,
+            ),
+            Err(PacketError::InvalidSignature)
+        ));
+
+        // Session is established both ways.
+        let reply = session_b.encrypt(
+            &id_a,
+            rand::random(),
+            &PacketHeader {
+                nonce: rand::random(),
+                auth_data: AuthData::Message { src_id: id_b },
+            },
+            &hex!("02c20102"),
+        );
+        let packet = decode_packet(&id_a, &reply).unwrap();
+        assert_eq!(session_a.decrypt(&packet).unwrap(), hex!("02c20102"));
+    }
+}
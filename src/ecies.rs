@@ -1,4 +1,9 @@
 //! ECIES protocol implementation
+//!
+//! Implements the RLPx auth/ack handshake, including the EIP-8 encoding, and the frame
+//! encryption that follows it. [`ECIESStream`] performs the handshake over any transport,
+//! as a client given the remote node ID (e.g. learned through discovery) or as a server,
+//! and then reads and writes encrypted frames through [`ECIESCodec`].
 
 mod algorithm;
 mod proto;
@@ -1,7 +1,7 @@
 use crate::{
     errors::ECIESError,
     mac::{HeaderBytes, MAC},
-    util::{hmac_sha256, id2pk, pk2id, sha256},
+    util::{hmac_sha256, id2pk, pk2id, sha256, NodeIdExt},
     PeerId,
 };
 use aes::{cipher::StreamCipher, Aes128, Aes256};
@@ -84,6 +84,7 @@ pub struct ECIES {
     remote_init_msg: Option<Bytes>,
 
     body_size: Option<usize>,
+    pub(crate) max_body_size: usize,
 }
 
 fn split_at_mut<T>(arr: &mut [T], mid: usize) -> Result<(&mut [T], &mut [T]), ECIESError> {
@@ -104,7 +105,7 @@ impl ECIES {
         ephemeral_secret_key: SecretKey,
     ) -> Result<Self, ECIESError> {
         let public_key = PublicKey::from_secret_key(SECP256K1, &secret_key);
-        let remote_public_key = id2pk(remote_id)?;
+        let remote_public_key = remote_id.to_public_key()?;
         let ephemeral_public_key = PublicKey::from_secret_key(SECP256K1, &ephemeral_secret_key);
 
         Ok(Self {
@@ -124,6 +125,7 @@ impl ECIES {
             remote_id: Some(remote_id),
 
             body_size: None,
+            max_body_size: MAX_BODY_SIZE,
             egress_aes: None,
             ingress_aes: None,
             egress_mac: None,
@@ -131,6 +133,8 @@ impl ECIES {
         })
     }
 
+    /// The nonce and ephemeral key are secrets, so they always come from the thread RNG,
+    /// which is cryptographically secure. Tests pass fixed ones to [`Self::new_static_client`].
     pub fn new_client(secret_key: SecretKey, remote_id: PeerId) -> Result<Self, ECIESError> {
         let nonce = H256::random();
         let ephemeral_secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
@@ -163,6 +167,7 @@ impl ECIES {
             remote_id: None,
 
             body_size: None,
+            max_body_size: MAX_BODY_SIZE,
             egress_aes: None,
             ingress_aes: None,
             egress_mac: None,
@@ -170,6 +175,7 @@ impl ECIES {
         })
     }
 
+    /// Same as [`Self::new_client`], tests pass fixed secrets to [`Self::new_static_server`].
     pub fn new_server(secret_key: SecretKey) -> Result<Self, ECIESError> {
         let nonce = H256::random();
         let ephemeral_secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
@@ -318,7 +324,11 @@ impl ECIES {
         )?;
         let remote_id = data.get_next()?.ok_or(ECIESError::InvalidAuthData)?;
         self.remote_id = Some(remote_id);
-        self.remote_public_key = Some(id2pk(remote_id).context("failed to parse peer id")?);
+        self.remote_public_key = Some(
+            remote_id
+                .to_public_key()
+                .context("failed to parse peer id")?,
+        );
         self.remote_nonce = Some(data.get_next()?.ok_or(ECIESError::InvalidAuthData)?);
 
         let x = ecdh_x(&self.remote_public_key.unwrap(), &self.secret_key);
@@ -511,12 +521,12 @@ impl ECIES {
         let body_size = usize::try_from(header.as_slice().read_uint::<BigEndian>(3)?)
             .context("excessive body len")?;
 
-        if body_size > MAX_BODY_SIZE {
+        if body_size > self.max_body_size {
             return Err(ECIESError::IO(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 format!(
                     "body size ({}) exceeds limit ({} bytes)",
-                    body_size, MAX_BODY_SIZE
+                    body_size, self.max_body_size
                 ),
             )));
         }
@@ -1,4 +1,4 @@
-use super::algorithm::{ECIES, MAX_BODY_SIZE};
+use super::algorithm::ECIES;
 use crate::{errors::ECIESError, transport::Transport, types::PeerId};
 use anyhow::{bail, Context as _};
 use bytes::{Bytes, BytesMut};
@@ -62,6 +62,14 @@ impl ECIESCodec {
             state: ECIESState::Auth,
         })
     }
+
+    /// Set the maximum size of a message body, both incoming and outgoing.
+    /// Incoming frames declaring a larger body are rejected before it is read.
+    #[must_use]
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.ecies.max_body_size = max_body_size;
+        self
+    }
 }
 
 impl Decoder for ECIESCodec {
@@ -154,13 +162,13 @@ impl Encoder<EgressECIESValue> for ECIESCodec {
                 Ok(())
             }
             EgressECIESValue::Message(data) => {
-                if data.len() > MAX_BODY_SIZE {
+                if data.len() > self.ecies.max_body_size {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidInput,
                         format!(
                             "body size ({}) exceeds limit ({} bytes)",
                             data.len(),
-                            MAX_BODY_SIZE
+                            self.ecies.max_body_size
                         ),
                     ));
                 }
@@ -295,3 +303,94 @@ where
         Pin::new(&mut self.get_mut().stream).poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::pk2id;
+    use secp256k1::{PublicKey, SECP256K1};
+
+    fn handshake() -> (ECIESCodec, ECIESCodec) {
+        let server_secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let server_id = pk2id(&PublicKey::from_secret_key(SECP256K1, &server_secret_key));
+        let client_secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+
+        let mut server = ECIESCodec::new_server(server_secret_key).unwrap();
+        let mut client = ECIESCodec::new_client(client_secret_key, server_id).unwrap();
+
+        let mut buf = BytesMut::new();
+        client.encode(EgressECIESValue::Auth, &mut buf).unwrap();
+        assert!(matches!(
+            server.decode(&mut buf).unwrap(),
+            Some(IngressECIESValue::AuthReceive(_))
+        ));
+
+        server.encode(EgressECIESValue::Ack, &mut buf).unwrap();
+        assert_eq!(
+            client.decode(&mut buf).unwrap(),
+            Some(IngressECIESValue::Ack)
+        );
+        assert!(buf.is_empty());
+
+        (client, server)
+    }
+
+    #[test]
+    fn multiple_frames_roundtrip() {
+        let (mut client, mut server) = handshake();
+
+        let messages = [
+            Bytes::from_static(&[0x01]),
+            Bytes::from(vec![0xab; 16]),
+            Bytes::from(vec![0xcd; 1000]),
+        ];
+
+        let mut buf = BytesMut::new();
+        for message in &messages {
+            client
+                .encode(EgressECIESValue::Message(message.clone()), &mut buf)
+                .unwrap();
+        }
+
+        // Feed frames byte by byte to exercise partial reads.
+        let mut received = Vec::new();
+        let mut input = BytesMut::new();
+        for byte in buf {
+            input.extend_from_slice(&[byte]);
+            if let Some(value) = server.decode(&mut input).unwrap() {
+                received.push(value);
+            }
+        }
+
+        assert_eq!(
+            received,
+            messages
+                .into_iter()
+                .map(IngressECIESValue::Message)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn oversized_frame_rejected() {
+        let (mut client, server) = handshake();
+        let mut server = server.with_max_body_size(16);
+
+        let mut buf = BytesMut::new();
+        client
+            .encode(
+                EgressECIESValue::Message(Bytes::from(vec![0; 17])),
+                &mut buf,
+            )
+            .unwrap();
+        assert!(server.decode(&mut buf).is_err());
+
+        let mut client = client.with_max_body_size(16);
+        assert!(client
+            .encode(
+                EgressECIESValue::Message(Bytes::from(vec![0; 17])),
+                &mut buf
+            )
+            .is_err());
+    }
+}
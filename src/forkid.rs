@@ -0,0 +1,342 @@
+//! EIP-2124 fork identifier, announced in the `eth` entry of node records (EIP-2364 / EIP-868)
+//! so that nodes of other chains and nodes which missed a fork can be told apart before
+//! connecting to them.
+
+use crate::types::Enr;
+use bytes::BufMut;
+use derive_more::*;
+use ethereum_types::H256;
+use fastrlp::{Decodable, DecodeError, Encodable, Header};
+
+/// ENR key of the entry carrying the fork ID.
+pub const ENR_KEY: &str = "eth";
+
+/// CRC32 checksum of the genesis hash and the blocks of all forks passed so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deref, From)]
+pub struct ForkHash(pub [u8; 4]);
+
+impl ForkHash {
+    fn genesis(genesis: H256) -> Self {
+        Self(crc32fast::hash(genesis.as_bytes()).to_be_bytes())
+    }
+
+    fn with_fork(self, block: u64) -> Self {
+        let mut hasher = crc32fast::Hasher::new_with_initial(u32::from_be_bytes(self.0));
+        hasher.update(&block.to_be_bytes());
+        Self(hasher.finalize().to_be_bytes())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ForkId {
+    pub hash: ForkHash,
+    /// Block number of the next fork that is known, or 0 if there is none.
+    pub next: u64,
+}
+
+impl ForkId {
+    /// Fork hash is encoded as a 4-byte string.
+    const HASH_LENGTH: usize = 1 + 4;
+
+    fn payload_length(&self) -> usize {
+        Self::HASH_LENGTH + self.next.length()
+    }
+}
+
+impl Encodable for ForkId {
+    fn encode(&self, out: &mut dyn BufMut) {
+        Header {
+            list: true,
+            payload_length: self.payload_length(),
+        }
+        .encode(out);
+        Header {
+            list: false,
+            payload_length: 4,
+        }
+        .encode(out);
+        out.put_slice(&self.hash.0);
+        self.next.encode(out);
+    }
+
+    fn length(&self) -> usize {
+        let payload_length = self.payload_length();
+        fastrlp::length_of_length(payload_length) + payload_length
+    }
+}
+
+impl Decodable for ForkId {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let b = &mut &**buf;
+        let header = Header::decode(b)?;
+        if !header.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+        let started_len = b.len();
+
+        let hash_header = Header::decode(b)?;
+        if hash_header.list {
+            return Err(DecodeError::UnexpectedList);
+        }
+        if hash_header.payload_length != 4 {
+            return Err(DecodeError::UnexpectedLength);
+        }
+        if b.len() < 4 {
+            return Err(DecodeError::InputTooShort);
+        }
+        let mut hash = [0; 4];
+        hash.copy_from_slice(&b[..4]);
+        *b = &b[4..];
+        let next = u64::decode(b)?;
+
+        let consumed = started_len - b.len();
+        if consumed != header.payload_length {
+            return Err(DecodeError::ListLengthMismatch {
+                expected: header.payload_length,
+                got: consumed,
+            });
+        }
+
+        *buf = *b;
+
+        Ok(Self {
+            hash: ForkHash(hash),
+            next,
+        })
+    }
+}
+
+/// Value of the `eth` ENR entry: a list starting with the fork ID, to be extended by
+/// future versions. Add it to a record with [`enr::EnrBuilder::add_value`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnrForkIdEntry(pub ForkId);
+
+impl rlp::Encodable for EnrForkIdEntry {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        let mut fork_id = Vec::new();
+        self.0.encode(&mut fork_id);
+        s.begin_list(1);
+        s.append_raw(&fork_id, 1);
+    }
+}
+
+impl Decodable for EnrForkIdEntry {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let b = &mut &**buf;
+        let header = Header::decode(b)?;
+        if !header.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+        if b.len() < header.payload_length {
+            return Err(DecodeError::InputTooShort);
+        }
+        let (payload, rest) = b.split_at(header.payload_length);
+        // Trailing elements are reserved for extensions and ignored.
+        let fork_id = ForkId::decode(&mut &*payload)?;
+        *buf = rest;
+
+        Ok(Self(fork_id))
+    }
+}
+
+/// Access to the fork ID of a node record.
+pub trait EnrForkIdExt {
+    /// Fork ID from the `eth` entry, `None` if the record has no such entry.
+    fn fork_id(&self) -> Option<Result<ForkId, DecodeError>>;
+}
+
+impl EnrForkIdExt for Enr {
+    fn fork_id(&self) -> Option<Result<ForkId, DecodeError>> {
+        self.get_raw_rlp(ENR_KEY)
+            .map(|mut entry| EnrForkIdEntry::decode(&mut entry).map(|entry| entry.0))
+    }
+}
+
+/// Outcome of checking a remote fork ID against ours.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForkIdValidation {
+    /// Same chain, and either side may still be syncing up to the other.
+    Compatible,
+    /// Remote is on a past fork of our chain and is not aware of the next fork,
+    /// so its software needs an update.
+    Stale,
+    /// Remote is on another chain, or we missed a fork it passed.
+    Incompatible,
+}
+
+/// Fork schedule of the local chain, validating fork IDs of remote nodes as per EIP-2124.
+#[derive(Clone, Debug)]
+pub struct ForkFilter {
+    /// Blocks of the forks in ascending order, without the genesis.
+    forks: Vec<u64>,
+    /// `hashes[i]` is the fork hash after passing first `i` forks.
+    hashes: Vec<ForkHash>,
+    head: u64,
+}
+
+impl ForkFilter {
+    /// Forks activated at the genesis block are ignored, as are duplicates.
+    pub fn new(genesis: H256, head: u64, forks: impl IntoIterator<Item = u64>) -> Self {
+        let mut forks = forks
+            .into_iter()
+            .filter(|block| *block != 0)
+            .collect::<Vec<_>>();
+        forks.sort_unstable();
+        forks.dedup();
+
+        let mut hashes = vec![ForkHash::genesis(genesis)];
+        for block in &forks {
+            let last = *hashes.last().unwrap();
+            hashes.push(last.with_fork(*block));
+        }
+
+        Self {
+            forks,
+            hashes,
+            head,
+        }
+    }
+
+    pub fn set_head(&mut self, head: u64) {
+        self.head = head;
+    }
+
+    /// Number of forks passed at the current head.
+    fn passed(&self) -> usize {
+        self.forks.partition_point(|block| *block <= self.head)
+    }
+
+    /// Our fork ID at the current head.
+    pub fn current(&self) -> ForkId {
+        let passed = self.passed();
+        ForkId {
+            hash: self.hashes[passed],
+            next: self.forks.get(passed).copied().unwrap_or(0),
+        }
+    }
+
+    pub fn validate(&self, remote: ForkId) -> ForkIdValidation {
+        let passed = self.passed();
+
+        if remote.hash == self.hashes[passed] {
+            // Remote announces a fork we should have passed already, but did not.
+            return if remote.next != 0 && self.head >= remote.next {
+                ForkIdValidation::Incompatible
+            } else {
+                ForkIdValidation::Compatible
+            };
+        }
+
+        if let Some(i) = self.hashes[..passed]
+            .iter()
+            .position(|hash| *hash == remote.hash)
+        {
+            // Remote is syncing, but has to be aware of the fork that follows.
+            return if remote.next == self.forks[i] {
+                ForkIdValidation::Compatible
+            } else {
+                ForkIdValidation::Stale
+            };
+        }
+
+        if self.hashes[passed + 1..].contains(&remote.hash) {
+            // We are syncing, remote is ahead on the same chain.
+            return ForkIdValidation::Compatible;
+        }
+
+        ForkIdValidation::Incompatible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+    use secp256k1::SecretKey;
+
+    const MAINNET_GENESIS: H256 = H256(hex!(
+        "d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+    ));
+    const MAINNET_FORKS: [u64; 12] = [
+        1_150_000, 1_920_000, 2_463_000, 2_675_000, 4_370_000, 7_280_000, 9_069_000, 9_200_000,
+        12_244_000, 12_965_000, 13_773_000, 15_050_000,
+    ];
+
+    fn id(hash: u32, next: u64) -> ForkId {
+        ForkId {
+            hash: ForkHash(hash.to_be_bytes()),
+            next,
+        }
+    }
+
+    #[test]
+    fn mainnet_fork_ids() {
+        let mut filter = ForkFilter::new(MAINNET_GENESIS, 0, MAINNET_FORKS);
+        for (head, expected) in [
+            (0, id(0xfc64ec04, 1_150_000)),
+            (1_149_999, id(0xfc64ec04, 1_150_000)),
+            (1_150_000, id(0x97c2c34c, 1_920_000)),
+            (4_370_000, id(0xa00bc324, 7_280_000)),
+            (7_280_000, id(0x668db0af, 9_069_000)),
+            (12_244_000, id(0x0eb440f6, 12_965_000)),
+            (15_050_000, id(0xf0afd0e3, 0)),
+            (20_000_000, id(0xf0afd0e3, 0)),
+        ] {
+            filter.set_head(head);
+            assert_eq!(filter.current(), expected, "head {head}");
+        }
+    }
+
+    #[test]
+    fn mainnet_validation() {
+        // Local is between Petersburg and Istanbul.
+        let filter = ForkFilter::new(MAINNET_GENESIS, 7_987_396, MAINNET_FORKS);
+        for (remote, expected) in [
+            // Same fork, aware of the next one or not.
+            (id(0x668db0af, 9_069_000), ForkIdValidation::Compatible),
+            (id(0x668db0af, 0), ForkIdValidation::Compatible),
+            // Remote is on Byzantium and aware of Petersburg.
+            (id(0xa00bc324, 7_280_000), ForkIdValidation::Compatible),
+            // Remote is on Byzantium and not aware of Petersburg.
+            (id(0xa00bc324, 0), ForkIdValidation::Stale),
+            // Remote is on Spurious Dragon, aware of a wrong next fork.
+            (id(0x3edd5b10, 4_370_001), ForkIdValidation::Stale),
+            // Remote is ahead of us on Istanbul.
+            (id(0x879d6e30, 9_200_000), ForkIdValidation::Compatible),
+            // Remote announces a fork we passed without applying it.
+            (id(0x668db0af, 7_279_999), ForkIdValidation::Incompatible),
+            // Another chain.
+            (id(0x5cddc0e1, 0), ForkIdValidation::Incompatible),
+        ] {
+            assert_eq!(filter.validate(remote), expected, "{remote:?}");
+        }
+    }
+
+    #[test]
+    fn enr_entry() {
+        let fork_id = id(0xf0afd0e3, 0);
+
+        let mut data = Vec::new();
+        fork_id.encode(&mut data);
+        assert_eq!(data, hex!("c684f0afd0e380"));
+        assert_eq!(data.len(), fork_id.length());
+        assert_eq!(ForkId::decode(&mut &data[..]).unwrap(), fork_id);
+
+        let secret_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let enr = enr::EnrBuilder::new("v4")
+            .add_value(ENR_KEY, &EnrForkIdEntry(fork_id))
+            .build(&secret_key)
+            .unwrap();
+        assert_eq!(enr.fork_id().unwrap().unwrap(), fork_id);
+
+        let enr = enr::EnrBuilder::new("v4").build(&secret_key).unwrap();
+        assert!(enr.fork_id().is_none());
+
+        // Extensions after the fork ID are ignored.
+        let entry = hex!("c8c684f0afd0e38001");
+        assert_eq!(
+            EnrForkIdEntry::decode(&mut &entry[..]).unwrap(),
+            EnrForkIdEntry(fork_id)
+        );
+    }
+}
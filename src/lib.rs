@@ -11,6 +11,7 @@
 pub mod disc;
 pub mod ecies;
 pub mod errors;
+pub mod forkid;
 pub mod mac;
 pub mod node_filter;
 pub mod peer;
@@ -20,9 +21,9 @@ pub mod types;
 pub mod util;
 
 pub use disc::*;
-pub use peer::{DisconnectReason, PeerStream};
-pub use rlpx::{ListenOptions, Swarm, SwarmBuilder};
+pub use peer::{DisconnectDirection, DisconnectReason, PeerStream, SharedCapability};
+pub use rlpx::{DisconnectStats, ListenOptions, Swarm, SwarmBuilder};
 pub use types::{
-    CapabilityId, CapabilityInfo, CapabilityName, CapabilityServer, CapabilityVersion,
+    CapabilityId, CapabilityInfo, CapabilityName, CapabilityServer, CapabilityVersion, Enr,
     InboundEvent, Message, NodeRecord, OutboundEvent, PeerId,
 };
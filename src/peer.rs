@@ -8,18 +8,20 @@ use futures::{ready, Sink, SinkExt};
 use num_traits::*;
 use secp256k1::{PublicKey, SecretKey, SECP256K1};
 use std::{
+    collections::BTreeMap,
     fmt::Debug,
     io,
     pin::Pin,
     task::{Context, Poll},
 };
+use thiserror::Error;
 use tokio_stream::{Stream, StreamExt};
 use tracing::*;
 
 const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
 
 /// RLPx disconnect reason.
-#[derive(Clone, Copy, Debug, Display, Primitive)]
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Hash, Primitive)]
 pub enum DisconnectReason {
     #[display(fmt = "disconnect requested")]
     DisconnectRequested = 0x00,
@@ -49,6 +51,72 @@ pub enum DisconnectReason {
     SubprotocolSpecific = 0x10,
 }
 
+/// Whether a Disconnect message was sent by us or received from the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DisconnectDirection {
+    Sent,
+    Received,
+}
+
+/// Disconnect exchanged during the Hello handshake, which fails the connection attempt.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+#[error("disconnect {direction:?} in handshake: {reason}")]
+pub struct HandshakeDisconnect {
+    pub direction: DisconnectDirection,
+    pub reason: DisconnectReason,
+}
+
+/// RLPx Disconnect message, encoded as `[reason]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisconnectMessage {
+    pub reason: DisconnectReason,
+}
+
+impl Encodable for DisconnectMessage {
+    fn encode(&self, out: &mut dyn BufMut) {
+        let reason = self.reason as u8;
+        Header {
+            list: true,
+            payload_length: reason.length(),
+        }
+        .encode(out);
+        reason.encode(out);
+    }
+
+    fn length(&self) -> usize {
+        let payload_length = (self.reason as u8).length();
+        length_of_length(payload_length) + payload_length
+    }
+}
+
+impl Decodable for DisconnectMessage {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        // Some clients send the reason as a bare byte instead of a single-element list.
+        let reason = if Header::decode(&mut &**buf)?.list {
+            let b = &mut &**buf;
+            let header = Header::decode(b)?;
+            let started_len = b.len();
+            let reason = u8::decode(b)?;
+            let consumed = started_len - b.len();
+            if consumed != header.payload_length {
+                return Err(DecodeError::ListLengthMismatch {
+                    expected: header.payload_length,
+                    got: consumed,
+                });
+            }
+            *buf = *b;
+            reason
+        } else {
+            u8::decode(buf)?
+        };
+
+        Ok(Self {
+            reason: DisconnectReason::from_u8(reason)
+                .ok_or(DecodeError::Custom("unknown disconnect reason"))?,
+        })
+    }
+}
+
 /// RLPx protocol version.
 #[derive(Copy, Clone, Debug, Primitive)]
 pub enum ProtocolVersion {
@@ -70,6 +138,68 @@ pub struct HelloMessage {
     pub id: PeerId,
 }
 
+/// Capabilities supported by both sides, keeping only the highest common version of each.
+///
+/// The result is sorted by name, which defines message ID offsets: the first capability
+/// takes IDs starting right after the reserved ones, each next one follows the previous.
+pub fn shared_capabilities(
+    local: &[CapabilityInfo],
+    remote: &[CapabilityMessage],
+) -> Vec<CapabilityInfo> {
+    let mut shared = BTreeMap::<CapabilityName, CapabilityInfo>::new();
+    for cap in local {
+        if remote
+            .iter()
+            .any(|v| v.name == cap.name && v.version == cap.version)
+        {
+            let entry = shared.entry(cap.name).or_insert(*cap);
+            if entry.version < cap.version {
+                *entry = *cap;
+            }
+        }
+    }
+
+    shared.into_values().collect()
+}
+
+/// Find the capability that an incoming message ID (with reserved IDs already subtracted)
+/// belongs to, and the ID of the message within that capability.
+fn capability_for_message_id(
+    shared_capabilities: &[CapabilityInfo],
+    mut message_id: usize,
+) -> Option<(CapabilityInfo, usize)> {
+    for cap in shared_capabilities {
+        if message_id < cap.length {
+            return Some((*cap, message_id));
+        }
+        message_id -= cap.length;
+    }
+
+    None
+}
+
+/// Capability shared with the peer, along with the message IDs it takes on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SharedCapability {
+    pub info: CapabilityInfo,
+    /// Wire ID of the first message of the capability: past the reserved IDs and the
+    /// capabilities before it, see [`shared_capabilities`].
+    pub offset: usize,
+}
+
+/// Message ID offsets of `shared_capabilities`, in the order they are given.
+fn with_offsets(shared_capabilities: &[CapabilityInfo]) -> Vec<SharedCapability> {
+    let mut offset = 0x10;
+    shared_capabilities
+        .iter()
+        .map(|&info| {
+            let cap = SharedCapability { info, offset };
+            offset += info.length;
+            cap
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 struct Snappy {
     encoder: snap::raw::Encoder,
@@ -85,6 +215,36 @@ impl Default for Snappy {
     }
 }
 
+impl Snappy {
+    /// Decompress a message body, refusing to inflate it beyond [`MAX_PAYLOAD_SIZE`] before
+    /// allocating anything.
+    fn decompress(&mut self, input: &[u8]) -> io::Result<Bytes> {
+        let payload_len = snap::raw::decompress_len(input)?;
+        if payload_len > MAX_PAYLOAD_SIZE {
+            return Err(payload_too_big(payload_len));
+        }
+        Ok(Bytes::from(self.decoder.decompress_vec(input)?))
+    }
+
+    fn compress_into(&mut self, payload: &[u8], out: &mut BytesMut) {
+        let mut buf = out.split_off(out.len());
+        buf.resize(snap::raw::max_compress_len(payload.len()), 0);
+        let compressed_len = self.encoder.compress(payload, &mut buf).unwrap();
+        buf.truncate(compressed_len);
+        out.unsplit(buf);
+    }
+}
+
+fn payload_too_big(len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "payload size ({}) exceeds limit ({} bytes)",
+            len, MAX_PAYLOAD_SIZE
+        ),
+    )
+}
+
 /// RLPx transport peer stream
 #[allow(unused)]
 #[derive(Debug)]
@@ -92,11 +252,14 @@ pub struct PeerStream<Io> {
     stream: ECIESStream<Io>,
     client_version: String,
     shared_capabilities: Vec<CapabilityInfo>,
+    negotiated_capabilities: Vec<SharedCapability>,
     port: u16,
     id: PeerId,
     remote_id: PeerId,
 
-    snappy: Snappy,
+    /// Message bodies are compressed from protocol version 5 of the peer on, see
+    /// [`ProtocolVersion::V5`].
+    snappy: Option<Snappy>,
 
     disconnected: bool,
 }
@@ -115,6 +278,19 @@ where
         &self.shared_capabilities
     }
 
+    /// Capabilities negotiated in Hello along with their message ID offsets, in wire order.
+    pub fn negotiated_capabilities(&self) -> &[SharedCapability] {
+        &self.negotiated_capabilities
+    }
+
+    /// Whether `version` of the capability `name` was negotiated, e.g. `eth/67`. Only the
+    /// highest version shared is negotiated for each capability.
+    pub fn supports(&self, name: &str, version: CapabilityVersion) -> bool {
+        self.shared_capabilities
+            .iter()
+            .any(|cap| cap.name.0.as_str() == name && cap.version == version)
+    }
+
     /// Connect to a peer over TCP
     pub async fn connect(
         transport: Io,
@@ -163,7 +339,6 @@ where
     ) -> anyhow::Result<Self> {
         let public_key = PublicKey::from_secret_key(SECP256K1, &secret_key);
         let id = pk2id(&public_key);
-        let nonhello_capabilities = capabilities.clone();
         let nonhello_client_version = client_version.clone();
 
         debug!("Connecting to RLPx peer {:02x}", transport.remote_id());
@@ -205,15 +380,16 @@ where
         let payload = &mut &hello[1..];
         match message_id {
             0 => {}
-            1 => {
-                let reason = u8::decode(payload).map(DisconnectReason::from_u8)?;
-                bail!(
-                    "explicit disconnect: {}",
-                    reason
-                        .map(|r| r.to_string())
-                        .unwrap_or_else(|| "(unknown)".to_string())
-                );
-            }
+            1 => match DisconnectMessage::decode(payload) {
+                Ok(DisconnectMessage { reason }) => {
+                    return Err(HandshakeDisconnect {
+                        direction: DisconnectDirection::Received,
+                        reason,
+                    }
+                    .into())
+                }
+                Err(_) => bail!("explicit disconnect: (unknown)"),
+            },
             _ => {
                 bail!(
                     "Hello failed because message id is not 0 but {}: {:02x?}",
@@ -225,27 +401,7 @@ where
 
         let val = HelloMessage::decode(payload).context("hello failed (rlp)")?;
         debug!("hello message: {:?}", val);
-        let mut shared_capabilities: Vec<CapabilityInfo> = Vec::new();
-
-        for cap_info in nonhello_capabilities {
-            let cap_match = val
-                .capabilities
-                .iter()
-                .any(|v| v.name == cap_info.name && v.version == cap_info.version);
-
-            if cap_match {
-                shared_capabilities.push(cap_info);
-            }
-        }
-
-        let shared_caps_original = shared_capabilities.clone();
-
-        for cap_info in shared_caps_original {
-            shared_capabilities
-                .retain(|v| v.name != cap_info.name || v.version >= cap_info.version);
-        }
-
-        shared_capabilities.sort_by_key(|v| v.name);
+        let shared_capabilities = shared_capabilities(&capabilities, &val.capabilities);
 
         let no_shared_caps = shared_capabilities.is_empty();
 
@@ -255,8 +411,9 @@ where
             client_version: nonhello_client_version,
             port,
             id,
+            negotiated_capabilities: with_offsets(&shared_capabilities),
             shared_capabilities,
-            snappy: Snappy::default(),
+            snappy: (val.protocol_version >= ProtocolVersion::V5 as usize).then(Snappy::default),
             disconnected: false,
         };
 
@@ -266,11 +423,14 @@ where
                 .send(PeerMessage::Disconnect(DisconnectReason::UselessPeer))
                 .await;
 
-            bail!(
+            return Err(anyhow::Error::new(HandshakeDisconnect {
+                direction: DisconnectDirection::Sent,
+                reason: DisconnectReason::UselessPeer,
+            })
+            .context(format!(
                 "Handshake failed - no shared capabilities (our: {:?}, their: {:?})",
-                capabilities,
-                val.capabilities
-            );
+                capabilities, val.capabilities
+            )));
         }
 
         Ok(this)
@@ -312,26 +472,24 @@ where
                 let (cap, id, data) = match u8::decode(&mut &val[..1]) {
                     Ok(message_id) => {
                         let input = &val[1..];
-                        let payload_len = snap::raw::decompress_len(input)?;
-                        if payload_len > MAX_PAYLOAD_SIZE {
-                            return Poll::Ready(Some(Err(io::Error::new(
-                                io::ErrorKind::InvalidInput,
-                                format!(
-                                    "payload size ({}) exceeds limit ({} bytes)",
-                                    payload_len, MAX_PAYLOAD_SIZE
-                                ),
-                            ))));
-                        }
-                        let data = Bytes::from(s.snappy.decoder.decompress_vec(input)?);
-                        trace!("Decompressed raw message data: {}", hex::encode(&data));
+                        let data = match &mut s.snappy {
+                            Some(snappy) => {
+                                let data = snappy.decompress(input)?;
+                                trace!("Decompressed raw message data: {}", hex::encode(&data));
+                                data
+                            }
+                            None if input.len() > MAX_PAYLOAD_SIZE => {
+                                return Poll::Ready(Some(Err(payload_too_big(input.len()))));
+                            }
+                            None => val.slice(1..),
+                        };
 
                         if message_id < 0x10 {
                             match message_id {
                                 0x01 => {
                                     s.disconnected = true;
-                                    if let Some(reason) = u8::decode(&mut &*data)
-                                        .ok()
-                                        .and_then(DisconnectReason::from_u8)
+                                    if let Ok(DisconnectMessage { reason }) =
+                                        DisconnectMessage::decode(&mut &*data)
                                     {
                                         return Poll::Ready(Some(Ok(PeerMessage::Disconnect(
                                             reason,
@@ -364,21 +522,18 @@ where
                             }
                         }
 
-                        let mut message_id = message_id as usize - 0x10;
-                        let mut index = 0;
-                        for cap in &s.shared_capabilities {
-                            if message_id > cap.length {
-                                message_id -= cap.length;
-                                index += 1;
+                        match capability_for_message_id(
+                            &s.shared_capabilities,
+                            message_id as usize - 0x10,
+                        ) {
+                            Some((cap, id)) => (cap, id, data),
+                            None => {
+                                return Poll::Ready(Some(Err(io::Error::new(
+                                    io::ErrorKind::Other,
+                                    "invalid message id (out of cap range)",
+                                ))));
                             }
                         }
-                        if index >= s.shared_capabilities.len() {
-                            return Poll::Ready(Some(Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                "invalid message id (out of cap range)",
-                            ))));
-                        }
-                        (s.shared_capabilities[index], message_id, data)
                     }
                     Err(e) => {
                         return Poll::Ready(Some(Err(io::Error::new(
@@ -429,12 +584,9 @@ where
         let (message_id, payload) = match message {
             PeerMessage::Disconnect(reason) => {
                 this.disconnected = true;
-                (
-                    0x01,
-                    fastrlp::encode_fixed_size(&reason.to_u8().unwrap())
-                        .to_vec()
-                        .into(),
-                )
+                let mut payload = BytesMut::new();
+                DisconnectMessage { reason }.encode(&mut payload);
+                (0x01, payload.freeze())
             }
             PeerMessage::Ping => {
                 debug!("sending ping message");
@@ -446,10 +598,10 @@ where
             }
             PeerMessage::Subprotocol(SubprotocolMessage { cap_name, message }) => {
                 let Message { id, data } = message;
-                let cap = *this
-                    .shared_capabilities
+                let SharedCapability { info: cap, offset } = *this
+                    .negotiated_capabilities
                     .iter()
-                    .find(|cap| cap.name == cap_name)
+                    .find(|cap| cap.info.name == cap_name)
                     .unwrap_or_else(|| {
                         panic!(
                             "attempted to send payload of unsupported capability ({}/{}/{})",
@@ -467,42 +619,20 @@ where
                     this.remote_id()
                 );
 
-                let mut message_id = 0x10;
-                for scap in &this.shared_capabilities {
-                    if scap == &cap {
-                        break;
-                    }
-
-                    message_id += scap.length;
-                }
-                message_id += id;
-
-                (message_id, data)
+                (offset + id, data)
             }
         };
 
-        let mut msg = BytesMut::with_capacity(2 + payload.len());
-        message_id.encode(&mut msg);
-
-        let mut buf = msg.split_off(msg.len());
-
         if payload.len() > MAX_PAYLOAD_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!(
-                    "payload size ({}) exceeds limit ({} bytes)",
-                    payload.len(),
-                    MAX_PAYLOAD_SIZE
-                ),
-            ));
+            return Err(payload_too_big(payload.len()));
         }
 
-        buf.resize(snap::raw::max_compress_len(payload.len()), 0);
-
-        let compressed_len = this.snappy.encoder.compress(&*payload, &mut buf).unwrap();
-        buf.truncate(compressed_len);
-
-        msg.unsplit(buf);
+        let mut msg = BytesMut::with_capacity(2 + payload.len());
+        message_id.encode(&mut msg);
+        match &mut this.snappy {
+            Some(snappy) => snappy.compress_into(&payload, &mut msg),
+            None => msg.extend_from_slice(&payload),
+        }
 
         Pin::new(&mut this.stream).start_send(msg.freeze())?;
 
@@ -517,3 +647,119 @@ where
         Pin::new(&mut self.get_mut().stream).poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrayvec::ArrayString;
+
+    fn cap(name: &str, version: usize, length: usize) -> CapabilityInfo {
+        CapabilityInfo {
+            name: CapabilityName(ArrayString::from(name).unwrap()),
+            version,
+            length,
+        }
+    }
+
+    fn remote(caps: &[(&str, usize)]) -> Vec<CapabilityMessage> {
+        caps.iter()
+            .map(|&(name, version)| CapabilityMessage {
+                name: CapabilityName(ArrayString::from(name).unwrap()),
+                version,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shared_capabilities_highest_common_version() {
+        let local = [cap("snap", 1, 8), cap("eth", 67, 17), cap("eth", 66, 17)];
+
+        assert_eq!(
+            shared_capabilities(&local, &remote(&[("eth", 65), ("eth", 66), ("snap", 1)])),
+            vec![cap("eth", 66, 17), cap("snap", 1, 8)]
+        );
+        assert_eq!(
+            shared_capabilities(&local, &remote(&[("eth", 66), ("eth", 67)])),
+            vec![cap("eth", 67, 17)]
+        );
+        assert!(shared_capabilities(&local, &remote(&[("les", 4), ("eth", 65)])).is_empty());
+    }
+
+    #[test]
+    fn disconnect_message() {
+        let message = DisconnectMessage {
+            reason: DisconnectReason::TooManyPeers,
+        };
+
+        let mut encoded = Vec::new();
+        message.encode(&mut encoded);
+        assert_eq!(encoded, [0xc1, 0x04]);
+        assert_eq!(encoded.len(), message.length());
+
+        for encoded in [&[0xc1, 0x04][..], &[0x04][..]] {
+            let buf = &mut &*encoded;
+            assert_eq!(DisconnectMessage::decode(buf).unwrap(), message);
+            assert!(buf.is_empty());
+        }
+
+        // Disconnect requested is encoded as an empty string, rather than a zero byte.
+        assert_eq!(
+            DisconnectMessage::decode(&mut &[0xc1, 0x80][..]).unwrap(),
+            DisconnectMessage {
+                reason: DisconnectReason::DisconnectRequested
+            }
+        );
+        assert!(DisconnectMessage::decode(&mut &[0xc1, 0x42][..]).is_err());
+        assert!(DisconnectMessage::decode(&mut &[0xc2, 0x04, 0x04][..]).is_err());
+    }
+
+    #[test]
+    fn snappy_roundtrip() {
+        let mut snappy = Snappy::default();
+        let payload = [0x42; 1024];
+        let mut frame = BytesMut::from(&[0x10][..]);
+        snappy.compress_into(&payload, &mut frame);
+        assert_eq!(frame[0], 0x10);
+        assert!(frame.len() < payload.len());
+        assert_eq!(&snappy.decompress(&frame[1..]).unwrap()[..], &payload[..]);
+
+        // Claims to inflate to one byte over the limit: 2^24 + 1 as varint.
+        let bomb = [0x81, 0x80, 0x80, 0x08, 0x00];
+        assert_eq!(
+            snap::raw::decompress_len(&bomb).unwrap(),
+            MAX_PAYLOAD_SIZE + 1
+        );
+        assert_eq!(
+            snappy.decompress(&bomb).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn message_id_offsets() {
+        let shared = [cap("eth", 66, 17), cap("snap", 1, 8)];
+
+        assert_eq!(capability_for_message_id(&shared, 0), Some((shared[0], 0)));
+        assert_eq!(
+            capability_for_message_id(&shared, 16),
+            Some((shared[0], 16))
+        );
+        assert_eq!(capability_for_message_id(&shared, 17), Some((shared[1], 0)));
+        assert_eq!(capability_for_message_id(&shared, 24), Some((shared[1], 7)));
+        assert_eq!(capability_for_message_id(&shared, 25), None);
+
+        assert_eq!(
+            with_offsets(&shared),
+            vec![
+                SharedCapability {
+                    info: shared[0],
+                    offset: 0x10
+                },
+                SharedCapability {
+                    info: shared[1],
+                    offset: 0x21
+                },
+            ]
+        );
+    }
+}
@@ -61,6 +61,50 @@ struct DisconnectSignal {
     reason: DisconnectReason,
 }
 
+/// Tally of the reasons in Disconnect messages sent and received, including the ones
+/// exchanged during the handshake, see [`Swarm::disconnect_stats`].
+///
+/// With the `metrics` feature, they are reported as the `rlpx_disconnects_total` counter too.
+#[derive(Debug, Default)]
+pub struct DisconnectStats {
+    counts: Mutex<HashMap<(DisconnectDirection, DisconnectReason), u64>>,
+}
+
+impl DisconnectStats {
+    fn record(&self, direction: DisconnectDirection, reason: DisconnectReason) {
+        *self.counts.lock().entry((direction, reason)).or_default() += 1;
+        #[cfg(feature = "metrics")]
+        ::metrics::increment_counter!(
+            "rlpx_disconnects_total",
+            "direction" => match direction {
+                DisconnectDirection::Sent => "sent",
+                DisconnectDirection::Received => "received",
+            },
+            "reason" => format!("{reason:?}"),
+        );
+    }
+
+    /// Record the disconnect that failed the handshake with `error`, if there was one.
+    fn record_handshake(&self, error: &anyhow::Error) {
+        if let Some(HandshakeDisconnect { direction, reason }) = error.downcast_ref() {
+            self.record(*direction, *reason);
+        }
+    }
+
+    pub fn get(&self, direction: DisconnectDirection, reason: DisconnectReason) -> u64 {
+        self.counts
+            .lock()
+            .get(&(direction, reason))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// All of the reasons seen so far.
+    pub fn snapshot(&self) -> HashMap<(DisconnectDirection, DisconnectReason), u64> {
+        self.counts.lock().clone()
+    }
+}
+
 #[derive(Debug)]
 struct ConnectedPeerState {
     _tasks: TaskGroup,
@@ -112,6 +156,7 @@ struct PeerStreamHandshakeData<C> {
     client_version: String,
     capabilities: Arc<CapabilitySet>,
     capability_server: Arc<C>,
+    disconnect_stats: Arc<DisconnectStats>,
 }
 
 async fn handle_incoming<TS, C>(
@@ -155,6 +200,7 @@ async fn handle_incoming<TS, C>(
 fn setup_peer_state<C, Io>(
     streams: Weak<Mutex<PeerStreams>>,
     capability_server: Arc<C>,
+    disconnect_stats: Arc<DisconnectStats>,
     remote_id: PeerId,
     peer: PeerStream<Io>,
 ) -> ConnectedPeerState
@@ -182,6 +228,7 @@ where
     tasks.spawn_with_name(format!("peer {} ingress router", remote_id), {
         let peer_disconnect_tx = peer_disconnect_tx;
         let capability_server = capability_server.clone();
+        let disconnect_stats = disconnect_stats.clone();
         let pinged = pinged.clone();
         async move {
             let disconnect_signal = {
@@ -209,6 +256,7 @@ where
                             }
                             Ok(PeerMessage::Disconnect(reason)) => {
                                 // Peer has requested disconnection.
+                                disconnect_stats.record(DisconnectDirection::Received, reason);
                                 return DisconnectSignal {
                                     initiator: DisconnectInitiator::Remote,
                                     reason,
@@ -290,6 +338,10 @@ where
 
                 if let Some((message, trigger)) = egress {
                     trace!("Sending message: {:?}", message);
+                    let sent_disconnect = match &message {
+                        PeerMessage::Disconnect(reason) => Some(*reason),
+                        _ => None,
+                    };
 
                     // Send egress message, force disconnect on error.
                     if let Err(e) = sink.send(message).await {
@@ -298,6 +350,8 @@ where
                             initiator: DisconnectInitiator::LocalForceful,
                             reason: DisconnectReason::TcpSubsystemError,
                         });
+                    } else if let Some(reason) = sent_disconnect {
+                        disconnect_stats.record(DisconnectDirection::Sent, reason);
                     } else if let Some(trigger) = trigger {
                         // Reason for signal in trigger:
                         // We don't want to timeout peer if our TCP socket is too slow
@@ -370,6 +424,7 @@ async fn handle_incoming_request<C, Io>(
         client_version,
         capabilities,
         capability_server,
+        disconnect_stats,
         port,
     } = handshake_data;
     // Do handshake and convert incoming connection into stream.
@@ -415,6 +470,7 @@ async fn handle_incoming_request<C, Io>(
                                 connection_state: PeerConnectionState::Connected(setup_peer_state(
                                     Arc::downgrade(&streams),
                                     capability_server,
+                                    disconnect_stats,
                                     remote_id,
                                     peer,
                                 )),
@@ -429,6 +485,7 @@ async fn handle_incoming_request<C, Io>(
         }
         Err(e) => {
             debug!("Peer disconnected with error {}", e);
+            disconnect_stats.record_handshake(&e);
         }
     }
 }
@@ -486,6 +543,8 @@ pub struct Swarm<C: CapabilityServer> {
     #[educe(Debug(ignore))]
     capability_server: Arc<C>,
 
+    disconnect_stats: Arc<DisconnectStats>,
+
     #[educe(Debug(ignore))]
     secret_key: SecretKey,
     client_version: String,
@@ -612,6 +671,7 @@ impl<C: CapabilityServer> Swarm<C> {
         ))));
 
         let capabilities = Arc::new(capabilities);
+        let disconnect_stats = Arc::<DisconnectStats>::default();
 
         if let Some(options) = &listen_options {
             let tcp_incoming = TcpListener::bind(options.addr)
@@ -625,6 +685,7 @@ impl<C: CapabilityServer> Swarm<C> {
                     client_version: client_version.clone(),
                     capabilities: capabilities.clone(),
                     capability_server: capability_server.clone(),
+                    disconnect_stats: disconnect_stats.clone(),
                 };
 
                 handle_incoming(
@@ -644,6 +705,7 @@ impl<C: CapabilityServer> Swarm<C> {
             node_filter,
             capabilities,
             capability_server,
+            disconnect_stats,
             secret_key,
             client_version,
             port,
@@ -673,6 +735,10 @@ impl<C: CapabilityServer> Swarm<C> {
                                                     debug!("Failed to get new peer: {e} ({disc_id})")
                                                 }
                                                 Some((disc_id, Ok(NodeRecord { id, addr }))) => {
+                                                    if addr.port() == 0 {
+                                                        debug!("Skipping non-dialable peer ({id}, {disc_id})");
+                                                        continue;
+                                                    }
                                                     let now = Instant::now();
                                                     if let Some(banned_timestamp) =
                                                         banlist.lock().get_mut(&id).copied()
@@ -746,6 +812,7 @@ impl<C: CapabilityServer> Swarm<C> {
 
         let capability_set = self.capabilities.get_capabilities().to_vec();
         let capability_server = self.capability_server.clone();
+        let disconnect_stats = self.disconnect_stats.clone();
 
         let secret_key = self.secret_key;
         let client_version = self.client_version.clone();
@@ -845,6 +912,9 @@ impl<C: CapabilityServer> Swarm<C> {
                 .await
             }
             .await;
+            if let Err(e) = &peer_res {
+                disconnect_stats.record_handshake(e);
+            }
 
             let streams = streams.clone();
             let mut streams_guard = streams.lock();
@@ -862,6 +932,7 @@ impl<C: CapabilityServer> Swarm<C> {
                                 PeerConnectionState::Connected(setup_peer_state(
                                     Arc::downgrade(&streams),
                                     capability_server,
+                                    disconnect_stats,
                                     remote_id,
                                     peer,
                                 ));
@@ -897,6 +968,11 @@ impl<C: CapabilityServer> Swarm<C> {
     pub fn num_peers(&self) -> usize {
         self.streams.lock().mapping.len()
     }
+
+    /// Disconnect reasons sent to and received from peers so far.
+    pub fn disconnect_stats(&self) -> &DisconnectStats {
+        &self.disconnect_stats
+    }
 }
 
 impl<C: CapabilityServer> Deref for Swarm<C> {
@@ -906,3 +982,43 @@ impl<C: CapabilityServer> Deref for Swarm<C> {
         &*self.capability_server
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disconnect_stats_by_direction() {
+        let stats = DisconnectStats::default();
+        stats.record(
+            DisconnectDirection::Received,
+            DisconnectReason::TooManyPeers,
+        );
+        stats.record(
+            DisconnectDirection::Received,
+            DisconnectReason::TooManyPeers,
+        );
+        stats.record_handshake(&anyhow::Error::new(HandshakeDisconnect {
+            direction: DisconnectDirection::Sent,
+            reason: DisconnectReason::UselessPeer,
+        }));
+        stats.record_handshake(&anyhow!("connection refused"));
+
+        assert_eq!(
+            stats.get(
+                DisconnectDirection::Received,
+                DisconnectReason::TooManyPeers
+            ),
+            2
+        );
+        assert_eq!(
+            stats.get(DisconnectDirection::Sent, DisconnectReason::UselessPeer),
+            1
+        );
+        assert_eq!(
+            stats.get(DisconnectDirection::Sent, DisconnectReason::TooManyPeers),
+            0
+        );
+        assert_eq!(stats.snapshot().len(), 2);
+    }
+}
@@ -1,4 +1,7 @@
-use crate::{peer::DisconnectReason, util::hex_debug};
+use crate::{
+    peer::DisconnectReason,
+    util::{hex_debug, short_id_debug},
+};
 use arrayvec::ArrayString;
 use async_trait::async_trait;
 use auto_impl::auto_impl;
@@ -9,10 +12,15 @@ pub use ethereum_types::H512 as PeerId;
 use fastrlp::*;
 use std::{collections::HashMap, fmt::Debug, future::pending, net::SocketAddr, str::FromStr};
 
+/// Ethereum Node Record (EIP-778) signed using the `v4` identity scheme.
+pub type Enr = enr::Enr<secp256k1::SecretKey>;
+
 /// Record that specifies information necessary to connect to RLPx node
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Educe)]
+#[educe(Debug)]
 pub struct NodeRecord {
     /// Node ID.
+    #[educe(Debug(method = "short_id_debug"))]
     pub id: PeerId,
     /// Address of RLPx TCP server.
     pub addr: SocketAddr,
@@ -149,3 +157,58 @@ pub struct Message {
     #[educe(Debug(method = "hex_debug"))]
     pub data: Bytes,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+    use std::net::Ipv4Addr;
+
+    /// Example record from EIP-778.
+    const TEST_ENR: &str = "enr:-IS4QHCYrYZbAKWCBRlAy5zzaDZXJBGkcnh4MHcBFZntXNFrdvJjX04jRzjzCBOonrkTfj499SZuOh8R33Ls8RRcy5wBgmlkgnY0gmlwhH8AAAGJc2VjcDI1NmsxoQPKY0yuDUmstAHYpMa2_oxVtw0RW_QAdpzBQA8yWM0xOIN1ZHCCdl8";
+
+    #[test]
+    fn enr_test_vector() {
+        let enr: Enr = TEST_ENR.parse().unwrap();
+
+        assert!(enr.verify());
+        assert_eq!(enr.seq(), 1);
+        assert_eq!(enr.id().as_deref(), Some("v4"));
+        assert_eq!(enr.ip4(), Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(enr.udp4(), Some(30303));
+        assert_eq!(enr.tcp4(), None);
+        assert_eq!(
+            enr.public_key().serialize(),
+            hex!("03ca634cae0d49acb401d8a4c6b6fe8c55b70d115bf400769cc1400f3258cd3138")
+        );
+    }
+
+    #[test]
+    fn enr_text_roundtrip() {
+        let enr: Enr = TEST_ENR.parse().unwrap();
+        assert_eq!(enr.to_base64(), TEST_ENR);
+
+        let key = secp256k1::SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let enr = enr::EnrBuilder::new("v4")
+            .ip4(Ipv4Addr::new(10, 0, 0, 1))
+            .tcp4(30303)
+            .udp4(30301)
+            .build(&key)
+            .unwrap();
+        let decoded: Enr = enr.to_base64().parse().unwrap();
+        assert_eq!(decoded.to_base64(), enr.to_base64());
+        assert_eq!(decoded.tcp4(), Some(30303));
+        assert_eq!(decoded.udp4(), Some(30301));
+    }
+
+    #[test]
+    fn enr_text_invalid() {
+        // Malformed base64
+        assert!("enr:-IS4Q$%^".parse::<Enr>().is_err());
+        // Truncated record
+        assert!(TEST_ENR[..TEST_ENR.len() - 4].parse::<Enr>().is_err());
+        // Signature changed, so it does not match the content anymore
+        let tampered = format!("{}D{}", &TEST_ENR[..10], &TEST_ENR[11..]);
+        assert!(tampered.parse::<Enr>().is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use super::types::*;
 use ethereum_types::H256;
+use fastrlp::{DecodeError, Header};
 use hmac::{Hmac, Mac};
 use secp256k1::PublicKey;
 use sha2::Sha256;
@@ -34,10 +35,61 @@ pub fn id2pk(id: PeerId) -> Result<PublicKey, secp256k1::Error> {
     PublicKey::from_slice(&s)
 }
 
+/// Decode ENR from the front of the buffer, advancing it past the record.
+///
+/// ENR is encoded and signed by the `enr` crate, so hand the whole record list over to it.
+pub fn decode_enr(buf: &mut &[u8]) -> Result<Enr, DecodeError> {
+    let enr_len = {
+        let rest = &mut &**buf;
+        let enr_header = Header::decode(rest)?;
+        if !enr_header.list {
+            return Err(DecodeError::UnexpectedString);
+        }
+        buf.len() - rest.len() + enr_header.payload_length
+    };
+    if buf.len() < enr_len {
+        return Err(DecodeError::InputTooShort);
+    }
+    let enr = rlp::decode::<Enr>(&buf[..enr_len]).map_err(|e| {
+        tracing::debug!("invalid ENR: {e}");
+        DecodeError::Custom("invalid ENR")
+    })?;
+    *buf = &buf[enr_len..];
+
+    Ok(enr)
+}
+
+/// Conversions between a 64-byte node ID and the secp256k1 public key it encodes,
+/// which is the uncompressed key without the `0x04` prefix.
+pub trait NodeIdExt: Sized {
+    fn from_public_key(public_key: &PublicKey) -> Self;
+
+    /// Recover the public key, failing if the ID is not a valid curve point.
+    fn to_public_key(&self) -> Result<PublicKey, secp256k1::Error>;
+}
+
+impl NodeIdExt for PeerId {
+    fn from_public_key(public_key: &PublicKey) -> Self {
+        pk2id(public_key)
+    }
+
+    fn to_public_key(&self) -> Result<PublicKey, secp256k1::Error> {
+        id2pk(*self)
+    }
+}
+
 pub fn hex_debug<T: AsRef<[u8]>>(s: &T, f: &mut Formatter) -> fmt::Result {
     f.write_str(&hex::encode(&s))
 }
 
+/// Abbreviated `0x1a2b…eff0` form of the ID, for `Debug` of types that are logged often.
+///
+/// Node IDs are [`PeerId`]s, whose `Display` is abbreviated the same way, while `Debug` and
+/// `LowerHex` print all 64 bytes.
+pub fn short_id_debug(id: &PeerId, f: &mut Formatter) -> fmt::Result {
+    fmt::Display::fmt(id, f)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -49,4 +101,39 @@ mod tests {
         let pubkey = PublicKey::from_secret_key(SECP256K1, &prikey);
         assert_eq!(pubkey, id2pk(pk2id(&pubkey)).unwrap());
     }
+
+    #[test]
+    fn node_id_public_key_conversion() {
+        let public_key = PublicKey::from_secret_key(
+            SECP256K1,
+            &SecretKey::new(&mut secp256k1::rand::thread_rng()),
+        );
+        let id = PeerId::from_public_key(&public_key);
+        assert_eq!(id.as_bytes(), &public_key.serialize_uncompressed()[1..],);
+        assert_eq!(id.to_public_key().unwrap(), public_key);
+
+        // Not on the curve
+        assert!(PeerId::zero().to_public_key().is_err());
+        assert!(PeerId::repeat_byte(0xff).to_public_key().is_err());
+    }
+
+    #[test]
+    fn node_id_formatting() {
+        let mut bytes = [0; 64];
+        bytes[..3].copy_from_slice(&[0x1a, 0x2b, 0x3c]);
+        bytes[62..].copy_from_slice(&[0xef, 0xf0]);
+        let id = PeerId::from(bytes);
+
+        assert_eq!(id.to_string(), "0x1a2b…eff0");
+        assert_eq!(format!("{:x}", id), hex::encode(bytes));
+        assert_eq!(format!("{:#x}", id), format!("0x{}", hex::encode(bytes)));
+
+        struct Short(PeerId);
+        impl fmt::Debug for Short {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                short_id_debug(&self.0, f)
+            }
+        }
+        assert_eq!(format!("{:?}", Short(id)), "0x1a2b…eff0");
+    }
 }